@@ -0,0 +1,296 @@
+use futures_util::future::{self, BoxFuture};
+use relay::{
+    client::{Client, Outbound, Priority, Statistics},
+    config::Config,
+    frame,
+    packets::{RequestPacket, ResponsePacket},
+    Server,
+};
+use std::{
+    hint::black_box,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{runtime::Runtime, sync::RwLock};
+use tungstenite::Message;
+
+const SAMPLES: usize = 20;
+const ITERATIONS: usize = 1000;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Default)]
+struct Sink {
+    bytes: AtomicUsize,
+    last_text: Mutex<Option<String>>,
+    statistics: Statistics,
+}
+
+impl Outbound for Sink {
+    fn send(&self, message: Message, _: Priority) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        self.bytes.fetch_add(message.len(), Ordering::Relaxed);
+
+        if let Message::Text(text) = message {
+            *self.last_text.lock().unwrap() = Some(text);
+        }
+
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+}
+
+fn create_client() -> (Client, Arc<Sink>) {
+    let sink = Arc::new(Sink::default());
+    let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+
+    (
+        Client::new(connection_id, sink.clone(), address, vec![]),
+        sink,
+    )
+}
+
+fn text(packet: &str) -> Message {
+    Message::Text(packet.to_string())
+}
+
+async fn member_count(server: &Arc<RwLock<Server>>, room_id: &str) -> usize {
+    server
+        .read()
+        .await
+        .inspect_room(room_id)
+        .map_or(0, |room| room.members.len())
+}
+
+async fn create_room(
+    server: &Arc<RwLock<Server>>,
+    size: usize,
+    members: usize,
+) -> (Vec<Client>, Vec<Arc<Sink>>, String) {
+    let (mut owner, sink) = create_client();
+
+    owner
+        .handle_message(
+            server,
+            text(&format!(r#"{{"type":"create","size":{}}}"#, size)),
+        )
+        .await;
+
+    let last_text = sink.last_text.lock().unwrap().take().unwrap();
+    let room_id = match serde_json::from_str(&last_text).unwrap() {
+        ResponsePacket::Create { id, .. } => id,
+        unknown => panic!("unexpected packet: {:?}", unknown),
+    };
+
+    let mut clients = vec![owner];
+    let mut sinks = vec![sink];
+    for _ in 0..members {
+        let (mut client, sink) = create_client();
+
+        client.handle_message(server, join(&room_id)).await;
+        clients.push(client);
+        sinks.push(sink);
+    }
+
+    assert_eq!(
+        members + 1,
+        member_count(server, &room_id).await,
+        "every client should have joined the room"
+    );
+
+    for sink in &sinks {
+        sink.bytes.store(0, Ordering::Relaxed);
+    }
+
+    (clients, sinks, room_id)
+}
+
+fn assert_received(sinks: &[Arc<Sink>], frame: &[u8]) {
+    for sink in sinks {
+        assert!(
+            sink.bytes.load(Ordering::Relaxed) >= ITERATIONS * frame.len(),
+            "every recipient should have received the relayed frames"
+        );
+    }
+}
+
+fn join(room_id: &str) -> Message {
+    text(&format!(r#"{{"type":"join","id":"{}"}}"#, room_id))
+}
+
+fn bench(name: &str, operations: usize, mut run: impl FnMut() -> Duration) {
+    run();
+
+    let mut samples: Vec<f64> = (0..SAMPLES)
+        .map(|_| run().as_nanos() as f64 / operations as f64)
+        .collect();
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    println!(
+        "{:<24} median {:>10.1} ns/op   min {:>10.1} ns/op   max {:>10.1} ns/op",
+        name,
+        samples[SAMPLES / 2],
+        samples[0],
+        samples[SAMPLES - 1]
+    );
+}
+
+fn bench_unicast(runtime: &Runtime) {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut clients, sinks, _) = runtime.block_on(create_room(&server, 2, 1));
+    let frame = [vec![1], vec![0; 64]].concat();
+
+    bench("unicast", ITERATIONS, || {
+        runtime.block_on(async {
+            let start = Instant::now();
+
+            for _ in 0..ITERATIONS {
+                clients[0]
+                    .handle_message(&server, Message::Binary(black_box(frame.clone())))
+                    .await;
+            }
+
+            start.elapsed()
+        })
+    });
+
+    assert_received(&sinks[1..], &frame);
+}
+
+fn bench_broadcast(runtime: &Runtime) {
+    const MEMBERS: usize = 64;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut clients, sinks, _) = runtime.block_on(create_room(&server, MEMBERS + 1, MEMBERS));
+    let frame = [vec![u8::MAX], vec![0; 64]].concat();
+
+    bench("broadcast (64 members)", ITERATIONS, || {
+        runtime.block_on(async {
+            let start = Instant::now();
+
+            for _ in 0..ITERATIONS {
+                clients[0]
+                    .handle_message(&server, Message::Binary(black_box(frame.clone())))
+                    .await;
+            }
+
+            start.elapsed()
+        })
+    });
+
+    assert_received(&sinks[1..], &frame);
+}
+
+fn bench_join_leave(runtime: &Runtime) {
+    const TASKS: usize = 4;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (_clients, _, room_id) = runtime.block_on(create_room(&server, frame::MAX_ROOM_SIZE, 0));
+
+    runtime.block_on(async {
+        let (mut client, _) = create_client();
+
+        client.handle_message(&server, join(&room_id)).await;
+        assert_eq!(
+            2,
+            member_count(&server, &room_id).await,
+            "the join should succeed"
+        );
+
+        client
+            .handle_message(&server, text(r#"{"type":"leave"}"#))
+            .await;
+        assert_eq!(
+            1,
+            member_count(&server, &room_id).await,
+            "the leave should succeed"
+        );
+    });
+
+    bench("join/leave (4 tasks)", TASKS * ITERATIONS, || {
+        runtime.block_on(async {
+            let start = Instant::now();
+
+            let tasks: Vec<_> = (0..TASKS)
+                .map(|_| {
+                    let server = server.clone();
+                    let room_id = room_id.clone();
+
+                    tokio::spawn(async move {
+                        let (mut client, _) = create_client();
+
+                        for _ in 0..ITERATIONS {
+                            client.handle_message(&server, join(&room_id)).await;
+                            client
+                                .handle_message(&server, text(r#"{"type":"leave"}"#))
+                                .await;
+                        }
+                    })
+                })
+                .collect();
+
+            for task in tasks {
+                task.await.unwrap();
+            }
+
+            start.elapsed()
+        })
+    });
+}
+
+fn bench_packets() {
+    let create = r#"{"type":"create","size":4,"maxBandwidth":65536,"timestamps":true}"#;
+
+    bench("parse create packet", ITERATIONS, || {
+        let start = Instant::now();
+
+        for _ in 0..ITERATIONS {
+            black_box(serde_json::from_str::<RequestPacket>(black_box(create)).unwrap());
+        }
+
+        start.elapsed()
+    });
+
+    let join = ResponsePacket::Join {
+        size: Some(4),
+        capacity: Some(8),
+        index: Some(4),
+        address: None,
+        addresses: None,
+        connection_id: Some(4),
+        virtual_address: None,
+        virtual_addresses: None,
+    };
+
+    bench("serialize join packet", ITERATIONS, || {
+        let start = Instant::now();
+
+        for _ in 0..ITERATIONS {
+            black_box(serde_json::to_string(black_box(&join)).unwrap());
+        }
+
+        start.elapsed()
+    });
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+    let _guard = runtime.enter();
+
+    bench_unicast(&runtime);
+    bench_broadcast(&runtime);
+    bench_join_leave(&runtime);
+    bench_packets();
+}
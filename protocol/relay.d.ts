@@ -0,0 +1,371 @@
+// Generated by `cargo run -p relay-protocol --bin relay-typescript`, do not edit.
+
+export const BROADCAST = 255;
+export const OWNER = 254;
+export const EXCLUDE = 253;
+export const MAX_INDEX = 252;
+export const MAX_ROOM_SIZE = 253;
+export const MAX_VIRTUAL_ADDRESS_LENGTH = 8;
+export const TIMESTAMP_LENGTH = 8;
+export const BATCH_HEADER_LENGTH = 4;
+export const CHUNK = 255;
+export const CHUNK_HEADER_LENGTH = 10;
+
+export type ErrorCode = "InvalidSize" | "AlreadyExists" | "DoesNotExist" | "IsFull" | "NotInRoom" | "RateLimited" | "UnsupportedPacket" | "IsLocked" | "NotOwner" | "Denied" | "StoreFull" | "InvalidOptions" | "InvalidFrame" | "InvalidPacket" | "UnknownTemplate" | "Unauthorized" | "UnjoinedTimeout" | "OriginNotAllowed" | "InvalidVirtualAddress" | "VirtualAddressTaken" | "LimitExceeded";
+export type CloseReason = "expired" | "closed" | "maxDuration";
+export type DropReason = "RATE_LIMIT" | "RECEIVER_CONGESTED" | "EXPIRED" | "BUDGET_EXHAUSTED" | "RECEIVER_LIMIT";
+export type RemovalReason = "Left" | "Disconnected" | "TimedOut" | "Closed" | "Expired";
+export type Compression = "lz4";
+
+export interface RoomOptions {
+  congestionThreshold?: number;
+  maxBandwidth?: number;
+  shareAddresses?: boolean;
+  ttlSeconds?: number;
+  timestamps?: boolean;
+  openStore?: boolean;
+  opaque?: boolean;
+  frameTtlMs?: number;
+  roster?: boolean;
+  conflate?: boolean;
+  allowedOrigins?: string[];
+  compression?: Compression;
+  compressionThreshold?: number;
+  virtualAddresses?: boolean;
+  notifyMembers?: boolean;
+  paced?: boolean;
+  tickRate?: number;
+  maxPacedBytes?: number;
+}
+
+export interface MemberSummary {
+  index: number;
+  id: number;
+}
+
+export interface MemberStatistics {
+  index: number;
+  queuedBytes: number;
+  congestionSkips: number;
+  expiredFrames: number;
+  conflatedFrames: number;
+  invalidPackets: number;
+  oversizedFrames: number;
+}
+
+export interface QualityReport {
+  received: number;
+  lost: number;
+  jitterMs: number;
+}
+
+export interface MemberQuality {
+  index: number;
+  report?: QualityReport;
+  reportedAt?: number;
+  sendFailures: number;
+  droppedFrames: number;
+}
+
+export interface HelloRequest {
+  type: "hello";
+  maxMessageSize?: number;
+  chunkSize?: number;
+}
+
+export interface JoinRequest {
+  type: "join";
+  id: string;
+  token?: string;
+  reservation?: string;
+  virtualAddress?: string;
+}
+
+export interface ReserveRequest {
+  type: "reserve";
+  id: string;
+  count: number;
+}
+
+export interface UnreserveRequest {
+  type: "unreserve";
+  id: string;
+  reservation: string;
+}
+
+export interface CreateRequest extends RoomOptions {
+  type: "create";
+  size?: number | null;
+  template?: string | null;
+  virtualAddress?: string;
+}
+
+export interface LeaveRequest {
+  type: "leave";
+}
+
+export interface StatsRequest {
+  type: "stats";
+}
+
+export interface MyAddressRequest {
+  type: "myAddress";
+}
+
+export interface LockRequest {
+  type: "lock";
+}
+
+export interface UnlockRequest {
+  type: "unlock";
+}
+
+export interface InfoRequest {
+  type: "info";
+  id: string;
+}
+
+export interface SetRequest {
+  type: "set";
+  key: string;
+  value: unknown;
+}
+
+export interface GetRequest {
+  type: "get";
+  key: string;
+}
+
+export interface CloseRoomRequest {
+  type: "closeRoom";
+  reason?: string | null;
+}
+
+export interface MigrateRequest {
+  type: "migrate";
+  size?: number | null;
+}
+
+export interface SubscribeRequest {
+  type: "subscribe";
+  sources: number[];
+}
+
+export interface UnsubscribeRequest {
+  type: "unsubscribe";
+}
+
+export interface WatchOccupancyRequest {
+  type: "watchOccupancy";
+  enabled: boolean;
+}
+
+export interface QueueDepthsRequest {
+  type: "queueDepths";
+}
+
+export interface QualityReportRequest {
+  type: "qualityReport";
+  received: number;
+  lost: number;
+  jitterMs: number;
+}
+
+export interface QualityRequest {
+  type: "quality";
+}
+
+export type RequestPacket =
+  | HelloRequest
+  | JoinRequest
+  | ReserveRequest
+  | UnreserveRequest
+  | CreateRequest
+  | LeaveRequest
+  | StatsRequest
+  | MyAddressRequest
+  | LockRequest
+  | UnlockRequest
+  | InfoRequest
+  | SetRequest
+  | GetRequest
+  | CloseRoomRequest
+  | MigrateRequest
+  | SubscribeRequest
+  | UnsubscribeRequest
+  | WatchOccupancyRequest
+  | QueueDepthsRequest
+  | QualityReportRequest
+  | QualityRequest;
+
+export interface HelloResponse {
+  type: "hello";
+  version: string;
+  capabilities: string[];
+  maxRoomSize: number;
+  maxMessageSize: number;
+  connectionId: number;
+  chunkSize?: number;
+}
+
+export interface JoinResponse {
+  type: "join";
+  size?: number;
+  capacity?: number;
+  index?: number;
+  address?: string;
+  addresses?: string[];
+  connectionId?: number;
+  virtualAddress?: string;
+  virtualAddresses?: string[];
+}
+
+export interface CreateResponse {
+  type: "create";
+  id: string;
+  size: number;
+  options: RoomOptions;
+}
+
+export interface LeaveResponse {
+  type: "leave";
+  index: number;
+  reason?: RemovalReason;
+}
+
+export interface LeftResponse {
+  type: "left";
+}
+
+export interface StatsResponse {
+  type: "stats";
+  members: MemberStatistics[];
+  rateLimitedFrames: number;
+  expiresAt?: number;
+}
+
+export interface MyAddressResponse {
+  type: "myAddress";
+  address: string;
+}
+
+export interface CloseResponse {
+  type: "close";
+  reason: CloseReason;
+  message?: string;
+}
+
+export interface MigratedResponse {
+  type: "migrated";
+  id: string;
+  index: number;
+}
+
+export interface LockedResponse {
+  type: "locked";
+  locked: boolean;
+}
+
+export interface SetResponse {
+  type: "set";
+  key: string;
+  value: unknown;
+  source: number;
+}
+
+export interface GetResponse {
+  type: "get";
+  key: string;
+  value: unknown;
+}
+
+export interface StoreResponse {
+  type: "store";
+  entries: Record<string, unknown>;
+}
+
+export interface RosterResponse {
+  type: "roster";
+  members: MemberSummary[];
+  more?: boolean;
+}
+
+export interface OccupancyResponse {
+  type: "occupancy";
+  count: number;
+  capacity: number;
+}
+
+export interface SubscriptionResponse {
+  type: "subscription";
+  sources?: number[];
+}
+
+export interface ReserveResponse {
+  type: "reserve";
+  id: string;
+  reservation: string;
+  count: number;
+  ttlSeconds: number;
+}
+
+export interface CaptureResponse {
+  type: "capture";
+  active: boolean;
+}
+
+export interface DroppedResponse {
+  type: "dropped";
+  count: number;
+  reason: DropReason;
+}
+
+export interface InfoResponse {
+  type: "info";
+  exists: boolean;
+  occupancy: number;
+  size: number;
+  locked: boolean;
+  expiresAt?: number;
+}
+
+export interface QueueDepthsResponse {
+  type: "queueDepths";
+  depths: number[];
+}
+
+export interface QualityResponse {
+  type: "quality";
+  members: MemberQuality[];
+}
+
+export interface ErrorResponse {
+  type: "error";
+  message: ErrorCode;
+  detail?: string;
+}
+
+export type ResponsePacket =
+  | HelloResponse
+  | JoinResponse
+  | CreateResponse
+  | LeaveResponse
+  | LeftResponse
+  | StatsResponse
+  | MyAddressResponse
+  | CloseResponse
+  | MigratedResponse
+  | LockedResponse
+  | SetResponse
+  | GetResponse
+  | StoreResponse
+  | RosterResponse
+  | OccupancyResponse
+  | SubscriptionResponse
+  | ReserveResponse
+  | CaptureResponse
+  | DroppedResponse
+  | InfoResponse
+  | QueueDepthsResponse
+  | QualityResponse
+  | ErrorResponse;
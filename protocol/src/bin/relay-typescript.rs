@@ -0,0 +1,3 @@
+fn main() {
+    print!("{}", relay_protocol::typescript::definitions());
+}
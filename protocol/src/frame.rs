@@ -0,0 +1,186 @@
+use crate::packets::Error;
+
+pub const BROADCAST: u8 = u8::MAX;
+pub const OWNER: u8 = u8::MAX - 1;
+pub const EXCLUDE: u8 = u8::MAX - 2;
+
+pub const MAX_INDEX: u8 = EXCLUDE - 1;
+pub const MAX_ROOM_SIZE: usize = MAX_INDEX as usize + 1;
+
+const _: () = assert!(MAX_INDEX < EXCLUDE && EXCLUDE < OWNER && OWNER < BROADCAST);
+
+pub const MAX_VIRTUAL_ADDRESS_LENGTH: usize = 8;
+pub const TIMESTAMP_LENGTH: usize = 8;
+pub const BATCH_HEADER_LENGTH: usize = 4;
+
+pub const CHUNK: u8 = u8::MAX;
+pub const CHUNK_HEADER_LENGTH: usize = 10;
+
+const _: () = assert!(MAX_INDEX < CHUNK && MAX_VIRTUAL_ADDRESS_LENGTH < CHUNK as usize);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination<'a> {
+    Index(u8),
+    Owner,
+    Broadcast,
+    Exclude(&'a [u8]),
+    Address(&'a [u8]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source<'a> {
+    Index(u8),
+    Address(&'a [u8]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chunk<'a> {
+    pub source: u8,
+    pub message_id: u32,
+    pub index: u16,
+    pub count: u16,
+    pub data: &'a [u8],
+}
+
+pub fn encode_destination(frame: &mut Vec<u8>, destination: Destination) {
+    match destination {
+        Destination::Index(index) => frame.push(index),
+        Destination::Owner => frame.push(OWNER),
+        Destination::Broadcast => frame.push(BROADCAST),
+        Destination::Exclude(excluded) => {
+            frame.extend_from_slice(&[EXCLUDE, excluded.len() as u8]);
+            frame.extend_from_slice(excluded);
+        }
+        Destination::Address(address) => {
+            frame.push(address.len() as u8);
+            frame.extend_from_slice(address);
+        }
+    }
+}
+
+pub fn decode_destination(
+    frame: &[u8],
+    virtual_addresses: bool,
+) -> Result<(Destination<'_>, &[u8]), Error> {
+    let Some((&first, rest)) = frame.split_first() else {
+        return Err(Error::InvalidFrame);
+    };
+
+    if virtual_addresses {
+        let (address, payload) = split(rest, usize::from(first), MAX_VIRTUAL_ADDRESS_LENGTH)?;
+
+        return Ok((Destination::Address(address), payload));
+    }
+
+    match first {
+        BROADCAST => Ok((Destination::Broadcast, rest)),
+        OWNER => Ok((Destination::Owner, rest)),
+        EXCLUDE => {
+            let Some((&count, rest)) = rest.split_first() else {
+                return Err(Error::InvalidFrame);
+            };
+
+            let (excluded, payload) = split(rest, usize::from(count), usize::MAX)?;
+
+            Ok((Destination::Exclude(excluded), payload))
+        }
+        index => Ok((Destination::Index(index), rest)),
+    }
+}
+
+pub fn encode_source(frame: &mut Vec<u8>, source: Source, timestamp: Option<u64>) {
+    match source {
+        Source::Index(index) => frame.push(index),
+        Source::Address(address) => {
+            frame.push(address.len() as u8);
+            frame.extend_from_slice(address);
+        }
+    }
+
+    if let Some(timestamp) = timestamp {
+        frame.extend_from_slice(&timestamp.to_be_bytes());
+    }
+}
+
+pub fn decode_source(
+    frame: &[u8],
+    virtual_addresses: bool,
+    timestamps: bool,
+) -> Result<(Source<'_>, Option<u64>, &[u8]), Error> {
+    let Some((&first, rest)) = frame.split_first() else {
+        return Err(Error::InvalidFrame);
+    };
+
+    let (source, rest) = if virtual_addresses {
+        let (address, rest) = split(rest, usize::from(first), MAX_VIRTUAL_ADDRESS_LENGTH)?;
+
+        (Source::Address(address), rest)
+    } else {
+        (Source::Index(first), rest)
+    };
+
+    if !timestamps {
+        return Ok((source, None, rest));
+    }
+
+    let (timestamp, payload) = split(rest, TIMESTAMP_LENGTH, TIMESTAMP_LENGTH)?;
+    let timestamp = u64::from_be_bytes(timestamp.try_into().unwrap());
+
+    Ok((source, Some(timestamp), payload))
+}
+
+pub fn encode_batch(batch: &mut Vec<u8>, frame: &[u8]) {
+    batch.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    batch.extend_from_slice(frame);
+}
+
+pub fn decode_batch(mut batch: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut frames = vec![];
+
+    while !batch.is_empty() {
+        let (header, rest) = split(batch, BATCH_HEADER_LENGTH, BATCH_HEADER_LENGTH)?;
+        let length = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+        let (frame, rest) = split(rest, length, usize::MAX)?;
+
+        frames.push(frame);
+        batch = rest;
+    }
+
+    Ok(frames)
+}
+
+pub fn encode_chunk(frame: &mut Vec<u8>, chunk: Chunk) {
+    frame.extend_from_slice(&[CHUNK, chunk.source]);
+    frame.extend_from_slice(&chunk.message_id.to_be_bytes());
+    frame.extend_from_slice(&chunk.index.to_be_bytes());
+    frame.extend_from_slice(&chunk.count.to_be_bytes());
+    frame.extend_from_slice(chunk.data);
+}
+
+pub fn decode_chunk(frame: &[u8]) -> Result<Chunk<'_>, Error> {
+    let (header, data) = split(frame, CHUNK_HEADER_LENGTH, CHUNK_HEADER_LENGTH)?;
+
+    let message_id = u32::from_be_bytes(header[2..6].try_into().unwrap());
+    let index = u16::from_be_bytes(header[6..8].try_into().unwrap());
+    let count = u16::from_be_bytes(header[8..10].try_into().unwrap());
+
+    if header[0] != CHUNK || index >= count {
+        return Err(Error::InvalidFrame);
+    }
+
+    Ok(Chunk {
+        source: header[1],
+        message_id,
+        index,
+        count,
+        data,
+    })
+}
+
+fn split(data: &[u8], length: usize, max_length: usize) -> Result<(&[u8], &[u8]), Error> {
+    if length > max_length || data.len() < length {
+        return Err(Error::InvalidFrame);
+    }
+
+    Ok(data.split_at(length))
+}
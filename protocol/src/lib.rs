@@ -0,0 +1,5 @@
+pub mod frame;
+pub mod packets;
+#[cfg(test)]
+mod tests;
+pub mod typescript;
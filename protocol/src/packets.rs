@@ -0,0 +1,577 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, net::SocketAddr, str::FromStr};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum RequestPacket {
+    #[serde(rename_all = "camelCase")]
+    Hello {
+        #[serde(
+            default,
+            alias = "max_message_size",
+            skip_serializing_if = "Option::is_none"
+        )]
+        max_message_size: Option<usize>,
+        #[serde(default, alias = "chunk_size", skip_serializing_if = "Option::is_none")]
+        chunk_size: Option<usize>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Join {
+        id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reservation: Option<String>,
+        #[serde(
+            default,
+            alias = "virtual_address",
+            skip_serializing_if = "Option::is_none"
+        )]
+        virtual_address: Option<String>,
+    },
+    Reserve {
+        id: String,
+        count: usize,
+    },
+    Unreserve {
+        id: String,
+        reservation: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Create {
+        size: Option<usize>,
+        template: Option<String>,
+        #[serde(
+            default,
+            alias = "virtual_address",
+            skip_serializing_if = "Option::is_none"
+        )]
+        virtual_address: Option<String>,
+        #[serde(flatten)]
+        options: CreateOptions,
+    },
+    Leave,
+    Stats,
+    MyAddress,
+    Lock,
+    Unlock,
+    Info {
+        id: String,
+    },
+    Set {
+        key: String,
+        value: serde_json::Value,
+    },
+    Get {
+        key: String,
+    },
+    CloseRoom {
+        reason: Option<String>,
+    },
+    Migrate {
+        size: Option<usize>,
+    },
+    Subscribe {
+        sources: Vec<usize>,
+    },
+    Unsubscribe,
+    WatchOccupancy {
+        enabled: bool,
+    },
+    QueueDepths,
+    #[serde(rename_all = "camelCase")]
+    QualityReport {
+        received: u32,
+        lost: u32,
+        #[serde(alias = "jitter_ms")]
+        jitter_ms: u16,
+    },
+    Quality,
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RoomOptions {
+    #[serde(
+        alias = "congestion_threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub congestion_threshold: Option<usize>,
+    #[serde(alias = "max_bandwidth", skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth: Option<u64>,
+    #[serde(alias = "share_addresses", skip_serializing_if = "std::ops::Not::not")]
+    pub share_addresses: bool,
+    #[serde(alias = "ttl_seconds", skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub timestamps: bool,
+    #[serde(alias = "open_store", skip_serializing_if = "std::ops::Not::not")]
+    pub open_store: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub opaque: bool,
+    #[serde(alias = "frame_ttl_ms", skip_serializing_if = "Option::is_none")]
+    pub frame_ttl_ms: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub roster: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub conflate: bool,
+    #[serde(alias = "allowed_origins", skip_serializing_if = "Vec::is_empty")]
+    pub allowed_origins: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    #[serde(
+        alias = "compression_threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub compression_threshold: Option<usize>,
+    #[serde(
+        alias = "virtual_addresses",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub virtual_addresses: bool,
+    #[serde(alias = "notify_members", skip_serializing_if = "is_true")]
+    pub notify_members: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub paced: bool,
+    #[serde(alias = "tick_rate", skip_serializing_if = "Option::is_none")]
+    pub tick_rate: Option<u32>,
+    #[serde(alias = "max_paced_bytes", skip_serializing_if = "Option::is_none")]
+    pub max_paced_bytes: Option<usize>,
+}
+
+impl Default for RoomOptions {
+    fn default() -> RoomOptions {
+        RoomOptions {
+            congestion_threshold: None,
+            max_bandwidth: None,
+            share_addresses: false,
+            ttl_seconds: None,
+            timestamps: false,
+            open_store: false,
+            opaque: false,
+            frame_ttl_ms: None,
+            roster: false,
+            conflate: false,
+            allowed_origins: vec![],
+            compression: None,
+            compression_threshold: None,
+            virtual_addresses: false,
+            notify_members: true,
+            paced: false,
+            tick_rate: None,
+            max_paced_bytes: None,
+        }
+    }
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CreateOptions {
+    #[serde(
+        alias = "congestion_threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub congestion_threshold: Option<usize>,
+    #[serde(alias = "max_bandwidth", skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth: Option<u64>,
+    #[serde(alias = "share_addresses", skip_serializing_if = "Option::is_none")]
+    pub share_addresses: Option<bool>,
+    #[serde(alias = "ttl_seconds", skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamps: Option<bool>,
+    #[serde(alias = "open_store", skip_serializing_if = "Option::is_none")]
+    pub open_store: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opaque: Option<bool>,
+    #[serde(alias = "frame_ttl_ms", skip_serializing_if = "Option::is_none")]
+    pub frame_ttl_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roster: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflate: Option<bool>,
+    #[serde(alias = "allowed_origins", skip_serializing_if = "Option::is_none")]
+    pub allowed_origins: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    #[serde(
+        alias = "compression_threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub compression_threshold: Option<usize>,
+    #[serde(alias = "virtual_addresses", skip_serializing_if = "Option::is_none")]
+    pub virtual_addresses: Option<bool>,
+    #[serde(alias = "notify_members", skip_serializing_if = "Option::is_none")]
+    pub notify_members: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paced: Option<bool>,
+    #[serde(alias = "tick_rate", skip_serializing_if = "Option::is_none")]
+    pub tick_rate: Option<u32>,
+    #[serde(alias = "max_paced_bytes", skip_serializing_if = "Option::is_none")]
+    pub max_paced_bytes: Option<usize>,
+}
+
+impl CreateOptions {
+    pub fn or(self, defaults: &RoomOptions) -> RoomOptions {
+        RoomOptions {
+            congestion_threshold: self.congestion_threshold.or(defaults.congestion_threshold),
+            max_bandwidth: self.max_bandwidth.or(defaults.max_bandwidth),
+            share_addresses: self.share_addresses.unwrap_or(defaults.share_addresses),
+            ttl_seconds: self.ttl_seconds.or(defaults.ttl_seconds),
+            timestamps: self.timestamps.unwrap_or(defaults.timestamps),
+            open_store: self.open_store.unwrap_or(defaults.open_store),
+            opaque: self.opaque.unwrap_or(defaults.opaque),
+            frame_ttl_ms: self.frame_ttl_ms.or(defaults.frame_ttl_ms),
+            roster: self.roster.unwrap_or(defaults.roster),
+            conflate: self.conflate.unwrap_or(defaults.conflate),
+            allowed_origins: self
+                .allowed_origins
+                .unwrap_or_else(|| defaults.allowed_origins.clone()),
+            compression: self.compression.or(defaults.compression),
+            compression_threshold: self
+                .compression_threshold
+                .or(defaults.compression_threshold),
+            virtual_addresses: self.virtual_addresses.unwrap_or(defaults.virtual_addresses),
+            notify_members: self.notify_members.unwrap_or(defaults.notify_members),
+            paced: self.paced.unwrap_or(defaults.paced),
+            tick_rate: self.tick_rate.or(defaults.tick_rate),
+            max_paced_bytes: self.max_paced_bytes.or(defaults.max_paced_bytes),
+        }
+    }
+}
+
+impl From<CreateOptions> for RoomOptions {
+    fn from(options: CreateOptions) -> RoomOptions {
+        options.or(&RoomOptions::default())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Lz4,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemberSummary {
+    pub index: usize,
+    pub id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemberStatistics {
+    pub index: usize,
+    pub queued_bytes: usize,
+    pub congestion_skips: u64,
+    pub expired_frames: u64,
+    pub conflated_frames: u64,
+    pub invalid_packets: u64,
+    pub oversized_frames: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QualityReport {
+    pub received: u32,
+    pub lost: u32,
+    pub jitter_ms: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemberQuality {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report: Option<QualityReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported_at: Option<u64>,
+    pub send_failures: u64,
+    pub dropped_frames: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ResponsePacket {
+    #[serde(rename_all = "camelCase")]
+    Hello {
+        version: String,
+        capabilities: Vec<String>,
+        max_room_size: usize,
+        max_message_size: usize,
+        connection_id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        chunk_size: Option<usize>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Join {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        capacity: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        index: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        address: Option<SocketAddr>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        addresses: Option<Vec<SocketAddr>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        connection_id: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        virtual_address: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        virtual_addresses: Option<Vec<String>>,
+    },
+    Create {
+        id: String,
+        size: usize,
+        options: RoomOptions,
+    },
+    Leave {
+        index: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<RemovalReason>,
+    },
+    Left,
+    #[serde(rename_all = "camelCase")]
+    Stats {
+        members: Vec<MemberStatistics>,
+        rate_limited_frames: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    MyAddress {
+        address: SocketAddr,
+    },
+    Close {
+        reason: CloseReason,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    Migrated {
+        id: String,
+        index: usize,
+    },
+    Locked {
+        locked: bool,
+    },
+    Set {
+        key: String,
+        value: serde_json::Value,
+        source: usize,
+    },
+    Get {
+        key: String,
+        value: serde_json::Value,
+    },
+    Store {
+        entries: BTreeMap<String, serde_json::Value>,
+    },
+    Roster {
+        members: Vec<MemberSummary>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        more: bool,
+    },
+    Occupancy {
+        count: usize,
+        capacity: usize,
+    },
+    Subscription {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sources: Option<Vec<usize>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Reserve {
+        id: String,
+        reservation: String,
+        count: usize,
+        ttl_seconds: u64,
+    },
+    Capture {
+        active: bool,
+    },
+    Dropped {
+        count: u64,
+        reason: DropReason,
+    },
+    #[serde(rename_all = "camelCase")]
+    Info {
+        exists: bool,
+        occupancy: usize,
+        size: usize,
+        locked: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    QueueDepths {
+        depths: Vec<u32>,
+    },
+    Quality {
+        members: Vec<MemberQuality>,
+    },
+    Error {
+        message: Error,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WireFormat {
+    V1,
+    #[default]
+    V2,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum V1Packet<'a> {
+    Join {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<usize>,
+    },
+    Create {
+        id: &'a str,
+    },
+    Leave {
+        index: usize,
+    },
+    Error {
+        message: &'a Error,
+    },
+}
+
+impl WireFormat {
+    pub fn supports(self, packet: &ResponsePacket) -> bool {
+        match self {
+            WireFormat::V1 => matches!(
+                packet,
+                ResponsePacket::Join { .. }
+                    | ResponsePacket::Create { .. }
+                    | ResponsePacket::Leave { .. }
+                    | ResponsePacket::Error {
+                        message: Error::InvalidSize
+                            | Error::AlreadyExists
+                            | Error::DoesNotExist
+                            | Error::IsFull,
+                        ..
+                    }
+            ),
+            WireFormat::V2 => true,
+        }
+    }
+
+    pub fn encode(self, packet: &ResponsePacket) -> Result<String, serde_json::Error> {
+        let v1_packet = match (self, packet) {
+            (WireFormat::V2, _) => None,
+            (WireFormat::V1, ResponsePacket::Join { size, .. }) => {
+                Some(V1Packet::Join { size: *size })
+            }
+            (WireFormat::V1, ResponsePacket::Create { id, .. }) => Some(V1Packet::Create { id }),
+            (WireFormat::V1, ResponsePacket::Leave { index, .. }) => {
+                Some(V1Packet::Leave { index: *index })
+            }
+            (WireFormat::V1, ResponsePacket::Error { message, .. }) => {
+                Some(V1Packet::Error { message })
+            }
+            (WireFormat::V1, _) => None,
+        };
+
+        match v1_packet {
+            Some(v1_packet) => serde_json::to_string(&v1_packet),
+            None => serde_json::to_string(packet),
+        }
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<WireFormat, ()> {
+        match value {
+            "1" => Ok(WireFormat::V1),
+            "2" => Ok(WireFormat::V2),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CloseReason {
+    Expired,
+    Closed,
+    MaxDuration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RemovalReason {
+    Left,
+    Disconnected,
+    TimedOut,
+    Closed,
+    Expired,
+}
+
+impl RemovalReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RemovalReason::Left => "Left",
+            RemovalReason::Disconnected => "Disconnected",
+            RemovalReason::TimedOut => "TimedOut",
+            RemovalReason::Closed => "Closed",
+            RemovalReason::Expired => "Expired",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DropReason {
+    RateLimit,
+    ReceiverCongested,
+    Expired,
+    BudgetExhausted,
+    ReceiverLimit,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 5] = [
+        DropReason::RateLimit,
+        DropReason::ReceiverCongested,
+        DropReason::Expired,
+        DropReason::BudgetExhausted,
+        DropReason::ReceiverLimit,
+    ];
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Error {
+    InvalidSize,
+    AlreadyExists,
+    DoesNotExist,
+    IsFull,
+    NotInRoom,
+    RateLimited,
+    UnsupportedPacket,
+    IsLocked,
+    NotOwner,
+    Denied,
+    StoreFull,
+    InvalidOptions,
+    InvalidFrame,
+    InvalidPacket,
+    UnknownTemplate,
+    Unauthorized,
+    UnjoinedTimeout,
+    OriginNotAllowed,
+    InvalidVirtualAddress,
+    VirtualAddressTaken,
+    LimitExceeded,
+}
@@ -0,0 +1,388 @@
+use crate::{
+    frame::{
+        self, Chunk, Destination, Source, BATCH_HEADER_LENGTH, BROADCAST, CHUNK,
+        CHUNK_HEADER_LENGTH, EXCLUDE, MAX_INDEX, MAX_ROOM_SIZE, MAX_VIRTUAL_ADDRESS_LENGTH, OWNER,
+        TIMESTAMP_LENGTH,
+    },
+    packets::Error,
+    typescript,
+};
+
+fn encode_destination(destination: Destination, payload: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![];
+    frame::encode_destination(&mut encoded, destination);
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+fn encode_source(source: Source, timestamp: Option<u64>, payload: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![];
+    frame::encode_source(&mut encoded, source, timestamp);
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+///
+/// Test every index byte decodes to its destination.
+///
+#[test]
+fn unit_decode_destination() {
+    for first in 0..=u8::MAX {
+        let encoded = [first, 0, 1, 2];
+        let (destination, payload) = frame::decode_destination(&encoded, false).unwrap();
+
+        match first {
+            BROADCAST => assert_eq!(
+                (Destination::Broadcast, &[0, 1, 2][..]),
+                (destination, payload)
+            ),
+            OWNER => assert_eq!((Destination::Owner, &[0, 1, 2][..]), (destination, payload)),
+            EXCLUDE => assert_eq!(
+                (Destination::Exclude(&[]), &[1, 2][..]),
+                (destination, payload)
+            ),
+            index => assert_eq!(
+                (Destination::Index(index), &[0, 1, 2][..]),
+                (destination, payload)
+            ),
+        }
+
+        assert_eq!(
+            Ok((destination, payload)),
+            frame::decode_destination(&encode_destination(destination, payload), false)
+        );
+    }
+
+    //
+    // Test a frame with only the index byte decodes to an empty payload.
+    //
+
+    assert_eq!(
+        Ok((Destination::Index(3), &[][..])),
+        frame::decode_destination(&[3], false)
+    );
+
+    //
+    // Test an empty frame is invalid in both modes.
+    //
+
+    assert_eq!(
+        Err(Error::InvalidFrame),
+        frame::decode_destination(&[], false)
+    );
+    assert_eq!(
+        Err(Error::InvalidFrame),
+        frame::decode_destination(&[], true)
+    );
+}
+
+///
+/// Test the sentinels are above every addressable index and round trip as themselves.
+///
+#[test]
+fn unit_sentinels() {
+    assert_eq!(usize::from(MAX_INDEX) + 1, MAX_ROOM_SIZE);
+
+    let mut encoded = vec![];
+    frame::encode_destination(&mut encoded, Destination::Index(MAX_INDEX));
+    assert_eq!(
+        Ok((Destination::Index(MAX_INDEX), &[][..])),
+        frame::decode_destination(&encoded, false)
+    );
+
+    for (sentinel, destination) in [
+        (BROADCAST, Destination::Broadcast),
+        (OWNER, Destination::Owner),
+        (EXCLUDE, Destination::Exclude(&[])),
+    ] {
+        assert!(sentinel > MAX_INDEX);
+
+        let mut encoded = vec![];
+        frame::encode_destination(&mut encoded, destination);
+        assert_eq!(sentinel, encoded[0]);
+        assert_eq!(
+            Ok((destination, &[][..])),
+            frame::decode_destination(&encoded, false)
+        );
+    }
+}
+
+///
+/// Test exclusion lists round trip and reject frames shorter than the list.
+///
+#[test]
+fn unit_decode_exclusion() {
+    let excluded: Vec<u8> = (0..=u8::MAX).collect();
+
+    for count in 0..=usize::from(u8::MAX) {
+        let encoded = encode_destination(Destination::Exclude(&excluded[..count]), b"data");
+        assert_eq!(2 + count + 4, encoded.len());
+
+        assert_eq!(
+            Ok((Destination::Exclude(&excluded[..count]), &b"data"[..])),
+            frame::decode_destination(&encoded, false)
+        );
+
+        assert_eq!(
+            Err(Error::InvalidFrame),
+            frame::decode_destination(&encoded[..count + 1], false)
+        );
+    }
+
+    assert_eq!(
+        Err(Error::InvalidFrame),
+        frame::decode_destination(&[EXCLUDE], false)
+    );
+}
+
+///
+/// Test every short header either decodes to a destination which encodes back to the same bytes, or is invalid.
+///
+#[test]
+fn unit_decode_headers() {
+    let mut frames: Vec<Vec<u8>> = vec![vec![]];
+    frames.extend((0..=u8::MAX).map(|first| vec![first]));
+    frames.extend((0..=u16::MAX).map(|header| header.to_be_bytes().to_vec()));
+    frames.extend((0..=u16::MAX).map(|header| {
+        let [count, excluded] = header.to_be_bytes();
+        vec![EXCLUDE, count, excluded]
+    }));
+
+    for frame in &frames {
+        for virtual_addresses in [false, true] {
+            let Ok((destination, payload)) = frame::decode_destination(frame, virtual_addresses)
+            else {
+                continue;
+            };
+
+            assert_eq!(frame, &encode_destination(destination, payload));
+
+            //
+            // Test sentinels never decode as indices, and exclusion lists never overrun the frame.
+            //
+
+            match destination {
+                Destination::Index(index) => assert!(index <= MAX_INDEX && !virtual_addresses),
+                Destination::Exclude(excluded) => assert_eq!(usize::from(frame[1]), excluded.len()),
+                Destination::Address(address) => {
+                    assert!(virtual_addresses && address.len() <= MAX_VIRTUAL_ADDRESS_LENGTH)
+                }
+                Destination::Broadcast | Destination::Owner => assert!(!virtual_addresses),
+            }
+        }
+    }
+
+    //
+    // Test exclusion lists truncated at every length are invalid.
+    //
+
+    for count in 1..=u8::MAX {
+        for length in 0..usize::from(count) {
+            let mut frame = vec![EXCLUDE, count];
+            frame.resize(2 + length, BROADCAST);
+
+            assert_eq!(
+                Err(Error::InvalidFrame),
+                frame::decode_destination(&frame, false)
+            );
+        }
+    }
+}
+
+///
+/// Test virtual addresses round trip and reject lengths beyond the maximum.
+///
+#[test]
+fn unit_decode_virtual_destination() {
+    let address = [b'a'; u8::MAX as usize];
+
+    for length in 0..=usize::from(u8::MAX) {
+        let mut encoded = vec![length as u8];
+        encoded.extend_from_slice(&address[..length]);
+        encoded.extend_from_slice(b"data");
+
+        let decoded = frame::decode_destination(&encoded, true);
+
+        if length > MAX_VIRTUAL_ADDRESS_LENGTH {
+            assert_eq!(Err(Error::InvalidFrame), decoded);
+            continue;
+        }
+
+        assert_eq!(
+            Ok((Destination::Address(&address[..length]), &b"data"[..])),
+            decoded
+        );
+        assert_eq!(
+            encoded,
+            encode_destination(Destination::Address(&address[..length]), b"data")
+        );
+
+        //
+        // Test a frame shorter than its address is invalid.
+        //
+
+        if length > 0 {
+            assert_eq!(
+                Err(Error::InvalidFrame),
+                frame::decode_destination(&encoded[..length], true)
+            );
+        }
+    }
+}
+
+///
+/// Test every source header round trips with and without timestamps.
+///
+#[test]
+fn unit_decode_source() {
+    for index in 0..=u8::MAX {
+        let encoded = encode_source(Source::Index(index), None, b"data");
+        assert_eq!(vec![index, b'd', b'a', b't', b'a'], encoded);
+        assert_eq!(
+            Ok((Source::Index(index), None, &b"data"[..])),
+            frame::decode_source(&encoded, false, false)
+        );
+
+        let encoded = encode_source(Source::Index(index), Some(u64::MAX - 7), b"data");
+        assert_eq!(1 + TIMESTAMP_LENGTH + 4, encoded.len());
+        assert_eq!(
+            Ok((Source::Index(index), Some(u64::MAX - 7), &b"data"[..])),
+            frame::decode_source(&encoded, false, true)
+        );
+    }
+
+    for length in 0..=MAX_VIRTUAL_ADDRESS_LENGTH {
+        let address = &b"abcdefgh"[..length];
+
+        let encoded = encode_source(Source::Address(address), None, b"");
+        assert_eq!(
+            Ok((Source::Address(address), None, &b""[..])),
+            frame::decode_source(&encoded, true, false)
+        );
+
+        let encoded = encode_source(Source::Address(address), Some(42), b"data");
+        assert_eq!(
+            Ok((Source::Address(address), Some(42), &b"data"[..])),
+            frame::decode_source(&encoded, true, true)
+        );
+
+        //
+        // Test a frame shorter than its timestamp is invalid.
+        //
+
+        assert_eq!(
+            Err(Error::InvalidFrame),
+            frame::decode_source(&encoded[..1 + length + TIMESTAMP_LENGTH - 1], true, true)
+        );
+    }
+
+    assert_eq!(
+        Err(Error::InvalidFrame),
+        frame::decode_source(&[], false, false)
+    );
+    assert_eq!(
+        Err(Error::InvalidFrame),
+        frame::decode_source(&[MAX_VIRTUAL_ADDRESS_LENGTH as u8 + 1], true, false)
+    );
+}
+
+///
+/// Test batches round trip their frames and reject frames shorter than their length.
+///
+#[test]
+fn unit_decode_batch() {
+    let frames: [&[u8]; 4] = [b"", b"a", b"data", &[0; 300]];
+
+    let mut batch = vec![];
+    for frame in frames {
+        frame::encode_batch(&mut batch, frame);
+    }
+
+    assert_eq!(frames.len() * BATCH_HEADER_LENGTH + 305, batch.len());
+    assert_eq!(Ok(frames.to_vec()), frame::decode_batch(&batch));
+    assert_eq!(Ok(vec![]), frame::decode_batch(&[]));
+
+    //
+    // Test every truncation of the batch is invalid, except at a frame boundary.
+    //
+
+    let boundaries = [0, 4, 9, 17, batch.len()];
+
+    for length in 0..batch.len() {
+        let decoded = frame::decode_batch(&batch[..length]);
+
+        match boundaries.iter().position(|&boundary| boundary == length) {
+            Some(count) => assert_eq!(Ok(frames[..count].to_vec()), decoded),
+            None => assert_eq!(Err(Error::InvalidFrame), decoded),
+        }
+    }
+}
+
+///
+/// Test chunk headers round trip and reject truncated or out of range headers.
+///
+#[test]
+fn unit_decode_chunk() {
+    let chunk = Chunk {
+        source: 7,
+        message_id: u32::MAX - 1,
+        index: 2,
+        count: 3,
+        data: b"data",
+    };
+
+    let mut encoded = vec![];
+    frame::encode_chunk(&mut encoded, chunk);
+
+    assert_eq!(CHUNK_HEADER_LENGTH + 4, encoded.len());
+    assert_eq!(CHUNK, encoded[0]);
+    assert_eq!(Ok(chunk), frame::decode_chunk(&encoded));
+
+    //
+    // Test a header shorter than its length is invalid, and an empty chunk is valid.
+    //
+
+    for length in 0..CHUNK_HEADER_LENGTH {
+        assert_eq!(
+            Err(Error::InvalidFrame),
+            frame::decode_chunk(&encoded[..length])
+        );
+    }
+
+    assert_eq!(
+        Ok(Chunk { data: b"", ..chunk }),
+        frame::decode_chunk(&encoded[..CHUNK_HEADER_LENGTH])
+    );
+
+    //
+    // Test a frame without the chunk sentinel, or with an index beyond its count, is invalid.
+    //
+
+    for invalid in [
+        Chunk { index: 3, ..chunk },
+        Chunk {
+            index: 0,
+            count: 0,
+            ..chunk
+        },
+    ] {
+        let mut encoded = vec![];
+        frame::encode_chunk(&mut encoded, invalid);
+
+        assert_eq!(Err(Error::InvalidFrame), frame::decode_chunk(&encoded));
+    }
+
+    encoded[0] = MAX_INDEX;
+    assert_eq!(Err(Error::InvalidFrame), frame::decode_chunk(&encoded));
+}
+
+///
+/// Test the checked-in TypeScript definitions match the generated definitions.
+///
+#[test]
+fn unit_typescript_definitions() {
+    assert!(
+        include_str!("../relay.d.ts") == typescript::definitions(),
+        "relay.d.ts is stale, regenerate it with `cargo run -p relay-protocol --bin relay-typescript > protocol/relay.d.ts`"
+    );
+}
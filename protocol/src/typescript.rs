@@ -0,0 +1,367 @@
+use crate::{
+    frame::{
+        BATCH_HEADER_LENGTH, BROADCAST, CHUNK, CHUNK_HEADER_LENGTH, EXCLUDE, MAX_INDEX,
+        MAX_ROOM_SIZE, MAX_VIRTUAL_ADDRESS_LENGTH, OWNER, TIMESTAMP_LENGTH,
+    },
+    packets::{CloseReason, Compression, DropReason, Error, RemovalReason},
+};
+use serde::{
+    de::{self, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+use std::fmt::Write;
+
+pub struct Packet {
+    pub tag: &'static str,
+    pub extends: Option<&'static str>,
+    pub fields: &'static [(&'static str, &'static str)],
+}
+
+impl Packet {
+    const fn new(tag: &'static str, fields: &'static [(&'static str, &'static str)]) -> Packet {
+        Packet {
+            tag,
+            extends: None,
+            fields,
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<&'static str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.trim_end_matches('?') == name)
+            .map(|(_, field_type)| *field_type)
+    }
+}
+
+pub const ROOM_OPTIONS: &[(&str, &str)] = &[
+    ("congestionThreshold?", "number"),
+    ("maxBandwidth?", "number"),
+    ("shareAddresses?", "boolean"),
+    ("ttlSeconds?", "number"),
+    ("timestamps?", "boolean"),
+    ("openStore?", "boolean"),
+    ("opaque?", "boolean"),
+    ("frameTtlMs?", "number"),
+    ("roster?", "boolean"),
+    ("conflate?", "boolean"),
+    ("allowedOrigins?", "string[]"),
+    ("compression?", "Compression"),
+    ("compressionThreshold?", "number"),
+    ("virtualAddresses?", "boolean"),
+    ("notifyMembers?", "boolean"),
+    ("paced?", "boolean"),
+    ("tickRate?", "number"),
+    ("maxPacedBytes?", "number"),
+];
+
+pub const MEMBER_SUMMARY: &[(&str, &str)] = &[("index", "number"), ("id", "number")];
+
+pub const MEMBER_STATISTICS: &[(&str, &str)] = &[
+    ("index", "number"),
+    ("queuedBytes", "number"),
+    ("congestionSkips", "number"),
+    ("expiredFrames", "number"),
+    ("conflatedFrames", "number"),
+    ("invalidPackets", "number"),
+    ("oversizedFrames", "number"),
+];
+
+pub const QUALITY_REPORT: &[(&str, &str)] = &[
+    ("received", "number"),
+    ("lost", "number"),
+    ("jitterMs", "number"),
+];
+
+pub const MEMBER_QUALITY: &[(&str, &str)] = &[
+    ("index", "number"),
+    ("report?", "QualityReport"),
+    ("reportedAt?", "number"),
+    ("sendFailures", "number"),
+    ("droppedFrames", "number"),
+];
+
+pub const REQUEST_PACKETS: &[Packet] = &[
+    Packet::new(
+        "hello",
+        &[("maxMessageSize?", "number"), ("chunkSize?", "number")],
+    ),
+    Packet::new(
+        "join",
+        &[
+            ("id", "string"),
+            ("token?", "string"),
+            ("reservation?", "string"),
+            ("virtualAddress?", "string"),
+        ],
+    ),
+    Packet::new("reserve", &[("id", "string"), ("count", "number")]),
+    Packet::new("unreserve", &[("id", "string"), ("reservation", "string")]),
+    Packet {
+        tag: "create",
+        extends: Some("RoomOptions"),
+        fields: &[
+            ("size?", "number | null"),
+            ("template?", "string | null"),
+            ("virtualAddress?", "string"),
+        ],
+    },
+    Packet::new("leave", &[]),
+    Packet::new("stats", &[]),
+    Packet::new("myAddress", &[]),
+    Packet::new("lock", &[]),
+    Packet::new("unlock", &[]),
+    Packet::new("info", &[("id", "string")]),
+    Packet::new("set", &[("key", "string"), ("value", "unknown")]),
+    Packet::new("get", &[("key", "string")]),
+    Packet::new("closeRoom", &[("reason?", "string | null")]),
+    Packet::new("migrate", &[("size?", "number | null")]),
+    Packet::new("subscribe", &[("sources", "number[]")]),
+    Packet::new("unsubscribe", &[]),
+    Packet::new("watchOccupancy", &[("enabled", "boolean")]),
+    Packet::new("queueDepths", &[]),
+    Packet::new("qualityReport", QUALITY_REPORT),
+    Packet::new("quality", &[]),
+];
+
+pub const RESPONSE_PACKETS: &[Packet] = &[
+    Packet::new(
+        "hello",
+        &[
+            ("version", "string"),
+            ("capabilities", "string[]"),
+            ("maxRoomSize", "number"),
+            ("maxMessageSize", "number"),
+            ("connectionId", "number"),
+            ("chunkSize?", "number"),
+        ],
+    ),
+    Packet::new(
+        "join",
+        &[
+            ("size?", "number"),
+            ("capacity?", "number"),
+            ("index?", "number"),
+            ("address?", "string"),
+            ("addresses?", "string[]"),
+            ("connectionId?", "number"),
+            ("virtualAddress?", "string"),
+            ("virtualAddresses?", "string[]"),
+        ],
+    ),
+    Packet::new(
+        "create",
+        &[
+            ("id", "string"),
+            ("size", "number"),
+            ("options", "RoomOptions"),
+        ],
+    ),
+    Packet::new(
+        "leave",
+        &[("index", "number"), ("reason?", "RemovalReason")],
+    ),
+    Packet::new("left", &[]),
+    Packet::new(
+        "stats",
+        &[
+            ("members", "MemberStatistics[]"),
+            ("rateLimitedFrames", "number"),
+            ("expiresAt?", "number"),
+        ],
+    ),
+    Packet::new("myAddress", &[("address", "string")]),
+    Packet::new(
+        "close",
+        &[("reason", "CloseReason"), ("message?", "string")],
+    ),
+    Packet::new("migrated", &[("id", "string"), ("index", "number")]),
+    Packet::new("locked", &[("locked", "boolean")]),
+    Packet::new(
+        "set",
+        &[
+            ("key", "string"),
+            ("value", "unknown"),
+            ("source", "number"),
+        ],
+    ),
+    Packet::new("get", &[("key", "string"), ("value", "unknown")]),
+    Packet::new("store", &[("entries", "Record<string, unknown>")]),
+    Packet::new(
+        "roster",
+        &[("members", "MemberSummary[]"), ("more?", "boolean")],
+    ),
+    Packet::new("occupancy", &[("count", "number"), ("capacity", "number")]),
+    Packet::new("subscription", &[("sources?", "number[]")]),
+    Packet::new(
+        "reserve",
+        &[
+            ("id", "string"),
+            ("reservation", "string"),
+            ("count", "number"),
+            ("ttlSeconds", "number"),
+        ],
+    ),
+    Packet::new("capture", &[("active", "boolean")]),
+    Packet::new("dropped", &[("count", "number"), ("reason", "DropReason")]),
+    Packet::new(
+        "info",
+        &[
+            ("exists", "boolean"),
+            ("occupancy", "number"),
+            ("size", "number"),
+            ("locked", "boolean"),
+            ("expiresAt?", "number"),
+        ],
+    ),
+    Packet::new("queueDepths", &[("depths", "number[]")]),
+    Packet::new("quality", &[("members", "MemberQuality[]")]),
+    Packet::new("error", &[("message", "ErrorCode"), ("detail?", "string")]),
+];
+
+pub fn definitions() -> String {
+    let mut output = String::from(
+        "// Generated by `cargo run -p relay-protocol --bin relay-typescript`, do not edit.\n\n",
+    );
+
+    for (name, value) in [
+        ("BROADCAST", usize::from(BROADCAST)),
+        ("OWNER", usize::from(OWNER)),
+        ("EXCLUDE", usize::from(EXCLUDE)),
+        ("MAX_INDEX", usize::from(MAX_INDEX)),
+        ("MAX_ROOM_SIZE", MAX_ROOM_SIZE),
+        ("MAX_VIRTUAL_ADDRESS_LENGTH", MAX_VIRTUAL_ADDRESS_LENGTH),
+        ("TIMESTAMP_LENGTH", TIMESTAMP_LENGTH),
+        ("BATCH_HEADER_LENGTH", BATCH_HEADER_LENGTH),
+        ("CHUNK", usize::from(CHUNK)),
+        ("CHUNK_HEADER_LENGTH", CHUNK_HEADER_LENGTH),
+    ] {
+        writeln!(output, "export const {} = {};", name, value).unwrap();
+    }
+
+    output.push('\n');
+
+    for (name, variants) in [
+        ("ErrorCode", variants::<Error>()),
+        ("CloseReason", variants::<CloseReason>()),
+        ("DropReason", variants::<DropReason>()),
+        ("RemovalReason", variants::<RemovalReason>()),
+        ("Compression", variants::<Compression>()),
+    ] {
+        let variants: Vec<_> = variants
+            .iter()
+            .map(|variant| format!("\"{}\"", variant))
+            .collect();
+
+        writeln!(output, "export type {} = {};", name, variants.join(" | ")).unwrap();
+    }
+
+    output.push('\n');
+
+    write_interface(&mut output, "RoomOptions", None, None, ROOM_OPTIONS);
+    write_interface(&mut output, "MemberSummary", None, None, MEMBER_SUMMARY);
+    write_interface(
+        &mut output,
+        "MemberStatistics",
+        None,
+        None,
+        MEMBER_STATISTICS,
+    );
+    write_interface(&mut output, "QualityReport", None, None, QUALITY_REPORT);
+    write_interface(&mut output, "MemberQuality", None, None, MEMBER_QUALITY);
+
+    for (union, packets) in [
+        ("RequestPacket", REQUEST_PACKETS),
+        ("ResponsePacket", RESPONSE_PACKETS),
+    ] {
+        let suffix = union.trim_end_matches("Packet");
+        let mut names = vec![];
+
+        for packet in packets {
+            let name = format!(
+                "{}{}{}",
+                packet.tag[..1].to_uppercase(),
+                &packet.tag[1..],
+                suffix
+            );
+
+            write_interface(
+                &mut output,
+                &name,
+                packet.extends,
+                Some(packet.tag),
+                packet.fields,
+            );
+            names.push(name);
+        }
+
+        writeln!(
+            output,
+            "export type {} =\n  | {};\n",
+            union,
+            names.join("\n  | ")
+        )
+        .unwrap();
+    }
+
+    output.pop();
+    output
+}
+
+fn write_interface(
+    output: &mut String,
+    name: &str,
+    extends: Option<&str>,
+    tag: Option<&str>,
+    fields: &[(&str, &str)],
+) {
+    match extends {
+        Some(extends) => writeln!(output, "export interface {} extends {} {{", name, extends),
+        None => writeln!(output, "export interface {} {{", name),
+    }
+    .unwrap();
+
+    if let Some(tag) = tag {
+        writeln!(output, "  type: \"{}\";", tag).unwrap();
+    }
+
+    for (field, field_type) in fields {
+        writeln!(output, "  {}: {};", field, field_type).unwrap();
+    }
+
+    output.push_str("}\n\n");
+}
+
+fn variants<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut probe = Probe(&[]);
+    let _ = T::deserialize(&mut probe);
+
+    probe.0
+}
+
+struct Probe(&'static [&'static str]);
+
+impl<'de> Deserializer<'de> for &mut Probe {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not an enum"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        variants: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0 = variants;
+
+        Err(de::Error::custom("probed"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
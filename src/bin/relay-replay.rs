@@ -0,0 +1,64 @@
+use relay::{capture::CaptureFile, replay::Replayer};
+use std::{env, path::Path, process, time::Duration};
+
+const USAGE: &str = "Usage: relay-replay <CAPTURE> <URL> [--wait <SECONDS>]";
+
+#[tokio::main]
+async fn main() {
+    let mut positional = vec![];
+    let mut wait = Duration::from_secs(5);
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "--wait" {
+            match args.next().and_then(|value| value.parse().ok()) {
+                Some(seconds) => wait = Duration::from_secs(seconds),
+                None => {
+                    println!("{}", USAGE);
+                    process::exit(1);
+                }
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    let [path, url] = positional.as_slice() else {
+        println!("{}", USAGE);
+        process::exit(1);
+    };
+
+    let capture_file = match CaptureFile::read(Path::new(path)) {
+        Ok(capture_file) => capture_file,
+        Err(error) => {
+            println!("Failed to read capture {}: {}", path, error);
+            process::exit(1);
+        }
+    };
+
+    let mut replayer = match Replayer::connect(url, &capture_file).await {
+        Ok(replayer) => replayer,
+        Err(error) => {
+            println!("Failed to connect to {}: {}", url, error);
+            process::exit(1);
+        }
+    };
+
+    println!("Replaying into room: {}", replayer.room_id);
+    println!(
+        "Starting {} frames in {} seconds",
+        capture_file.records.len(),
+        wait.as_secs()
+    );
+
+    tokio::time::sleep(wait).await;
+
+    if let Err(error) = replayer.play(&capture_file.records).await {
+        println!("Failed to replay: {}", error);
+        process::exit(1);
+    }
+
+    replayer.close().await;
+
+    println!("Replay finished");
+}
@@ -0,0 +1,5 @@
+mod relay;
+#[cfg(test)]
+mod tests;
+
+pub use relay::*;
@@ -1,30 +1,168 @@
-use std::env;
-use tokio::net::TcpListener;
+#[cfg(unix)]
+use relay::systemd;
+use relay::{
+    config::{Command, Config},
+    selftest, Server,
+};
+use std::{env, net, process};
+use tokio::{
+    io::{self, BufReader},
+    net::TcpListener,
+};
 
-mod relay;
-mod tests;
+fn main() {
+    let config = match Config::parse(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{}", error);
+            process::exit(1);
+        }
+    };
+
+    match config.command {
+        Command::Run => {}
+        Command::Version => {
+            let features: Vec<_> = [
+                ("compression", cfg!(feature = "compression")),
+                ("latency-metrics", cfg!(feature = "latency-metrics")),
+                ("metrics", cfg!(feature = "metrics")),
+                ("mqtt", cfg!(feature = "mqtt")),
+                ("test-util", cfg!(feature = "test-util")),
+            ]
+            .into_iter()
+            .filter_map(|(feature, enabled)| enabled.then_some(feature))
+            .collect();
+
+            let features = if features.is_empty() {
+                String::from("none")
+            } else {
+                features.join(", ")
+            };
+
+            println!("relay {}", Server::VERSION);
+            println!("features: {}", features);
+            process::exit(0);
+        }
+        Command::SelfTest => {
+            let runtime = match config.build_runtime() {
+                Ok(runtime) => runtime,
+                Err(error) => {
+                    println!("Failed to start runtime: {}", error);
+                    process::exit(1);
+                }
+            };
+
+            let report = runtime.block_on(selftest::run(selftest::STEP_TIMEOUT));
+            println!("{}", report);
+            process::exit(if report.passed() { 0 } else { 1 });
+        }
+        Command::CheckConfig => match config.validate() {
+            Ok(()) => {
+                println!("Configuration is valid");
+                process::exit(0);
+            }
+            Err(error) => {
+                println!("{}", error);
+                process::exit(1);
+            }
+        },
+    }
+
+    #[cfg(unix)]
+    let inherited = match systemd::listeners() {
+        Ok(inherited) => inherited,
+        Err(error) => {
+            println!("Failed to adopt inherited sockets: {}", error);
+            process::exit(1);
+        }
+    };
+
+    #[cfg(not(unix))]
+    let inherited = vec![];
+
+    let runtime = match config.build_runtime() {
+        Ok(runtime) => runtime,
+        Err(error) => {
+            println!("Failed to start runtime: {}", error);
+            process::exit(1);
+        }
+    };
+
+    runtime.block_on(run(config, inherited));
+}
 
-#[tokio::main]
-async fn main() {
-    let address = env::args().nth(1).unwrap_or("0.0.0.0".to_string());
-    let port = env::args().nth(2).unwrap_or("0".to_string());
-    let host = env::args().nth(3).unwrap_or("".to_string());
+async fn listen(inherited: Option<net::TcpListener>, address: &str, port: &str) -> TcpListener {
+    let listener = match inherited {
+        Some(listener) => TcpListener::from_std(listener),
+        None => TcpListener::bind(&format!("{}:{}", address, port)).await,
+    };
 
-    let server = relay::Server::new();
+    match listener {
+        Ok(listener) => listener,
+        Err(_) => {
+            println!("Failed to listen on: {}:{}", address, port);
+            process::exit(1);
+        }
+    }
+}
 
-    if let Ok(listener) = TcpListener::bind(&format!("{}:{}", address, port)).await {
-        println!("Listening on: {}", listener.local_addr().unwrap());
+async fn run(config: Config, inherited: Vec<net::TcpListener>) {
+    let address = config.address.clone();
+    let port = config.port.clone();
+    #[cfg(feature = "metrics")]
+    let metrics_port = config.metrics_port.clone();
 
-        while let Ok((tcp_stream, _)) = listener.accept().await {
-            tcp_stream.set_nodelay(true).unwrap();
+    println!("Relay {}", Server::VERSION);
 
-            tokio::spawn(relay::Server::handle_connection(
-                tcp_stream,
-                server.clone(),
-                host.clone(),
-            ));
+    let server = match Server::new(config) {
+        Ok(server) => server,
+        Err(error) => {
+            println!("Failed to start: {}", error);
+            process::exit(1);
         }
-    } else {
-        println!("Failed to listen on: {}:{}", address, port);
+    };
+
+    #[cfg(unix)]
+    tokio::spawn(Server::handle_hangup(server.clone()));
+
+    tokio::spawn(Server::read_commands(
+        server.clone(),
+        BufReader::new(io::stdin()),
+    ));
+
+    let mut inherited = inherited.into_iter();
+    let listener = listen(inherited.next(), &address, &port).await;
+    #[cfg(feature = "metrics")]
+    let metrics_listener = inherited.next();
+
+    #[cfg(feature = "metrics")]
+    if metrics_listener.is_some() || metrics_port.is_some() {
+        let metrics_port = metrics_port.unwrap_or_default();
+        let metrics_listener = listen(metrics_listener, &address, &metrics_port).await;
+
+        println!(
+            "Serving metrics on: {}",
+            metrics_listener.local_addr().unwrap()
+        );
+
+        tokio::spawn(Server::serve_metrics(server.clone(), metrics_listener));
     }
+
+    println!("Listening on: {}", listener.local_addr().unwrap());
+
+    #[cfg(unix)]
+    {
+        systemd::notify("READY=1");
+
+        tokio::select! {
+            _ = Server::serve(server, listener) => {}
+            _ = systemd::terminated() => {
+                systemd::notify("STOPPING=1");
+                process::exit(0);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    Server::serve(server, listener).await;
 }
@@ -0,0 +1,115 @@
+use super::packets::{QualityReport, RemovalReason};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, LineWriter, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditEvent {
+    Create,
+    Join,
+    Leave,
+    Close,
+    Migrate,
+    Quality,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub timestamp: u128,
+    pub event: AuditEvent,
+    pub room_id: String,
+    pub connection_id: u64,
+    pub ip: IpAddr,
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<RemovalReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<QualityReport>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        event: AuditEvent,
+        room_id: &str,
+        connection_id: u64,
+        ip: IpAddr,
+        index: usize,
+        reason: Option<RemovalReason>,
+    ) -> AuditEntry {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        AuditEntry {
+            timestamp,
+            event,
+            room_id: room_id.to_string(),
+            connection_id,
+            ip,
+            index,
+            reason,
+            quality: None,
+        }
+    }
+}
+
+enum Command {
+    Write(AuditEntry),
+    Reopen,
+}
+
+pub struct AuditLog {
+    sender: mpsc::UnboundedSender<Command>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let path = path.to_path_buf();
+        let mut writer = AuditLog::open_writer(&path)?;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+
+        thread::spawn(move || {
+            while let Some(command) = receiver.blocking_recv() {
+                match command {
+                    Command::Write(entry) => {
+                        let mut line = serde_json::to_vec(&entry).unwrap();
+                        line.push(b'\n');
+
+                        if let Err(error) = writer.write_all(&line) {
+                            println!("Failed to write audit log: {}", error);
+                        }
+                    }
+                    Command::Reopen => match AuditLog::open_writer(&path) {
+                        Ok(reopened_writer) => writer = reopened_writer,
+                        Err(error) => println!("Failed to reopen audit log: {}", error),
+                    },
+                }
+            }
+        });
+
+        Ok(AuditLog { sender })
+    }
+
+    fn open_writer(path: &PathBuf) -> io::Result<LineWriter<File>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(LineWriter::new(file))
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        let _ = self.sender.send(Command::Write(entry));
+    }
+
+    pub fn reopen(&self) {
+        let _ = self.sender.send(Command::Reopen);
+    }
+}
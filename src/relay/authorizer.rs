@@ -0,0 +1,43 @@
+use futures_util::future::BoxFuture;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DenyReason {
+    pub detail: Option<String>,
+}
+
+impl DenyReason {
+    pub fn new(detail: impl Into<String>) -> DenyReason {
+        DenyReason {
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+/// Decides whether a client may join a room.
+///
+/// The authorizer is consulted before every join, with the `token` of the [`join`] packet.
+/// A denial, or a panic while authorizing, fails the join with an `"Unauthorized"` error.
+///
+/// [`join`]: crate::relay::packets::RequestPacket::Join
+pub trait JoinAuthorizer: Send + Sync {
+    fn authorize<'a>(
+        &'a self,
+        room_id: &'a str,
+        token: Option<&'a str>,
+        address: SocketAddr,
+    ) -> BoxFuture<'a, Result<(), DenyReason>>;
+}
+
+pub struct AllowAll;
+
+impl JoinAuthorizer for AllowAll {
+    fn authorize<'a>(
+        &'a self,
+        _: &'a str,
+        _: Option<&'a str>,
+        _: SocketAddr,
+    ) -> BoxFuture<'a, Result<(), DenyReason>> {
+        Box::pin(async { Ok(()) })
+    }
+}
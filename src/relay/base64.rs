@@ -0,0 +1,72 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const PADDING: u8 = b'=';
+
+pub fn encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (index, &byte)| {
+                group | u32::from(byte) << (16 - 8 * index)
+            });
+
+        for index in 0..4 {
+            if index <= chunk.len() {
+                output.push(char::from(
+                    ALPHABET[(group >> (18 - 6 * index)) as usize & 63],
+                ));
+            } else {
+                output.push(char::from(PADDING));
+            }
+        }
+    }
+
+    output
+}
+
+pub fn decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.as_bytes();
+
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(input.len() / 4 * 3);
+
+    for (position, chunk) in input.chunks(4).enumerate() {
+        let last = position == input.len() / 4 - 1;
+        let padding = chunk
+            .iter()
+            .rev()
+            .take_while(|&&byte| byte == PADDING)
+            .count();
+
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut group = 0u32;
+        for &byte in &chunk[..4 - padding] {
+            group = group << 6 | u32::from(value(byte)?);
+        }
+        group <<= 6 * padding;
+
+        let bytes = group.to_be_bytes();
+        output.extend_from_slice(&bytes[1..4 - padding]);
+    }
+
+    Some(output)
+}
+
+fn value(byte: u8) -> Option<u8> {
+    match byte {
+        b'A'..=b'Z' => Some(byte - b'A'),
+        b'a'..=b'z' => Some(byte - b'a' + 26),
+        b'0'..=b'9' => Some(byte - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
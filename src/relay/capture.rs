@@ -0,0 +1,243 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureLimits {
+    pub max_duration: Duration,
+    pub max_bytes: u64,
+    pub max_payload: usize,
+}
+
+impl Default for CaptureLimits {
+    fn default() -> CaptureLimits {
+        CaptureLimits {
+            max_duration: Duration::from_secs(60),
+            max_bytes: 16 << 20,
+            max_payload: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Data(Vec<u8>),
+    Hash(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub offset: Duration,
+    pub source: u8,
+    pub destination: u8,
+    pub length: usize,
+    pub payload: Payload,
+}
+
+impl Record {
+    const DATA: u8 = 0;
+    const HASH: u8 = 1;
+
+    pub fn frame(&self) -> Vec<u8> {
+        let mut frame = vec![self.destination];
+
+        if let Payload::Data(data) = &self.payload {
+            frame.extend_from_slice(data);
+        }
+
+        frame.resize(self.length + 1, 0);
+        frame
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let (kind, data) = match &self.payload {
+            Payload::Data(data) => (Record::DATA, data.clone()),
+            Payload::Hash(hash) => (Record::HASH, hash.to_be_bytes().to_vec()),
+        };
+
+        let mut encoded = Vec::with_capacity(19 + data.len());
+        encoded.extend_from_slice(&(self.offset.as_micros() as u64).to_be_bytes());
+        encoded.push(self.source);
+        encoded.push(self.destination);
+        encoded.push(kind);
+        encoded.extend_from_slice(&(self.length as u32).to_be_bytes());
+        encoded.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        encoded.extend_from_slice(&data);
+        encoded
+    }
+
+    fn decode(reader: &mut impl Read) -> io::Result<Option<Record>> {
+        let mut offset = [0; 8];
+        match reader.read_exact(&mut offset) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        }
+
+        let mut fields = [0; 11];
+        reader.read_exact(&mut fields)?;
+
+        let length = u32::from_be_bytes(fields[3..7].try_into().unwrap()) as usize;
+        let data_length = u32::from_be_bytes(fields[7..11].try_into().unwrap()) as usize;
+
+        let mut data = vec![0; data_length];
+        reader.read_exact(&mut data)?;
+
+        let payload = match fields[2] {
+            Record::DATA if data.len() <= length => Payload::Data(data),
+            Record::HASH if data.len() == 8 => {
+                Payload::Hash(u64::from_be_bytes(data.as_slice().try_into().unwrap()))
+            }
+            _ => return Err(invalid_data("invalid record")),
+        };
+
+        Ok(Some(Record {
+            offset: Duration::from_micros(u64::from_be_bytes(offset)),
+            source: fields[0],
+            destination: fields[1],
+            length,
+            payload,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureFile {
+    pub size: usize,
+    pub records: Vec<Record>,
+}
+
+impl CaptureFile {
+    pub const MAGIC: &'static [u8; 8] = b"RELAYCAP";
+    pub const VERSION: u8 = 1;
+    const HEADER_LENGTH: u64 = 10;
+
+    pub fn read(path: &Path) -> io::Result<CaptureFile> {
+        CaptureFile::decode(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn decode(reader: &mut impl Read) -> io::Result<CaptureFile> {
+        let mut header = [0; CaptureFile::HEADER_LENGTH as usize];
+        reader.read_exact(&mut header)?;
+
+        if &header[..8] != CaptureFile::MAGIC {
+            return Err(invalid_data("not a capture file"));
+        }
+
+        if header[8] != CaptureFile::VERSION {
+            return Err(invalid_data("unsupported capture version"));
+        }
+
+        let mut records = vec![];
+        while let Some(record) = Record::decode(reader)? {
+            records.push(record);
+        }
+
+        Ok(CaptureFile {
+            size: usize::from(header[9]),
+            records,
+        })
+    }
+}
+
+pub struct Capture {
+    pub(crate) started_at: Instant,
+    limits: CaptureLimits,
+    written: AtomicU64,
+    full: AtomicBool,
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+    writer: thread::JoinHandle<()>,
+}
+
+impl Capture {
+    pub fn create(path: &Path, size: usize, limits: CaptureLimits) -> io::Result<Capture> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(CaptureFile::MAGIC)?;
+        writer.write_all(&[CaptureFile::VERSION, size as u8])?;
+        writer.flush()?;
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        let writer = thread::spawn(move || {
+            while let Some(record) = receiver.blocking_recv() {
+                let mut result = writer.write_all(&record);
+
+                while let Ok(record) = receiver.try_recv() {
+                    result = result.and_then(|()| writer.write_all(&record));
+                }
+
+                if let Err(error) = result.and_then(|()| writer.flush()) {
+                    println!("Failed to write capture: {}", error);
+                    return;
+                }
+            }
+        });
+
+        Ok(Capture {
+            started_at: Instant::now(),
+            limits,
+            written: AtomicU64::new(CaptureFile::HEADER_LENGTH),
+            full: AtomicBool::new(false),
+            sender,
+            writer,
+        })
+    }
+
+    pub fn record(&self, source: u8, destination: u8, payload: &[u8], opaque: bool) -> bool {
+        if self.is_full() {
+            return false;
+        }
+
+        let payload_data = if opaque {
+            Payload::Hash(fnv1a(payload))
+        } else {
+            Payload::Data(payload[..payload.len().min(self.limits.max_payload)].to_vec())
+        };
+
+        let record = Record {
+            offset: self.started_at.elapsed(),
+            source,
+            destination,
+            length: payload.len(),
+            payload: payload_data,
+        }
+        .encode();
+
+        let length = record.len() as u64;
+        let written = self.written.fetch_add(length, Ordering::Relaxed);
+
+        if written + length > self.limits.max_bytes {
+            return !self.full.swap(true, Ordering::Relaxed);
+        }
+
+        let _ = self.sender.send(record);
+
+        false
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full.load(Ordering::Relaxed)
+    }
+
+    pub fn finish(self) {
+        drop(self.sender);
+
+        let _ = self.writer.join();
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,2318 @@
+#[cfg(feature = "compression")]
+use super::lz4;
+use super::{
+    audit::AuditEvent,
+    authorizer::DenyReason,
+    frame::{self, Chunk, Destination, Source},
+    metrics::{Delivery, Timestamp},
+    packets::{
+        CloseReason, CreateOptions, DropReason, Error, MemberQuality, MemberStatistics,
+        QualityReport, RemovalReason, RequestPacket, ResponsePacket, RoomOptions, WireFormat,
+    },
+    reassembly::ReassemblyStream,
+    room::{Effect, Member, Migration, Mutation, Room, TokenBucket},
+    server::Server,
+    trace::{Direction, Trace},
+};
+use futures_util::{
+    future::{self, join_all, BoxFuture},
+    stream::SplitSink,
+    FutureExt, SinkExt,
+};
+use std::{
+    fmt,
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+    vec,
+};
+use tokio::{sync::Mutex, sync::RwLock};
+use tokio_tungstenite::{
+    tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message},
+    WebSocketStream,
+};
+
+#[derive(Default)]
+pub struct Statistics {
+    pub congestion_skips: AtomicU64,
+    pub expired_frames: AtomicU64,
+    pub conflated_frames: AtomicU64,
+    pub invalid_packets: AtomicU64,
+    pub send_failures: AtomicU64,
+    pub oversized_frames: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct DroppedFrames {
+    pub rate_limit: AtomicU64,
+    pub receiver_congested: AtomicU64,
+    pub expired: AtomicU64,
+    pub budget_exhausted: AtomicU64,
+    pub receiver_limit: AtomicU64,
+    forward: Vec<Arc<DroppedFrames>>,
+}
+
+impl DroppedFrames {
+    /// Creates counters which record every drop into each of `targets` instead, for a paced batch which
+    /// carries frames from several senders.
+    pub fn forwarding(targets: Vec<Arc<DroppedFrames>>) -> DroppedFrames {
+        DroppedFrames {
+            forward: targets,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&self, reason: DropReason) {
+        if !self.forward.is_empty() {
+            for target in &self.forward {
+                target.record(reason);
+            }
+
+            return;
+        }
+
+        self.counter(reason).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn take(&self) -> Option<(u64, DropReason)> {
+        let mut count = 0;
+        let mut dominant = (0, DropReason::RateLimit);
+
+        for reason in DropReason::ALL {
+            let dropped = self.counter(reason).swap(0, Ordering::Relaxed);
+
+            count += dropped;
+            if dropped > dominant.0 {
+                dominant = (dropped, reason);
+            }
+        }
+
+        (count > 0).then_some((count, dominant.1))
+    }
+
+    fn counter(&self, reason: DropReason) -> &AtomicU64 {
+        match reason {
+            DropReason::RateLimit => &self.rate_limit,
+            DropReason::ReceiverCongested => &self.receiver_congested,
+            DropReason::Expired => &self.expired,
+            DropReason::BudgetExhausted => &self.budget_exhausted,
+            DropReason::ReceiverLimit => &self.receiver_limit,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Low,
+}
+
+pub trait Outbound: Send + Sync {
+    fn send(
+        &self,
+        message: Message,
+        priority: Priority,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>>;
+
+    fn send_frame(
+        &self,
+        message: Message,
+        _: Option<Duration>,
+        _: Option<ConflationKey>,
+        _: &Arc<DroppedFrames>,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        self.send(message, Priority::Low)
+    }
+
+    fn close(
+        &self,
+        close_frame: Option<CloseFrame<'static>>,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        match close_frame {
+            Some(close_frame) => self.send(Message::Close(Some(close_frame)), Priority::High),
+            None => Box::pin(future::ready(Ok(()))),
+        }
+    }
+
+    fn queued_bytes(&self) -> usize {
+        0
+    }
+
+    fn is_congested(&self) -> bool {
+        false
+    }
+
+    fn statistics(&self) -> &Statistics;
+}
+
+pub struct Socket {
+    sink: Mutex<SplitSink<WebSocketStream<ReassemblyStream>, Message>>,
+    closed: AtomicBool,
+    statistics: Statistics,
+    trace: Arc<Trace>,
+}
+
+impl Socket {
+    pub fn new(
+        sink: SplitSink<WebSocketStream<ReassemblyStream>, Message>,
+        trace: Arc<Trace>,
+    ) -> Socket {
+        Socket {
+            sink: Mutex::new(sink),
+            closed: AtomicBool::new(false),
+            statistics: Statistics::default(),
+            trace,
+        }
+    }
+}
+
+impl Outbound for Socket {
+    fn send(&self, message: Message, _: Priority) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        Box::pin(async move {
+            debug_assert!(
+                !self.closed.load(Ordering::Relaxed),
+                "Sent a message after the socket was closed"
+            );
+
+            self.trace.record(Direction::Outbound, &message);
+            self.sink.lock().await.send(message).await
+        })
+    }
+
+    fn close(
+        &self,
+        close_frame: Option<CloseFrame<'static>>,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        Box::pin(async move {
+            self.closed.store(true, Ordering::Relaxed);
+
+            let mut sink = self.sink.lock().await;
+
+            let Some(close_frame) = close_frame else {
+                return sink.flush().await;
+            };
+
+            let message = Message::Close(Some(close_frame));
+            self.trace.record(Direction::Outbound, &message);
+
+            let _ = sink.send(message).await;
+            sink.close().await
+        })
+    }
+
+    fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+}
+
+pub type Sender = Arc<dyn Outbound>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientId(pub u64);
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(formatter)
+    }
+}
+
+pub type ConflationKey = (u64, u8);
+
+const _: () = assert!(
+    Server::MAX_MESSAGE_SIZE / (Client::MIN_CHUNK_SIZE - frame::CHUNK_HEADER_LENGTH)
+        < u16::MAX as usize
+);
+
+pub struct Client {
+    pub(crate) connection_id: u64,
+    pub(crate) sender: Sender,
+    pub(crate) room_id: Option<String>,
+    pub(crate) origin: Option<String>,
+    pub(crate) lz4: bool,
+    pub(crate) dropped: Arc<DroppedFrames>,
+    pub(crate) wire_format: WireFormat,
+    pub(crate) max_message_size: usize,
+    pub(crate) chunk_size: Option<usize>,
+    pub(crate) dropped_responses: Arc<AtomicU64>,
+    migration: Arc<Migration>,
+    address: SocketAddr,
+    capabilities: Vec<String>,
+    rate_limited_at: Option<Instant>,
+    invalid_packet_at: Option<Instant>,
+    info_bucket: TokenBucket,
+    queue_depths_bucket: TokenBucket,
+    quality_bucket: TokenBucket,
+    rooms_created: usize,
+}
+
+impl Client {
+    const RATE_LIMITED_INTERVAL: Duration = Duration::from_secs(1);
+    const INVALID_PACKET_INTERVAL: Duration = Duration::from_secs(20);
+    const DETAIL_SEGMENT_LENGTH: usize = 128;
+    const INFO_RATE: u64 = 10;
+    const QUEUE_DEPTHS_RATE: u64 = 10;
+    const QUALITY_REPORT_RATE: u64 = 1;
+    const CLOSED_CODE: u16 = 4001;
+    const UNJOINED_CODE: u16 = 4002;
+    const TIMED_OUT_CODE: u16 = 4003;
+    const EXPIRED_CODE: u16 = 4004;
+    pub const DROPPED_INTERVAL: Duration = Duration::from_secs(1);
+    pub const MAX_RESPONSE_LENGTH: usize = 64 << 10;
+    pub const MIN_CHUNK_SIZE: usize = 4 << 10;
+
+    pub fn new(
+        connection_id: u64,
+        sender: Sender,
+        address: SocketAddr,
+        capabilities: Vec<String>,
+    ) -> Client {
+        Client {
+            connection_id,
+            sender,
+            room_id: None,
+            origin: None,
+            lz4: false,
+            dropped: Arc::new(DroppedFrames::default()),
+            wire_format: WireFormat::default(),
+            max_message_size: usize::MAX,
+            chunk_size: None,
+            dropped_responses: Arc::default(),
+            migration: Arc::new(Migration::default()),
+            address,
+            capabilities,
+            rate_limited_at: None,
+            invalid_packet_at: None,
+            info_bucket: TokenBucket::new(Client::INFO_RATE),
+            queue_depths_bucket: TokenBucket::new(Client::QUEUE_DEPTHS_RATE),
+            quality_bucket: TokenBucket::new(Client::QUALITY_REPORT_RATE),
+            rooms_created: 0,
+        }
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        ClientId(self.connection_id)
+    }
+
+    fn member(&self, virtual_address: Option<String>, now: Instant) -> Member {
+        let mut member = Member::new(
+            self.client_id(),
+            self.sender.clone(),
+            self.address,
+            self.lz4,
+            self.dropped.clone(),
+            self.migration.clone(),
+            now,
+        );
+        member.wire_format = self.wire_format;
+        member.virtual_address = virtual_address;
+        member.max_message_size = self.max_message_size;
+        member.chunk_size = self.chunk_size;
+
+        member
+    }
+
+    async fn send(&self, sender: Sender, message: Message) {
+        if let Err(error) = sender.send(message, Priority::High).await {
+            println!("Failed to send: {}", error);
+        }
+    }
+
+    async fn close(&self, close_frame: Option<CloseFrame<'static>>) {
+        if let Err(error) = self.sender.close(close_frame).await {
+            println!("Failed to send: {}", error);
+        }
+    }
+
+    pub(crate) async fn complete(futures: Vec<BoxFuture<'_, Result<(), tungstenite::Error>>>) {
+        for result in join_all(futures).await {
+            if let Err(error) = result {
+                println!("Failed to send: {}", error);
+            }
+        }
+    }
+
+    fn serialize(&self, packet: &ResponsePacket) -> Option<Message> {
+        encode(self.wire_format, packet, &self.dropped_responses)
+    }
+
+    async fn send_packet(&self, sender: Sender, packet: ResponsePacket) {
+        if let Some(message) = self.serialize(&packet) {
+            self.send(sender, message).await;
+        }
+    }
+
+    async fn send_error_packet(&self, sender: Sender, message: Error) {
+        self.send_detailed_error_packet(sender, message, &[]).await
+    }
+
+    async fn send_detailed_error_packet(&self, sender: Sender, message: Error, detail: &[&str]) {
+        self.send_packet(sender, Client::error_packet(message, detail))
+            .await
+    }
+
+    async fn send_retry_after_packet(&self, retry_after: Duration) {
+        self.send_detailed_error_packet(
+            self.sender.clone(),
+            Error::RateLimited,
+            &[&retry_after.as_secs_f64().ceil().to_string()],
+        )
+        .await
+    }
+
+    pub(crate) fn error_packet(message: Error, detail: &[&str]) -> ResponsePacket {
+        let detail = (!detail.is_empty()).then(|| {
+            detail
+                .iter()
+                .map(|segment| Client::sanitize_detail(segment))
+                .collect::<Vec<_>>()
+                .join(": ")
+        });
+
+        ResponsePacket::Error { message, detail }
+    }
+
+    pub(crate) fn sanitize_detail(segment: &str) -> String {
+        segment
+            .chars()
+            .filter_map(|character| match character {
+                '\t' | '\n' | '\r' => Some(' '),
+                '<' | '>' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' => None,
+                character if character.is_control() => None,
+                character => Some(character),
+            })
+            .take(Client::DETAIL_SEGMENT_LENGTH)
+            .collect()
+    }
+
+    async fn handle_hello(&mut self, max_message_size: Option<usize>, chunk_size: Option<usize>) {
+        if let Some(max_message_size) = max_message_size {
+            self.max_message_size = max_message_size.clamp(1, Server::MAX_MESSAGE_SIZE);
+        }
+
+        if let Some(chunk_size) = chunk_size {
+            self.chunk_size =
+                Some(chunk_size.clamp(Client::MIN_CHUNK_SIZE, Server::MAX_MESSAGE_SIZE));
+        }
+
+        let hello_packet = ResponsePacket::Hello {
+            version: Server::VERSION.to_string(),
+            capabilities: self.capabilities.clone(),
+            max_room_size: Room::MAX_ROOM_SIZE - 1,
+            max_message_size: Server::MAX_MESSAGE_SIZE,
+            connection_id: self.connection_id,
+            chunk_size: self.chunk_size,
+        };
+
+        self.send_packet(self.sender.clone(), hello_packet).await
+    }
+
+    pub(crate) fn check_options(options: &RoomOptions) -> Result<(), &'static str> {
+        if options.allowed_origins.len() > Room::MAX_ALLOWED_ORIGINS
+            || options
+                .allowed_origins
+                .iter()
+                .any(|host| host.is_empty() || host.len() > Room::MAX_ORIGIN_LENGTH)
+        {
+            return Err("allowedOrigins");
+        }
+
+        if options.opaque && options.timestamps {
+            return Err("timestamps");
+        }
+
+        if (options.opaque || !cfg!(feature = "compression")) && options.compression.is_some() {
+            return Err("compression");
+        }
+
+        if options
+            .tick_rate
+            .is_some_and(|tick_rate| tick_rate == 0 || tick_rate > Room::MAX_TICK_RATE)
+        {
+            return Err("tickRate");
+        }
+
+        if options.paced && options.conflate {
+            return Err("conflate");
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn handle_create_room(
+        &mut self,
+        server: &Arc<RwLock<Server>>,
+        size_option: Option<usize>,
+        options: RoomOptions,
+        virtual_address: Option<String>,
+    ) {
+        let size = size_option.unwrap_or(Room::DEFAULT_ROOM_SIZE);
+        if size == Room::MIN_ROOM_SIZE || size >= Room::MAX_ROOM_SIZE {
+            return self
+                .send_error_packet(self.sender.clone(), Error::InvalidSize)
+                .await;
+        }
+
+        if let Err(field) = Client::check_options(&options) {
+            return self
+                .send_detailed_error_packet(self.sender.clone(), Error::InvalidOptions, &[field])
+                .await;
+        }
+
+        if let Err(error) = Client::check_virtual_address(&options, virtual_address.as_deref()) {
+            return self.send_error_packet(self.sender.clone(), error).await;
+        }
+
+        if self.room_id.is_some() {
+            return;
+        }
+
+        let shared_server = server.clone();
+        let mut server = server.write().await;
+        let now = server.clock.now();
+
+        if self.rooms_created >= server.config.max_rooms_per_connection {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::LimitExceeded)
+                .await;
+        }
+
+        if let Some(create_limiter) = &mut server.create_limiter {
+            if let Err(retry_after) = create_limiter.take(self.address.ip(), now) {
+                drop(server);
+
+                return self.send_retry_after_packet(retry_after).await;
+            }
+        }
+
+        let room_id = server.rng.uuid().to_string();
+        if server.deny_list.denies_room(&room_id) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::Denied)
+                .await;
+        }
+
+        if server.rooms.contains_key(&room_id) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::AlreadyExists)
+                .await;
+        }
+
+        let mut options = options;
+        server.limit_options(&mut options);
+
+        let create_packet = ResponsePacket::Create {
+            id: room_id.clone(),
+            size,
+            options: options.clone(),
+        };
+
+        debug_assert!(
+            server.room_of(self.client_id()).is_none(),
+            "Client {} created a room while in another room",
+            self.client_id()
+        );
+
+        let mut room = Room::new(size, options, now);
+        room.dropped_responses = server.dropped_responses.clone();
+        let index = room.add(self.member(virtual_address, now));
+        let acknowledgement = (room.members[index].known_epoch.clone(), room.epoch);
+
+        if server.config.max_room_duration > 0 {
+            room.limit_duration(now + Duration::from_secs(server.config.max_room_duration));
+        }
+
+        Server::schedule_expiry(&shared_server, &room_id, &mut room);
+        Server::schedule_pacing(&shared_server, &room_id, &mut room);
+
+        server.rooms.insert(room_id.clone(), room);
+        self.room_id = Some(room_id.clone());
+        self.rooms_created += 1;
+
+        server.record_event(
+            AuditEvent::Create,
+            &room_id,
+            self.connection_id,
+            self.address.ip(),
+            0,
+            None,
+        );
+
+        drop(server);
+
+        self.send_packet(self.sender.clone(), create_packet).await;
+
+        let (known_epoch, epoch) = acknowledgement;
+        known_epoch.advance(epoch);
+    }
+
+    pub(crate) async fn handle_create_templated_room(
+        &mut self,
+        server: &Arc<RwLock<Server>>,
+        size: Option<usize>,
+        template: String,
+        options: CreateOptions,
+        virtual_address: Option<String>,
+    ) {
+        let templated = server
+            .read()
+            .await
+            .templates
+            .get(&template)
+            .map(|room_template| room_template.apply(size, options));
+
+        let Some((size, options)) = templated else {
+            return self
+                .send_detailed_error_packet(
+                    self.sender.clone(),
+                    Error::UnknownTemplate,
+                    &[&template],
+                )
+                .await;
+        };
+
+        self.handle_create_room(server, size, options, virtual_address)
+            .await
+    }
+
+    async fn check_scanning(&self, server: &RwLock<Server>) -> Result<(), Duration> {
+        server.read().await.check_scanning(self.address.ip())
+    }
+
+    fn allows_origin(&self, allowed_origins: &[String]) -> bool {
+        allowed_origins.is_empty()
+            || self.origin.as_ref().is_some_and(|origin| {
+                allowed_origins
+                    .iter()
+                    .any(|host| Server::matches_host(origin, host))
+            })
+    }
+
+    pub(crate) async fn handle_join_room(
+        &mut self,
+        server: &RwLock<Server>,
+        room_id: String,
+        token: Option<String>,
+        reservation: Option<String>,
+        virtual_address: Option<String>,
+    ) {
+        if let Err(retry_after) = self.check_scanning(server).await {
+            return self.send_retry_after_packet(retry_after).await;
+        }
+
+        let join_authorizer = server.read().await.join_authorizer.clone();
+        let authorization = join_authorizer.authorize(&room_id, token.as_deref(), self.address);
+
+        let deny_reason = match AssertUnwindSafe(authorization).catch_unwind().await {
+            Ok(Ok(())) => None,
+            Ok(Err(deny_reason)) => Some(deny_reason),
+            Err(_) => {
+                println!(
+                    "Join authorizer panicked for connection {} in room: {}",
+                    self.connection_id, room_id
+                );
+
+                Some(DenyReason::default())
+            }
+        };
+
+        if let Some(deny_reason) = deny_reason {
+            return self
+                .send_detailed_error_packet(
+                    self.sender.clone(),
+                    Error::Unauthorized,
+                    deny_reason.detail.as_deref().as_slice(),
+                )
+                .await;
+        }
+
+        if reservation.is_none() && virtual_address.is_none() && self.room_id.is_none() {
+            let server = server.read().await;
+            let now = server.clock.now();
+
+            let refusal = server
+                .rooms
+                .get(&room_id)
+                .filter(|room| {
+                    !server.deny_list.denies_room(&room_id)
+                        && !room.locked
+                        && self.allows_origin(&room.options.allowed_origins)
+                        && Client::check_virtual_address(&room.options, None).is_ok()
+                        && room.is_full(now)
+                })
+                .map(|room| room.refuse_join(self.address.ip(), now));
+
+            if let Some(refusal) = refusal {
+                drop(server);
+
+                return self.send_refused_join_packet(Error::IsFull, refusal).await;
+            }
+        }
+
+        let mut server = server.write().await;
+
+        if server.room_of(self.client_id()).is_some() {
+            return;
+        }
+
+        let now = server.clock.now();
+        let rng = server.rng.clone();
+
+        if server.deny_list.denies_room(&room_id) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::Denied)
+                .await;
+        }
+
+        let Some(room) = server.rooms.get_mut(&room_id) else {
+            server.record_unknown_room(self.address.ip());
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::DoesNotExist)
+                .await;
+        };
+
+        if !self.allows_origin(&room.options.allowed_origins) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::OriginNotAllowed)
+                .await;
+        }
+
+        let mutation = Mutation::Join {
+            member: Box::new(self.member(virtual_address.clone(), now)),
+            reservation,
+        };
+
+        let effects = match room.apply(mutation, &*rng, now) {
+            Ok(effects) => effects,
+            Err(error) => {
+                let refusal = room.refuse_join(self.address.ip(), now);
+                drop(server);
+
+                return self.send_refused_join_packet(error, refusal).await;
+            }
+        };
+
+        self.room_id = Some(room_id.clone());
+
+        let share_addresses = room.options.share_addresses;
+        let virtual_addresses = room.options.virtual_addresses;
+
+        let mut joined = vec![];
+        let mut futures = vec![];
+        let mut joined_index = 0;
+        let mut acknowledgement = None;
+
+        for effect in &effects {
+            match effect {
+                Effect::Joined {
+                    index,
+                    epoch,
+                    peers,
+                } => {
+                    joined_index = *index;
+                    acknowledgement = Some((room.members[*index].known_epoch.clone(), *epoch));
+
+                    let join_packet = ResponsePacket::Join {
+                        size: Some(*index),
+                        capacity: Some(room.size),
+                        index: Some(*index),
+                        address: None,
+                        addresses: share_addresses
+                            .then(|| room.members.iter().map(|member| member.address).collect()),
+                        connection_id: Some(self.connection_id),
+                        virtual_address: None,
+                        virtual_addresses: virtual_addresses.then(|| {
+                            room.members
+                                .iter()
+                                .filter_map(|member| member.virtual_address.clone())
+                                .collect()
+                        }),
+                    };
+
+                    joined.extend(
+                        self.serialize(&join_packet)
+                            .map(|message| self.sender.send(message, Priority::High)),
+                    );
+
+                    if !room.store.is_empty() {
+                        let store_packet = ResponsePacket::Store {
+                            entries: room.store.clone(),
+                        };
+
+                        joined.extend(
+                            self.serialize(&store_packet)
+                                .map(|message| self.sender.send(message, Priority::High)),
+                        );
+                    }
+
+                    if room
+                        .capture
+                        .as_ref()
+                        .is_some_and(|capture| !capture.is_full())
+                    {
+                        let capture_packet = ResponsePacket::Capture { active: true };
+
+                        joined.extend(
+                            self.serialize(&capture_packet)
+                                .map(|message| self.sender.send(message, Priority::High)),
+                        );
+                    }
+
+                    let peer_join_notification = Notification::new(
+                        ResponsePacket::Join {
+                            size: None,
+                            capacity: Some(room.size),
+                            index: Some(*index),
+                            address: share_addresses.then_some(self.address),
+                            addresses: None,
+                            connection_id: None,
+                            virtual_address: virtual_address.clone(),
+                            virtual_addresses: None,
+                        },
+                        &self.dropped_responses,
+                    );
+
+                    let epoch = *epoch;
+
+                    futures.extend(peers.iter().map(|peer| {
+                        let send = peer.send(&peer_join_notification);
+
+                        Box::pin(async move {
+                            let result = match send {
+                                Some(send) => send.await,
+                                None => Ok(()),
+                            };
+                            peer.known_epoch.advance(epoch);
+
+                            result
+                        }) as BoxFuture<'_, _>
+                    }));
+                }
+                Effect::Notify(client_id, sender, message) if *client_id == self.client_id() => {
+                    joined.push(sender.send(message.clone(), Priority::High));
+                }
+                Effect::Notify(_, sender, message) => {
+                    futures.push(sender.send(message.clone(), Priority::High));
+                }
+                _ => {}
+            }
+        }
+
+        server.record_event(
+            AuditEvent::Join,
+            &room_id,
+            self.connection_id,
+            self.address.ip(),
+            joined_index,
+            None,
+        );
+
+        drop(server);
+
+        Client::complete(joined).await;
+
+        if let Some((known_epoch, epoch)) = acknowledgement {
+            known_epoch.advance(epoch);
+        }
+
+        Client::complete(futures).await;
+    }
+
+    pub(crate) async fn handle_leave_room(&mut self, server: &RwLock<Server>) {
+        match self.leave_room(server, RemovalReason::Left).await {
+            Ok(()) => {
+                self.send_packet(self.sender.clone(), ResponsePacket::Left)
+                    .await
+            }
+            Err(error) => self.send_error_packet(self.sender.clone(), error).await,
+        }
+    }
+
+    async fn leave_room(
+        &mut self,
+        server: &RwLock<Server>,
+        reason: RemovalReason,
+    ) -> Result<(), Error> {
+        let mut server = server.write().await;
+        self.follow_migration();
+
+        let Some(room_id) = self.room_id.take() else {
+            return Err(Error::NotInRoom);
+        };
+
+        let rng = server.rng.clone();
+        let now = server.clock.now();
+
+        let Some(room) = server.rooms.get_mut(&room_id) else {
+            return Ok(());
+        };
+
+        let mutation = Mutation::Leave {
+            client_id: self.client_id(),
+        };
+        let effects = room.apply(mutation, &*rng, now)?;
+
+        let mut futures = vec![];
+        let mut left_index = None;
+
+        for effect in &effects {
+            match effect {
+                Effect::Left { index, peers } => {
+                    left_index = Some(*index);
+
+                    let leave_notification = Notification::new(
+                        ResponsePacket::Leave {
+                            index: *index,
+                            reason: Some(reason),
+                        },
+                        &self.dropped_responses,
+                    );
+
+                    futures.extend(
+                        peers
+                            .iter()
+                            .filter_map(|peer| peer.send(&leave_notification)),
+                    );
+                }
+                Effect::Notify(_, sender, message) => {
+                    futures.push(sender.send(message.clone(), Priority::High));
+                }
+                _ => {}
+            }
+        }
+
+        let Some(index) = left_index else {
+            return Ok(());
+        };
+
+        if room.members.is_empty() {
+            server.rooms.remove(&room_id);
+        }
+
+        server.record_event(
+            AuditEvent::Leave,
+            &room_id,
+            self.connection_id,
+            self.address.ip(),
+            index,
+            Some(reason),
+        );
+
+        drop(server);
+        Client::complete(futures).await;
+
+        Ok(())
+    }
+
+    pub(crate) async fn handle_lock_room(&self, server: &RwLock<Server>, locked: bool) {
+        let mut server = server.write().await;
+        let rng = server.rng.clone();
+        let now = server.clock.now();
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get_mut(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        let mutation = Mutation::Lock {
+            client_id: self.client_id(),
+            locked,
+        };
+
+        let effects = match room.apply(mutation, &*rng, now) {
+            Ok(effects) => effects,
+            Err(error) => {
+                drop(server);
+
+                return self.send_error_packet(self.sender.clone(), error).await;
+            }
+        };
+
+        drop(server);
+
+        let mut futures = vec![];
+        for effect in &effects {
+            if let Effect::Locked { locked, recipients } = effect {
+                let locked_notification = Notification::new(
+                    ResponsePacket::Locked { locked: *locked },
+                    &self.dropped_responses,
+                );
+
+                futures.extend(
+                    recipients
+                        .iter()
+                        .filter_map(|peer| peer.send(&locked_notification)),
+                );
+            }
+        }
+
+        Client::complete(futures).await;
+    }
+
+    pub(crate) async fn handle_watch_occupancy(&self, server: &RwLock<Server>, enabled: bool) {
+        let mut server = server.write().await;
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get_mut(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        if !room.is_owner(self.client_id()) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotOwner)
+                .await;
+        }
+
+        room.watch_occupancy = enabled;
+
+        let occupancy_packet = ResponsePacket::Occupancy {
+            count: room.members.len(),
+            capacity: room.size,
+        };
+
+        drop(server);
+
+        if enabled {
+            self.send_packet(self.sender.clone(), occupancy_packet)
+                .await;
+        }
+    }
+
+    pub(crate) async fn handle_reserve(
+        &self,
+        server: &RwLock<Server>,
+        room_id: String,
+        count: usize,
+    ) {
+        if let Err(retry_after) = self.check_scanning(server).await {
+            return self.send_retry_after_packet(retry_after).await;
+        }
+
+        let mut server = server.write().await;
+        let rng = server.rng.clone();
+        let now = server.clock.now();
+
+        let Some(room) = server.rooms.get_mut(&room_id) else {
+            server.record_unknown_room(self.address.ip());
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::DoesNotExist)
+                .await;
+        };
+
+        let effects = room.apply(Mutation::Reserve { count }, &*rng, now);
+        drop(server);
+
+        let effects = match effects {
+            Ok(effects) => effects,
+            Err(error) => return self.send_error_packet(self.sender.clone(), error).await,
+        };
+
+        for effect in effects {
+            if let Effect::Reserved { reservation } = effect {
+                let reserve_packet = ResponsePacket::Reserve {
+                    id: room_id.clone(),
+                    reservation,
+                    count,
+                    ttl_seconds: Room::RESERVATION_TTL.as_secs(),
+                };
+
+                self.send_packet(self.sender.clone(), reserve_packet).await;
+            }
+        }
+    }
+
+    pub(crate) async fn handle_unreserve(
+        &self,
+        server: &RwLock<Server>,
+        room_id: String,
+        reservation: String,
+    ) {
+        let mut server = server.write().await;
+        let rng = server.rng.clone();
+        let now = server.clock.now();
+
+        if let Some(room) = server.rooms.get_mut(&room_id) {
+            let _ = room.apply(Mutation::Unreserve { reservation }, &*rng, now);
+        }
+    }
+
+    pub(crate) async fn handle_subscribe(
+        &self,
+        server: &RwLock<Server>,
+        sources: Option<Vec<usize>>,
+    ) {
+        let mut server = server.write().await;
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get_mut(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        let Some(index) = room.position(self.client_id()) else {
+            return;
+        };
+
+        let sources = sources.map(|sources| {
+            sources
+                .into_iter()
+                .filter_map(|source| room.members.get(source))
+                .map(|member| member.id)
+                .collect()
+        });
+
+        room.members[index].sources = sources;
+
+        let sources = room.members[index].sources.as_ref().map(|sources| {
+            room.members
+                .iter()
+                .enumerate()
+                .filter(|(_, member)| sources.contains(&member.id))
+                .map(|(index, _)| index)
+                .collect()
+        });
+
+        drop(server);
+
+        self.send_packet(
+            self.sender.clone(),
+            ResponsePacket::Subscription { sources },
+        )
+        .await
+    }
+
+    pub(crate) async fn handle_close_room(
+        &mut self,
+        server: &RwLock<Server>,
+        message: Option<String>,
+    ) {
+        let mut server = server.write().await;
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        if !room.is_owner(self.client_id()) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotOwner)
+                .await;
+        }
+
+        let room_id = self.room_id.take().unwrap();
+        let mut room = server.rooms.remove(&room_id).unwrap();
+        let owner = room.owner.unwrap_or_default();
+        let members = room.remove_all(&*server.rng, server.clock.now());
+
+        server.record_event(
+            AuditEvent::Close,
+            &room_id,
+            self.connection_id,
+            self.address.ip(),
+            owner,
+            Some(RemovalReason::Closed),
+        );
+
+        drop(server);
+
+        let close_packet = ResponsePacket::Close {
+            reason: CloseReason::Closed,
+            message,
+        };
+        Server::close_room(
+            members,
+            close_packet,
+            Client::close_frame(RemovalReason::Closed),
+            &self.dropped_responses,
+        )
+        .await
+    }
+
+    pub(crate) async fn handle_migrate(
+        &mut self,
+        server: &Arc<RwLock<Server>>,
+        size_option: Option<usize>,
+    ) {
+        let shared_server = server.clone();
+        let mut server = server.write().await;
+        self.follow_migration();
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        if !room.is_owner(self.client_id()) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotOwner)
+                .await;
+        }
+
+        let size = size_option.unwrap_or(room.size);
+        if size == Room::MIN_ROOM_SIZE || size >= Room::MAX_ROOM_SIZE || size < room.members.len() {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::InvalidSize)
+                .await;
+        }
+
+        let room_id = server.rng.uuid().to_string();
+        if server.deny_list.denies_room(&room_id) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::Denied)
+                .await;
+        }
+
+        if server.rooms.contains_key(&room_id) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::AlreadyExists)
+                .await;
+        }
+
+        let previous_room_id = self.room_id.replace(room_id.clone()).unwrap();
+        let mut previous_room = server.rooms.remove(&previous_room_id).unwrap();
+
+        let mut room = Room::new(size, previous_room.options.clone(), server.clock.now());
+        room.dropped_responses = server.dropped_responses.clone();
+        room.next_member_id = previous_room.next_member_id;
+        room.epoch = previous_room.epoch;
+        room.owner = previous_room.owner;
+        room.watch_occupancy = previous_room.watch_occupancy;
+
+        if let Some(deadline) = previous_room.deadline {
+            room.limit_duration(deadline);
+        }
+
+        room.members = previous_room.remove_all(&*server.rng, server.clock.now());
+        room.occupancy.store(room.members.len(), Ordering::Relaxed);
+
+        Server::schedule_expiry(&shared_server, &room_id, &mut room);
+        Server::schedule_pacing(&shared_server, &room_id, &mut room);
+
+        let peers: Vec<_> = room.members.iter().map(Member::peer).collect();
+
+        for member in &room.members {
+            if member.client_id != self.client_id() {
+                member.migration.set(room_id.clone());
+            }
+        }
+
+        let owner = room.owner.unwrap_or_default();
+        server.rooms.insert(room_id.clone(), room);
+
+        server.record_event(
+            AuditEvent::Migrate,
+            &previous_room_id,
+            self.connection_id,
+            self.address.ip(),
+            owner,
+            None,
+        );
+        server.record_event(
+            AuditEvent::Create,
+            &room_id,
+            self.connection_id,
+            self.address.ip(),
+            owner,
+            None,
+        );
+
+        drop(server);
+
+        let capture_notification = Notification::new(
+            ResponsePacket::Capture { active: false },
+            &self.dropped_responses,
+        );
+        let captured = previous_room.capture.is_some();
+        drop(previous_room);
+
+        let mut futures = vec![];
+        for (index, peer) in peers.iter().enumerate() {
+            let migrated_notification = Notification::new(
+                ResponsePacket::Migrated {
+                    id: room_id.clone(),
+                    index,
+                },
+                &self.dropped_responses,
+            );
+
+            futures.extend(peer.send(&migrated_notification));
+            if captured {
+                futures.extend(peer.send(&capture_notification));
+            }
+        }
+
+        Client::complete(futures).await;
+    }
+
+    fn follow_migration(&mut self) {
+        if let Some(room_id) = self.migration.take() {
+            self.room_id = Some(room_id);
+        }
+    }
+
+    pub(crate) fn close_frame(reason: RemovalReason) -> Option<CloseFrame<'static>> {
+        let code = match reason {
+            RemovalReason::Left | RemovalReason::Disconnected => return None,
+            RemovalReason::TimedOut => Client::TIMED_OUT_CODE,
+            RemovalReason::Closed => Client::CLOSED_CODE,
+            RemovalReason::Expired => Client::EXPIRED_CODE,
+        };
+
+        Some(CloseFrame {
+            code: CloseCode::Library(code),
+            reason: reason.as_str().into(),
+        })
+    }
+
+    pub(crate) async fn handle_unjoined_timeout(&self) {
+        self.send_error_packet(self.sender.clone(), Error::UnjoinedTimeout)
+            .await;
+
+        let close_frame = CloseFrame {
+            code: CloseCode::Library(Client::UNJOINED_CODE),
+            reason: "".into(),
+        };
+
+        self.close(Some(close_frame)).await;
+    }
+
+    pub(crate) async fn handle_set(
+        &self,
+        server: &RwLock<Server>,
+        key: String,
+        value: serde_json::Value,
+    ) {
+        let mut server = server.write().await;
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get_mut(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        let Some(source) = room.position(self.client_id()) else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        if !room.options.open_store && Some(source) != room.owner {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotOwner)
+                .await;
+        }
+
+        if !room.set(key.clone(), value.clone()) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::StoreFull)
+                .await;
+        }
+
+        let set_notification = Notification::new(
+            ResponsePacket::Set { key, value, source },
+            &self.dropped_responses,
+        );
+
+        let peers: Vec<_> = room.members.iter().map(Member::peer).collect();
+        let futures: Vec<_> = peers
+            .iter()
+            .filter_map(|peer| peer.send(&set_notification))
+            .collect();
+
+        drop(server);
+        Client::complete(futures).await;
+    }
+
+    pub(crate) async fn handle_get(&self, server: &RwLock<Server>, key: String) {
+        let server = server.read().await;
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        let value = room.store.get(&key).cloned().unwrap_or_default();
+
+        drop(server);
+
+        self.send_packet(self.sender.clone(), ResponsePacket::Get { key, value })
+            .await
+    }
+
+    pub(crate) async fn handle_info(&mut self, server: &RwLock<Server>, room_id: String) {
+        if !self.info_bucket.take(1, server.read().await.clock.now()) {
+            return self
+                .send_detailed_error_packet(self.sender.clone(), Error::RateLimited, &["info"])
+                .await;
+        }
+
+        if let Err(retry_after) = self.check_scanning(server).await {
+            return self.send_retry_after_packet(retry_after).await;
+        }
+
+        let server = server.read().await;
+
+        let info_packet = match server.rooms.get(&room_id) {
+            Some(room) => ResponsePacket::Info {
+                exists: true,
+                occupancy: room.members.len(),
+                size: room.size,
+                locked: room.locked,
+                expires_at: room
+                    .expires_at
+                    .map(|expires_at| server.unix_millis(expires_at)),
+            },
+            None => {
+                server.record_unknown_room(self.address.ip());
+
+                ResponsePacket::Info {
+                    exists: false,
+                    occupancy: 0,
+                    size: 0,
+                    locked: false,
+                    expires_at: None,
+                }
+            }
+        };
+
+        drop(server);
+
+        self.send_packet(self.sender.clone(), info_packet).await
+    }
+
+    pub(crate) async fn handle_queue_depths(&mut self, server: &RwLock<Server>) {
+        if !self
+            .queue_depths_bucket
+            .take(1, server.read().await.clock.now())
+        {
+            return self
+                .send_detailed_error_packet(
+                    self.sender.clone(),
+                    Error::RateLimited,
+                    &["queueDepths"],
+                )
+                .await;
+        }
+
+        let server = server.read().await;
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        let depths = room
+            .members
+            .iter()
+            .map(|member| u32::try_from(member.sender.queued_bytes()).unwrap_or(u32::MAX))
+            .collect();
+
+        drop(server);
+
+        self.send_packet(self.sender.clone(), ResponsePacket::QueueDepths { depths })
+            .await
+    }
+
+    pub(crate) async fn handle_quality_report(
+        &mut self,
+        server: &RwLock<Server>,
+        report: QualityReport,
+    ) {
+        if !self.quality_bucket.take(1, server.read().await.clock.now()) {
+            return self
+                .send_detailed_error_packet(
+                    self.sender.clone(),
+                    Error::RateLimited,
+                    &["qualityReport"],
+                )
+                .await;
+        }
+
+        let mut server = server.write().await;
+        self.follow_migration();
+
+        let now = server.clock.now();
+
+        let Some((room_id, room)) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| Some(room_id).zip(server.rooms.get_mut(room_id)))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        let Some(index) = room.position(self.client_id()) else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        room.members[index].quality = Some((report, now));
+
+        let room_id = room_id.clone();
+        server.record_quality(
+            &room_id,
+            self.connection_id,
+            self.address.ip(),
+            index,
+            report,
+        );
+    }
+
+    pub(crate) async fn handle_quality(&self, server: &RwLock<Server>) {
+        let server = server.read().await;
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        if !room.is_owner(self.client_id()) {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotOwner)
+                .await;
+        }
+
+        let members = room
+            .members
+            .iter()
+            .enumerate()
+            .map(|(index, member)| {
+                let statistics = member.sender.statistics();
+
+                MemberQuality {
+                    index,
+                    report: member.quality.map(|(report, _)| report),
+                    reported_at: member
+                        .quality
+                        .map(|(_, reported_at)| server.unix_millis(reported_at)),
+                    send_failures: statistics.send_failures.load(Ordering::Relaxed),
+                    dropped_frames: statistics.congestion_skips.load(Ordering::Relaxed)
+                        + statistics.expired_frames.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+
+        drop(server);
+
+        self.send_packet(self.sender.clone(), ResponsePacket::Quality { members })
+            .await
+    }
+
+    pub(crate) async fn handle_stats(&self, server: &RwLock<Server>) {
+        let server = server.read().await;
+
+        let Some(room) = self
+            .room_id
+            .as_ref()
+            .and_then(|room_id| server.rooms.get(room_id))
+        else {
+            drop(server);
+
+            return self
+                .send_error_packet(self.sender.clone(), Error::NotInRoom)
+                .await;
+        };
+
+        let members = room
+            .members
+            .iter()
+            .enumerate()
+            .map(|(index, member)| MemberStatistics {
+                index,
+                queued_bytes: member.sender.queued_bytes(),
+                congestion_skips: member
+                    .sender
+                    .statistics()
+                    .congestion_skips
+                    .load(Ordering::Relaxed),
+                expired_frames: member
+                    .sender
+                    .statistics()
+                    .expired_frames
+                    .load(Ordering::Relaxed),
+                conflated_frames: member
+                    .sender
+                    .statistics()
+                    .conflated_frames
+                    .load(Ordering::Relaxed),
+                invalid_packets: member
+                    .sender
+                    .statistics()
+                    .invalid_packets
+                    .load(Ordering::Relaxed),
+                oversized_frames: member
+                    .sender
+                    .statistics()
+                    .oversized_frames
+                    .load(Ordering::Relaxed),
+            })
+            .collect();
+
+        let rate_limited_frames = room.rate_limited_frames.load(Ordering::Relaxed);
+        let expires_at = room
+            .expires_at
+            .map(|expires_at| server.unix_millis(expires_at));
+
+        drop(server);
+
+        self.send_packet(
+            self.sender.clone(),
+            ResponsePacket::Stats {
+                members,
+                rate_limited_frames,
+                expires_at,
+            },
+        )
+        .await
+    }
+
+    async fn handle_my_address(&self) {
+        let my_address_packet = ResponsePacket::MyAddress {
+            address: self.address,
+        };
+
+        self.send_packet(self.sender.clone(), my_address_packet)
+            .await
+    }
+
+    pub async fn handle_message(&mut self, server: &Arc<RwLock<Server>>, message: Message) {
+        self.follow_migration();
+
+        if message.is_text() {
+            let Ok(text) = message.into_text() else {
+                return;
+            };
+
+            let value = match serde_json::from_str::<serde_json::Value>(&text) {
+                Ok(value) => value,
+                Err(error) => return self.handle_invalid_packet(server, error, &text).await,
+            };
+
+            let Some(packet_type) = value.get("type").and_then(|value| value.as_str()) else {
+                let error = "expected a string field `type`";
+
+                return self.handle_invalid_packet(server, error, &text).await;
+            };
+
+            let packet_type = packet_type.to_string();
+
+            let packet = match serde_json::from_value(value) {
+                Ok(packet) => packet,
+                Err(error) => return self.handle_invalid_packet(server, error, &text).await,
+            };
+
+            match packet {
+                RequestPacket::Hello {
+                    max_message_size,
+                    chunk_size,
+                } => self.handle_hello(max_message_size, chunk_size).await,
+                RequestPacket::Create {
+                    size,
+                    template: None,
+                    virtual_address,
+                    options,
+                } => {
+                    self.handle_create_room(server, size, options.into(), virtual_address)
+                        .await
+                }
+                RequestPacket::Create {
+                    size,
+                    template: Some(template),
+                    virtual_address,
+                    options,
+                } => {
+                    self.handle_create_templated_room(
+                        server,
+                        size,
+                        template,
+                        options,
+                        virtual_address,
+                    )
+                    .await
+                }
+                RequestPacket::Join {
+                    id,
+                    token,
+                    reservation,
+                    virtual_address,
+                } => {
+                    self.handle_join_room(server, id, token, reservation, virtual_address)
+                        .await
+                }
+                RequestPacket::Reserve { id, count } => {
+                    self.handle_reserve(server, id, count).await
+                }
+                RequestPacket::Unreserve { id, reservation } => {
+                    self.handle_unreserve(server, id, reservation).await
+                }
+                RequestPacket::Leave => self.handle_leave_room(server).await,
+                RequestPacket::Stats => self.handle_stats(server).await,
+                RequestPacket::MyAddress => self.handle_my_address().await,
+                RequestPacket::Lock => self.handle_lock_room(server, true).await,
+                RequestPacket::Unlock => self.handle_lock_room(server, false).await,
+                RequestPacket::Info { id } => self.handle_info(server, id).await,
+                RequestPacket::Set { key, value } => self.handle_set(server, key, value).await,
+                RequestPacket::Get { key } => self.handle_get(server, key).await,
+                RequestPacket::CloseRoom { reason } => self.handle_close_room(server, reason).await,
+                RequestPacket::Migrate { size } => self.handle_migrate(server, size).await,
+                RequestPacket::Subscribe { sources } => {
+                    self.handle_subscribe(server, Some(sources)).await
+                }
+                RequestPacket::Unsubscribe => self.handle_subscribe(server, None).await,
+                RequestPacket::WatchOccupancy { enabled } => {
+                    self.handle_watch_occupancy(server, enabled).await
+                }
+                RequestPacket::QueueDepths => self.handle_queue_depths(server).await,
+                RequestPacket::QualityReport {
+                    received,
+                    lost,
+                    jitter_ms,
+                } => {
+                    let report = QualityReport {
+                        received,
+                        lost,
+                        jitter_ms,
+                    };
+
+                    self.handle_quality_report(server, report).await
+                }
+                RequestPacket::Quality => self.handle_quality(server).await,
+                RequestPacket::Unsupported => {
+                    self.send_detailed_error_packet(
+                        self.sender.clone(),
+                        Error::UnsupportedPacket,
+                        &[&packet_type],
+                    )
+                    .await
+                }
+            }
+        } else if message.is_binary() {
+            let Some(room_id) = self.room_id.clone() else {
+                return;
+            };
+
+            let received_at = Timestamp::now();
+            let pool = server.read().await.pool.clone();
+            let task = Client::relay_frame(
+                server.clone(),
+                self.client_id(),
+                room_id.clone(),
+                message.into_data(),
+                received_at,
+            );
+
+            match pool.run(&room_id, task).await {
+                Ok(()) => {}
+                Err(Error::RateLimited) => self.send_rate_limited_packet(server).await,
+                Err(error) => self.send_error_packet(self.sender.clone(), error).await,
+            }
+        }
+    }
+
+    async fn relay_frame(
+        server: Arc<RwLock<Server>>,
+        client_id: ClientId,
+        room_id: String,
+        data: Vec<u8>,
+        received_at: Timestamp,
+    ) -> Result<(), Error> {
+        let server = server.read().await;
+
+        let Some(room) = server.rooms.get(&room_id) else {
+            return Ok(());
+        };
+
+        let Some(index) = room.position(client_id) else {
+            return Ok(());
+        };
+
+        let (destination, payload) =
+            frame::decode_destination(&data, room.options.virtual_addresses)?;
+        let broadcast = matches!(
+            destination,
+            Destination::Broadcast | Destination::Exclude(_) | Destination::Address([])
+        );
+
+        if room.options.conflate && payload.is_empty() {
+            return Err(Error::InvalidFrame);
+        }
+
+        let Some(mut recipients) = room.resolve(index, destination) else {
+            return Ok(());
+        };
+
+        let epoch = room.members[index].known_epoch.get();
+        recipients
+            .retain(|member| member.joined_epoch <= epoch && member.knows(&room.members[index]));
+
+        let source = u8::try_from(index).unwrap();
+        let conflation_key = (room.options.conflate && payload[0] != 0)
+            .then(|| (room.members[index].id, payload[0]));
+
+        let header_source = match &room.members[index].virtual_address {
+            Some(virtual_address) => Source::Address(virtual_address.as_bytes()),
+            None => Source::Index(source),
+        };
+        let now = server.clock.now();
+        let timestamp = room
+            .options
+            .timestamps
+            .then(|| now.saturating_duration_since(room.created_at).as_micros() as u64);
+
+        let mut relayed = Vec::with_capacity(payload.len() + 1 + Room::MAX_VIRTUAL_ADDRESS_LENGTH);
+        frame::encode_source(&mut relayed, header_source, timestamp);
+
+        let payload_offset = relayed.len();
+        relayed.extend_from_slice(payload);
+
+        let data = relayed;
+
+        if let Some(congestion_threshold) = room.options.congestion_threshold.filter(|_| broadcast)
+        {
+            recipients.retain(|member| {
+                if !member.sender.is_congested()
+                    && member.sender.queued_bytes() <= congestion_threshold
+                {
+                    return true;
+                }
+
+                member
+                    .sender
+                    .statistics()
+                    .congestion_skips
+                    .fetch_add(1, Ordering::Relaxed);
+                room.members[index]
+                    .dropped
+                    .record(DropReason::ReceiverCongested);
+
+                false
+            });
+        }
+
+        #[cfg(feature = "compression")]
+        let compressed = room
+            .options
+            .compression
+            .filter(|_| recipients.iter().any(|member| member.lz4))
+            .map(|_| {
+                let threshold = room
+                    .options
+                    .compression_threshold
+                    .unwrap_or(Room::DEFAULT_COMPRESSION_THRESHOLD);
+
+                Client::compress_frame(&data, payload_offset, threshold)
+            });
+
+        #[cfg(not(feature = "compression"))]
+        let compressed: Option<Vec<u8>> = None;
+
+        let encoded = |member: &Member| match &compressed {
+            Some(compressed) if member.lz4 => compressed,
+            _ => &data,
+        };
+
+        let chunk_limit = |member: &Member| {
+            member
+                .chunk_size
+                .map(|chunk_size| chunk_size.min(member.max_message_size))
+                .filter(|&chunk_size| chunk_size >= Client::MIN_CHUNK_SIZE)
+        };
+
+        recipients.retain(|member| {
+            if encoded(member).len() <= member.max_message_size || chunk_limit(member).is_some() {
+                return true;
+            }
+
+            member
+                .sender
+                .statistics()
+                .oversized_frames
+                .fetch_add(1, Ordering::Relaxed);
+            room.members[index]
+                .dropped
+                .record(DropReason::ReceiverLimit);
+
+            false
+        });
+
+        if let Some(bandwidth) = &room.bandwidth {
+            let cost = (data.len() * recipients.len()) as u64;
+
+            if !bandwidth.lock().unwrap().take(cost, now) {
+                room.rate_limited_frames.fetch_add(1, Ordering::Relaxed);
+                room.members[index].dropped.record(DropReason::RateLimit);
+
+                return Err(Error::RateLimited);
+            }
+        }
+
+        #[cfg(any(test, feature = "test-util"))]
+        if let Some(relay_hook) = &server.relay_hook {
+            relay_hook.pause().await;
+        }
+
+        let mut announced = vec![];
+        if let Some(capture) = &room.capture {
+            let captured_destination = match destination {
+                Destination::Index(destination_index) => destination_index,
+                Destination::Owner => Room::OWNER,
+                Destination::Address(virtual_address) => room
+                    .resolve_address(virtual_address)
+                    .map_or(Room::BROADCAST, |destination_index| destination_index as u8),
+                Destination::Broadcast | Destination::Exclude(_) => Room::BROADCAST,
+            };
+
+            if capture.record(
+                source,
+                captured_destination,
+                &data[payload_offset..],
+                room.options.opaque,
+            ) {
+                announced.extend(room.members.iter().map(Member::peer));
+            }
+        }
+
+        let length = data.len();
+        room.members[index].record_sent(length, now);
+
+        let mut senders: Vec<_> = recipients
+            .iter()
+            .map(|member| {
+                member.record_received(length);
+                (member.sender.clone(), member.lz4, chunk_limit(member))
+            })
+            .collect();
+
+        if length * senders.len() > server.outbound_budget.remaining() {
+            senders.sort_by_cached_key(|(sender, _, _)| sender.queued_bytes());
+        }
+
+        let delivery = if broadcast {
+            Delivery::Broadcast
+        } else {
+            Delivery::Unicast
+        };
+
+        let metrics = server.metrics.clone();
+        let latency = metrics.delivery_latency(delivery);
+
+        let message_id = OnceLock::new();
+        let chunks = |frame: &[u8], chunk_size: Option<usize>| {
+            chunk_size
+                .filter(|&chunk_size| frame.len() > chunk_size)
+                .map(|chunk_size| {
+                    let message_id = *message_id.get_or_init(|| {
+                        room.members[index]
+                            .message_ids
+                            .fetch_add(1, Ordering::Relaxed)
+                    });
+
+                    Client::chunk_frame(frame, source, message_id, chunk_size)
+                })
+        };
+
+        if let Some(pacer) = &room.pacer {
+            for member in &recipients {
+                let frame = encoded(member);
+
+                let dropped = &room.members[index].dropped;
+                let pushed = match chunks(frame, chunk_limit(member)) {
+                    Some(chunks) => chunks
+                        .iter()
+                        .all(|chunk| pacer.push(member.client_id, chunk, dropped)),
+                    None => pacer.push(member.client_id, frame, dropped),
+                };
+
+                if !pushed {
+                    member
+                        .sender
+                        .statistics()
+                        .congestion_skips
+                        .fetch_add(1, Ordering::Relaxed);
+                    room.members[index]
+                        .dropped
+                        .record(DropReason::ReceiverCongested);
+
+                    continue;
+                }
+
+                pacer.received(member.client_id, delivery, received_at);
+            }
+
+            senders.clear();
+        }
+
+        let ttl = room.options.frame_ttl_ms.map(Duration::from_millis);
+        let dropped = room.members[index].dropped.clone();
+        let dropped = &dropped;
+        let mut futures: Vec<_> = senders
+            .iter()
+            .map(|(sender, lz4, chunk_size)| {
+                let frame = match &compressed {
+                    Some(compressed) if *lz4 => compressed,
+                    _ => &data,
+                };
+
+                let future = match chunks(frame, *chunk_size) {
+                    Some(chunks) => Box::pin(async move {
+                        for chunk in chunks {
+                            sender
+                                .send_frame(Message::Binary(chunk), None, None, dropped)
+                                .await?;
+                        }
+
+                        Ok(())
+                    }),
+                    None => sender.send_frame(
+                        Message::Binary(frame.clone()),
+                        ttl,
+                        conflation_key,
+                        dropped,
+                    ),
+                };
+
+                Box::pin(async move {
+                    future.await?;
+                    latency.record(received_at);
+
+                    Ok(())
+                }) as BoxFuture<'_, _>
+            })
+            .collect();
+
+        let capture_notification = Notification::new(
+            ResponsePacket::Capture { active: false },
+            &server.dropped_responses,
+        );
+        futures.extend(
+            announced
+                .iter()
+                .filter_map(|peer| peer.send(&capture_notification)),
+        );
+
+        drop(server);
+
+        Client::complete(futures).await;
+
+        Ok(())
+    }
+
+    pub(crate) fn check_virtual_address(
+        options: &RoomOptions,
+        virtual_address: Option<&str>,
+    ) -> Result<(), Error> {
+        let valid = match virtual_address {
+            Some(virtual_address) => {
+                options.virtual_addresses
+                    && !virtual_address.is_empty()
+                    && virtual_address.len() <= Room::MAX_VIRTUAL_ADDRESS_LENGTH
+            }
+            None => !options.virtual_addresses,
+        };
+
+        if !valid {
+            return Err(Error::InvalidVirtualAddress);
+        }
+
+        Ok(())
+    }
+
+    fn chunk_frame(frame: &[u8], source: u8, message_id: u32, chunk_size: usize) -> Vec<Vec<u8>> {
+        let length = chunk_size - frame::CHUNK_HEADER_LENGTH;
+        let count = frame.len().div_ceil(length) as u16;
+
+        frame
+            .chunks(length)
+            .enumerate()
+            .map(|(index, data)| {
+                let mut chunk = Vec::with_capacity(frame::CHUNK_HEADER_LENGTH + data.len());
+                frame::encode_chunk(
+                    &mut chunk,
+                    Chunk {
+                        source,
+                        message_id,
+                        index: index as u16,
+                        count,
+                        data,
+                    },
+                );
+
+                chunk
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "compression")]
+    fn compress_frame(data: &[u8], payload_offset: usize, threshold: usize) -> Vec<u8> {
+        let (header, payload) = data.split_at(payload_offset);
+
+        let mut frame = Vec::with_capacity(data.len() + 1);
+        frame.extend_from_slice(header);
+
+        if payload.len() > threshold {
+            let compressed = lz4::compress(payload);
+
+            if compressed.len() + 4 < payload.len() {
+                frame.push(Room::LZ4);
+                frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                frame.extend_from_slice(&compressed);
+
+                return frame;
+            }
+        }
+
+        frame.push(Room::UNCOMPRESSED);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    pub(crate) async fn handle_dropped_frames(&self) {
+        let Some((count, reason)) = self.dropped.take() else {
+            return;
+        };
+
+        self.send_packet(
+            self.sender.clone(),
+            ResponsePacket::Dropped { count, reason },
+        )
+        .await
+    }
+
+    async fn send_refused_join_packet(&self, error: Error, refusal: Result<(), Duration>) {
+        match refusal {
+            Ok(()) => self.send_error_packet(self.sender.clone(), error).await,
+            Err(_) => {
+                self.send_detailed_error_packet(self.sender.clone(), Error::RateLimited, &["join"])
+                    .await
+            }
+        }
+    }
+
+    async fn send_rate_limited_packet(&mut self, server: &RwLock<Server>) {
+        let now = server.read().await.clock.now();
+
+        if let Some(rate_limited_at) = self.rate_limited_at {
+            if now.duration_since(rate_limited_at) < Client::RATE_LIMITED_INTERVAL {
+                return;
+            }
+        }
+
+        self.rate_limited_at = Some(now);
+
+        self.send_error_packet(self.sender.clone(), Error::RateLimited)
+            .await
+    }
+
+    async fn handle_invalid_packet(
+        &mut self,
+        server: &RwLock<Server>,
+        error: impl ToString,
+        text: &str,
+    ) {
+        server
+            .read()
+            .await
+            .invalid_packets
+            .fetch_add(1, Ordering::Relaxed);
+
+        self.sender
+            .statistics()
+            .invalid_packets
+            .fetch_add(1, Ordering::Relaxed);
+
+        let now = server.read().await.clock.now();
+
+        if let Some(invalid_packet_at) = self.invalid_packet_at {
+            if now.duration_since(invalid_packet_at) < Client::INVALID_PACKET_INTERVAL {
+                return;
+            }
+        }
+
+        self.invalid_packet_at = Some(now);
+
+        self.send_detailed_error_packet(
+            self.sender.clone(),
+            Error::InvalidPacket,
+            &[&error.to_string(), text],
+        )
+        .await
+    }
+
+    pub async fn handle_close(&mut self, server: &RwLock<Server>, reason: RemovalReason) {
+        let _ = self.leave_room(server, reason).await;
+
+        self.close(Client::close_frame(reason)).await;
+
+        server.write().await.traces.remove(&self.connection_id);
+    }
+}
+
+pub(crate) fn encode(
+    wire_format: WireFormat,
+    packet: &ResponsePacket,
+    dropped_responses: &AtomicU64,
+) -> Option<Message> {
+    if !wire_format.supports(packet) {
+        return None;
+    }
+
+    check_response(wire_format.encode(packet), dropped_responses)
+}
+
+pub struct Notification {
+    packet: ResponsePacket,
+    dropped_responses: Arc<AtomicU64>,
+    v1: OnceLock<Option<Message>>,
+    v2: OnceLock<Option<Message>>,
+}
+
+impl Notification {
+    pub fn new(packet: ResponsePacket, dropped_responses: &Arc<AtomicU64>) -> Notification {
+        Notification {
+            packet,
+            dropped_responses: dropped_responses.clone(),
+            v1: OnceLock::new(),
+            v2: OnceLock::new(),
+        }
+    }
+
+    pub fn encode(&self, wire_format: WireFormat) -> Option<Message> {
+        let encoded = match wire_format {
+            WireFormat::V1 => &self.v1,
+            WireFormat::V2 => &self.v2,
+        };
+
+        encoded
+            .get_or_init(|| encode(wire_format, &self.packet, &self.dropped_responses))
+            .clone()
+    }
+}
+
+fn check_response(
+    result: Result<String, serde_json::Error>,
+    dropped_responses: &AtomicU64,
+) -> Option<Message> {
+    let text = match result {
+        Ok(text) => text,
+        Err(error) => {
+            println!("Failed to serialize a response: {}", error);
+            dropped_responses.fetch_add(1, Ordering::Relaxed);
+
+            return None;
+        }
+    };
+
+    if text.len() > Client::MAX_RESPONSE_LENGTH {
+        println!("Dropped a response of {} bytes", text.len());
+        dropped_responses.fetch_add(1, Ordering::Relaxed);
+
+        return None;
+    }
+
+    Some(Message::Text(text))
+}
@@ -0,0 +1,59 @@
+use futures_util::future::BoxFuture;
+use std::time::Instant;
+use tokio::time;
+use uuid::{Builder, Uuid};
+
+/// Tells the time for the timeouts and rate limits of the server, such as room expiry,
+/// reservations, frame expiry and frame timestamps.
+///
+/// The server uses the [`SystemClock`] unless another clock is installed with
+/// [`Server::set_clock`], which lets tests advance time instead of sleeping.
+///
+/// [`Server::set_clock`]: crate::relay::Server::set_clock
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Box::pin(time::sleep_until(deadline.into()))
+    }
+}
+
+/// Generates the random values of the server, such as room identifiers and reservation tokens.
+///
+/// The server uses the [`SystemRng`] unless another generator is installed with
+/// [`Server::set_rng`].
+///
+/// [`Server::set_rng`]: crate::relay::Server::set_rng
+pub trait Rng: Send + Sync {
+    fn next_u64(&self) -> u64;
+
+    fn uuid(&self) -> Uuid {
+        let bytes = (u128::from(self.next_u64()) << 64 | u128::from(self.next_u64())).to_le_bytes();
+
+        Builder::from_random_bytes(bytes).into_uuid()
+    }
+}
+
+pub struct SystemRng;
+
+impl Rng for SystemRng {
+    fn next_u64(&self) -> u64 {
+        let (high, low) = Uuid::new_v4().as_u64_pair();
+
+        high ^ low
+    }
+
+    fn uuid(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
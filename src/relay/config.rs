@@ -0,0 +1,472 @@
+#[cfg(feature = "mqtt")]
+use super::mqtt::Broker;
+use super::{deny::DenyList, packets::WireFormat, templates::Templates};
+use socket2::SockRef;
+use std::{
+    fs::{self, OpenOptions},
+    io, mem,
+    net::IpAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+};
+use tokio::{net::TcpStream, runtime};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Command {
+    #[default]
+    Run,
+    CheckConfig,
+    SelfTest,
+    Version,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Runtime {
+    Current,
+    #[default]
+    Multi,
+}
+
+impl FromStr for Runtime {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Runtime, ()> {
+        match value {
+            "current" => Ok(Runtime::Current),
+            "multi" => Ok(Runtime::Multi),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub send_buffer_bytes: Option<usize>,
+    pub recv_buffer_bytes: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            nodelay: true,
+            send_buffer_bytes: None,
+            recv_buffer_bytes: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    pub fn apply(&self, tcp_stream: &TcpStream) -> io::Result<()> {
+        tcp_stream.set_nodelay(self.nodelay)?;
+
+        let socket = SockRef::from(tcp_stream);
+
+        if let Some(send_buffer_bytes) = self.send_buffer_bytes {
+            socket.set_send_buffer_size(send_buffer_bytes)?;
+        }
+
+        if let Some(recv_buffer_bytes) = self.recv_buffer_bytes {
+            socket.set_recv_buffer_size(recv_buffer_bytes)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuthConfig {
+    pub host: String,
+    pub api_keys: Vec<String>,
+    pub allow_null_origin: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MqttConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub presence: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub command: Command,
+    pub address: String,
+    pub port: String,
+    pub metrics_port: Option<String>,
+    pub auth: AuthConfig,
+    pub max_room_bandwidth: Option<u64>,
+    pub max_room_ttl: Option<u64>,
+    pub max_room_duration: u64,
+    pub max_connections: Option<usize>,
+    pub max_handshakes: usize,
+    pub max_creates_per_minute: Option<usize>,
+    pub max_failed_joins_per_minute: Option<usize>,
+    pub max_global_failed_joins_per_minute: Option<usize>,
+    pub max_rooms_per_connection: usize,
+    pub unjoined_timeout: u64,
+    pub message_timeout: u64,
+    pub send_timeout: u64,
+    pub test_console: bool,
+    pub long_polling: bool,
+    pub audit_quality: bool,
+    pub max_outbound_bytes: usize,
+    pub wire_format: WireFormat,
+    pub audit_log: Option<PathBuf>,
+    pub deny_list: Option<PathBuf>,
+    pub templates: Option<PathBuf>,
+    pub inline_templates: Templates,
+    pub mqtt: Option<MqttConfig>,
+    pub socket: SocketOptions,
+    pub workers: usize,
+    pub runtime: Runtime,
+    pub worker_threads: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            command: Command::Run,
+            address: String::from("0.0.0.0"),
+            port: String::from("0"),
+            metrics_port: None,
+            auth: AuthConfig::default(),
+            max_room_bandwidth: None,
+            max_room_ttl: None,
+            max_room_duration: Config::DEFAULT_MAX_ROOM_DURATION,
+            max_connections: None,
+            max_handshakes: Config::DEFAULT_MAX_HANDSHAKES,
+            max_creates_per_minute: None,
+            max_failed_joins_per_minute: None,
+            max_global_failed_joins_per_minute: None,
+            max_rooms_per_connection: Config::DEFAULT_MAX_ROOMS_PER_CONNECTION,
+            unjoined_timeout: Config::DEFAULT_UNJOINED_TIMEOUT,
+            message_timeout: Config::DEFAULT_MESSAGE_TIMEOUT,
+            send_timeout: 0,
+            test_console: false,
+            long_polling: false,
+            audit_quality: false,
+            max_outbound_bytes: Config::DEFAULT_MAX_OUTBOUND_BYTES,
+            wire_format: WireFormat::default(),
+            audit_log: None,
+            deny_list: None,
+            templates: None,
+            inline_templates: Templates::default(),
+            mqtt: None,
+            socket: SocketOptions::default(),
+            workers: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            runtime: Runtime::default(),
+            worker_threads: None,
+        }
+    }
+}
+
+impl Config {
+    pub const DEFAULT_MAX_HANDSHAKES: usize = 1024;
+    pub const DEFAULT_MAX_ROOM_DURATION: u64 = 24 * 60 * 60;
+    pub const DEFAULT_MAX_ROOMS_PER_CONNECTION: usize = 100;
+    pub const DEFAULT_UNJOINED_TIMEOUT: u64 = 60;
+    pub const DEFAULT_MESSAGE_TIMEOUT: u64 = 30_000;
+    pub const DEFAULT_MAX_OUTBOUND_BYTES: usize = 256 << 20;
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut positional = vec![];
+        let mut flags = vec![];
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                positional.push(arg);
+                continue;
+            };
+
+            match flag {
+                "version" => {
+                    config.command = Command::Version;
+                    continue;
+                }
+                "check-config" => {
+                    config.command = Command::CheckConfig;
+                    continue;
+                }
+                "self-test" => {
+                    config.command = Command::SelfTest;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let Some(value) = args.next() else {
+                return Err(format!("Missing value for: --{}", flag));
+            };
+
+            flags.push((flag.to_string(), value));
+        }
+
+        if let Some((_, path)) = flags.iter().rev().find(|(flag, _)| flag == "config") {
+            config.load(Path::new(path))?;
+        }
+
+        for (index, arg) in positional.into_iter().enumerate() {
+            match index {
+                0 => config.address = arg,
+                1 => config.port = arg,
+                2 => config.auth.host = arg,
+                _ => return Err(format!("Unexpected argument: {}", arg)),
+            }
+        }
+
+        let mut replace_api_keys = !config.auth.api_keys.is_empty();
+
+        for (flag, value) in flags {
+            match flag.as_str() {
+                "config" => {}
+                "api-key" if mem::take(&mut replace_api_keys) => config.auth.api_keys = vec![value],
+                _ => config.set(&flag, value)?,
+            }
+        }
+
+        if config.runtime == Runtime::Current && config.worker_threads.is_some() {
+            return Err(String::from(
+                "Invalid combination: --worker-threads requires --runtime multi",
+            ));
+        }
+
+        Ok(config)
+    }
+
+    fn load(&mut self, path: &Path) -> Result<(), String> {
+        let invalid = |error: String| format!("Invalid config {}: {}", path.display(), error);
+
+        let contents = fs::read_to_string(path).map_err(|error| invalid(error.to_string()))?;
+        let table: toml::Table = contents
+            .parse()
+            .map_err(|error: toml::de::Error| invalid(error.to_string()))?;
+
+        for (key, value) in table {
+            self.load_value(&key, value).map_err(invalid)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_value(&mut self, key: &str, value: toml::Value) -> Result<(), String> {
+        let value = match value {
+            toml::Value::Array(values) if key == "api-key" => {
+                for value in values {
+                    let toml::Value::String(value) = value else {
+                        return Err(format!("Invalid value for {}: {}", key, value));
+                    };
+
+                    self.auth.api_keys.push(value);
+                }
+
+                return Ok(());
+            }
+            toml::Value::Table(table) if key == "templates" => {
+                let templates = toml::Value::Table(table)
+                    .try_into()
+                    .map_err(|error| format!("Invalid value for {}: {}", key, error))?;
+
+                self.inline_templates = Templates::new(templates)?;
+
+                return Ok(());
+            }
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Boolean(true) => String::from("on"),
+            toml::Value::Boolean(false) => String::from("off"),
+            value => return Err(format!("Invalid value for {}: {}", key, value)),
+        };
+
+        match key {
+            "address" => self.address = value,
+            "port" => self.port = value,
+            "host" => self.auth.host = value,
+            "config" | "version" | "check-config" | "self-test" => {
+                return Err(format!("Unexpected argument: {}", key));
+            }
+            _ => self.set(key, value)?,
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, flag: &str, value: String) -> Result<(), String> {
+        match flag {
+            "max-room-bandwidth" => {
+                self.max_room_bandwidth = Some(parse_value(flag, &value)?);
+            }
+            "max-room-ttl" => self.max_room_ttl = Some(parse_value(flag, &value)?),
+            "max-room-duration" => self.max_room_duration = parse_value(flag, &value)?,
+            "unjoined-timeout" => self.unjoined_timeout = parse_value(flag, &value)?,
+            "message-timeout" => self.message_timeout = parse_value(flag, &value)?,
+            "send-timeout" => self.send_timeout = parse_value(flag, &value)?,
+            "max-outbound-bytes" => {
+                self.max_outbound_bytes = parse_positive_value(flag, &value)?;
+            }
+            "wire-format" => self.wire_format = parse_value(flag, &value)?,
+            "max-connections" => {
+                self.max_connections = Some(parse_positive_value(flag, &value)?);
+            }
+            "max-handshakes" => self.max_handshakes = parse_positive_value(flag, &value)?,
+            "max-creates-per-minute" => {
+                self.max_creates_per_minute = Some(parse_positive_value(flag, &value)?);
+            }
+            "max-failed-joins-per-minute" => {
+                self.max_failed_joins_per_minute = Some(parse_positive_value(flag, &value)?);
+            }
+            "max-global-failed-joins-per-minute" => {
+                self.max_global_failed_joins_per_minute = Some(parse_positive_value(flag, &value)?);
+            }
+            "max-rooms-per-connection" => {
+                self.max_rooms_per_connection = parse_positive_value(flag, &value)?;
+            }
+            #[cfg(feature = "metrics")]
+            "metrics-port" => self.metrics_port = Some(value),
+            "api-key" => self.auth.api_keys.push(value),
+            "workers" => self.workers = parse_positive_value(flag, &value)?,
+            "runtime" => self.runtime = parse_value(flag, &value)?,
+            "worker-threads" => {
+                self.worker_threads = Some(parse_positive_value(flag, &value)?);
+            }
+            "audit-log" => self.audit_log = Some(PathBuf::from(value)),
+            "deny-list" => self.deny_list = Some(PathBuf::from(value)),
+            "templates" => self.templates = Some(PathBuf::from(value)),
+            #[cfg(feature = "mqtt")]
+            "mqtt-url" => self.mqtt.get_or_insert_with(MqttConfig::default).url = value,
+            #[cfg(feature = "mqtt")]
+            "mqtt-username" => {
+                self.mqtt.get_or_insert_with(MqttConfig::default).username = Some(value);
+            }
+            #[cfg(feature = "mqtt")]
+            "mqtt-password" => {
+                self.mqtt.get_or_insert_with(MqttConfig::default).password = Some(value);
+            }
+            #[cfg(feature = "mqtt")]
+            "mqtt-presence" => {
+                self.mqtt.get_or_insert_with(MqttConfig::default).presence =
+                    parse_switch(flag, &value)?;
+            }
+            "null-origin" => self.auth.allow_null_origin = parse_switch(flag, &value)?,
+            "nodelay" => self.socket.nodelay = parse_switch(flag, &value)?,
+            "test-console" => self.test_console = parse_switch(flag, &value)?,
+            "long-polling" => self.long_polling = parse_switch(flag, &value)?,
+            "audit-quality" => self.audit_quality = parse_switch(flag, &value)?,
+            "send-buffer-bytes" => {
+                self.socket.send_buffer_bytes = Some(parse_positive_value(flag, &value)?);
+            }
+            "recv-buffer-bytes" => {
+                self.socket.recv_buffer_bytes = Some(parse_positive_value(flag, &value)?);
+            }
+            _ => return Err(format!("Unknown argument: --{}", flag)),
+        }
+
+        Ok(())
+    }
+
+    pub fn build_runtime(&self) -> io::Result<runtime::Runtime> {
+        let mut builder = match self.runtime {
+            Runtime::Current => runtime::Builder::new_current_thread(),
+            Runtime::Multi => runtime::Builder::new_multi_thread(),
+        };
+
+        if let Some(worker_threads) = self.worker_threads {
+            builder.worker_threads(worker_threads);
+        }
+
+        builder.enable_all().build()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.address.parse::<IpAddr>().is_err() {
+            return Err(format!("Invalid address: {}", self.address));
+        }
+
+        if self.port.parse::<u16>().is_err() {
+            return Err(format!("Invalid port: {}", self.port));
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_port) = &self.metrics_port {
+            if metrics_port.parse::<u16>().is_err() {
+                return Err(format!("Invalid metrics port: {}", metrics_port));
+            }
+        }
+
+        if let Some(path) = &self.audit_log {
+            let writable = if path.exists() {
+                OpenOptions::new().append(true).open(path).map(|_| ())
+            } else {
+                match path
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                {
+                    Some(parent) if !parent.is_dir() => Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "directory does not exist",
+                    )),
+                    _ => Ok(()),
+                }
+            };
+
+            if let Err(error) = writable {
+                return Err(format!("Invalid audit log {}: {}", path.display(), error));
+            }
+        }
+
+        if let Some(path) = &self.deny_list {
+            if let Err(error) = DenyList::load(path) {
+                return Err(format!("Invalid deny list {}: {}", path.display(), error));
+            }
+        }
+
+        self.load_templates()?;
+
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            if mqtt.url.is_empty() {
+                return Err(String::from("Missing value for: --mqtt-url"));
+            }
+
+            Broker::parse_url(&mqtt.url)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn load_templates(&self) -> Result<Templates, String> {
+        match &self.templates {
+            Some(path) => Templates::load(path)
+                .map_err(|error| error.to_string())
+                .and_then(|templates| templates.merge(&self.inline_templates))
+                .map_err(|error| format!("Invalid templates {}: {}", path.display(), error)),
+            None => Ok(self.inline_templates.clone()),
+        }
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for --{}: {}", flag, value))
+}
+
+fn parse_switch(flag: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("Invalid value for --{}: {}", flag, value)),
+    }
+}
+
+fn parse_positive_value(flag: &str, value: &str) -> Result<usize, String> {
+    match parse_value(flag, value)? {
+        0 => Err(format!("Invalid value for --{}: {}", flag, value)),
+        value => Ok(value),
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    net::IpAddr,
+    path::Path,
+};
+
+#[derive(Debug, Default)]
+pub struct DenyList {
+    networks: HashMap<u8, HashSet<IpAddr>>,
+    rooms: HashSet<String>,
+    room_prefixes: HashMap<usize, HashSet<String>>,
+}
+
+impl DenyList {
+    pub fn load(path: &Path) -> io::Result<DenyList> {
+        let contents = fs::read_to_string(path)?;
+
+        DenyList::parse(&contents)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    pub fn parse(contents: &str) -> Result<DenyList, String> {
+        let mut deny_list = DenyList::default();
+
+        for (number, line) in contents.lines().enumerate() {
+            let entry = line.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            if let Ok(ip) = entry.parse::<IpAddr>() {
+                let prefix_length = match ip {
+                    IpAddr::V4(_) => 32,
+                    IpAddr::V6(_) => 128,
+                };
+
+                deny_list.insert_network(ip, prefix_length);
+            } else if let Some((ip, prefix_length)) = entry.split_once('/') {
+                let network = ip
+                    .parse::<IpAddr>()
+                    .ok()
+                    .zip(prefix_length.parse::<u8>().ok())
+                    .and_then(|(ip, prefix_length)| {
+                        mask(ip, prefix_length).map(|network| (network, prefix_length))
+                    });
+
+                let Some((network, prefix_length)) = network else {
+                    return Err(format!("Invalid network on line {}: {}", number + 1, entry));
+                };
+
+                deny_list.insert_network(network, prefix_length);
+            } else if let Some(prefix) = entry.strip_suffix('*') {
+                deny_list
+                    .room_prefixes
+                    .entry(prefix.len())
+                    .or_default()
+                    .insert(prefix.to_string());
+            } else {
+                deny_list.rooms.insert(entry.to_string());
+            }
+        }
+
+        Ok(deny_list)
+    }
+
+    fn insert_network(&mut self, network: IpAddr, prefix_length: u8) {
+        self.networks
+            .entry(prefix_length)
+            .or_default()
+            .insert(network);
+    }
+
+    pub fn denies_ip(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|(prefix_length, networks)| {
+            mask(ip, *prefix_length).is_some_and(|network| networks.contains(&network))
+        })
+    }
+
+    pub fn denies_room(&self, room_id: &str) -> bool {
+        if self.rooms.contains(room_id) {
+            return true;
+        }
+
+        self.room_prefixes.iter().any(|(length, prefixes)| {
+            room_id
+                .get(..*length)
+                .is_some_and(|prefix| prefixes.contains(prefix))
+        })
+    }
+}
+
+fn mask(ip: IpAddr, prefix_length: u8) -> Option<IpAddr> {
+    match ip {
+        IpAddr::V4(ip) if prefix_length <= 32 => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_length))
+                .unwrap_or(0);
+
+            Some(IpAddr::from((u32::from(ip) & mask).to_be_bytes()))
+        }
+        IpAddr::V6(ip) if prefix_length <= 128 => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_length))
+                .unwrap_or(0);
+
+            Some(IpAddr::from((u128::from(ip) & mask).to_be_bytes()))
+        }
+        _ => None,
+    }
+}
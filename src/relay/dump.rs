@@ -0,0 +1,280 @@
+use super::{
+    packets::RoomOptions,
+    room::{Member, Room},
+    server::Server,
+};
+use serde::Serialize;
+use std::{io, net::SocketAddr, sync::atomic::Ordering, time::Instant};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::RwLock,
+};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerDump {
+    pub version: &'static str,
+    pub rooms: usize,
+    pub sessions: usize,
+    pub traces: usize,
+    pub connection_panics: u64,
+    pub invalid_packets: u64,
+    pub slow_messages: u64,
+    pub limits: LimitsDump,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LimitsDump {
+    pub connections: Usage,
+    pub handshakes: Usage,
+    pub outbound_bytes: Usage,
+    pub max_room_bandwidth: Option<u64>,
+    pub max_room_ttl: Option<u64>,
+    pub max_room_duration: u64,
+    pub max_creates_per_minute: Option<usize>,
+    pub max_failed_joins_per_minute: Option<usize>,
+    pub max_global_failed_joins_per_minute: Option<usize>,
+    pub max_rooms_per_connection: usize,
+    pub unjoined_timeout: u64,
+    pub message_timeout: u64,
+    pub send_timeout: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Usage {
+    pub limit: Option<usize>,
+    pub used: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomDump {
+    pub id: String,
+    pub size: usize,
+    pub owner: Option<usize>,
+    pub locked: bool,
+    pub watch_occupancy: bool,
+    pub captured: bool,
+    pub options: RoomOptions,
+    pub store_keys: usize,
+    pub store_bytes: usize,
+    pub rate_limited_frames: u64,
+    pub reservations: Vec<ReservationDump>,
+    pub timers: TimersDump,
+    pub members: Vec<MemberDump>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReservationDump {
+    pub remaining: usize,
+    pub expires_in_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimersDump {
+    pub expires_in_ms: Option<u128>,
+    pub deadline_in_ms: Option<u128>,
+    pub vacant_for_ms: Option<u128>,
+    pub tick_interval_ms: Option<u128>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemberDump {
+    pub index: usize,
+    pub id: u64,
+    pub connection_id: u64,
+    pub address: SocketAddr,
+    pub virtual_address: Option<String>,
+    pub lz4: bool,
+    pub subscriptions: Option<usize>,
+    pub uptime_ms: u128,
+    pub idle_ms: u128,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub queued_bytes: usize,
+    pub congested: bool,
+    pub congestion_skips: u64,
+    pub expired_frames: u64,
+    pub conflated_frames: u64,
+    pub invalid_packets: u64,
+    pub send_failures: u64,
+    pub max_message_size: Option<usize>,
+    pub oversized_frames: u64,
+    pub chunk_size: Option<usize>,
+}
+
+impl ServerDump {
+    pub fn new(server: &Server) -> ServerDump {
+        let config = &server.config;
+
+        ServerDump {
+            version: Server::VERSION,
+            rooms: server.rooms.len(),
+            sessions: server.sessions.len(),
+            traces: server.traces.len(),
+            connection_panics: server.connection_panics(),
+            invalid_packets: server.invalid_packets(),
+            slow_messages: server.slow_messages(),
+            limits: LimitsDump {
+                connections: Usage {
+                    limit: config.max_connections,
+                    used: server.connections(),
+                },
+                handshakes: Usage {
+                    limit: Some(config.max_handshakes),
+                    used: server.handshakes(),
+                },
+                outbound_bytes: Usage {
+                    limit: Some(config.max_outbound_bytes),
+                    used: server.outbound_budget.used(),
+                },
+                max_room_bandwidth: config.max_room_bandwidth,
+                max_room_ttl: config.max_room_ttl,
+                max_room_duration: config.max_room_duration,
+                max_creates_per_minute: config.max_creates_per_minute,
+                max_failed_joins_per_minute: config.max_failed_joins_per_minute,
+                max_global_failed_joins_per_minute: config.max_global_failed_joins_per_minute,
+                max_rooms_per_connection: config.max_rooms_per_connection,
+                unjoined_timeout: config.unjoined_timeout,
+                message_timeout: config.message_timeout,
+                send_timeout: config.send_timeout,
+            },
+        }
+    }
+}
+
+impl RoomDump {
+    pub fn new(room_id: &str, room: &Room, now: Instant) -> RoomDump {
+        let remaining = |instant: Instant| instant.saturating_duration_since(now).as_millis();
+
+        RoomDump {
+            id: room_id.to_string(),
+            size: room.size,
+            owner: room.owner,
+            locked: room.locked,
+            watch_occupancy: room.watch_occupancy,
+            captured: room.capture.is_some(),
+            options: room.options.clone(),
+            store_keys: room.store.len(),
+            store_bytes: room.store_bytes,
+            rate_limited_frames: room.rate_limited_frames.load(Ordering::Relaxed),
+            reservations: room
+                .reservations
+                .values()
+                .filter(|reservation| reservation.expires_at > now)
+                .map(|reservation| ReservationDump {
+                    remaining: reservation.remaining,
+                    expires_in_ms: remaining(reservation.expires_at),
+                })
+                .collect(),
+            timers: TimersDump {
+                expires_in_ms: room.expires_at.map(remaining),
+                deadline_in_ms: room.deadline.map(remaining),
+                vacant_for_ms: room.vacant_until.map(remaining),
+                tick_interval_ms: room.pacer.as_ref().map(|pacer| pacer.interval.as_millis()),
+            },
+            members: room
+                .members
+                .iter()
+                .enumerate()
+                .map(|(index, member)| MemberDump::new(index, member, now))
+                .collect(),
+        }
+    }
+}
+
+impl MemberDump {
+    pub fn new(index: usize, member: &Member, now: Instant) -> MemberDump {
+        let sender = &member.sender;
+        let statistics = sender.statistics();
+
+        MemberDump {
+            index,
+            id: member.id,
+            connection_id: member.client_id.0,
+            address: member.address,
+            virtual_address: member.virtual_address.clone(),
+            lz4: member.lz4,
+            subscriptions: member.sources.as_ref().map(|sources| sources.len()),
+            uptime_ms: now.saturating_duration_since(member.joined_at).as_millis(),
+            idle_ms: now
+                .saturating_duration_since(member.last_active_at())
+                .as_millis(),
+            bytes_sent: member.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: member.bytes_received.load(Ordering::Relaxed),
+            queued_bytes: sender.queued_bytes(),
+            congested: sender.is_congested(),
+            congestion_skips: statistics.congestion_skips.load(Ordering::Relaxed),
+            expired_frames: statistics.expired_frames.load(Ordering::Relaxed),
+            conflated_frames: statistics.conflated_frames.load(Ordering::Relaxed),
+            invalid_packets: statistics.invalid_packets.load(Ordering::Relaxed),
+            send_failures: statistics.send_failures.load(Ordering::Relaxed),
+            max_message_size: (member.max_message_size != usize::MAX)
+                .then_some(member.max_message_size),
+            oversized_frames: statistics.oversized_frames.load(Ordering::Relaxed),
+            chunk_size: member.chunk_size,
+        }
+    }
+}
+
+pub async fn write(
+    server: &RwLock<Server>,
+    output: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<()> {
+    let (server_dump, mut room_ids) = {
+        let server = server.read().await;
+        let room_ids: Vec<_> = server.rooms.keys().cloned().collect();
+
+        (ServerDump::new(&server), room_ids)
+    };
+
+    room_ids.sort();
+
+    output.write_all(b"{\n  \"server\": ").await?;
+    output
+        .write_all(indent(&server_dump, 2)?.as_bytes())
+        .await?;
+    output.write_all(b",\n  \"rooms\": [").await?;
+
+    let mut written = 0;
+
+    for room_id in room_ids {
+        let room_dump = {
+            let server = server.read().await;
+            let now = server.clock.now();
+
+            server
+                .rooms
+                .get(&room_id)
+                .map(|room| RoomDump::new(&room_id, room, now))
+        };
+
+        let Some(room_dump) = room_dump else {
+            continue;
+        };
+
+        output
+            .write_all(if written == 0 { b"\n    " } else { b",\n    " })
+            .await?;
+        output.write_all(indent(&room_dump, 4)?.as_bytes()).await?;
+
+        written += 1;
+    }
+
+    if written > 0 {
+        output.write_all(b"\n  ").await?;
+    }
+
+    output.write_all(b"]\n}\n").await?;
+    output.flush().await
+}
+
+fn indent(value: &impl Serialize, width: usize) -> io::Result<String> {
+    let pretty = serde_json::to_string_pretty(value)?;
+
+    Ok(pretty.replace('\n', &format!("\n{}", " ".repeat(width))))
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn take(&mut self, limit: f64, rate: f64, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(limit);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - self.tokens) / rate));
+        }
+
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+struct Backoff {
+    strikes: u32,
+    until: Instant,
+}
+
+pub struct IpLimiter {
+    limit: u64,
+    period: Duration,
+    capacity: usize,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl IpLimiter {
+    pub const DEFAULT_CAPACITY: usize = 4096;
+
+    pub fn new(limit: u64, period: Duration, capacity: usize) -> IpLimiter {
+        IpLimiter {
+            limit,
+            period,
+            capacity,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn take(&mut self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= self.capacity {
+            self.evict(now);
+        }
+
+        let limit = self.limit as f64;
+        let rate = limit / self.period.as_secs_f64();
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: limit,
+            updated: now,
+        });
+
+        bucket.take(limit, rate, now)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    fn evict(&mut self, now: Instant) {
+        let limit = self.limit as f64;
+        let rate = limit / self.period.as_secs_f64();
+
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+
+            bucket.tokens + elapsed * rate < limit
+        });
+
+        if self.buckets.len() < self.capacity {
+            return;
+        }
+
+        let oldest = self
+            .buckets
+            .iter()
+            .min_by_key(|(_, bucket)| bucket.updated)
+            .map(|(ip, _)| *ip);
+
+        if let Some(ip) = oldest {
+            self.buckets.remove(&ip);
+        }
+    }
+}
+
+pub struct ScanGuard {
+    ip_limiter: Option<IpLimiter>,
+    global: Option<(u64, Bucket)>,
+    capacity: usize,
+    backoffs: HashMap<IpAddr, Backoff>,
+}
+
+impl ScanGuard {
+    pub const PERIOD: Duration = Duration::from_secs(60);
+    pub const BASE_BACKOFF: Duration = Duration::from_secs(1);
+    pub const MAX_BACKOFF: Duration = Duration::from_secs(64);
+    pub const BAN_STRIKES: u32 = 8;
+    pub const BAN_DURATION: Duration = Duration::from_secs(600);
+
+    pub fn new(
+        ip_limit: Option<u64>,
+        global_limit: Option<u64>,
+        capacity: usize,
+        now: Instant,
+    ) -> ScanGuard {
+        ScanGuard {
+            ip_limiter: ip_limit.map(|limit| IpLimiter::new(limit, ScanGuard::PERIOD, capacity)),
+            global: global_limit.map(|limit| {
+                (
+                    limit,
+                    Bucket {
+                        tokens: limit as f64,
+                        updated: now,
+                    },
+                )
+            }),
+            capacity,
+            backoffs: HashMap::new(),
+        }
+    }
+
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        match self.backoffs.get(&ip) {
+            Some(backoff) if backoff.until > now => Err(backoff.until - now),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        self.backoffs
+            .get(&ip)
+            .is_some_and(|backoff| ScanGuard::bans(backoff, now))
+    }
+
+    pub fn banned(&self, now: Instant) -> usize {
+        self.backoffs
+            .values()
+            .filter(|backoff| ScanGuard::bans(backoff, now))
+            .count()
+    }
+
+    pub fn fail(&mut self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let ip_budget = self
+            .ip_limiter
+            .as_mut()
+            .map_or(Ok(()), |ip_limiter| ip_limiter.take(ip, now));
+        let global_budget = self.global.as_mut().map_or(Ok(()), |(limit, bucket)| {
+            let limit = *limit as f64;
+
+            bucket.take(limit, limit / ScanGuard::PERIOD.as_secs_f64(), now)
+        });
+
+        if ip_budget.is_ok() && global_budget.is_ok() {
+            return Ok(());
+        }
+
+        if !self.backoffs.contains_key(&ip) && self.backoffs.len() >= self.capacity {
+            self.evict(now);
+        }
+
+        let backoff = self.backoffs.entry(ip).or_insert(Backoff {
+            strikes: 0,
+            until: now,
+        });
+
+        if backoff.until + ScanGuard::BAN_DURATION <= now {
+            backoff.strikes = 0;
+        }
+
+        backoff.strikes += 1;
+
+        let duration = if backoff.strikes >= ScanGuard::BAN_STRIKES {
+            ScanGuard::BAN_DURATION
+        } else {
+            (ScanGuard::BASE_BACKOFF * 2u32.pow(backoff.strikes - 1)).min(ScanGuard::MAX_BACKOFF)
+        };
+
+        backoff.until = now + duration;
+
+        Err(duration)
+    }
+
+    pub fn len(&self) -> usize {
+        self.backoffs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backoffs.is_empty()
+    }
+
+    fn bans(backoff: &Backoff, now: Instant) -> bool {
+        backoff.strikes >= ScanGuard::BAN_STRIKES && backoff.until > now
+    }
+
+    fn evict(&mut self, now: Instant) {
+        self.backoffs
+            .retain(|_, backoff| backoff.until + ScanGuard::BAN_DURATION > now);
+
+        if self.backoffs.len() < self.capacity {
+            return;
+        }
+
+        let oldest = self
+            .backoffs
+            .iter()
+            .filter(|(_, backoff)| backoff.strikes < ScanGuard::BAN_STRIKES)
+            .min_by_key(|(_, backoff)| backoff.until)
+            .or_else(|| {
+                self.backoffs
+                    .iter()
+                    .min_by_key(|(_, backoff)| backoff.until)
+            })
+            .map(|(ip, _)| *ip);
+
+        if let Some(ip) = oldest {
+            self.backoffs.remove(&ip);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+const MIN_MATCH: usize = 4;
+const MATCH_LIMIT: usize = 12;
+const LAST_LITERALS: usize = 5;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_LOG: u32 = 12;
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 16);
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut position = 0;
+
+    if input.len() > MATCH_LIMIT {
+        let limit = input.len() - MATCH_LIMIT;
+        let match_end = input.len() - LAST_LITERALS;
+
+        while position < limit {
+            let sequence = read_u32(input, position);
+            let hash = hash(sequence);
+            let candidate = table[hash];
+            table[hash] = position + 1;
+
+            if candidate > 0
+                && position - (candidate - 1) <= MAX_OFFSET
+                && read_u32(input, candidate - 1) == sequence
+            {
+                let candidate = candidate - 1;
+                let mut length = MIN_MATCH;
+
+                while position + length < match_end
+                    && input[candidate + length] == input[position + length]
+                {
+                    length += 1;
+                }
+
+                write_sequence(
+                    &mut output,
+                    &input[anchor..position],
+                    Some((position - candidate, length)),
+                );
+
+                position += length;
+                anchor = position;
+            } else {
+                position += 1;
+            }
+        }
+    }
+
+    write_sequence(&mut output, &input[anchor..], None);
+    output
+}
+
+pub fn decompress(input: &[u8], length: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(length);
+    let mut position = 0;
+
+    loop {
+        let token = *input.get(position)?;
+        position += 1;
+
+        let mut literal_length = usize::from(token >> 4);
+        if literal_length == 15 {
+            literal_length += read_length(input, &mut position)?;
+        }
+
+        if output.len() + literal_length > length {
+            return None;
+        }
+
+        output.extend_from_slice(input.get(position..position + literal_length)?);
+        position += literal_length;
+
+        if position == input.len() {
+            break;
+        }
+
+        let offset = usize::from(u16::from_le_bytes(
+            input.get(position..position + 2)?.try_into().unwrap(),
+        ));
+        position += 2;
+
+        if offset == 0 || offset > output.len() {
+            return None;
+        }
+
+        let mut match_length = usize::from(token & 15) + MIN_MATCH;
+        if token & 15 == 15 {
+            match_length += read_length(input, &mut position)?;
+        }
+
+        if output.len() + match_length > length {
+            return None;
+        }
+
+        let start = output.len() - offset;
+        for index in start..start + match_length {
+            output.push(output[index]);
+        }
+    }
+
+    (output.len() == length).then_some(output)
+}
+
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_length = found.map_or(0, |(_, length)| length - MIN_MATCH);
+
+    output.push((literals.len().min(15) << 4 | match_length.min(15)) as u8);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+
+    output.extend_from_slice(literals);
+
+    if let Some((offset, _)) = found {
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+
+        if match_length >= 15 {
+            write_length(output, match_length - 15);
+        }
+    }
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+
+    output.push(length as u8);
+}
+
+fn read_length(input: &[u8], position: &mut usize) -> Option<usize> {
+    let mut length = 0;
+
+    loop {
+        let byte = *input.get(*position)?;
+        *position += 1;
+        length += usize::from(byte);
+
+        if byte != 255 {
+            return Some(length);
+        }
+    }
+}
+
+fn read_u32(input: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes(input[position..position + 4].try_into().unwrap())
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
@@ -0,0 +1,193 @@
+use std::{fmt::Write, time::Duration};
+#[cfg(feature = "latency-metrics")]
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Unicast,
+    Broadcast,
+}
+
+impl Delivery {
+    pub const ALL: [Delivery; 2] = [Delivery::Unicast, Delivery::Broadcast];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Delivery::Unicast => "unicast",
+            Delivery::Broadcast => "broadcast",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    #[cfg(feature = "latency-metrics")]
+    instant: Instant,
+}
+
+impl Timestamp {
+    #[inline]
+    pub fn now() -> Timestamp {
+        Timestamp {
+            #[cfg(feature = "latency-metrics")]
+            instant: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    #[cfg(feature = "latency-metrics")]
+    buckets: [AtomicU64; Histogram::BOUNDS.len() + 1],
+    #[cfg(feature = "latency-metrics")]
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub const BOUNDS: [Duration; 16] = [
+        Duration::from_micros(10),
+        Duration::from_micros(25),
+        Duration::from_micros(50),
+        Duration::from_micros(100),
+        Duration::from_micros(250),
+        Duration::from_micros(500),
+        Duration::from_millis(1),
+        Duration::from_micros(2500),
+        Duration::from_millis(5),
+        Duration::from_millis(10),
+        Duration::from_millis(25),
+        Duration::from_millis(50),
+        Duration::from_millis(100),
+        Duration::from_millis(250),
+        Duration::from_millis(500),
+        Duration::from_secs(1),
+    ];
+
+    #[inline]
+    pub fn record(&self, #[allow(unused)] received_at: Timestamp) {
+        #[cfg(feature = "latency-metrics")]
+        {
+            let elapsed = received_at.instant.elapsed();
+            let bucket = Histogram::BOUNDS.partition_point(|&bound| bound < elapsed);
+
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+            self.sum_nanos
+                .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn buckets(&self) -> Vec<u64> {
+        #[cfg(feature = "latency-metrics")]
+        return self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+
+        #[cfg(not(feature = "latency-metrics"))]
+        return vec![0; Histogram::BOUNDS.len() + 1];
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets().iter().sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        #[cfg(feature = "latency-metrics")]
+        return Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed));
+
+        #[cfg(not(feature = "latency-metrics"))]
+        return Duration::ZERO;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    unicast: Histogram,
+    broadcast: Histogram,
+}
+
+impl Metrics {
+    pub const LATENCY: &'static str = "relay_delivery_latency_seconds";
+
+    pub fn delivery_latency(&self, delivery: Delivery) -> &Histogram {
+        match delivery {
+            Delivery::Unicast => &self.unicast,
+            Delivery::Broadcast => &self.broadcast,
+        }
+    }
+
+    pub fn render(&self, output: &mut String) {
+        if !cfg!(feature = "latency-metrics") {
+            return;
+        }
+
+        let name = Metrics::LATENCY;
+
+        writeln!(output, "# TYPE {} histogram", name).unwrap();
+        writeln!(output, "# UNIT {} seconds", name).unwrap();
+        writeln!(
+            output,
+            "# HELP {} Time from a frame being received to it being handed to each destination's writer.",
+            name
+        )
+        .unwrap();
+
+        for delivery in Delivery::ALL {
+            let histogram = self.delivery_latency(delivery);
+            let label = delivery.label();
+            let mut count = 0;
+
+            for (bucket, bound) in histogram.buckets().into_iter().zip(
+                Histogram::BOUNDS
+                    .iter()
+                    .map(|bound| render_seconds(*bound))
+                    .chain([String::from("+Inf")]),
+            ) {
+                count += bucket;
+
+                writeln!(
+                    output,
+                    "{}_bucket{{delivery=\"{}\",le=\"{}\"}} {}",
+                    name, label, bound, count
+                )
+                .unwrap();
+            }
+
+            writeln!(output, "{}_count{{delivery=\"{}\"}} {}", name, label, count).unwrap();
+            writeln!(
+                output,
+                "{}_sum{{delivery=\"{}\"}} {}",
+                name,
+                label,
+                render_seconds(histogram.sum())
+            )
+            .unwrap();
+        }
+    }
+}
+
+pub fn render_counter(output: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(output, "# TYPE {} counter", name).unwrap();
+    writeln!(output, "# HELP {} {}", name, help).unwrap();
+    writeln!(output, "{}_total {}", name, value).unwrap();
+}
+
+pub fn render_gauge(output: &mut String, name: &str, help: &str, value: usize) {
+    writeln!(output, "# TYPE {} gauge", name).unwrap();
+    writeln!(output, "# HELP {} {}", name, help).unwrap();
+    writeln!(output, "{} {}", name, value).unwrap();
+}
+
+fn render_seconds(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+
+    if seconds.fract() == 0.0 {
+        format!("{:.1}", seconds)
+    } else {
+        seconds.to_string()
+    }
+}
@@ -0,0 +1,241 @@
+use super::{audit::AuditEntry, config::MqttConfig};
+use futures_util::future::BoxFuture;
+use serde_json::json;
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+    time,
+};
+
+pub trait MqttClient: Send + 'static {
+    fn publish<'a>(
+        &'a mut self,
+        topic: &'a str,
+        payload: &'a [u8],
+        retain: bool,
+    ) -> BoxFuture<'a, io::Result<()>>;
+}
+
+struct Publish {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+pub struct MqttPublisher {
+    sender: mpsc::Sender<Publish>,
+    presence: bool,
+    dropped: AtomicU64,
+}
+
+impl MqttPublisher {
+    pub const QUEUE_CAPACITY: usize = 1024;
+
+    pub fn start(mut client: impl MqttClient, presence: bool) -> MqttPublisher {
+        let (sender, mut receiver) = mpsc::channel::<Publish>(MqttPublisher::QUEUE_CAPACITY);
+
+        tokio::spawn(async move {
+            while let Some(publish) = receiver.recv().await {
+                if let Err(error) = client
+                    .publish(&publish.topic, &publish.payload, publish.retain)
+                    .await
+                {
+                    println!("Failed to publish to MQTT broker: {}", error);
+                }
+            }
+        });
+
+        MqttPublisher {
+            sender,
+            presence,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, entry: &AuditEntry) {
+        self.publish(
+            format!("relay/rooms/{}/events", entry.room_id),
+            serde_json::to_vec(entry).unwrap(),
+            false,
+        );
+    }
+
+    pub fn presence(&self, room_id: &str, occupancy: Option<(usize, usize)>) {
+        if !self.presence {
+            return;
+        }
+
+        let payload = occupancy.map_or_else(Vec::new, |(occupancy, size)| {
+            serde_json::to_vec(&json!({ "occupancy": occupancy, "size": size })).unwrap()
+        });
+
+        self.publish(format!("relay/rooms/{}/presence", room_id), payload, true);
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn publish(&self, topic: String, payload: Vec<u8>, retain: bool) {
+        let publish = Publish {
+            topic,
+            payload,
+            retain,
+        };
+
+        if self.sender.try_send(publish).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct Broker {
+    config: MqttConfig,
+    stream: Option<TcpStream>,
+}
+
+impl Broker {
+    pub const DEFAULT_PORT: u16 = 1883;
+    pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    const CONNECT: u8 = 0x10;
+    const CONNACK: u8 = 0x20;
+    const PUBLISH: u8 = 0x30;
+
+    pub fn new(config: MqttConfig) -> Broker {
+        Broker {
+            config,
+            stream: None,
+        }
+    }
+
+    pub fn parse_url(url: &str) -> Result<String, String> {
+        let host = url
+            .strip_prefix("mqtt://")
+            .filter(|host| !host.is_empty() && !host.contains('/'))
+            .ok_or_else(|| format!("Invalid MQTT URL: {}", url))?;
+
+        match host.rsplit_once(':') {
+            Some((_, port)) if !host.ends_with(']') => match port.parse::<u16>() {
+                Ok(_) => Ok(host.to_string()),
+                Err(_) => Err(format!("Invalid MQTT URL: {}", url)),
+            },
+            _ => Ok(format!("{}:{}", host, Broker::DEFAULT_PORT)),
+        }
+    }
+
+    async fn connect(&self) -> io::Result<TcpStream> {
+        let address = Broker::parse_url(&self.config.url)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+
+        let mut stream = time::timeout(Broker::CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        stream.write_all(&self.connect_packet()).await?;
+
+        let mut connack = [0; 4];
+        time::timeout(Broker::CONNECT_TIMEOUT, stream.read_exact(&mut connack))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        match connack {
+            [Broker::CONNACK, 2, _, 0] => Ok(stream),
+            [Broker::CONNACK, 2, _, code] => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("connection refused with code {}", code),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid connack packet",
+            )),
+        }
+    }
+
+    fn connect_packet(&self) -> Vec<u8> {
+        let mut flags = 0x02;
+        if self.config.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.config.password.is_some() {
+            flags |= 0x40;
+        }
+
+        let mut body = vec![];
+        write_string(&mut body, b"MQTT");
+        body.extend_from_slice(&[4, flags, 0, 0]);
+        write_string(
+            &mut body,
+            format!("relay-{}", uuid::Uuid::new_v4().simple()).as_bytes(),
+        );
+
+        for field in [&self.config.username, &self.config.password]
+            .into_iter()
+            .flatten()
+        {
+            write_string(&mut body, field.as_bytes());
+        }
+
+        encode_packet(Broker::CONNECT, &body)
+    }
+}
+
+impl MqttClient for Broker {
+    fn publish<'a>(
+        &'a mut self,
+        topic: &'a str,
+        payload: &'a [u8],
+        retain: bool,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => self.stream.insert(self.connect().await?),
+            };
+
+            let mut body = vec![];
+            write_string(&mut body, topic.as_bytes());
+            body.extend_from_slice(payload);
+
+            let packet = encode_packet(Broker::PUBLISH | u8::from(retain), &body);
+
+            if let Err(error) = stream.write_all(&packet).await {
+                self.stream = None;
+                return Err(error);
+            }
+
+            Ok(())
+        })
+    }
+}
+
+fn write_string(output: &mut Vec<u8>, value: &[u8]) {
+    output.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    output.extend_from_slice(value);
+}
+
+fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+
+        if length == 0 {
+            packet.push(byte);
+            break;
+        }
+
+        packet.push(byte | 0x80);
+    }
+
+    packet.extend_from_slice(body);
+    packet
+}
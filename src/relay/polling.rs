@@ -0,0 +1,189 @@
+use super::{
+    base64,
+    client::{Client, Outbound, Priority, Statistics},
+    packets::RemovalReason,
+    Server,
+};
+use futures_util::future::BoxFuture;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{Mutex as ClientMutex, Notify, OwnedSemaphorePermit, RwLock},
+    time,
+};
+use tungstenite::Message;
+
+#[derive(Default)]
+pub struct Mailbox {
+    messages: Mutex<VecDeque<Message>>,
+    closed: AtomicBool,
+    arrived: Notify,
+    space: Notify,
+    statistics: Statistics,
+}
+
+impl Mailbox {
+    pub const CAPACITY: usize = 256;
+
+    fn is_empty(&self) -> bool {
+        self.messages.lock().unwrap().is_empty()
+    }
+
+    fn take(&self) -> Vec<Message> {
+        let messages = self.messages.lock().unwrap().drain(..).collect();
+        self.space.notify_waiters();
+
+        messages
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.space.notify_waiters();
+    }
+}
+
+impl Outbound for Arc<Mailbox> {
+    fn send(&self, message: Message, _: Priority) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        Box::pin(async move {
+            loop {
+                let space = self.space.notified();
+
+                if self.closed.load(Ordering::Relaxed) {
+                    return Err(tungstenite::Error::AlreadyClosed);
+                }
+
+                {
+                    let mut messages = self.messages.lock().unwrap();
+
+                    if messages.len() < Mailbox::CAPACITY {
+                        messages.push_back(message);
+                        self.arrived.notify_one();
+
+                        return Ok(());
+                    }
+                }
+
+                space.await;
+            }
+        })
+    }
+
+    fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+}
+
+pub struct Session {
+    pub(crate) client: ClientMutex<Client>,
+    mailbox: Arc<Mailbox>,
+    activity: Notify,
+    ended: Notify,
+}
+
+impl Session {
+    pub const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+    pub const EXPIRY: Duration = Duration::from_secs(35);
+
+    pub fn new(client: Client, mailbox: Arc<Mailbox>) -> Session {
+        Session {
+            client: ClientMutex::new(client),
+            mailbox,
+            activity: Notify::new(),
+            ended: Notify::new(),
+        }
+    }
+
+    pub fn touch(&self) {
+        self.activity.notify_one();
+    }
+
+    pub async fn poll(&self, server: &RwLock<Server>) -> String {
+        self.touch();
+
+        let clock = server.read().await.clock.clone();
+        let mut timeout = clock.sleep_until(clock.now() + Session::POLL_TIMEOUT);
+
+        while self.mailbox.is_empty() {
+            tokio::select! {
+                _ = self.mailbox.arrived.notified() => {}
+                _ = &mut timeout => break,
+            }
+        }
+
+        let mut messages = vec![];
+        let mut close = None;
+
+        for message in self.mailbox.take() {
+            match message {
+                Message::Text(text) => messages.push(text),
+                Message::Binary(data) => messages.push(format!("\"{}\"", base64::encode(&data))),
+                Message::Close(frame) => {
+                    close = Some(frame.map_or((1000, String::new()), |frame| {
+                        (u16::from(frame.code), frame.reason.into_owned())
+                    }))
+                }
+                _ => {}
+            }
+        }
+
+        self.touch();
+
+        let Some((code, reason)) = close else {
+            return format!(r#"{{"messages":[{}]}}"#, messages.join(","));
+        };
+
+        self.ended.notify_one();
+
+        format!(
+            r#"{{"messages":[{}],"close":{{"code":{},"reason":{}}}}}"#,
+            messages.join(","),
+            code,
+            serde_json::Value::from(reason)
+        )
+    }
+
+    pub async fn run(
+        self: Arc<Session>,
+        server: Arc<RwLock<Server>>,
+        token: String,
+        failed: Arc<Notify>,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let clock = server.read().await.clock.clone();
+        let mut deadline = clock.now() + Session::EXPIRY;
+
+        let mut dropped_frames = time::interval(Client::DROPPED_INTERVAL);
+        dropped_frames.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+        let mut removal_reason = RemovalReason::Disconnected;
+
+        loop {
+            tokio::select! {
+                _ = self.activity.notified() => deadline = clock.now() + Session::EXPIRY,
+                _ = clock.sleep_until(deadline) => {
+                    removal_reason = RemovalReason::TimedOut;
+                    break;
+                }
+                _ = dropped_frames.tick() => self.client.lock().await.handle_dropped_frames().await,
+                _ = self.ended.notified() => break,
+                _ = failed.notified() => break,
+            }
+        }
+
+        server.write().await.sessions.remove(&token);
+        Mailbox::close(&self.mailbox);
+        self.client
+            .lock()
+            .await
+            .handle_close(&server, removal_reason)
+            .await;
+
+        drop(permit);
+    }
+}
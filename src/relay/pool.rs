@@ -0,0 +1,100 @@
+use futures_util::future::BoxFuture;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
+
+type Task = BoxFuture<'static, ()>;
+type Queues = Arc<Mutex<HashMap<String, VecDeque<Task>>>>;
+
+#[derive(Clone)]
+pub struct Pool {
+    queues: Queues,
+    ready: mpsc::UnboundedSender<String>,
+}
+
+impl Pool {
+    pub const TIME_SLICE: Duration = Duration::from_millis(1);
+
+    pub fn new(workers: usize) -> Pool {
+        let (ready, receiver) = mpsc::unbounded_channel();
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        let queues = Queues::default();
+
+        for _ in 0..workers.max(1) {
+            tokio::spawn(Pool::work(
+                queues.clone(),
+                ready.downgrade(),
+                receiver.clone(),
+            ));
+        }
+
+        Pool { queues, ready }
+    }
+
+    pub async fn run<T: Send + 'static>(
+        &self,
+        room_id: &str,
+        task: impl Future<Output = T> + Send + 'static,
+    ) -> T {
+        let (sender, receiver) = oneshot::channel();
+        let task: Task = Box::pin(async move {
+            let _ = sender.send(task.await);
+        });
+
+        {
+            let mut queues = self.queues.lock().unwrap();
+
+            if let Some(queue) = queues.get_mut(room_id) {
+                queue.push_back(task);
+            } else {
+                queues.insert(room_id.to_string(), VecDeque::from([task]));
+
+                let _ = self.ready.send(room_id.to_string());
+            }
+        }
+
+        receiver.await.unwrap()
+    }
+
+    async fn work(
+        queues: Queues,
+        ready: mpsc::WeakUnboundedSender<String>,
+        receiver: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<String>>>,
+    ) {
+        loop {
+            let Some(room_id) = receiver.lock().await.recv().await else {
+                return;
+            };
+
+            let started_at = Instant::now();
+
+            while started_at.elapsed() < Pool::TIME_SLICE {
+                let task = queues
+                    .lock()
+                    .unwrap()
+                    .get_mut(&room_id)
+                    .and_then(|queue| queue.pop_front());
+
+                let Some(task) = task else {
+                    break;
+                };
+
+                task.await;
+            }
+
+            let mut queues = queues.lock().unwrap();
+
+            if queues.get(&room_id).is_some_and(|queue| !queue.is_empty()) {
+                if let Some(ready) = ready.upgrade() {
+                    let _ = ready.send(room_id);
+                }
+            } else {
+                queues.remove(&room_id);
+            }
+        }
+    }
+}
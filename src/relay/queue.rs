@@ -0,0 +1,534 @@
+use super::{
+    client::{ConflationKey, DroppedFrames, Outbound, Priority, Statistics},
+    clock::Clock,
+    packets::DropReason,
+};
+use futures_util::future::{self, BoxFuture};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, oneshot, Notify},
+    time,
+};
+use tungstenite::{error::ProtocolError, protocol::CloseFrame, Message};
+
+pub struct OutboundBudget {
+    limit: usize,
+    used: AtomicUsize,
+    dropped_frames: AtomicU64,
+}
+
+impl OutboundBudget {
+    pub fn new(limit: usize) -> OutboundBudget {
+        OutboundBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            dropped_frames: AtomicU64::new(0),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.used())
+    }
+
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    fn try_reserve(&self, length: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(length).filter(|&used| used <= self.limit)
+            })
+            .is_ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendFailure {
+    Fatal,
+    Transient,
+}
+
+impl SendFailure {
+    pub fn classify(error: &tungstenite::Error) -> SendFailure {
+        match error {
+            tungstenite::Error::Io(error) => match error.kind() {
+                io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted => SendFailure::Transient,
+                _ => SendFailure::Fatal,
+            },
+            tungstenite::Error::WriteBufferFull(_) | tungstenite::Error::Capacity(_) => {
+                SendFailure::Transient
+            }
+            _ => SendFailure::Fatal,
+        }
+    }
+}
+
+pub struct SendPolicy {
+    timeout: Option<Duration>,
+    fatal_errors: AtomicU64,
+    transient_errors: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+impl SendPolicy {
+    pub fn new(timeout: Option<Duration>) -> SendPolicy {
+        SendPolicy {
+            timeout,
+            fatal_errors: AtomicU64::new(0),
+            transient_errors: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+        }
+    }
+
+    pub fn fatal_errors(&self) -> u64 {
+        self.fatal_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn transient_errors(&self) -> u64 {
+        self.transient_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, failure: SendFailure) {
+        let counter = match failure {
+            SendFailure::Fatal => &self.fatal_errors,
+            SendFailure::Transient => &self.transient_errors,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct Reservation {
+    length: usize,
+    queued_bytes: Arc<AtomicUsize>,
+    budget: Arc<OutboundBudget>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.queued_bytes.fetch_sub(self.length, Ordering::Relaxed);
+        self.budget.used.fetch_sub(self.length, Ordering::Relaxed);
+    }
+}
+
+struct Frame {
+    message: Message,
+    expires_at: Option<Instant>,
+    conflation: Option<(ConflationKey, u64)>,
+    dropped: Option<Arc<DroppedFrames>>,
+    _reservation: Reservation,
+}
+
+type Closing = (Option<CloseFrame<'static>>, oneshot::Sender<()>);
+
+enum Command {
+    Write(Priority, Frame),
+    Close(Option<CloseFrame<'static>>, oneshot::Sender<()>),
+}
+
+#[derive(Default)]
+struct Conflation {
+    latest: HashMap<ConflationKey, u64>,
+    sequence: u64,
+}
+
+impl Conflation {
+    fn push(&mut self, key: ConflationKey) -> u64 {
+        self.sequence += 1;
+        self.latest.insert(key, self.sequence);
+
+        self.sequence
+    }
+
+    fn pop(&mut self, key: ConflationKey, sequence: u64) -> bool {
+        if self.latest.get(&key) != Some(&sequence) {
+            return false;
+        }
+
+        self.latest.remove(&key);
+        true
+    }
+}
+
+pub struct Queue {
+    high: mpsc::UnboundedSender<Frame>,
+    low: mpsc::UnboundedSender<Frame>,
+    closing: mpsc::UnboundedSender<Closing>,
+    closed: Arc<AtomicBool>,
+    queued_bytes: Arc<AtomicUsize>,
+    budget: Arc<OutboundBudget>,
+    policy: Arc<SendPolicy>,
+    clock: Arc<dyn Clock>,
+    statistics: Arc<Statistics>,
+    conflation: Arc<Mutex<Conflation>>,
+    congested: Arc<AtomicBool>,
+    failed: Arc<Notify>,
+}
+
+impl Queue {
+    pub const HIGH_BUDGET: usize = 64 << 10;
+    pub const LOW_BUDGET: usize = 16 << 10;
+    pub const MAX_SEND_TIMEOUTS: u32 = 3;
+    pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn new(
+        outbound: impl Outbound + 'static,
+        budget: Arc<OutboundBudget>,
+        policy: Arc<SendPolicy>,
+        clock: Arc<dyn Clock>,
+    ) -> Queue {
+        let (high, high_receiver) = mpsc::unbounded_channel::<Frame>();
+        let (low, low_receiver) = mpsc::unbounded_channel::<Frame>();
+        let (closing, closing_receiver) = mpsc::unbounded_channel::<Closing>();
+        let closed = Arc::new(AtomicBool::new(false));
+        let queued_bytes = Arc::new(AtomicUsize::new(0));
+        let statistics = Arc::new(Statistics::default());
+        let conflation = Arc::new(Mutex::new(Conflation::default()));
+        let congested = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(Notify::new());
+
+        tokio::spawn({
+            let mut writer = Writer {
+                outbound,
+                policy: policy.clone(),
+                clock: clock.clone(),
+                statistics: statistics.clone(),
+                conflation: conflation.clone(),
+                congested: congested.clone(),
+                failed: failed.clone(),
+                closed: closed.clone(),
+                timeouts: 0,
+            };
+            let mut lanes = Lanes {
+                high: high_receiver,
+                low: low_receiver,
+                closing: closing_receiver,
+                turn: Priority::High,
+                spent: 0,
+            };
+
+            async move {
+                while let Some(command) = lanes.recv().await {
+                    match command {
+                        Command::Write(priority, frame) => {
+                            if !writer.write(priority, frame).await {
+                                break;
+                            }
+                        }
+                        Command::Close(close_frame, closed) => {
+                            let _ = writer.outbound.close(close_frame).await;
+                            let _ = closed.send(());
+
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Queue {
+            high,
+            low,
+            closing,
+            closed,
+            queued_bytes,
+            budget,
+            policy,
+            clock,
+            statistics,
+            conflation,
+            congested,
+            failed,
+        }
+    }
+
+    pub fn failed(&self) -> Arc<Notify> {
+        self.failed.clone()
+    }
+
+    fn reserve(&self, length: usize) -> Reservation {
+        self.queued_bytes.fetch_add(length, Ordering::Relaxed);
+
+        Reservation {
+            length,
+            queued_bytes: self.queued_bytes.clone(),
+            budget: self.budget.clone(),
+        }
+    }
+
+    fn enqueue(
+        &self,
+        frame: Frame,
+        priority: Priority,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Box::pin(future::ready(Ok(())));
+        }
+
+        let sender = match priority {
+            Priority::High => &self.high,
+            Priority::Low => &self.low,
+        };
+
+        if sender.send(frame).is_err() {
+            return Box::pin(future::ready(Err(tungstenite::Error::AlreadyClosed)));
+        }
+
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+impl Outbound for Queue {
+    fn send(
+        &self,
+        message: Message,
+        priority: Priority,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        self.budget.used.fetch_add(message.len(), Ordering::Relaxed);
+
+        let frame = Frame {
+            _reservation: self.reserve(message.len()),
+            message,
+            expires_at: None,
+            conflation: None,
+            dropped: None,
+        };
+
+        self.enqueue(frame, priority)
+    }
+
+    fn send_frame(
+        &self,
+        message: Message,
+        ttl: Option<Duration>,
+        conflation_key: Option<ConflationKey>,
+        dropped: &Arc<DroppedFrames>,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        if !self.budget.try_reserve(message.len()) {
+            self.budget.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            dropped.record(DropReason::BudgetExhausted);
+
+            return Box::pin(future::ready(Ok(())));
+        }
+
+        let conflation = conflation_key.map(|key| (key, self.conflation.lock().unwrap().push(key)));
+
+        let frame = Frame {
+            _reservation: self.reserve(message.len()),
+            message,
+            expires_at: ttl.map(|ttl| self.clock.now() + ttl),
+            conflation,
+            dropped: (ttl.is_some() || self.policy.timeout.is_some()).then(|| dropped.clone()),
+        };
+
+        self.enqueue(frame, Priority::Low)
+    }
+
+    fn close(
+        &self,
+        close_frame: Option<CloseFrame<'static>>,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return Box::pin(future::ready(Ok(())));
+        }
+
+        let (closed, receiver) = oneshot::channel();
+
+        if self.closing.send((close_frame, closed)).is_err() {
+            return Box::pin(future::ready(Ok(())));
+        }
+
+        Box::pin(async move {
+            let _ = time::timeout(Queue::CLOSE_TIMEOUT, receiver).await;
+
+            Ok(())
+        })
+    }
+
+    fn queued_bytes(&self) -> usize {
+        self.queued_bytes.load(Ordering::Relaxed)
+    }
+
+    fn is_congested(&self) -> bool {
+        self.congested.load(Ordering::Relaxed)
+    }
+
+    fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+}
+
+struct Writer<O> {
+    outbound: O,
+    policy: Arc<SendPolicy>,
+    clock: Arc<dyn Clock>,
+    statistics: Arc<Statistics>,
+    conflation: Arc<Mutex<Conflation>>,
+    congested: Arc<AtomicBool>,
+    failed: Arc<Notify>,
+    closed: Arc<AtomicBool>,
+    timeouts: u32,
+}
+
+impl<O: Outbound> Writer<O> {
+    async fn write(&mut self, priority: Priority, frame: Frame) -> bool {
+        let superseded = frame
+            .conflation
+            .is_some_and(|(key, sequence)| !self.conflation.lock().unwrap().pop(key, sequence));
+
+        if superseded {
+            self.statistics
+                .conflated_frames
+                .fetch_add(1, Ordering::Relaxed);
+
+            return true;
+        }
+
+        if frame
+            .expires_at
+            .is_some_and(|expires_at| expires_at < self.clock.now())
+        {
+            self.statistics
+                .expired_frames
+                .fetch_add(1, Ordering::Relaxed);
+
+            if let Some(dropped) = &frame.dropped {
+                dropped.record(DropReason::Expired);
+            }
+
+            return true;
+        }
+
+        let send = self.outbound.send(frame.message, priority);
+        let result = match self.policy.timeout {
+            Some(timeout) => time::timeout(timeout, send).await.ok(),
+            None => Some(send.await),
+        };
+
+        match result {
+            Some(Ok(())) => {
+                self.timeouts = 0;
+                self.congested.store(false, Ordering::Relaxed);
+            }
+            Some(Err(_)) if self.closed.load(Ordering::Relaxed) => return false,
+            Some(Err(tungstenite::Error::Protocol(ProtocolError::SendAfterClosing))) => {
+                self.closed.store(true, Ordering::Relaxed);
+                return false;
+            }
+            Some(Err(error)) => {
+                let failure = SendFailure::classify(&error);
+                self.policy.record(failure);
+                self.statistics
+                    .send_failures
+                    .fetch_add(1, Ordering::Relaxed);
+
+                println!("Failed to write: {}", error);
+
+                if failure == SendFailure::Fatal {
+                    self.closed.store(true, Ordering::Relaxed);
+                    self.failed.notify_one();
+                    return false;
+                }
+            }
+            None => {
+                self.timeouts += 1;
+                self.policy.timeouts.fetch_add(1, Ordering::Relaxed);
+                self.statistics
+                    .send_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                self.congested.store(true, Ordering::Relaxed);
+
+                if let Some(dropped) = &frame.dropped {
+                    dropped.record(DropReason::ReceiverCongested);
+                }
+
+                if self.timeouts >= Queue::MAX_SEND_TIMEOUTS {
+                    self.closed.store(true, Ordering::Relaxed);
+                    self.failed.notify_one();
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+struct Lanes {
+    high: mpsc::UnboundedReceiver<Frame>,
+    low: mpsc::UnboundedReceiver<Frame>,
+    closing: mpsc::UnboundedReceiver<Closing>,
+    turn: Priority,
+    spent: usize,
+}
+
+impl Lanes {
+    async fn recv(&mut self) -> Option<Command> {
+        let (budget, other) = match self.turn {
+            Priority::High => (Queue::HIGH_BUDGET, Priority::Low),
+            Priority::Low => (Queue::LOW_BUDGET, Priority::High),
+        };
+
+        let mut order = [self.turn, other];
+        if self.spent >= budget {
+            order.swap(0, 1);
+        }
+
+        for priority in order {
+            if let Ok(frame) = self.receiver(priority).try_recv() {
+                return Some(self.take(priority, frame));
+            }
+        }
+
+        self.spent = 0;
+
+        let (priority, frame) = tokio::select! {
+            biased;
+            Some(frame) = self.high.recv() => (Priority::High, frame),
+            Some(frame) = self.low.recv() => (Priority::Low, frame),
+            Some((close_frame, closed)) = self.closing.recv() => {
+                return Some(Command::Close(close_frame, closed));
+            }
+            else => return None,
+        };
+
+        Some(self.take(priority, frame))
+    }
+
+    fn receiver(&mut self, priority: Priority) -> &mut mpsc::UnboundedReceiver<Frame> {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Low => &mut self.low,
+        }
+    }
+
+    fn take(&mut self, priority: Priority, frame: Frame) -> Command {
+        if priority != self.turn {
+            self.turn = priority;
+            self.spent = 0;
+        }
+
+        self.spent += frame.message.len();
+
+        Command::Write(priority, frame)
+    }
+}
@@ -0,0 +1,121 @@
+use super::clock::Clock;
+use futures_util::future::BoxFuture;
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+#[derive(Default)]
+pub struct Reassembly {
+    completed: AtomicBool,
+}
+
+impl Reassembly {
+    pub fn complete(&self) {
+        self.completed.store(true, Ordering::Relaxed);
+    }
+}
+
+pub struct ReassemblyStream {
+    stream: TcpStream,
+    reassembly: Arc<Reassembly>,
+    timeout: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    armed: bool,
+    deadline: Option<BoxFuture<'static, ()>>,
+}
+
+impl ReassemblyStream {
+    pub fn new(
+        stream: TcpStream,
+        timeout: Option<Duration>,
+        clock: Arc<dyn Clock>,
+    ) -> (ReassemblyStream, Arc<Reassembly>) {
+        let reassembly = Arc::new(Reassembly::default());
+        let reassembly_stream = ReassemblyStream {
+            stream,
+            reassembly: reassembly.clone(),
+            timeout,
+            clock,
+            armed: false,
+            deadline: None,
+        };
+
+        (reassembly_stream, reassembly)
+    }
+}
+
+impl AsyncRead for ReassemblyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        if this.reassembly.completed.swap(false, Ordering::Relaxed) {
+            this.armed = true;
+            this.deadline = None;
+        }
+
+        if let Some(deadline) = &mut this.deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+            }
+        }
+
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+
+        if let Some(timeout) = this.timeout {
+            if this.armed && this.deadline.is_none() && buf.filled().len() > filled {
+                let mut deadline = this.clock.sleep_until(this.clock.now() + timeout);
+                let _ = deadline.as_mut().poll(cx);
+
+                this.deadline = Some(deadline);
+            }
+        }
+
+        result
+    }
+}
+
+impl AsyncWrite for ReassemblyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,133 @@
+use super::{
+    capture::{CaptureFile, Record},
+    packets::{CreateOptions, RequestPacket, ResponsePacket},
+    room::Room,
+};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use std::io;
+use tokio::{net::TcpStream, task::JoinSet, time};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tungstenite::Message;
+
+type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub struct Replayer {
+    pub room_id: String,
+    sinks: Vec<SplitSink<Stream, Message>>,
+    drains: JoinSet<()>,
+}
+
+impl Replayer {
+    pub async fn connect(url: &str, capture_file: &CaptureFile) -> io::Result<Replayer> {
+        let sources = capture_file
+            .records
+            .iter()
+            .map(|record| usize::from(record.source) + 1)
+            .max()
+            .unwrap_or(1);
+
+        let size = capture_file
+            .size
+            .max(sources + 1)
+            .min(Room::MAX_ROOM_SIZE - 1);
+
+        let mut streams = vec![];
+        let mut room_id = String::new();
+
+        for index in 0..sources {
+            let (mut stream, _) = connect_async(url).await.map_err(io::Error::other)?;
+
+            let request_packet = if index == 0 {
+                RequestPacket::Create {
+                    size: Some(size),
+                    template: None,
+                    options: CreateOptions::default(),
+                    virtual_address: None,
+                }
+            } else {
+                RequestPacket::Join {
+                    id: room_id.clone(),
+                    token: None,
+                    reservation: None,
+                    virtual_address: None,
+                }
+            };
+
+            let serialized_packet = serde_json::to_string(&request_packet).unwrap();
+            stream
+                .send(Message::Text(serialized_packet))
+                .await
+                .map_err(io::Error::other)?;
+
+            match Replayer::read_packet(&mut stream).await? {
+                ResponsePacket::Create { id, .. } => room_id = id,
+                ResponsePacket::Join {
+                    index: Some(joined_index),
+                    ..
+                } if joined_index == index => {}
+                packet => return Err(io::Error::other(format!("unexpected packet: {:?}", packet))),
+            }
+
+            streams.push(stream);
+        }
+
+        let mut sinks = vec![];
+        let mut drains = JoinSet::new();
+
+        for stream in streams {
+            let (sink, mut stream) = stream.split();
+
+            sinks.push(sink);
+            drains.spawn(async move { while let Some(Ok(_)) = stream.next().await {} });
+        }
+
+        Ok(Replayer {
+            room_id,
+            sinks,
+            drains,
+        })
+    }
+
+    pub async fn play(&mut self, records: &[Record]) -> io::Result<()> {
+        let Some(first) = records.first() else {
+            return Ok(());
+        };
+
+        let started_at = time::Instant::now();
+
+        for record in records {
+            time::sleep_until(started_at + record.offset.saturating_sub(first.offset)).await;
+
+            let Some(sink) = self.sinks.get_mut(usize::from(record.source)) else {
+                continue;
+            };
+
+            sink.send(Message::Binary(record.frame()))
+                .await
+                .map_err(io::Error::other)?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn close(mut self) {
+        for sink in &mut self.sinks {
+            let _ = sink.close().await;
+        }
+
+        while self.drains.join_next().await.is_some() {}
+    }
+
+    async fn read_packet(stream: &mut Stream) -> io::Result<ResponsePacket> {
+        loop {
+            let message = match stream.next().await {
+                Some(message) => message.map_err(io::Error::other)?,
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+
+            if let Message::Text(text) = message {
+                return serde_json::from_str(&text).map_err(io::Error::other);
+            }
+        }
+    }
+}
@@ -0,0 +1,868 @@
+use super::{
+    capture::Capture,
+    client::{encode, Client, ClientId, DroppedFrames, Notification, Priority, Sender},
+    clock::Rng,
+    frame::{self, Destination},
+    limiter::IpLimiter,
+    metrics::{Delivery, Timestamp},
+    packets::{Error, MemberSummary, QualityReport, ResponsePacket, RoomOptions, WireFormat},
+};
+use futures_util::future::BoxFuture;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    mem,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::AbortHandle;
+use tungstenite::Message;
+
+#[derive(Default)]
+pub struct Migration {
+    room_id: Mutex<Option<String>>,
+}
+
+impl Migration {
+    pub fn set(&self, room_id: String) {
+        *self.room_id.lock().unwrap() = Some(room_id);
+    }
+
+    pub fn take(&self) -> Option<String> {
+        self.room_id.lock().unwrap().take()
+    }
+}
+
+#[derive(Default)]
+pub struct Epoch(AtomicU64);
+
+impl Epoch {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn advance(&self, epoch: u64) {
+        self.0.fetch_max(epoch, Ordering::Release);
+    }
+}
+
+#[derive(Clone)]
+pub struct Peer {
+    pub(crate) sender: Sender,
+    pub(crate) wire_format: WireFormat,
+    pub(crate) known_epoch: Arc<Epoch>,
+}
+
+impl Peer {
+    pub fn send(
+        &self,
+        notification: &Notification,
+    ) -> Option<BoxFuture<'_, Result<(), tungstenite::Error>>> {
+        notification
+            .encode(self.wire_format)
+            .map(|message| self.sender.send(message, Priority::High))
+    }
+}
+
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            updated: None,
+        }
+    }
+
+    pub fn take(&mut self, amount: u64, now: Instant) -> bool {
+        if let Some(updated) = self.updated {
+            let elapsed = now.saturating_duration_since(updated).as_secs_f64();
+
+            self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        }
+
+        self.updated = Some(now);
+
+        if self.tokens < amount as f64 {
+            return false;
+        }
+
+        self.tokens -= amount as f64;
+        true
+    }
+}
+
+#[derive(Default)]
+pub struct Batch {
+    pub(crate) frames: Vec<u8>,
+    pub(crate) sources: Vec<Arc<DroppedFrames>>,
+    pub(crate) received: Vec<(Delivery, Timestamp)>,
+}
+
+pub struct Pacer {
+    pub(crate) interval: Duration,
+    pub(crate) tick: Option<AbortHandle>,
+    max_bytes: usize,
+    batches: Mutex<HashMap<ClientId, Batch>>,
+}
+
+impl Pacer {
+    pub fn new(tick_rate: u32, max_bytes: usize) -> Pacer {
+        Pacer {
+            interval: Duration::from_secs(1) / tick_rate,
+            tick: None,
+            max_bytes,
+            batches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn push(&self, client_id: ClientId, frame: &[u8], dropped: &Arc<DroppedFrames>) -> bool {
+        let mut batches = self.batches.lock().unwrap();
+        let batch = batches.entry(client_id).or_default();
+
+        if batch.frames.len() + frame::BATCH_HEADER_LENGTH + frame.len() > self.max_bytes {
+            return false;
+        }
+
+        if !batch
+            .sources
+            .iter()
+            .any(|source| Arc::ptr_eq(source, dropped))
+        {
+            batch.sources.push(dropped.clone());
+        }
+
+        frame::encode_batch(&mut batch.frames, frame);
+        true
+    }
+
+    /// Records when a frame buffered for `client_id` was received, so its latency is measured once the batch
+    /// carrying it has been sent.
+    pub fn received(&self, client_id: ClientId, delivery: Delivery, received_at: Timestamp) {
+        if let Some(batch) = self.batches.lock().unwrap().get_mut(&client_id) {
+            batch.received.push((delivery, received_at));
+        }
+    }
+
+    pub fn take(&self) -> HashMap<ClientId, Batch> {
+        mem::take(&mut *self.batches.lock().unwrap())
+    }
+}
+
+pub struct Member {
+    pub(crate) id: u64,
+    pub(crate) client_id: ClientId,
+    pub(crate) sender: Sender,
+    pub(crate) wire_format: WireFormat,
+    pub(crate) address: SocketAddr,
+    pub(crate) joined_at: Instant,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) active_micros: AtomicU64,
+    pub(crate) sources: Option<BTreeSet<u64>>,
+    pub(crate) lz4: bool,
+    pub(crate) dropped: Arc<DroppedFrames>,
+    pub(crate) virtual_address: Option<String>,
+    pub(crate) migration: Arc<Migration>,
+    pub(crate) quality: Option<(QualityReport, Instant)>,
+    pub(crate) max_message_size: usize,
+    pub(crate) chunk_size: Option<usize>,
+    pub(crate) message_ids: AtomicU32,
+    pub(crate) joined_epoch: u64,
+    pub(crate) known_epoch: Arc<Epoch>,
+}
+
+impl Member {
+    pub fn new(
+        client_id: ClientId,
+        sender: Sender,
+        address: SocketAddr,
+        lz4: bool,
+        dropped: Arc<DroppedFrames>,
+        migration: Arc<Migration>,
+        now: Instant,
+    ) -> Member {
+        Member {
+            id: 0,
+            client_id,
+            sender,
+            wire_format: WireFormat::default(),
+            address,
+            joined_at: now,
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            active_micros: AtomicU64::new(0),
+            sources: None,
+            lz4,
+            dropped,
+            virtual_address: None,
+            migration,
+            quality: None,
+            max_message_size: usize::MAX,
+            chunk_size: None,
+            message_ids: AtomicU32::new(0),
+            joined_epoch: 0,
+            known_epoch: Arc::default(),
+        }
+    }
+
+    pub fn record_sent(&self, bytes: usize, now: Instant) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.active_micros.fetch_max(
+            now.saturating_duration_since(self.joined_at).as_micros() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn accepts(&self, source: &Member) -> bool {
+        self.sources
+            .as_ref()
+            .is_none_or(|sources| sources.contains(&source.id))
+    }
+
+    pub fn peer(&self) -> Peer {
+        Peer {
+            sender: self.sender.clone(),
+            wire_format: self.wire_format,
+            known_epoch: self.known_epoch.clone(),
+        }
+    }
+
+    pub fn knows(&self, member: &Member) -> bool {
+        self.known_epoch.get() >= member.joined_epoch
+    }
+
+    pub fn last_active_at(&self) -> Instant {
+        self.joined_at + Duration::from_micros(self.active_micros.load(Ordering::Relaxed))
+    }
+}
+
+pub struct Reservation {
+    pub(crate) remaining: usize,
+    pub(crate) expires_at: Instant,
+}
+
+pub enum Mutation {
+    Join {
+        member: Box<Member>,
+        reservation: Option<String>,
+    },
+    Leave {
+        client_id: ClientId,
+    },
+    Lock {
+        client_id: ClientId,
+        locked: bool,
+    },
+    Reserve {
+        count: usize,
+    },
+    Unreserve {
+        reservation: String,
+    },
+    RemoveAll,
+}
+
+pub enum Effect {
+    Joined {
+        index: usize,
+        epoch: u64,
+        peers: Vec<Peer>,
+    },
+    Left {
+        index: usize,
+        peers: Vec<Peer>,
+    },
+    Locked {
+        locked: bool,
+        recipients: Vec<Peer>,
+    },
+    Reserved {
+        reservation: String,
+    },
+    Removed {
+        members: Vec<Member>,
+    },
+    Notify(ClientId, Sender, Message),
+}
+
+pub struct Room {
+    pub(crate) size: usize,
+    pub(crate) members: Vec<Member>,
+    pub(crate) occupancy: AtomicUsize,
+    pub(crate) next_member_id: u64,
+    pub(crate) epoch: u64,
+    pub(crate) owner: Option<usize>,
+    pub(crate) locked: bool,
+    pub(crate) watch_occupancy: bool,
+    pub(crate) store: BTreeMap<String, serde_json::Value>,
+    pub(crate) store_bytes: usize,
+    pub(crate) options: RoomOptions,
+    pub(crate) bandwidth: Option<Mutex<TokenBucket>>,
+    pub(crate) refused_joins: Mutex<IpLimiter>,
+    pub(crate) rate_limited_frames: AtomicU64,
+    pub(crate) dropped_responses: Arc<AtomicU64>,
+    pub(crate) created_at: Instant,
+    pub(crate) expires_at: Option<Instant>,
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) vacant_until: Option<Instant>,
+    pub(crate) expiry: Option<AbortHandle>,
+    pub(crate) capture: Option<Capture>,
+    pub(crate) reservations: HashMap<String, Reservation>,
+    pub(crate) pacer: Option<Pacer>,
+}
+
+impl Room {
+    pub const MIN_ROOM_SIZE: usize = 0;
+    pub const MAX_ROOM_SIZE: usize = frame::MAX_ROOM_SIZE + 1;
+    pub const DEFAULT_ROOM_SIZE: usize = 2;
+
+    pub const MAX_STORE_BYTES: usize = 16 << 10;
+    pub const MAX_STORE_KEYS: usize = 64;
+    pub const MAX_ALLOWED_ORIGINS: usize = 16;
+    pub const MAX_ORIGIN_LENGTH: usize = 253;
+    pub const RESERVATION_TTL: Duration = Duration::from_secs(30);
+    pub const PROVISIONED_GRACE: Duration = Duration::from_secs(60);
+    pub const JOIN_RATE: u64 = 5;
+    pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+    pub const DEFAULT_TICK_RATE: u32 = 30;
+    pub const MAX_TICK_RATE: u32 = 1000;
+    pub const DEFAULT_MAX_PACED_BYTES: usize = 64 << 10;
+    pub const MAX_VIRTUAL_ADDRESS_LENGTH: usize = frame::MAX_VIRTUAL_ADDRESS_LENGTH;
+
+    pub const BROADCAST: u8 = frame::BROADCAST;
+    pub const OWNER: u8 = frame::OWNER;
+    pub const EXCLUDE: u8 = frame::EXCLUDE;
+
+    pub const UNCOMPRESSED: u8 = 0;
+    pub const LZ4: u8 = 1;
+
+    pub fn new(size: usize, options: RoomOptions, now: Instant) -> Room {
+        Room {
+            members: Vec::new(),
+            occupancy: AtomicUsize::new(0),
+            next_member_id: 0,
+            epoch: 0,
+            owner: Some(0),
+            locked: false,
+            watch_occupancy: false,
+            store: BTreeMap::new(),
+            store_bytes: 0,
+            size,
+            bandwidth: options
+                .max_bandwidth
+                .map(|rate| Mutex::new(TokenBucket::new(rate))),
+            refused_joins: Mutex::new(IpLimiter::new(
+                Room::JOIN_RATE,
+                Duration::from_secs(1),
+                IpLimiter::DEFAULT_CAPACITY,
+            )),
+            created_at: now,
+            expires_at: options
+                .ttl_seconds
+                .map(|ttl_seconds| now + Duration::from_secs(ttl_seconds)),
+            deadline: None,
+            vacant_until: None,
+            dropped_responses: Arc::default(),
+            expiry: None,
+            capture: None,
+            reservations: HashMap::new(),
+            pacer: options.paced.then(|| {
+                Pacer::new(
+                    options.tick_rate.unwrap_or(Room::DEFAULT_TICK_RATE),
+                    options
+                        .max_paced_bytes
+                        .unwrap_or(Room::DEFAULT_MAX_PACED_BYTES),
+                )
+            }),
+            options,
+            rate_limited_frames: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&mut self, mut member: Member) -> usize {
+        debug_assert!(
+            self.position(member.client_id).is_none(),
+            "Client {} is already a member of the room",
+            member.client_id
+        );
+
+        self.epoch += 1;
+
+        member.id = self.next_member_id;
+        member.joined_epoch = self.epoch;
+        self.members.push(member);
+        self.next_member_id += 1;
+        self.occupancy.store(self.members.len(), Ordering::Relaxed);
+
+        self.members.len() - 1
+    }
+
+    pub fn apply(
+        &mut self,
+        mutation: Mutation,
+        rng: &dyn Rng,
+        now: Instant,
+    ) -> Result<Vec<Effect>, Error> {
+        let membership_changed = matches!(mutation, Mutation::Join { .. } | Mutation::Leave { .. });
+
+        let mut effects = match mutation {
+            Mutation::Join {
+                member,
+                reservation,
+            } => {
+                if self.locked {
+                    return Err(Error::IsLocked);
+                }
+
+                let virtual_address = member.virtual_address.as_deref();
+                let taken = virtual_address.is_some_and(|virtual_address| {
+                    self.resolve_address(virtual_address.as_bytes()).is_some()
+                });
+
+                match Client::check_virtual_address(&self.options, virtual_address) {
+                    Ok(()) if taken => return Err(Error::VirtualAddressTaken),
+                    Ok(()) => {}
+                    Err(error) => return Err(error),
+                }
+
+                let claimed = reservation.is_some_and(|reservation| self.claim(&reservation, now));
+                if !claimed && self.members.len() + self.reserved(now) >= self.size {
+                    return Err(Error::IsFull);
+                }
+
+                let client_id = member.client_id;
+                let index = self.add(*member);
+                self.vacant_until = None;
+
+                let epoch = self.epoch;
+                let mut peers = vec![];
+
+                for member in self
+                    .members
+                    .iter()
+                    .filter(|member| member.client_id != client_id)
+                {
+                    if self.options.notify_members {
+                        peers.push(member.peer());
+                    } else {
+                        member.known_epoch.advance(epoch);
+                    }
+                }
+
+                vec![Effect::Joined {
+                    index,
+                    epoch,
+                    peers,
+                }]
+            }
+            Mutation::Leave { client_id } => {
+                let Some(index) = self.position(client_id) else {
+                    return Ok(vec![]);
+                };
+
+                self.remove(index);
+
+                let peers = self
+                    .members
+                    .iter()
+                    .filter(|_| self.options.notify_members)
+                    .map(Member::peer)
+                    .collect();
+
+                vec![Effect::Left { index, peers }]
+            }
+            Mutation::Lock { client_id, locked } => {
+                if !self.is_owner(client_id) {
+                    return Err(Error::NotOwner);
+                }
+
+                let recipients = if self.locked == locked {
+                    self.members
+                        .iter()
+                        .filter(|member| member.client_id == client_id)
+                        .map(Member::peer)
+                        .collect()
+                } else {
+                    self.locked = locked;
+                    self.members.iter().map(Member::peer).collect()
+                };
+
+                vec![Effect::Locked { locked, recipients }]
+            }
+            Mutation::Reserve { count } => {
+                if count == 0 {
+                    return Err(Error::InvalidSize);
+                }
+
+                if self.locked {
+                    return Err(Error::IsLocked);
+                }
+
+                let reservation = self.reserve(count, rng, now).ok_or(Error::IsFull)?;
+
+                vec![Effect::Reserved { reservation }]
+            }
+            Mutation::Unreserve { reservation } => {
+                self.reservations.remove(&reservation);
+
+                vec![]
+            }
+            Mutation::RemoveAll => {
+                self.owner = self.owner.map(|_| 0);
+                self.watch_occupancy = false;
+                self.reservations.clear();
+                self.occupancy.store(0, Ordering::Relaxed);
+
+                vec![Effect::Removed {
+                    members: mem::take(&mut self.members),
+                }]
+            }
+        };
+
+        if membership_changed {
+            effects.extend(
+                self.membership_changed()
+                    .into_iter()
+                    .map(|(client_id, sender, message)| Effect::Notify(client_id, sender, message)),
+            );
+        }
+
+        debug_assert_eq!(None, self.violation(now));
+
+        Ok(effects)
+    }
+
+    pub fn remove_all(&mut self, rng: &dyn Rng, now: Instant) -> Vec<Member> {
+        match self.apply(Mutation::RemoveAll, rng, now).as_deref_mut() {
+            Ok([Effect::Removed { members }]) => mem::take(members),
+            _ => vec![],
+        }
+    }
+
+    pub fn violation(&self, now: Instant) -> Option<&'static str> {
+        let reserved: usize = self
+            .reservations
+            .values()
+            .filter(|reservation| reservation.expires_at > now)
+            .map(|reservation| reservation.remaining)
+            .sum();
+
+        if self.members.len() + reserved > self.size {
+            return Some("the occupancy and reservations exceed the size");
+        }
+
+        if self.occupancy.load(Ordering::Relaxed) != self.members.len() {
+            return Some("the occupancy does not match the members");
+        }
+
+        if self
+            .members
+            .iter()
+            .any(|member| member.joined_epoch > self.epoch)
+        {
+            return Some("a member joined after the current epoch");
+        }
+
+        if self
+            .owner
+            .is_some_and(|owner| owner >= self.members.len().max(1))
+        {
+            return Some("the owner is not a member");
+        }
+
+        if self
+            .reservations
+            .values()
+            .any(|reservation| reservation.remaining == 0)
+        {
+            return Some("a reservation has no remaining slots");
+        }
+
+        if self
+            .members
+            .iter()
+            .enumerate()
+            .any(|(index, member)| self.position(member.client_id) != Some(index))
+        {
+            return Some("a client is a member more than once");
+        }
+
+        None
+    }
+
+    pub fn limit_duration(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+
+        if self
+            .expires_at
+            .is_none_or(|expires_at| deadline < expires_at)
+        {
+            self.expires_at = Some(deadline);
+        }
+    }
+
+    pub fn reserved(&mut self, now: Instant) -> usize {
+        self.reservations
+            .retain(|_, reservation| reservation.expires_at > now);
+
+        self.reservations
+            .values()
+            .map(|reservation| reservation.remaining)
+            .sum()
+    }
+
+    pub fn is_full(&self, now: Instant) -> bool {
+        let reserved: usize = self
+            .reservations
+            .values()
+            .filter(|reservation| reservation.expires_at > now)
+            .map(|reservation| reservation.remaining)
+            .sum();
+
+        self.occupancy.load(Ordering::Relaxed) + reserved >= self.size
+    }
+
+    pub fn refuse_join(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        self.refused_joins.lock().unwrap().take(ip, now)
+    }
+
+    pub fn reserve(&mut self, count: usize, rng: &dyn Rng, now: Instant) -> Option<String> {
+        if self.members.len() + self.reserved(now) + count > self.size {
+            return None;
+        }
+
+        let token = rng.uuid().to_string();
+        self.reservations.insert(
+            token.clone(),
+            Reservation {
+                remaining: count,
+                expires_at: now + Room::RESERVATION_TTL,
+            },
+        );
+
+        Some(token)
+    }
+
+    pub fn claim(&mut self, token: &str, now: Instant) -> bool {
+        self.reserved(now);
+
+        let Some(reservation) = self.reservations.get_mut(token) else {
+            return false;
+        };
+
+        reservation.remaining -= 1;
+        if reservation.remaining == 0 {
+            self.reservations.remove(token);
+        }
+
+        true
+    }
+
+    pub fn set(&mut self, key: String, value: serde_json::Value) -> bool {
+        let size = |key: &str, value: &serde_json::Value| key.len() + value.to_string().len();
+
+        let previous_size = self
+            .store
+            .get(&key)
+            .map_or(0, |previous_value| size(&key, previous_value));
+
+        if value.is_null() {
+            self.store.remove(&key);
+            self.store_bytes -= previous_size;
+
+            return true;
+        }
+
+        let store_bytes = self.store_bytes - previous_size + size(&key, &value);
+        if store_bytes > Room::MAX_STORE_BYTES {
+            return false;
+        }
+
+        if previous_size == 0 && self.store.len() >= Room::MAX_STORE_KEYS {
+            return false;
+        }
+
+        self.store.insert(key, value);
+        self.store_bytes = store_bytes;
+
+        true
+    }
+
+    pub fn resolve(&self, source: usize, destination: Destination) -> Option<Vec<&Member>> {
+        let broadcast = |excluded: &[u8]| {
+            self.members
+                .iter()
+                .enumerate()
+                .filter(|&(index, member)| {
+                    index != source
+                        && member.accepts(&self.members[source])
+                        && !excluded.contains(&(index as u8))
+                })
+                .map(|(_, member)| member)
+                .collect()
+        };
+
+        match destination {
+            Destination::Index(index) => self
+                .members
+                .get(usize::from(index))
+                .map(|member| vec![member]),
+            Destination::Owner => Some(
+                self.owner
+                    .filter(|&owner| owner != source)
+                    .map(|owner| &self.members[owner])
+                    .into_iter()
+                    .collect(),
+            ),
+            Destination::Broadcast | Destination::Address([]) => Some(broadcast(&[])),
+            Destination::Exclude(excluded) => Some(broadcast(excluded)),
+            Destination::Address(virtual_address) => self
+                .resolve_address(virtual_address)
+                .map(|index| vec![&self.members[index]]),
+        }
+    }
+
+    pub fn resolve_address(&self, virtual_address: &[u8]) -> Option<usize> {
+        self.members.iter().position(|member| {
+            member
+                .virtual_address
+                .as_ref()
+                .is_some_and(|member_address| member_address.as_bytes() == virtual_address)
+        })
+    }
+
+    pub fn roster(&self) -> Vec<MemberSummary> {
+        self.members
+            .iter()
+            .enumerate()
+            .map(|(index, member)| MemberSummary {
+                index,
+                id: member.id,
+            })
+            .collect()
+    }
+
+    pub fn roster_pages(&self, max_length: usize) -> Vec<ResponsePacket> {
+        let empty_length = serde_json::to_vec(&ResponsePacket::Roster {
+            members: vec![],
+            more: true,
+        })
+        .map_or(0, |json| json.len());
+
+        let mut pages = vec![];
+        let mut members = vec![];
+        let mut length = empty_length;
+
+        for member in self.roster() {
+            let member_length = serde_json::to_vec(&member).map_or(0, |json| json.len()) + 1;
+
+            if !members.is_empty() && length + member_length > max_length {
+                pages.push(ResponsePacket::Roster {
+                    members: mem::take(&mut members),
+                    more: true,
+                });
+                length = empty_length;
+            }
+
+            length += member_length;
+            members.push(member);
+        }
+
+        pages.push(ResponsePacket::Roster {
+            members,
+            more: false,
+        });
+
+        pages
+    }
+
+    fn membership_changed(&self) -> Vec<(ClientId, Sender, Message)> {
+        let mut notifications = vec![];
+
+        if self.options.roster {
+            for roster_packet in self.roster_pages(Client::MAX_RESPONSE_LENGTH) {
+                let roster_notification = Notification::new(roster_packet, &self.dropped_responses);
+
+                notifications.extend(self.members.iter().filter_map(|member| {
+                    roster_notification
+                        .encode(member.wire_format)
+                        .map(|message| (member.client_id, member.sender.clone(), message))
+                }));
+            }
+        }
+
+        if self.watch_occupancy {
+            if let Some(owner) = self.owner.and_then(|owner| self.members.get(owner)) {
+                let occupancy_packet = ResponsePacket::Occupancy {
+                    count: self.members.len(),
+                    capacity: self.size,
+                };
+
+                notifications.extend(
+                    encode(
+                        owner.wire_format,
+                        &occupancy_packet,
+                        &self.dropped_responses,
+                    )
+                    .map(|message| (owner.client_id, owner.sender.clone(), message)),
+                );
+            }
+        }
+
+        notifications
+    }
+
+    pub fn position(&self, client_id: ClientId) -> Option<usize> {
+        self.members
+            .iter()
+            .position(|member| member.client_id == client_id)
+    }
+
+    pub fn is_owner(&self, client_id: ClientId) -> bool {
+        self.owner
+            .and_then(|owner| self.members.get(owner))
+            .is_some_and(|owner| owner.client_id == client_id)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Sender {
+        match self.owner {
+            Some(owner) if index < owner => self.owner = Some(owner - 1),
+            Some(owner) if index == owner => {
+                self.owner = Some(0);
+                self.watch_occupancy = false;
+            }
+            _ => {}
+        }
+
+        let member = self.members.remove(index);
+        self.occupancy.store(self.members.len(), Ordering::Relaxed);
+
+        member.sender
+    }
+}
+
+impl Drop for Room {
+    fn drop(&mut self) {
+        if let Some(expiry) = &self.expiry {
+            expiry.abort();
+        }
+
+        if let Some(tick) = self.pacer.as_ref().and_then(|pacer| pacer.tick.as_ref()) {
+            tick.abort();
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use super::{
+    config::Config,
+    frame::BROADCAST,
+    packets::{CreateOptions, RequestPacket, ResponsePacket},
+    Server,
+};
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time,
+};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tungstenite::Message;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct Step {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub steps: Vec<Step>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.error.is_none())
+    }
+
+    async fn step<T>(
+        &mut self,
+        name: &'static str,
+        timeout: Duration,
+        future: impl Future<Output = Result<T, String>>,
+    ) -> Option<T> {
+        let started_at = Instant::now();
+
+        let result = match time::timeout(timeout, future).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("timed out after {} ms", timeout.as_millis())),
+        };
+
+        let (value, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(error) => (None, Some(error)),
+        };
+
+        self.steps.push(Step {
+            name,
+            elapsed: started_at.elapsed(),
+            error,
+        });
+
+        value
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            let elapsed = step.elapsed.as_millis();
+
+            match &step.error {
+                None => writeln!(f, "ok     {} ({} ms)", step.name, elapsed)?,
+                Some(error) => writeln!(f, "failed {} ({} ms): {}", step.name, elapsed, error)?,
+            }
+        }
+
+        if self.passed() {
+            write!(f, "Self-test passed")
+        } else {
+            write!(f, "Self-test failed")
+        }
+    }
+}
+
+pub async fn run(timeout: Duration) -> Report {
+    let mut report = Report::default();
+
+    let Some((addr, accept_loop)) = report.step("start", timeout, start()).await else {
+        return report;
+    };
+
+    scenario(&mut report, addr, timeout).await;
+    accept_loop.abort();
+
+    report
+}
+
+async fn start() -> Result<(SocketAddr, JoinHandle<()>), String> {
+    let server = Server::new(Config::default()).map_err(|error| error.to_string())?;
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .map_err(|error| error.to_string())?;
+    let addr = listener.local_addr().map_err(|error| error.to_string())?;
+
+    Ok((addr, tokio::spawn(Server::serve(server, listener))))
+}
+
+async fn scenario(report: &mut Report, addr: SocketAddr, timeout: Duration) {
+    let Some((mut owner, mut member)) = report
+        .step("connect", timeout, async {
+            Ok((connect(addr).await?, connect(addr).await?))
+        })
+        .await
+    else {
+        return;
+    };
+
+    let Some(room_id) = report
+        .step("create", timeout, async {
+            send(
+                &mut owner,
+                RequestPacket::Create {
+                    size: Some(2),
+                    template: None,
+                    virtual_address: None,
+                    options: CreateOptions::default(),
+                },
+            )
+            .await?;
+
+            match receive(&mut owner).await? {
+                ResponsePacket::Create { id, .. } => Ok(id),
+                packet => Err(unexpected(packet)),
+            }
+        })
+        .await
+    else {
+        return;
+    };
+
+    let joined = report
+        .step("join", timeout, async {
+            send(
+                &mut member,
+                RequestPacket::Join {
+                    id: room_id.clone(),
+                    token: None,
+                    reservation: None,
+                    virtual_address: None,
+                },
+            )
+            .await?;
+
+            match receive(&mut member).await? {
+                ResponsePacket::Join { index: Some(1), .. } => {}
+                packet => return Err(unexpected(packet)),
+            }
+
+            match receive(&mut owner).await? {
+                ResponsePacket::Join { .. } => Ok(()),
+                packet => Err(unexpected(packet)),
+            }
+        })
+        .await;
+
+    if joined.is_none() {
+        return;
+    }
+
+    let relayed = report
+        .step("unicast", timeout, async {
+            send_frame(&mut owner, vec![1, b'u']).await?;
+            expect_frame(&mut member, vec![0, b'u']).await
+        })
+        .await;
+
+    if relayed.is_none() {
+        return;
+    }
+
+    let relayed = report
+        .step("broadcast", timeout, async {
+            send_frame(&mut member, vec![BROADCAST, b'b']).await?;
+            expect_frame(&mut owner, vec![1, b'b']).await
+        })
+        .await;
+
+    if relayed.is_none() {
+        return;
+    }
+
+    let left = report
+        .step("leave", timeout, async {
+            send(&mut member, RequestPacket::Leave).await?;
+
+            match receive(&mut member).await? {
+                ResponsePacket::Left => {}
+                packet => return Err(unexpected(packet)),
+            }
+
+            match receive(&mut owner).await? {
+                ResponsePacket::Leave { index: 1, .. } => Ok(()),
+                packet => Err(unexpected(packet)),
+            }
+        })
+        .await;
+
+    if left.is_none() {
+        return;
+    }
+
+    report
+        .step("room-destroyed", timeout, async {
+            send(&mut owner, RequestPacket::Leave).await?;
+
+            match receive(&mut owner).await? {
+                ResponsePacket::Left => {}
+                packet => return Err(unexpected(packet)),
+            }
+
+            send(&mut member, RequestPacket::Info { id: room_id }).await?;
+
+            match receive(&mut member).await? {
+                ResponsePacket::Info { exists: false, .. } => Ok(()),
+                packet => Err(unexpected(packet)),
+            }
+        })
+        .await;
+
+    let _ = owner.close(None).await;
+    let _ = member.close(None).await;
+}
+
+async fn connect(addr: SocketAddr) -> Result<Socket, String> {
+    let (socket, _) = connect_async(format!("ws://{}", addr))
+        .await
+        .map_err(|error| error.to_string())?;
+
+    Ok(socket)
+}
+
+async fn send(socket: &mut Socket, packet: RequestPacket) -> Result<(), String> {
+    let text = serde_json::to_string(&packet).map_err(|error| error.to_string())?;
+
+    socket
+        .send(Message::Text(text))
+        .await
+        .map_err(|error| error.to_string())
+}
+
+async fn send_frame(socket: &mut Socket, frame: Vec<u8>) -> Result<(), String> {
+    socket
+        .send(Message::Binary(frame))
+        .await
+        .map_err(|error| error.to_string())
+}
+
+async fn next(socket: &mut Socket) -> Result<Message, String> {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Ok(message)) => return Ok(message),
+            Some(Err(error)) => return Err(error.to_string()),
+            None => return Err(String::from("connection closed")),
+        }
+    }
+}
+
+async fn receive(socket: &mut Socket) -> Result<ResponsePacket, String> {
+    match next(socket).await? {
+        Message::Text(text) => serde_json::from_str(&text).map_err(|error| error.to_string()),
+        message => Err(format!("unexpected message: {:?}", message)),
+    }
+}
+
+async fn expect_frame(socket: &mut Socket, expected: Vec<u8>) -> Result<(), String> {
+    match next(socket).await? {
+        Message::Binary(frame) if frame == expected => Ok(()),
+        message => Err(format!("unexpected message: {:?}", message)),
+    }
+}
+
+fn unexpected(packet: ResponsePacket) -> String {
+    format!("unexpected packet: {:?}", packet)
+}
@@ -0,0 +1,2020 @@
+#[cfg(feature = "mqtt")]
+use super::mqtt::{Broker, MqttPublisher};
+#[cfg(unix)]
+use super::systemd;
+#[cfg(any(test, feature = "test-util"))]
+use super::testing::{RelayHook, TraceCapture};
+use super::{
+    audit::{AuditEntry, AuditEvent, AuditLog},
+    authorizer::{AllowAll, JoinAuthorizer},
+    base64,
+    capture::{Capture, CaptureLimits},
+    client::{Client, ClientId, DroppedFrames, Notification, Priority, Sender, Socket},
+    clock::{Clock, Rng, SystemClock, SystemRng},
+    config::{AuthConfig, Config},
+    deny::DenyList,
+    limiter::{IpLimiter, ScanGuard},
+    metrics::{Delivery, Histogram, Metrics},
+    packets::{
+        CloseReason, Error, QualityReport, RemovalReason, ResponsePacket, RoomOptions, WireFormat,
+    },
+    polling::{Mailbox, Session},
+    pool::Pool,
+    queue::{OutboundBudget, Queue, SendPolicy},
+    reassembly::ReassemblyStream,
+    room::{Member, Room},
+    templates::Templates,
+    trace::{Direction, Trace},
+};
+#[cfg(feature = "metrics")]
+use super::{dump, metrics};
+use futures_util::{
+    future::{join_all, BoxFuture},
+    FutureExt, StreamExt,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, RwLock, Semaphore},
+    task::JoinSet,
+    time,
+};
+use tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{HeaderValue, StatusCode, Uri},
+    protocol::{CloseFrame, Message, WebSocketConfig},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DebugStats {
+    pub rooms: usize,
+    pub senders: usize,
+    pub sender_references: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomSnapshot {
+    pub id: String,
+    pub size: usize,
+    pub owner: Option<usize>,
+    pub locked: bool,
+    pub members: Vec<MemberSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemberSnapshot {
+    pub index: usize,
+    pub connection_id: u64,
+    pub address: SocketAddr,
+    pub uptime: Duration,
+    pub idle: Duration,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Settings for a connection handled by [`Server::handle_connection`].
+///
+/// ```
+/// # #[tokio::main]
+/// # async fn main() -> std::io::Result<()> {
+/// use relay::{config::Config, server::ConnectionOptions, Server};
+///
+/// let server = Server::new(Config::default())?;
+/// let options = ConnectionOptions::new(&*server.read().await);
+///
+/// assert!(options.auth.host.is_empty());
+/// assert!(options.handshake.is_none());
+/// assert_ne!(options.connection_id, ConnectionOptions::new(&*server.read().await).connection_id);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ConnectionOptions {
+    pub connection_id: u64,
+    pub auth: Arc<AuthConfig>,
+    pub handshake: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionOptions {
+    pub fn new(server: &Server) -> ConnectionOptions {
+        ConnectionOptions {
+            connection_id: server.connection_ids.fetch_add(1, Ordering::Relaxed),
+            auth: server.auth.clone(),
+            handshake: None,
+        }
+    }
+}
+
+enum Route {
+    Default,
+    Create,
+    Join(String),
+}
+
+pub struct Server {
+    pub(crate) rooms: HashMap<String, Room>,
+    pub(crate) config: Config,
+    pub(crate) auth: Arc<AuthConfig>,
+    pub(crate) audit_log: Option<AuditLog>,
+    #[cfg(feature = "mqtt")]
+    pub(crate) mqtt: Option<MqttPublisher>,
+    pub(crate) deny_list: DenyList,
+    pub(crate) create_limiter: Option<IpLimiter>,
+    pub(crate) scan_guard: Option<Mutex<ScanGuard>>,
+    pub(crate) templates: Templates,
+    pub(crate) join_authorizer: Arc<dyn JoinAuthorizer>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) rng: Arc<dyn Rng>,
+    pub(crate) connection_panics: AtomicU64,
+    pub(crate) invalid_packets: AtomicU64,
+    pub(crate) slow_messages: AtomicU64,
+    pub(crate) failed_joins: AtomicU64,
+    pub(crate) dropped_responses: Arc<AtomicU64>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) pool: Pool,
+    pub(crate) handshakes: Arc<Semaphore>,
+    pub(crate) connections: Arc<Semaphore>,
+    pub(crate) connection_ids: Arc<AtomicU64>,
+    pub(crate) outbound_budget: Arc<OutboundBudget>,
+    pub(crate) send_policy: Arc<SendPolicy>,
+    pub(crate) traces: HashMap<u64, Arc<Trace>>,
+    pub(crate) sessions: HashMap<String, Arc<Session>>,
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) relay_hook: Option<Arc<RelayHook>>,
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) trace_capture: Option<Arc<TraceCapture>>,
+}
+
+impl Server {
+    pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+    pub const MAX_MESSAGE_SIZE: usize = 64 << 20;
+    pub const MAX_FRAME_SIZE: usize = 16 << 20;
+    pub const SERVICE_UNAVAILABLE: &'static [u8] =
+        b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+    pub const NOT_FOUND: &'static [u8] = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+    pub const HEALTH: &'static str = r#"{"status":"ok"}"#;
+    pub const CONSOLE: &'static str = include_str!("console.html");
+    #[cfg(feature = "metrics")]
+    pub const METRICS_CONTENT_TYPE: &'static str =
+        "application/openmetrics-text; version=1.0.0; charset=utf-8";
+    pub const MAX_URI_LENGTH: usize = 2048;
+    pub const MAX_HEADER_LENGTH: usize = 1024;
+    pub const MAX_BODY_LENGTH: usize = 1 << 20;
+    pub const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+    pub const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+    pub const CAPABILITIES: [&'static str; 31] = [
+        "addresses",
+        "bandwidth",
+        "capture",
+        "chunking",
+        "close",
+        "compression",
+        "conflation",
+        "congestion",
+        "depths",
+        "dropped",
+        "exclusion",
+        "expiry",
+        "info",
+        "lock",
+        "migrate",
+        "notify",
+        "occupancy",
+        "opaque",
+        "origins",
+        "owner",
+        "pacing",
+        "quality",
+        "reserve",
+        "roster",
+        "stats",
+        "store",
+        "subscribe",
+        "templates",
+        "timestamps",
+        "ttl",
+        "virtual",
+    ];
+    pub const SUBPROTOCOLS: [(&'static str, Option<WireFormat>); 3] = [
+        ("relay", None),
+        ("relay-v1", Some(WireFormat::V1)),
+        ("relay-v2", Some(WireFormat::V2)),
+    ];
+
+    pub fn capabilities(host: &str) -> Vec<String> {
+        let mut capabilities: Vec<String> = Server::CAPABILITIES.map(String::from).to_vec();
+
+        if !host.is_empty() {
+            capabilities.push(String::from("origin"));
+        }
+
+        if !cfg!(feature = "compression") {
+            capabilities.retain(|capability| capability != "compression");
+        }
+
+        capabilities
+    }
+
+    pub fn new(config: Config) -> io::Result<Arc<RwLock<Server>>> {
+        let audit_log = match &config.audit_log {
+            Some(path) => Some(AuditLog::open(path)?),
+            None => None,
+        };
+
+        let deny_list = match &config.deny_list {
+            Some(path) => DenyList::load(path)?,
+            None => DenyList::default(),
+        };
+
+        let templates = config
+            .load_templates()
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+        let create_limiter = config.max_creates_per_minute.map(|limit| {
+            IpLimiter::new(
+                limit as u64,
+                Duration::from_secs(60),
+                IpLimiter::DEFAULT_CAPACITY,
+            )
+        });
+
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+
+        let scan_guard = (config.max_failed_joins_per_minute.is_some()
+            || config.max_global_failed_joins_per_minute.is_some())
+        .then(|| {
+            Mutex::new(ScanGuard::new(
+                config.max_failed_joins_per_minute.map(|limit| limit as u64),
+                config
+                    .max_global_failed_joins_per_minute
+                    .map(|limit| limit as u64),
+                IpLimiter::DEFAULT_CAPACITY,
+                clock.now(),
+            ))
+        });
+
+        #[cfg(feature = "mqtt")]
+        let mqtt = config
+            .mqtt
+            .as_ref()
+            .map(|mqtt| MqttPublisher::start(Broker::new(mqtt.clone()), mqtt.presence));
+
+        let pool = Pool::new(config.workers);
+        let handshakes = Arc::new(Semaphore::new(config.max_handshakes));
+        let connections = Arc::new(Semaphore::new(
+            config.max_connections.unwrap_or(Semaphore::MAX_PERMITS),
+        ));
+
+        let outbound_budget = Arc::new(OutboundBudget::new(config.max_outbound_bytes));
+        let send_policy = Arc::new(SendPolicy::new(
+            (config.send_timeout > 0).then(|| Duration::from_millis(config.send_timeout)),
+        ));
+
+        Ok(Arc::new(RwLock::new(Server {
+            rooms: HashMap::new(),
+            auth: Arc::new(config.auth.clone()),
+            config,
+            audit_log,
+            #[cfg(feature = "mqtt")]
+            mqtt,
+            deny_list,
+            create_limiter,
+            scan_guard,
+            templates,
+            join_authorizer: Arc::new(AllowAll),
+            clock,
+            rng: Arc::new(SystemRng),
+            connection_panics: AtomicU64::new(0),
+            invalid_packets: AtomicU64::new(0),
+            slow_messages: AtomicU64::new(0),
+            failed_joins: AtomicU64::new(0),
+            dropped_responses: Arc::default(),
+            metrics: Arc::default(),
+            pool,
+            handshakes,
+            connections,
+            connection_ids: Arc::new(AtomicU64::new(1)),
+            outbound_budget,
+            send_policy,
+            traces: HashMap::new(),
+            sessions: HashMap::new(),
+            #[cfg(any(test, feature = "test-util"))]
+            relay_hook: None,
+            #[cfg(any(test, feature = "test-util"))]
+            trace_capture: None,
+        })))
+    }
+
+    pub fn set_join_authorizer(&mut self, join_authorizer: impl JoinAuthorizer + 'static) {
+        self.join_authorizer = Arc::new(join_authorizer);
+    }
+
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Arc::new(clock);
+    }
+
+    pub fn set_rng(&mut self, rng: impl Rng + 'static) {
+        self.rng = Arc::new(rng);
+    }
+
+    #[cfg(feature = "mqtt")]
+    pub fn set_mqtt_publisher(&mut self, mqtt: MqttPublisher) {
+        self.mqtt = Some(mqtt);
+    }
+
+    pub(crate) fn record_event(
+        &self,
+        event: AuditEvent,
+        room_id: &str,
+        connection_id: u64,
+        ip: IpAddr,
+        index: usize,
+        reason: Option<RemovalReason>,
+    ) {
+        self.publish(AuditEntry::new(
+            event,
+            room_id,
+            connection_id,
+            ip,
+            index,
+            reason,
+        ));
+    }
+
+    pub(crate) fn record_quality(
+        &self,
+        room_id: &str,
+        connection_id: u64,
+        ip: IpAddr,
+        index: usize,
+        report: QualityReport,
+    ) {
+        if !self.config.audit_quality {
+            return;
+        }
+
+        let mut entry =
+            AuditEntry::new(AuditEvent::Quality, room_id, connection_id, ip, index, None);
+        entry.quality = Some(report);
+
+        self.publish(entry);
+    }
+
+    fn publish(&self, entry: AuditEntry) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.record(&entry);
+            mqtt.presence(
+                &entry.room_id,
+                self.rooms
+                    .get(&entry.room_id)
+                    .map(|room| (room.members.len(), room.size)),
+            );
+        }
+
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(entry);
+        }
+    }
+
+    pub(crate) fn room_of(&self, client_id: ClientId) -> Option<&String> {
+        let mut room_ids = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.position(client_id).is_some())
+            .map(|(room_id, _)| room_id);
+        let room_id = room_ids.next();
+
+        debug_assert!(
+            room_ids.next().is_none(),
+            "Client {} is a member of more than one room",
+            client_id
+        );
+
+        room_id
+    }
+
+    pub(crate) fn unix_millis(&self, instant: Instant) -> u64 {
+        let remaining = instant.saturating_duration_since(self.clock.now());
+        let elapsed = self.clock.now().saturating_duration_since(instant);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        (now + remaining).saturating_sub(elapsed).as_millis() as u64
+    }
+
+    pub(crate) fn limit_options(&self, options: &mut RoomOptions) {
+        options.max_bandwidth = match (self.config.max_room_bandwidth, options.max_bandwidth) {
+            (Some(max_room_bandwidth), Some(max_bandwidth)) => {
+                Some(max_bandwidth.min(max_room_bandwidth))
+            }
+            (max_room_bandwidth, max_bandwidth) => max_bandwidth.or(max_room_bandwidth),
+        };
+        options.ttl_seconds = match (self.config.max_room_ttl, options.ttl_seconds) {
+            (Some(max_room_ttl), Some(ttl_seconds)) => Some(ttl_seconds.min(max_room_ttl)),
+            (max_room_ttl, ttl_seconds) => ttl_seconds.or(max_room_ttl),
+        };
+    }
+
+    pub fn debug_stats(&self) -> DebugStats {
+        let senders = self
+            .rooms
+            .values()
+            .flat_map(|room| room.members.iter().map(|member| &member.sender));
+
+        DebugStats {
+            rooms: self.rooms.len(),
+            senders: senders.clone().count(),
+            sender_references: senders.map(Arc::strong_count).sum(),
+        }
+    }
+
+    pub fn list_rooms(&self) -> Vec<RoomSnapshot> {
+        let mut snapshots: Vec<_> = self
+            .rooms
+            .keys()
+            .filter_map(|room_id| self.inspect_room(room_id))
+            .collect();
+
+        snapshots.sort_by(|a, b| a.id.cmp(&b.id));
+        snapshots
+    }
+
+    pub fn inspect_room(&self, room_id: &str) -> Option<RoomSnapshot> {
+        let room = self.rooms.get(room_id)?;
+        let now = self.clock.now();
+
+        let members = room
+            .members
+            .iter()
+            .enumerate()
+            .map(|(index, member)| MemberSnapshot {
+                index,
+                connection_id: member.client_id.0,
+                address: member.address,
+                uptime: now.saturating_duration_since(member.joined_at),
+                idle: now.saturating_duration_since(member.last_active_at()),
+                bytes_sent: member.bytes_sent.load(Ordering::Relaxed),
+                bytes_received: member.bytes_received.load(Ordering::Relaxed),
+            })
+            .collect();
+
+        Some(RoomSnapshot {
+            id: room_id.to_string(),
+            size: room.size,
+            owner: room.owner,
+            locked: room.locked,
+            members,
+        })
+    }
+
+    pub fn connection_panics(&self) -> u64 {
+        self.connection_panics.load(Ordering::Relaxed)
+    }
+
+    pub fn invalid_packets(&self) -> u64 {
+        self.invalid_packets.load(Ordering::Relaxed)
+    }
+
+    pub fn slow_messages(&self) -> u64 {
+        self.slow_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_responses(&self) -> u64 {
+        self.dropped_responses.load(Ordering::Relaxed)
+    }
+
+    pub fn failed_joins(&self) -> u64 {
+        self.failed_joins.load(Ordering::Relaxed)
+    }
+
+    pub fn banned_ips(&self) -> usize {
+        self.scan_guard.as_ref().map_or(0, |scan_guard| {
+            scan_guard.lock().unwrap().banned(self.clock.now())
+        })
+    }
+
+    pub(crate) fn check_scanning(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.scan_guard.as_ref().map_or(Ok(()), |scan_guard| {
+            scan_guard.lock().unwrap().check(ip, self.clock.now())
+        })
+    }
+
+    pub(crate) fn record_unknown_room(&self, ip: IpAddr) {
+        self.failed_joins.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(scan_guard) = &self.scan_guard {
+            let _ = scan_guard.lock().unwrap().fail(ip, self.clock.now());
+        }
+    }
+
+    pub(crate) fn denies_ip(&self, ip: IpAddr) -> bool {
+        self.deny_list.denies_ip(ip)
+            || self.scan_guard.as_ref().is_some_and(|scan_guard| {
+                scan_guard.lock().unwrap().is_banned(ip, self.clock.now())
+            })
+    }
+
+    pub fn handshakes(&self) -> usize {
+        self.config.max_handshakes - self.handshakes.available_permits()
+    }
+
+    pub fn connections(&self) -> usize {
+        let max_connections = self
+            .config
+            .max_connections
+            .unwrap_or(Semaphore::MAX_PERMITS);
+
+        max_connections - self.connections.available_permits()
+    }
+
+    pub fn delivery_latency(&self, delivery: Delivery) -> &Histogram {
+        self.metrics.delivery_latency(delivery)
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&self) -> String {
+        let mut output = String::new();
+
+        metrics::render_gauge(
+            &mut output,
+            "relay_rooms",
+            "Number of open rooms.",
+            self.rooms.len(),
+        );
+        metrics::render_gauge(
+            &mut output,
+            "relay_connections",
+            "Number of established connections.",
+            self.connections(),
+        );
+        metrics::render_gauge(
+            &mut output,
+            "relay_handshakes",
+            "Number of connections in the handshake.",
+            self.handshakes(),
+        );
+        metrics::render_counter(
+            &mut output,
+            "relay_connection_panics",
+            "Connections which panicked.",
+            self.connection_panics(),
+        );
+        metrics::render_counter(
+            &mut output,
+            "relay_invalid_packets",
+            "Text packets which failed to parse.",
+            self.invalid_packets(),
+        );
+        metrics::render_counter(
+            &mut output,
+            "relay_failed_joins",
+            "Joins, reservations and info packets for rooms which do not exist.",
+            self.failed_joins(),
+        );
+        metrics::render_gauge(
+            &mut output,
+            "relay_banned_ips",
+            "IP addresses temporarily banned for scanning room IDs.",
+            self.banned_ips(),
+        );
+        metrics::render_counter(
+            &mut output,
+            "relay_dropped_responses",
+            "Text packets which could not be serialized or were too large to send.",
+            self.dropped_responses(),
+        );
+        metrics::render_counter(
+            &mut output,
+            "relay_slow_messages",
+            "Connections which were closed because a message took too long to arrive.",
+            self.slow_messages(),
+        );
+        metrics::render_gauge(
+            &mut output,
+            "relay_outbound_bytes",
+            "Bytes queued to be sent to every connection.",
+            self.outbound_budget.used(),
+        );
+        metrics::render_counter(
+            &mut output,
+            "relay_budget_dropped_frames",
+            "Binary packets dropped because the outbound budget was exhausted.",
+            self.outbound_budget.dropped_frames(),
+        );
+        metrics::render_counter(
+            &mut output,
+            "relay_fatal_send_errors",
+            "Sends which failed because the connection was lost, closing the connection.",
+            self.send_policy.fatal_errors(),
+        );
+        metrics::render_counter(
+            &mut output,
+            "relay_transient_send_errors",
+            "Sends which failed without closing the connection.",
+            self.send_policy.transient_errors(),
+        );
+        metrics::render_counter(
+            &mut output,
+            "relay_send_timeouts",
+            "Packets dropped because they took longer than the send timeout to be sent.",
+            self.send_policy.timeouts(),
+        );
+
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            metrics::render_counter(
+                &mut output,
+                "relay_mqtt_dropped_events",
+                "Events which were dropped because the MQTT queue was full.",
+                mqtt.dropped(),
+            );
+        }
+
+        self.metrics.render(&mut output);
+
+        output.push_str("# EOF\n");
+        output
+    }
+
+    pub fn reload(&mut self) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.reopen();
+        }
+
+        if let Some(path) = &self.config.deny_list {
+            match DenyList::load(path) {
+                Ok(deny_list) => self.deny_list = deny_list,
+                Err(error) => println!("Failed to reload deny list: {}", error),
+            }
+        }
+
+        if self.config.templates.is_some() {
+            match self.config.load_templates() {
+                Ok(templates) => self.templates = templates,
+                Err(error) => println!("Failed to reload templates: {}", error),
+            }
+        }
+    }
+
+    pub fn trace_connection(&self, connection_id: u64) -> Option<bool> {
+        self.traces.get(&connection_id).map(|trace| trace.toggle())
+    }
+
+    pub fn handle_command(&self, command: &str) -> String {
+        let words: Vec<_> = command.split_whitespace().collect();
+
+        match words.as_slice() {
+            ["trace", "connection", connection_id] => {
+                let traced = connection_id
+                    .parse()
+                    .ok()
+                    .and_then(|connection_id| self.trace_connection(connection_id));
+
+                match traced {
+                    Some(true) => format!("Tracing connection {}", connection_id),
+                    Some(false) => format!("Stopped tracing connection {}", connection_id),
+                    None => format!("Unknown connection: {}", connection_id),
+                }
+            }
+            _ => format!("Unknown command: {}", command.trim()),
+        }
+    }
+
+    pub async fn handle_provision_command(server: &Arc<RwLock<Server>>, arguments: &str) -> String {
+        let arguments = arguments.trim();
+        let (room_id, arguments) = arguments.split_once(' ').unwrap_or((arguments, ""));
+        let (size, arguments) = arguments
+            .trim()
+            .split_once(' ')
+            .unwrap_or((arguments.trim(), ""));
+        let (first_joiner_owner, options) = match arguments.trim().strip_prefix("owner") {
+            Some(options) => (true, options.trim()),
+            None => (false, arguments.trim()),
+        };
+
+        let Ok(size) = size.parse() else {
+            return format!("Invalid size: {}", size);
+        };
+
+        let options = match options {
+            "" => RoomOptions::default(),
+            options => match serde_json::from_str(options) {
+                Ok(options) => options,
+                Err(error) => return format!("Invalid options: {}", error),
+            },
+        };
+
+        let provisioned = Server::provision_room(
+            server,
+            room_id.to_string(),
+            size,
+            options,
+            first_joiner_owner,
+        );
+
+        match provisioned.await {
+            Ok(()) => format!("Provisioned room {}", room_id),
+            Err(error) => format!("Failed to provision room {}: {:?}", room_id, error),
+        }
+    }
+
+    pub async fn read_commands(server: Arc<RwLock<Server>>, reader: impl AsyncBufRead + Unpin) {
+        let mut lines = reader.lines();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let output = match line.trim().strip_prefix("provision ") {
+                Some(arguments) => Server::handle_provision_command(&server, arguments).await,
+                None => server.read().await.handle_command(&line),
+            };
+
+            println!("{}", output);
+        }
+    }
+
+    #[cfg(unix)]
+    pub async fn handle_hangup(server: Arc<RwLock<Server>>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            println!("Failed to listen for hangup signals");
+            return;
+        };
+
+        while hangup.recv().await.is_some() {
+            systemd::notify("RELOADING=1");
+            server.write().await.reload();
+            systemd::notify("READY=1");
+        }
+    }
+
+    pub async fn serve(server: Arc<RwLock<Server>>, listener: TcpListener) {
+        let (auth, socket_options, handshakes, connection_ids) = {
+            let server = server.read().await;
+
+            (
+                server.auth.clone(),
+                server.config.socket.clone(),
+                server.handshakes.clone(),
+                server.connection_ids.clone(),
+            )
+        };
+
+        let mut connections = JoinSet::new();
+
+        while let Ok((tcp_stream, address)) = listener.accept().await {
+            while let Some(Some(_)) = connections.join_next().now_or_never() {}
+
+            let Ok(handshake) = handshakes.clone().try_acquire_owned() else {
+                tokio::spawn(Server::reject_connection(tcp_stream));
+                continue;
+            };
+
+            if let Err(error) = socket_options.apply(&tcp_stream) {
+                println!("Failed to configure socket: {}", error);
+            }
+
+            let connection_id = connection_ids.fetch_add(1, Ordering::Relaxed);
+            let options = ConnectionOptions {
+                connection_id,
+                auth: auth.clone(),
+                handshake: Some(handshake),
+            };
+
+            let connection = Server::handle_connection(server.clone(), tcp_stream, options);
+
+            connections.spawn(Server::catch_panics(
+                server.clone(),
+                connection_id,
+                address,
+                connection,
+            ));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub async fn serve_metrics(server: Arc<RwLock<Server>>, listener: TcpListener) {
+        while let Ok((tcp_stream, _)) = listener.accept().await {
+            tokio::spawn(Server::handle_scrape(server.clone(), tcp_stream));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    async fn handle_scrape(server: Arc<RwLock<Server>>, mut tcp_stream: TcpStream) {
+        let mut buffer = vec![0; 4096];
+        let mut length = 0;
+
+        while !buffer[..length]
+            .windows(4)
+            .any(|window| window == b"\r\n\r\n")
+        {
+            let read = time::timeout(
+                Server::REJECT_TIMEOUT,
+                tcp_stream.read(&mut buffer[length..]),
+            )
+            .await;
+
+            match read {
+                Ok(Ok(read)) if read > 0 => length += read,
+                _ => return,
+            }
+
+            if length == buffer.len() {
+                return;
+            }
+        }
+
+        let request_line = buffer[..length].split(|&byte| byte == b'\r').next();
+        let path = request_line.and_then(|request_line| {
+            let mut parts = request_line.split(|&byte| byte == b' ');
+
+            (parts.next() == Some(b"GET"))
+                .then(|| parts.next())
+                .flatten()
+                .map(|target| {
+                    target
+                        .split(|&byte| byte == b'?')
+                        .next()
+                        .unwrap_or_default()
+                })
+        });
+
+        match path {
+            Some(b"/metrics") => {
+                let body = server.read().await.render_metrics();
+                Server::write_response(&mut tcp_stream, Server::METRICS_CONTENT_TYPE, &body).await;
+            }
+            Some(b"/dump") => {
+                let head = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n";
+
+                if tcp_stream.write_all(head.as_bytes()).await.is_ok() {
+                    let _ = dump::write(&server, &mut tcp_stream).await;
+                }
+            }
+            _ => {
+                let _ = tcp_stream.write_all(Server::NOT_FOUND).await;
+            }
+        }
+
+        let _ = tcp_stream.shutdown().await;
+    }
+
+    async fn handle_plain_request(
+        server: &Arc<RwLock<Server>>,
+        tcp_stream: &mut TcpStream,
+        auth: &AuthConfig,
+        handshake: OwnedSemaphorePermit,
+        test_console: bool,
+    ) -> Option<OwnedSemaphorePermit> {
+        let mut buffer = vec![0; 4096];
+        let peek = time::timeout(Server::REJECT_TIMEOUT, async {
+            loop {
+                let length = tcp_stream.peek(&mut buffer).await?;
+                let request = &buffer[..length];
+
+                if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                    return io::Result::Ok(Some(end + 4));
+                }
+
+                if length == 0 || length == buffer.len() {
+                    return Ok(None);
+                }
+
+                time::sleep(Server::PEEK_INTERVAL).await;
+            }
+        })
+        .await;
+
+        let Ok(Ok(Some(length))) = peek else {
+            return Some(handshake);
+        };
+
+        let Some(request) = Server::parse_head(&String::from_utf8_lossy(&buffer[..length])) else {
+            return Some(handshake);
+        };
+
+        let is_upgrade = request
+            .headers()
+            .get_all("Upgrade")
+            .iter()
+            .any(|header_value| header_value.as_bytes().eq_ignore_ascii_case(b"websocket"));
+
+        if is_upgrade {
+            return Some(handshake);
+        }
+
+        let _ = tcp_stream.read_exact(&mut buffer[..length]).await;
+
+        match (request.method().as_str(), request.uri().path()) {
+            ("GET", "/healthz") => {
+                Server::write_response(tcp_stream, "application/json", Server::HEALTH).await;
+            }
+            ("GET", "/") if test_console => {
+                Server::write_response(tcp_stream, "text/html; charset=utf-8", Server::CONSOLE)
+                    .await;
+            }
+            (_, path) if path.starts_with("/lp/") => {
+                drop(handshake);
+
+                match Server::handle_long_poll(server, tcp_stream, &request, auth).await {
+                    Ok(Some(body)) => {
+                        Server::write_response(tcp_stream, "application/json", &body).await;
+                    }
+                    Ok(None) => Server::write_status(tcp_stream, StatusCode::NO_CONTENT).await,
+                    Err(status) => Server::write_status(tcp_stream, status).await,
+                }
+            }
+            _ => {
+                let _ = tcp_stream.write_all(Server::NOT_FOUND).await;
+            }
+        }
+
+        let _ = tcp_stream.shutdown().await;
+        None
+    }
+
+    fn parse_head(head: &str) -> Option<Request> {
+        let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+        let mut request_line = lines.next()?.split(' ');
+        let mut builder = Request::builder()
+            .method(request_line.next()?)
+            .uri(request_line.next()?);
+
+        for line in lines {
+            let (name, value) = line.split_once(':')?;
+            builder = builder.header(name.trim(), value.trim());
+        }
+
+        builder.body(()).ok()
+    }
+
+    async fn handle_long_poll(
+        server: &Arc<RwLock<Server>>,
+        tcp_stream: &mut TcpStream,
+        request: &Request,
+        auth: &AuthConfig,
+    ) -> Result<Option<String>, StatusCode> {
+        Server::check_limits(request)?;
+
+        if !server.read().await.config.long_polling {
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        match (request.method().as_str(), request.uri().path()) {
+            ("POST", "/lp/connect") => {
+                Server::authorize(request, auth)?;
+
+                let address = tcp_stream
+                    .peer_addr()
+                    .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+                Server::connect_session(server, request, address, auth)
+                    .await
+                    .map(Some)
+            }
+            ("POST", "/lp/send") => {
+                let session = Server::find_session(server, request).await?;
+                let body = Server::read_body(tcp_stream, request).await?;
+
+                let is_json = request
+                    .headers()
+                    .get("Content-Type")
+                    .and_then(|header_value| header_value.to_str().ok())
+                    .is_some_and(|content_type| content_type.starts_with("application/json"));
+
+                let message = if is_json {
+                    Message::Text(String::from_utf8(body).map_err(|_| StatusCode::BAD_REQUEST)?)
+                } else {
+                    let data = std::str::from_utf8(&body)
+                        .ok()
+                        .and_then(|text| base64::decode(text.trim()))
+                        .ok_or(StatusCode::BAD_REQUEST)?;
+
+                    Message::Binary(data)
+                };
+
+                session.touch();
+                session
+                    .client
+                    .lock()
+                    .await
+                    .handle_message(server, message)
+                    .await;
+                session.touch();
+
+                Ok(None)
+            }
+            ("GET", "/lp/poll") => {
+                let session = Server::find_session(server, request).await?;
+
+                Ok(Some(session.poll(server).await))
+            }
+            _ => Err(StatusCode::NOT_FOUND),
+        }
+    }
+
+    async fn connect_session(
+        server: &Arc<RwLock<Server>>,
+        request: &Request,
+        address: SocketAddr,
+        auth: &AuthConfig,
+    ) -> Result<String, StatusCode> {
+        let mut locked_server = server.write().await;
+
+        let Ok(permit) = locked_server.connections.clone().try_acquire_owned() else {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        };
+
+        let connection_id = locked_server.connection_ids.fetch_add(1, Ordering::Relaxed);
+        let mailbox = Arc::new(Mailbox::default());
+        let queue = Queue::new(
+            mailbox.clone(),
+            locked_server.outbound_budget.clone(),
+            locked_server.send_policy.clone(),
+            locked_server.clock.clone(),
+        );
+        let failed = queue.failed();
+
+        let mut client = Client::new(
+            connection_id,
+            Arc::new(queue),
+            address,
+            Server::capabilities(&auth.host),
+        );
+        client.dropped_responses = locked_server.dropped_responses.clone();
+        client.origin = request
+            .headers()
+            .get("Origin")
+            .and_then(|header_value| header_value.to_str().ok())
+            .and_then(Server::origin_host);
+        client.lz4 = Server::client_capabilities(request).any(|capability| capability == "lz4");
+        client.wire_format = locked_server.config.wire_format;
+
+        let token = locked_server.rng.uuid().to_string();
+        let session = Arc::new(Session::new(client, mailbox));
+        locked_server
+            .sessions
+            .insert(token.clone(), session.clone());
+
+        drop(locked_server);
+
+        tokio::spawn(session.run(server.clone(), token.clone(), failed, permit));
+
+        Ok(format!(r#"{{"token":"{}"}}"#, token))
+    }
+
+    async fn find_session(
+        server: &RwLock<Server>,
+        request: &Request,
+    ) -> Result<Arc<Session>, StatusCode> {
+        let token = request
+            .uri()
+            .query()
+            .and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("token="))
+            })
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+        server
+            .read()
+            .await
+            .sessions
+            .get(token)
+            .cloned()
+            .ok_or(StatusCode::NOT_FOUND)
+    }
+
+    async fn read_body(
+        tcp_stream: &mut TcpStream,
+        request: &Request,
+    ) -> Result<Vec<u8>, StatusCode> {
+        let length = match request.headers().get("Content-Length") {
+            Some(header_value) => header_value
+                .to_str()
+                .ok()
+                .and_then(|length| length.parse::<usize>().ok())
+                .ok_or(StatusCode::BAD_REQUEST)?,
+            None => 0,
+        };
+
+        if length > Server::MAX_BODY_LENGTH {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        let mut body = vec![0; length];
+
+        match time::timeout(Server::REJECT_TIMEOUT, tcp_stream.read_exact(&mut body)).await {
+            Ok(Ok(_)) => Ok(body),
+            _ => Err(StatusCode::REQUEST_TIMEOUT),
+        }
+    }
+
+    async fn write_status(tcp_stream: &mut TcpStream, status: StatusCode) {
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        );
+
+        let _ = tcp_stream.write_all(head.as_bytes()).await;
+    }
+
+    async fn write_response(tcp_stream: &mut TcpStream, content_type: &str, body: &str) {
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            content_type,
+            body.len()
+        );
+
+        let _ = tcp_stream.write_all(head.as_bytes()).await;
+        let _ = tcp_stream.write_all(body.as_bytes()).await;
+    }
+
+    async fn reject_connection(mut tcp_stream: TcpStream) {
+        let mut buffer = [0; 4096];
+        let _ = time::timeout(Server::REJECT_TIMEOUT, tcp_stream.read(&mut buffer)).await;
+
+        let _ = tcp_stream.write_all(Server::SERVICE_UNAVAILABLE).await;
+        let _ = tcp_stream.shutdown().await;
+    }
+
+    pub(crate) async fn catch_panics(
+        server: Arc<RwLock<Server>>,
+        connection_id: u64,
+        address: SocketAddr,
+        future: impl Future<Output = ()>,
+    ) {
+        let Err(panic) = AssertUnwindSafe(future).catch_unwind().await else {
+            return;
+        };
+
+        let message = if let Some(message) = panic.downcast_ref::<&str>() {
+            message
+        } else if let Some(message) = panic.downcast_ref::<String>() {
+            message.as_str()
+        } else {
+            "unknown panic"
+        };
+
+        println!(
+            "Connection {} from {} panicked: {}",
+            connection_id, address, message
+        );
+
+        server
+            .read()
+            .await
+            .connection_panics
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn schedule_expiry(server: &Arc<RwLock<Server>>, room_id: &str, room: &mut Room) {
+        if let Some(expires_at) = room.expires_at {
+            let expiry = tokio::spawn(Server::expire_room(
+                server.clone(),
+                room_id.to_string(),
+                expires_at,
+            ));
+
+            room.expiry = Some(expiry.abort_handle());
+        }
+    }
+
+    pub(crate) fn schedule_pacing(server: &Arc<RwLock<Server>>, room_id: &str, room: &mut Room) {
+        if let Some(pacer) = &mut room.pacer {
+            let tick = tokio::spawn(Server::pace_room(
+                server.clone(),
+                room_id.to_string(),
+                pacer.interval,
+            ));
+
+            pacer.tick = Some(tick.abort_handle());
+        }
+    }
+
+    pub(crate) async fn pace_room(server: Arc<RwLock<Server>>, room_id: String, period: Duration) {
+        let mut ticks = time::interval(period);
+        ticks.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+        loop {
+            ticks.tick().await;
+
+            let locked_server = server.read().await;
+
+            let Some(room) = locked_server.rooms.get(&room_id) else {
+                return;
+            };
+
+            let Some(pacer) = &room.pacer else {
+                return;
+            };
+
+            let (senders, batches): (Vec<_>, Vec<_>) = pacer
+                .take()
+                .into_iter()
+                .filter_map(|(client_id, batch)| {
+                    let index = room.position(client_id)?;
+
+                    Some((room.members[index].sender.clone(), batch))
+                })
+                .unzip();
+
+            let ttl = room.options.frame_ttl_ms.map(Duration::from_millis);
+            let metrics = locked_server.metrics.clone();
+
+            drop(locked_server);
+
+            let metrics = &metrics;
+            let futures = senders
+                .iter()
+                .zip(batches)
+                .map(|(sender, batch)| {
+                    let dropped = Arc::new(DroppedFrames::forwarding(batch.sources));
+                    let future =
+                        sender.send_frame(Message::Binary(batch.frames), ttl, None, &dropped);
+                    let received = batch.received;
+
+                    Box::pin(async move {
+                        future.await?;
+
+                        for (delivery, received_at) in received {
+                            metrics.delivery_latency(delivery).record(received_at);
+                        }
+
+                        Ok(())
+                    }) as BoxFuture<'_, _>
+                })
+                .collect();
+
+            Client::complete(futures).await;
+        }
+    }
+
+    /// Creates an empty room with the given ID, for a backend to hand to its clients before anyone has joined.
+    ///
+    /// The room is removed if nobody joins it within [`Room::PROVISIONED_GRACE`], and is removed like any other
+    /// room once everyone has left. Unless `first_joiner_owner` is set, the room has no owner, so owner packets
+    /// such as [`closeRoom`](crate::relay::packets::RequestPacket::CloseRoom) are refused for every client.
+    pub async fn provision_room(
+        server: &Arc<RwLock<Server>>,
+        room_id: String,
+        size: usize,
+        options: RoomOptions,
+        first_joiner_owner: bool,
+    ) -> Result<(), Error> {
+        if size == Room::MIN_ROOM_SIZE || size >= Room::MAX_ROOM_SIZE {
+            return Err(Error::InvalidSize);
+        }
+
+        if Client::check_options(&options).is_err() {
+            return Err(Error::InvalidOptions);
+        }
+
+        let mut locked_server = server.write().await;
+
+        if locked_server.deny_list.denies_room(&room_id) {
+            return Err(Error::Denied);
+        }
+
+        if locked_server.rooms.contains_key(&room_id) {
+            return Err(Error::AlreadyExists);
+        }
+
+        let mut options = options;
+        locked_server.limit_options(&mut options);
+
+        let now = locked_server.clock.now();
+        let vacant_until = now + Room::PROVISIONED_GRACE;
+
+        let mut room = Room::new(size, options, now);
+        room.dropped_responses = locked_server.dropped_responses.clone();
+        room.owner = first_joiner_owner.then_some(0);
+        room.vacant_until = Some(vacant_until);
+
+        if locked_server.config.max_room_duration > 0 {
+            room.limit_duration(now + Duration::from_secs(locked_server.config.max_room_duration));
+        }
+
+        Server::schedule_expiry(server, &room_id, &mut room);
+        Server::schedule_pacing(server, &room_id, &mut room);
+        locked_server.rooms.insert(room_id.clone(), room);
+
+        drop(locked_server);
+
+        tokio::spawn(Server::release_room(server.clone(), room_id, vacant_until));
+
+        Ok(())
+    }
+
+    async fn release_room(server: Arc<RwLock<Server>>, room_id: String, vacant_until: Instant) {
+        let sleep = server.read().await.clock.sleep_until(vacant_until);
+        sleep.await;
+
+        let mut server = server.write().await;
+
+        if server
+            .rooms
+            .get(&room_id)
+            .is_some_and(|room| room.vacant_until == Some(vacant_until))
+        {
+            server.rooms.remove(&room_id);
+            server.record_event(
+                AuditEvent::Close,
+                &room_id,
+                0,
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                0,
+                Some(RemovalReason::Expired),
+            );
+        }
+    }
+
+    pub(crate) async fn expire_room(
+        server: Arc<RwLock<Server>>,
+        room_id: String,
+        expires_at: Instant,
+    ) {
+        let sleep = server.read().await.clock.sleep_until(expires_at);
+        sleep.await;
+
+        let mut server = server.write().await;
+
+        match server.rooms.get(&room_id) {
+            Some(room) if room.expires_at == Some(expires_at) => {}
+            _ => return,
+        }
+
+        let mut room = server.rooms.remove(&room_id).unwrap();
+        room.expiry = None;
+
+        let owner = room.owner;
+        let members = room.remove_all(&*server.rng, server.clock.now());
+
+        let (connection_id, ip, index) =
+            match owner.and_then(|owner| Some((owner, members.get(owner)?))) {
+                Some((index, owner)) => (owner.client_id.0, owner.address.ip(), index),
+                None => (0, IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            };
+
+        server.record_event(
+            AuditEvent::Close,
+            &room_id,
+            connection_id,
+            ip,
+            index,
+            Some(RemovalReason::Expired),
+        );
+
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &server.mqtt {
+            mqtt.presence(&room_id, None);
+        }
+
+        drop(server);
+
+        let close_packet = ResponsePacket::Close {
+            reason: if room.deadline == Some(expires_at) {
+                CloseReason::MaxDuration
+            } else {
+                CloseReason::Expired
+            },
+            message: None,
+        };
+
+        Server::close_room(
+            members,
+            close_packet,
+            Client::close_frame(RemovalReason::Expired),
+            &room.dropped_responses,
+        )
+        .await
+    }
+
+    pub async fn start_capture(
+        server: &Arc<RwLock<Server>>,
+        room_id: &str,
+        path: &Path,
+        limits: CaptureLimits,
+    ) -> io::Result<()> {
+        let mut locked_server = server.write().await;
+
+        let Some(room) = locked_server.rooms.get_mut(room_id) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "room does not exist",
+            ));
+        };
+
+        if room.capture.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "room is already being captured",
+            ));
+        }
+
+        let capture = Capture::create(path, room.size, limits)?;
+        let started_at = capture.started_at;
+        room.capture = Some(capture);
+
+        let capture_notification = Notification::new(
+            ResponsePacket::Capture { active: true },
+            &room.dropped_responses,
+        );
+        let peers: Vec<_> = room.members.iter().map(Member::peer).collect();
+
+        let futures: Vec<_> = peers
+            .iter()
+            .filter_map(|peer| peer.send(&capture_notification))
+            .collect();
+
+        drop(locked_server);
+
+        tokio::spawn({
+            let server = server.clone();
+            let room_id = room_id.to_string();
+
+            async move {
+                time::sleep(limits.max_duration).await;
+
+                Server::end_capture(&server, &room_id, Some(started_at)).await;
+            }
+        });
+
+        Client::complete(futures).await;
+
+        Ok(())
+    }
+
+    pub async fn stop_capture(server: &RwLock<Server>, room_id: &str) -> bool {
+        Server::end_capture(server, room_id, None).await
+    }
+
+    async fn end_capture(
+        server: &RwLock<Server>,
+        room_id: &str,
+        started_at: Option<Instant>,
+    ) -> bool {
+        let mut locked_server = server.write().await;
+
+        let Some(room) = locked_server.rooms.get_mut(room_id) else {
+            return false;
+        };
+
+        match &room.capture {
+            Some(capture)
+                if started_at.is_none_or(|started_at| capture.started_at == started_at) => {}
+            _ => return false,
+        }
+
+        let capture = room.capture.take().unwrap();
+        let peers: Vec<_> = if capture.is_full() {
+            vec![]
+        } else {
+            room.members.iter().map(Member::peer).collect()
+        };
+
+        let dropped_responses = room.dropped_responses.clone();
+
+        drop(locked_server);
+
+        let capture_notification = Notification::new(
+            ResponsePacket::Capture { active: false },
+            &dropped_responses,
+        );
+        let futures: Vec<_> = peers
+            .iter()
+            .filter_map(|peer| peer.send(&capture_notification))
+            .collect();
+
+        Client::complete(futures).await;
+
+        let _ = tokio::task::spawn_blocking(move || capture.finish()).await;
+
+        true
+    }
+
+    pub(crate) async fn close_room(
+        members: Vec<Member>,
+        close_packet: ResponsePacket,
+        close_frame: Option<CloseFrame<'static>>,
+        dropped_responses: &Arc<AtomicU64>,
+    ) {
+        let close_notification = Notification::new(close_packet, dropped_responses);
+
+        let mut futures = vec![];
+        for member in &members {
+            let close_packet = close_notification.encode(member.wire_format);
+
+            futures.push(async {
+                if let Some(close_packet) = close_packet {
+                    member.sender.send(close_packet, Priority::High).await?;
+                }
+
+                member.sender.close(close_frame.clone()).await
+            });
+        }
+
+        for result in join_all(futures).await {
+            if let Err(error) = result {
+                println!("Failed to send: {}", error);
+            }
+        }
+    }
+
+    pub(crate) fn check_limits(request: &Request) -> Result<(), StatusCode> {
+        let uri_length = request
+            .uri()
+            .path_and_query()
+            .map_or(0, |path_and_query| path_and_query.as_str().len());
+
+        if uri_length > Server::MAX_URI_LENGTH {
+            return Err(StatusCode::URI_TOO_LONG);
+        }
+
+        let too_long = ["Origin", "Authorization"].iter().any(|name| {
+            request
+                .headers()
+                .get_all(*name)
+                .iter()
+                .any(|header_value| header_value.len() > Server::MAX_HEADER_LENGTH)
+        });
+
+        if too_long {
+            return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn authorize(request: &Request, config: &AuthConfig) -> Result<(), StatusCode> {
+        if config.host.is_empty() {
+            return Ok(());
+        }
+
+        let Some(api_key) = Server::api_key(request) else {
+            return Server::check_origin(request.headers().get("Origin"), config);
+        };
+
+        let valid = config.api_keys.iter().fold(false, |valid, key| {
+            valid | constant_time_eq(key.as_bytes(), api_key.as_bytes())
+        });
+
+        if !valid {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        Ok(())
+    }
+
+    fn api_key(request: &Request) -> Option<&str> {
+        let header_key = request
+            .headers()
+            .get("Authorization")
+            .and_then(|header_value| header_value.to_str().ok())
+            .and_then(|authorization| authorization.strip_prefix("Bearer "));
+
+        header_key.or_else(|| {
+            request
+                .uri()
+                .query()?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("key="))
+        })
+    }
+
+    pub(crate) fn client_capabilities(request: &Request) -> impl Iterator<Item = &str> {
+        request
+            .uri()
+            .query()
+            .and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("capabilities="))
+            })
+            .into_iter()
+            .flat_map(|capabilities| capabilities.split(','))
+    }
+
+    pub(crate) fn select_subprotocol(
+        request: &Request,
+    ) -> Result<Option<(&'static str, Option<WireFormat>)>, StatusCode> {
+        let offered = request.headers().get_all("Sec-WebSocket-Protocol");
+
+        if offered.iter().next().is_none() {
+            return Ok(None);
+        }
+
+        offered
+            .iter()
+            .filter_map(|header_value| header_value.to_str().ok())
+            .flat_map(|protocols| protocols.split(','))
+            .map(str::trim)
+            .find_map(|protocol| {
+                Server::SUBPROTOCOLS
+                    .into_iter()
+                    .find(|(supported, _)| *supported == protocol)
+            })
+            .map(Some)
+            .ok_or(StatusCode::BAD_REQUEST)
+    }
+
+    pub(crate) fn check_origin(
+        header_value: Option<&HeaderValue>,
+        config: &AuthConfig,
+    ) -> Result<(), StatusCode> {
+        let Some(header_value) = header_value else {
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        if header_value.len() > Server::MAX_HEADER_LENGTH {
+            return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        }
+
+        let Ok(origin) = header_value.to_str() else {
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        if origin == "null" && config.allow_null_origin {
+            return Ok(());
+        }
+
+        if origin == "null" {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let Some(origin_host) = Server::origin_host(origin) else {
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        if !Server::matches_host(&origin_host, &config.host) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn origin_host(origin: &str) -> Option<String> {
+        let origin_uri = origin.parse::<Uri>().ok()?;
+
+        origin_uri
+            .host()
+            .filter(|host| !host.is_empty())
+            .map(str::to_ascii_lowercase)
+    }
+
+    pub(crate) fn matches_host(origin_host: &str, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let is_subdomain = origin_host
+            .strip_suffix(&host)
+            .is_some_and(|prefix| prefix.ends_with('.'));
+
+        origin_host == host || is_subdomain
+    }
+
+    fn route(server: &RwLock<Server>, path: &str) -> Result<Route, StatusCode> {
+        if path == "/" {
+            return Ok(Route::Default);
+        }
+
+        if path == "/create" {
+            return Ok(Route::Create);
+        }
+
+        let Some(room_id) = path.strip_prefix("/room/") else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+
+        // The join rechecks the room, so the pre-check is skipped when the lock is contended.
+        if let Ok(server) = server.try_read() {
+            if !server.rooms.contains_key(room_id) {
+                return Err(StatusCode::NOT_FOUND);
+            }
+        }
+
+        Ok(Route::Join(room_id.to_string()))
+    }
+
+    /// Handles a connection which was accepted outside of [`Server::serve`].
+    ///
+    /// ```no_run
+    /// # async fn run() -> std::io::Result<()> {
+    /// use relay::{config::Config, server::ConnectionOptions, Server};
+    /// use tokio::net::TcpListener;
+    ///
+    /// let server = Server::new(Config::default())?;
+    /// let listener = TcpListener::bind("127.0.0.1:8080").await?;
+    ///
+    /// loop {
+    ///     let (tcp_stream, _) = listener.accept().await?;
+    ///     let options = ConnectionOptions::new(&*server.read().await);
+    ///
+    ///     tokio::spawn(Server::handle_connection(server.clone(), tcp_stream, options));
+    /// }
+    /// # }
+    /// ```
+    ///
+    /// Without a handshake permit in the options, a permit is acquired for the connection, and the connection is
+    /// rejected when `max_handshakes` connections are already in the handshake.
+    #[allow(clippy::result_large_err)]
+    pub async fn handle_connection(
+        server: Arc<RwLock<Server>>,
+        mut tcp_stream: TcpStream,
+        options: ConnectionOptions,
+    ) {
+        let Ok(address) = tcp_stream.peer_addr() else {
+            return;
+        };
+
+        let (
+            denied,
+            connections,
+            handshakes,
+            outbound_budget,
+            send_policy,
+            dropped_responses,
+            unjoined_timeout,
+            message_timeout,
+            wire_format,
+            test_console,
+            clock,
+        ) = {
+            let server = server.read().await;
+
+            (
+                server.denies_ip(address.ip()),
+                server.connections.clone(),
+                server.handshakes.clone(),
+                server.outbound_budget.clone(),
+                server.send_policy.clone(),
+                server.dropped_responses.clone(),
+                server.config.unjoined_timeout,
+                server.config.message_timeout,
+                server.config.wire_format,
+                server.config.test_console,
+                server.clock.clone(),
+            )
+        };
+
+        let ConnectionOptions {
+            connection_id,
+            auth,
+            handshake,
+        } = options;
+        let handshake = match handshake.map_or_else(|| handshakes.try_acquire_owned(), Ok) {
+            Ok(handshake) => handshake,
+            Err(_) => return Server::reject_connection(tcp_stream).await,
+        };
+
+        let handshake = if denied {
+            handshake
+        } else {
+            let plain_request = Server::handle_plain_request(
+                &server,
+                &mut tcp_stream,
+                &auth,
+                handshake,
+                test_console,
+            );
+
+            match plain_request.await {
+                Some(handshake) => handshake,
+                None => return,
+            }
+        };
+
+        let mut route = Route::Default;
+        let mut permit = None;
+        let mut origin = None;
+        let mut lz4 = false;
+        let mut wire_format = wire_format;
+
+        let callback = |request: &Request, mut response: Response| {
+            let status = if denied {
+                Err(StatusCode::FORBIDDEN)
+            } else {
+                Server::check_limits(request).and_then(|()| Server::authorize(request, &auth))
+            };
+
+            let status = status
+                .and_then(|()| Server::route(&server, request.uri().path()))
+                .and_then(|request_route| {
+                    Server::select_subprotocol(request)
+                        .map(|subprotocol| (request_route, subprotocol))
+                })
+                .and_then(
+                    |(request_route, subprotocol)| match connections.try_acquire_owned() {
+                        Ok(connection) => Ok((request_route, subprotocol, connection)),
+                        Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+                    },
+                );
+
+            match status {
+                Ok((request_route, subprotocol, connection)) => {
+                    route = request_route;
+                    permit = Some(connection);
+                    origin = request
+                        .headers()
+                        .get("Origin")
+                        .and_then(|header_value| header_value.to_str().ok())
+                        .and_then(Server::origin_host);
+                    lz4 =
+                        Server::client_capabilities(request).any(|capability| capability == "lz4");
+
+                    if let Some((protocol, protocol_wire_format)) = subprotocol {
+                        response
+                            .headers_mut()
+                            .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(protocol));
+                        wire_format = protocol_wire_format.unwrap_or(wire_format);
+                    }
+
+                    Ok(response)
+                }
+                Err(status) => {
+                    let mut response = ErrorResponse::new(None);
+                    *response.status_mut() = status;
+
+                    Err(response)
+                }
+            }
+        };
+
+        let config = WebSocketConfig {
+            max_message_size: Some(Server::MAX_MESSAGE_SIZE),
+            max_frame_size: Some(Server::MAX_FRAME_SIZE),
+            ..Default::default()
+        };
+
+        let message_timeout = (message_timeout > 0).then(|| Duration::from_millis(message_timeout));
+        let (reassembly_stream, reassembly) =
+            ReassemblyStream::new(tcp_stream, message_timeout, clock.clone());
+
+        let websocket_stream = tokio_tungstenite::accept_hdr_async_with_config(
+            reassembly_stream,
+            callback,
+            Some(config),
+        )
+        .await;
+
+        drop(handshake);
+
+        if let Ok(websocket_stream) = websocket_stream {
+            reassembly.complete();
+
+            #[allow(unused_mut)]
+            let mut trace = Trace::new(connection_id);
+
+            #[cfg(any(test, feature = "test-util"))]
+            {
+                trace.capture = server.read().await.trace_capture.clone();
+            }
+
+            let trace = Arc::new(trace);
+            server
+                .write()
+                .await
+                .traces
+                .insert(connection_id, trace.clone());
+
+            let (sender, mut receiver) = websocket_stream.split();
+            let queue = Queue::new(
+                Socket::new(sender, trace.clone()),
+                outbound_budget,
+                send_policy,
+                clock.clone(),
+            );
+            let failed = queue.failed();
+            let sender: Sender = Arc::new(queue);
+
+            let mut client = Client::new(
+                connection_id,
+                sender,
+                address,
+                Server::capabilities(&auth.host),
+            );
+            client.dropped_responses = dropped_responses;
+            client.origin = origin;
+            client.lz4 = lz4;
+            client.wire_format = wire_format;
+
+            match route {
+                Route::Default => {}
+                Route::Create => {
+                    client
+                        .handle_create_room(&server, None, RoomOptions::default(), None)
+                        .await
+                }
+                Route::Join(room_id) => {
+                    client
+                        .handle_join_room(&server, room_id, None, None, None)
+                        .await
+                }
+            }
+
+            let mut unjoined_deadline = (unjoined_timeout > 0 && client.room_id.is_none())
+                .then(|| clock.sleep_until(clock.now() + Duration::from_secs(unjoined_timeout)));
+
+            let mut dropped_frames = time::interval(Client::DROPPED_INTERVAL);
+            dropped_frames.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+            let mut removal_reason = RemovalReason::Disconnected;
+
+            loop {
+                let message = tokio::select! {
+                    message = receiver.next() => message,
+                    _ = async { unjoined_deadline.as_mut().unwrap().await }, if unjoined_deadline.is_some() => {
+                        client.handle_unjoined_timeout().await;
+                        break;
+                    }
+                    _ = dropped_frames.tick() => {
+                        client.handle_dropped_frames().await;
+                        continue;
+                    }
+                    _ = failed.notified() => {
+                        println!("Connection {} from {} failed to write", connection_id, address);
+                        break;
+                    }
+                };
+
+                let Some(message) = message else {
+                    break;
+                };
+
+                match message {
+                    Ok(message) => {
+                        reassembly.complete();
+                        trace.record(Direction::Inbound, &message);
+
+                        let result = AssertUnwindSafe(client.handle_message(&server, message))
+                            .catch_unwind()
+                            .await;
+
+                        if let Err(panic) = result {
+                            client
+                                .handle_close(&server, RemovalReason::Disconnected)
+                                .await;
+                            panic::resume_unwind(panic);
+                        }
+
+                        if client.room_id.is_some() {
+                            unjoined_deadline = None;
+                        }
+                    }
+                    Err(tungstenite::Error::Io(error))
+                        if error.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        server
+                            .read()
+                            .await
+                            .slow_messages
+                            .fetch_add(1, Ordering::Relaxed);
+
+                        println!(
+                            "Connection {} from {} took too long to send a message",
+                            connection_id, address
+                        );
+
+                        removal_reason = RemovalReason::TimedOut;
+                        break;
+                    }
+                    Err(error) => {
+                        println!("Failed to read message: {}", error);
+                        break;
+                    }
+                }
+            }
+
+            client.handle_close(&server, removal_reason).await;
+
+            drop(permit);
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b)
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
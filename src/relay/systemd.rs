@@ -0,0 +1,97 @@
+use socket2::{SockRef, Type};
+use std::{
+    env,
+    ffi::OsStr,
+    future, io,
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::{ffi::OsStrExt, net::UnixDatagram},
+    },
+    process,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+pub const LISTEN_FDS_START: RawFd = 3;
+
+pub fn listeners() -> io::Result<Vec<TcpListener>> {
+    adopt(LISTEN_FDS_START)
+}
+
+pub fn adopt(first_fd: RawFd) -> io::Result<Vec<TcpListener>> {
+    let pid = env::var("LISTEN_PID");
+    let fds = env::var("LISTEN_FDS");
+
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    let (Ok(pid), Ok(fds)) = (pid, fds) else {
+        return Ok(vec![]);
+    };
+
+    if pid != process::id().to_string() {
+        return Ok(vec![]);
+    }
+
+    let Ok(count) = fds.parse::<RawFd>() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid LISTEN_FDS: {}", fds),
+        ));
+    };
+
+    (first_fd..first_fd.saturating_add(count))
+        .map(|fd| {
+            // The file descriptors from LISTEN_FDS are passed to this process to own.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+            if SockRef::from(&listener).r#type()? != Type::STREAM {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("File descriptor {} is not a stream socket", fd),
+                ));
+            }
+
+            listener.local_addr()?;
+            listener.set_nonblocking(true)?;
+
+            Ok(listener)
+        })
+        .collect()
+}
+
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    if let Err(error) = send(&path, state) {
+        println!("Failed to notify systemd: {}", error);
+    }
+}
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?
+        }
+        _ => socket.send_to(state.as_bytes(), path)?,
+    };
+
+    Ok(())
+}
+
+pub async fn terminated() {
+    let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+        println!("Failed to listen for terminate signals");
+        return future::pending().await;
+    };
+
+    terminate.recv().await;
+}
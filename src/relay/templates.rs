@@ -0,0 +1,83 @@
+use super::{
+    frame,
+    packets::{CreateOptions, RoomOptions},
+};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, io, path::Path};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Template {
+    pub size: Option<usize>,
+    #[serde(flatten)]
+    pub options: RoomOptions,
+}
+
+impl Template {
+    ///
+    /// Fills in the size and options of a create packet from the template.
+    /// Every field which is set in the packet takes precedence, including options which disable the template's options.
+    ///
+    pub fn apply(
+        &self,
+        size: Option<usize>,
+        options: CreateOptions,
+    ) -> (Option<usize>, RoomOptions) {
+        (size.or(self.size), options.or(&self.options))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Templates {
+    templates: HashMap<String, Template>,
+}
+
+impl Templates {
+    pub fn load(path: &Path) -> io::Result<Templates> {
+        let contents = fs::read_to_string(path)?;
+
+        Templates::parse(&contents)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    pub fn parse(contents: &str) -> Result<Templates, String> {
+        let templates: HashMap<String, Template> =
+            serde_json::from_str(contents).map_err(|error| error.to_string())?;
+
+        Templates::new(templates)
+    }
+
+    pub fn new(templates: HashMap<String, Template>) -> Result<Templates, String> {
+        for (name, template) in &templates {
+            if let Some(size) = template
+                .size
+                .filter(|&size| size == 0 || size > frame::MAX_ROOM_SIZE)
+            {
+                return Err(format!(
+                    "size {} of template {} is not between 1 and {}",
+                    size,
+                    name,
+                    frame::MAX_ROOM_SIZE
+                ));
+            }
+        }
+
+        Ok(Templates { templates })
+    }
+
+    pub fn merge(mut self, other: &Templates) -> Result<Templates, String> {
+        for (name, template) in &other.templates {
+            if self.templates.contains_key(name) {
+                return Err(format!("template {} is defined more than once", name));
+            }
+
+            self.templates.insert(name.clone(), template.clone());
+        }
+
+        Ok(self)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Template> {
+        self.templates.get(name)
+    }
+}
@@ -0,0 +1,189 @@
+use super::{
+    clock::{Clock, Rng},
+    config::Config,
+    Server,
+};
+use futures_util::future::{self, BoxFuture};
+use std::{
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{watch, Notify, RwLock},
+    task::AbortHandle,
+};
+use tungstenite::{connect, stream::MaybeTlsStream, WebSocket};
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub server: Arc<RwLock<Server>>,
+    shutdown_handle: AbortHandle,
+}
+
+impl TestServer {
+    pub async fn start() -> TestServer {
+        TestServer::start_with_config(Config::default()).await
+    }
+
+    pub async fn start_with_config(config: Config) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind");
+
+        TestServer::start_with_listener(config, listener)
+    }
+
+    pub fn start_with_listener(config: Config, listener: TcpListener) -> TestServer {
+        let server = Server::new(config).expect("Failed to start");
+        let addr = listener.local_addr().unwrap();
+
+        let accept_loop = tokio::spawn(Server::serve(server.clone(), listener));
+
+        TestServer {
+            addr,
+            server,
+            shutdown_handle: accept_loop.abort_handle(),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("ws://{}{}", self.addr, path)
+    }
+
+    pub fn connect(&self) -> WebSocket<MaybeTlsStream<TcpStream>> {
+        self.connect_to("/")
+    }
+
+    pub fn connect_to(&self, path: &str) -> WebSocket<MaybeTlsStream<TcpStream>> {
+        let (socket, _) = connect(self.url(path)).expect("Failed to connect");
+        socket
+    }
+}
+
+#[derive(Default)]
+pub struct RelayHook {
+    relayed: Notify,
+    resumed: Notify,
+}
+
+impl RelayHook {
+    pub async fn install(server: &RwLock<Server>) -> Arc<RelayHook> {
+        let relay_hook = Arc::new(RelayHook::default());
+        server.write().await.relay_hook = Some(relay_hook.clone());
+
+        relay_hook
+    }
+
+    pub async fn remove(server: &RwLock<Server>) {
+        server.write().await.relay_hook = None;
+    }
+
+    pub async fn paused(&self) {
+        self.relayed.notified().await
+    }
+
+    pub fn resume(&self) {
+        self.resumed.notify_one()
+    }
+
+    pub(crate) async fn pause(&self) {
+        self.relayed.notify_one();
+        self.resumed.notified().await
+    }
+}
+
+#[derive(Default)]
+pub struct TraceCapture {
+    lines: Mutex<Vec<String>>,
+}
+
+impl TraceCapture {
+    pub async fn install(server: &RwLock<Server>) -> Arc<TraceCapture> {
+        let trace_capture = Arc::new(TraceCapture::default());
+        server.write().await.trace_capture = Some(trace_capture.clone());
+
+        trace_capture
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+
+    pub(crate) fn push(&self, line: String) {
+        self.lines.lock().unwrap().push(line);
+    }
+}
+
+pub struct ManualClock {
+    now: watch::Sender<Instant>,
+}
+
+impl ManualClock {
+    pub async fn install(server: &RwLock<Server>) -> Arc<ManualClock> {
+        let manual_clock = Arc::new(ManualClock {
+            now: watch::channel(Instant::now()).0,
+        });
+        server.write().await.clock = manual_clock.clone();
+
+        manual_clock
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let mut now = self.now.subscribe();
+
+        Box::pin(async move {
+            if now.wait_for(|now| *now >= deadline).await.is_err() {
+                future::pending().await
+            }
+        })
+    }
+}
+
+pub struct SeededRng {
+    state: AtomicU64,
+}
+
+impl SeededRng {
+    pub async fn install(server: &RwLock<Server>, seed: u64) -> Arc<SeededRng> {
+        let seeded_rng = Arc::new(SeededRng {
+            state: AtomicU64::new(seed),
+        });
+        server.write().await.rng = seeded_rng.clone();
+
+        seeded_rng
+    }
+}
+
+impl Rng for SeededRng {
+    fn next_u64(&self) -> u64 {
+        let mut value = self
+            .state
+            .fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e3779b97f4a7c15);
+
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+        value ^ (value >> 31)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown_handle.abort();
+    }
+}
@@ -0,0 +1,106 @@
+#[cfg(any(test, feature = "test-util"))]
+use super::testing::TraceCapture;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::Arc;
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tungstenite::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+pub struct Trace {
+    connection_id: u64,
+    enabled: AtomicBool,
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) capture: Option<Arc<TraceCapture>>,
+}
+
+impl Trace {
+    pub fn new(connection_id: u64) -> Trace {
+        Trace {
+            connection_id,
+            enabled: AtomicBool::new(false),
+            #[cfg(any(test, feature = "test-util"))]
+            capture: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn toggle(&self) -> bool {
+        !self.enabled.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn record(&self, direction: Direction, message: &Message) {
+        if self.is_enabled() {
+            self.write(direction, message);
+        }
+    }
+
+    #[cold]
+    fn write(&self, direction: Direction, message: &Message) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let (direction, peer) = match direction {
+            Direction::Inbound => ("in", "destination"),
+            Direction::Outbound => ("out", "source"),
+        };
+
+        let kind = match message {
+            Message::Text(_) => "text",
+            Message::Binary(_) => "binary",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Close(_) => "close",
+            Message::Frame(_) => "frame",
+        };
+
+        let mut line = format!(
+            "Trace {} at {}: {} {} of {} bytes",
+            self.connection_id,
+            timestamp,
+            direction,
+            kind,
+            message.len()
+        );
+
+        match message {
+            Message::Text(text) => {
+                let packet_type = serde_json::from_str::<serde_json::Value>(text)
+                    .ok()
+                    .and_then(|value| value.get("type")?.as_str().map(String::from));
+
+                if let Some(packet_type) = packet_type {
+                    write!(line, ", type {}", packet_type).unwrap();
+                }
+            }
+            Message::Binary(data) => {
+                if let Some(index) = data.first() {
+                    write!(line, ", {} {}", peer, index).unwrap();
+                }
+            }
+            _ => {}
+        }
+
+        #[cfg(any(test, feature = "test-util"))]
+        if let Some(capture) = &self.capture {
+            return capture.push(line);
+        }
+
+        println!("{}", line);
+    }
+}
@@ -1,667 +1,13216 @@
-#[cfg(test)]
-mod tests {
-    use crate::relay::{Error, RequestPacket, ResponsePacket, Server};
+#[cfg(feature = "metrics")]
+use crate::relay::dump;
+#[cfg(feature = "compression")]
+use crate::relay::lz4;
+#[cfg(feature = "latency-metrics")]
+use crate::relay::metrics::{Delivery, Histogram};
+#[cfg(unix)]
+use crate::relay::systemd;
+use crate::relay::{
+    authorizer::{DenyReason, JoinAuthorizer},
+    base64,
+    capture::{CaptureFile, CaptureLimits, Payload},
+    client::{self, Client, ClientId, DroppedFrames, Outbound, Priority, Statistics},
+    clock::{Clock, Rng},
+    config::{AuthConfig, Command, Config, Runtime, SocketOptions},
+    deny::DenyList,
+    frame,
+    limiter::{IpLimiter, ScanGuard},
+    packets::{
+        CloseReason, Compression, CreateOptions, DropReason, Error, MemberQuality,
+        MemberStatistics, MemberSummary, QualityReport, RemovalReason, RequestPacket,
+        ResponsePacket, RoomOptions, WireFormat,
+    },
+    polling::Session,
+    queue::{Queue, SendFailure},
+    replay::Replayer,
+    room::{Effect, Member, Migration, Mutation, Pacer, Room, TokenBucket},
+    selftest,
+    server::{ConnectionOptions, DebugStats},
+    templates::Templates,
+    testing::{ManualClock, RelayHook, SeededRng, TestServer, TraceCapture},
+    typescript, Server,
+};
+#[cfg(feature = "mqtt")]
+use crate::relay::{
+    config::MqttConfig,
+    mqtt::{Broker, MqttClient, MqttPublisher},
+};
+
+use futures_util::future::BoxFuture;
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    panic::AssertUnwindSafe,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+use tungstenite::{
+    client::IntoClientRequest,
+    connect,
+    error::ProtocolError,
+    handshake::server::Request,
+    http::{HeaderValue, StatusCode},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    stream::MaybeTlsStream,
+    Message, WebSocket,
+};
+
+macro_rules! create_socket {
+    ($value:expr) => {{
+        let (socket, _) = connect(format!("ws://{}", $value)).unwrap();
+        socket
+    }};
+}
+
+macro_rules! close_socket {
+    ($value:expr) => {{
+        $value.close(None).unwrap();
+        loop {
+            if $value.read().is_err() {
+                break;
+            }
+        }
+    }};
+}
+
+macro_rules! write_binary_message {
+    ($value:expr, $value2:expr) => {
+        $value.send(Message::Binary($value2)).unwrap()
+    };
+}
+
+macro_rules! write_message {
+    ($value:expr, $value2:expr) => {
+        let packet = $value2;
+        let serialized_packet = serde_json::to_string(&packet).unwrap();
+        $value.send(Message::Text(serialized_packet)).unwrap()
+    };
+}
+
+macro_rules! read_message {
+    ($value:expr, $pattern:pat => $extracted_value:expr) => {
+        match serde_json::from_str(&$value.read().unwrap().clone().into_text().unwrap()).unwrap() {
+            $pattern => $extracted_value,
+            unknown => panic!("pattern doesn't match: {:?}", unknown),
+        }
+    };
+}
+
+macro_rules! read_binary_message {
+    ($value:expr) => {
+        $value.read().unwrap().clone().into_data()
+    };
+}
+
+macro_rules! read_recorded_message {
+    ($value:expr, $pattern:pat => $extracted_value:expr) => {
+        match serde_json::from_str($value.messages.lock().unwrap().remove(0).to_text().unwrap())
+            .unwrap()
+        {
+            $pattern => $extracted_value,
+            unknown => panic!("pattern doesn't match: {:?}", unknown),
+        }
+    };
+}
+
+macro_rules! read_recorded_binary_message {
+    ($value:expr) => {
+        $value.messages.lock().unwrap().remove(0).into_data()
+    };
+}
+
+///
+/// An in-memory sender which records every message sent to it.
+///
+#[derive(Default)]
+struct Recorder {
+    messages: Mutex<Vec<Message>>,
+    statistics: Statistics,
+}
+
+impl Recorder {
+    fn is_empty(&self) -> bool {
+        self.messages.lock().unwrap().is_empty()
+    }
+}
+
+impl Outbound for Recorder {
+    fn send(&self, message: Message, _: Priority) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        self.messages.lock().unwrap().push(message);
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+}
+
+///
+/// An in-memory sender which waits for the given delay before recording each message.
+///
+struct SlowRecorder {
+    recorder: Arc<Recorder>,
+    delay: Duration,
+}
+
+impl Outbound for SlowRecorder {
+    fn send(
+        &self,
+        message: Message,
+        priority: Priority,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        Box::pin(async move {
+            tokio::time::sleep(self.delay).await;
+            self.recorder.send(message, priority).await
+        })
+    }
+
+    fn statistics(&self) -> &Statistics {
+        self.recorder.statistics()
+    }
+}
+
+///
+/// An in-memory sender which fails or stalls the sends it is told to, and records every other message.
+///
+#[derive(Clone, Default)]
+struct FaultyRecorder {
+    recorder: Arc<Recorder>,
+    faults: Arc<Mutex<VecDeque<Option<tungstenite::Error>>>>,
+}
+
+impl FaultyRecorder {
+    fn fail(&self, error: tungstenite::Error) {
+        self.faults.lock().unwrap().push_back(Some(error));
+    }
+
+    fn stall(&self) {
+        self.faults.lock().unwrap().push_back(None);
+    }
+}
+
+impl Outbound for FaultyRecorder {
+    fn send(
+        &self,
+        message: Message,
+        priority: Priority,
+    ) -> BoxFuture<'_, Result<(), tungstenite::Error>> {
+        match self.faults.lock().unwrap().pop_front() {
+            Some(Some(error)) => Box::pin(async { Err(error) }),
+            Some(None) => Box::pin(futures_util::future::pending()),
+            None => self.recorder.send(message, priority),
+        }
+    }
+
+    fn statistics(&self) -> &Statistics {
+        self.recorder.statistics()
+    }
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+///
+/// Allocates a connection ID which is unique across every test.
+///
+fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+///
+/// Creates a client which is backed by a recorder rather than a socket.
+///
+fn create_client() -> (Client, Arc<Recorder>) {
+    let recorder = Arc::new(Recorder::default());
+    let client = Client::new(
+        next_connection_id(),
+        recorder.clone(),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    (client, recorder)
+}
+
+///
+/// Creates a member with the given client ID which is backed by a recorder.
+///
+fn create_member(client_id: u64) -> Member {
+    Member::new(
+        ClientId(client_id),
+        Arc::new(Recorder::default()),
+        "127.0.0.1:0".parse().unwrap(),
+        false,
+        Arc::new(DroppedFrames::default()),
+        Arc::new(Migration::default()),
+        Instant::now(),
+    )
+}
+
+///
+/// Asserts every key of a JSON value, including the keys of nested objects, is in camel case.
+///
+fn assert_camel_case(value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object {
+                assert!(!key.contains('_'), "{} is not in camel case", key);
+                assert_camel_case(value);
+            }
+        }
+        serde_json::Value::Array(array) => array.iter().for_each(assert_camel_case),
+        _ => {}
+    }
+}
+
+///
+/// Test origin header restrictions.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn origin() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start_with_config(Config {
+        auth: AuthConfig {
+            host: String::from("example.com"),
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Test no origin header.
+    //
+
+    assert!(connect(format!("ws://{}", socket_addr)).is_err());
+
+    //
+    // Test empty origin header.
+    //
+
+    let mut request = format!("ws://{}", socket_addr)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Origin", HeaderValue::from_static(""));
+
+    assert!(connect(request).is_err());
+
+    //
+    // Test invalid origin header value.
+    //
+
+    let mut request = format!("ws://{}", socket_addr)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Origin", HeaderValue::from_static("null"));
+
+    assert!(connect(request).is_err());
+
+    //
+    // Test non-matching root host.
+    //
+
+    let mut request = format!("ws://{}", socket_addr)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Origin", HeaderValue::from_static(".com"));
+
+    assert!(connect(request).is_err());
+
+    //
+    // Test non-matching root host.
+    //
+
+    let mut request = format!("ws://{}", socket_addr)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Origin", HeaderValue::from_static("not-example.com"));
+
+    assert!(connect(request).is_err());
+
+    //
+    // Test matching root host.
+    //
+
+    let mut request = format!("ws://{}", socket_addr)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Origin", HeaderValue::from_static("example.com"));
+
+    assert!(connect(request).is_ok());
+
+    //
+    // Test matching subdomain host.
+    //
+
+    let mut request = format!("ws://{}", socket_addr)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Origin", HeaderValue::from_static("subdomain.example.com"));
+
+    assert!(connect(request).is_ok());
+}
+
+///
+/// Test a listener inherited through socket activation is adopted and served, and systemd is notified.
+///
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn systemd_activation() {
+    use std::os::{fd::IntoRawFd, unix::net::UnixDatagram};
+
+    //
+    // Test nothing is adopted when the activation was meant for another process.
+    //
+
+    std::env::set_var("LISTEN_PID", (std::process::id() + 1).to_string());
+    std::env::set_var("LISTEN_FDS", "1");
+
+    assert!(systemd::adopt(systemd::LISTEN_FDS_START)
+        .unwrap()
+        .is_empty());
+    assert!(std::env::var_os("LISTEN_PID").is_none());
+    assert!(std::env::var_os("LISTEN_FDS").is_none());
+
+    //
+    // Test an invalid count and a socket which is not a stream socket are rejected.
+    //
+
+    std::env::set_var("LISTEN_PID", std::process::id().to_string());
+    std::env::set_var("LISTEN_FDS", "one");
+
+    assert!(systemd::adopt(systemd::LISTEN_FDS_START).is_err());
+
+    let datagram = UnixDatagram::unbound().unwrap().into_raw_fd();
+
+    std::env::set_var("LISTEN_PID", std::process::id().to_string());
+    std::env::set_var("LISTEN_FDS", "1");
+
+    assert!(systemd::adopt(datagram).is_err());
+
+    //
+    // Pre-bind a listener as systemd would, and adopt it.
+    //
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let fd = listener.into_raw_fd();
+
+    std::env::set_var("LISTEN_PID", std::process::id().to_string());
+    std::env::set_var("LISTEN_FDS", "1");
+
+    let mut listeners = systemd::adopt(fd).unwrap();
+    assert_eq!(1, listeners.len());
+    assert_eq!(address, listeners[0].local_addr().unwrap());
+    assert!(std::env::var_os("LISTEN_FDS").is_none());
+
+    //
+    // Test the adopted listener accepts connections.
+    //
+
+    let listener = TcpListener::from_std(listeners.remove(0)).unwrap();
+    let test_server = TestServer::start_with_listener(Config::default(), listener);
+    assert_eq!(address, test_server.addr);
+
+    let mut socket = create_socket!(test_server.addr);
+
+    write_message!(
+        socket,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Hello { .. } => ());
+
+    close_socket!(socket);
+
+    //
+    // Test states are sent to the notify socket, by path and by abstract name.
+    //
+
+    let path = std::env::temp_dir().join(format!("relay-notify-{}.sock", uuid::Uuid::new_v4()));
+    let notify_socket = UnixDatagram::bind(&path).unwrap();
+    let mut buffer = [0; 64];
+
+    std::env::set_var("NOTIFY_SOCKET", &path);
+    systemd::notify("READY=1");
+
+    let length = notify_socket.recv(&mut buffer).unwrap();
+    assert_eq!(b"READY=1", &buffer[..length]);
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let name = format!("relay-notify-{}", uuid::Uuid::new_v4());
+        let notify_socket =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+
+        std::env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+        systemd::notify("STOPPING=1");
+
+        let length = notify_socket.recv(&mut buffer).unwrap();
+        assert_eq!(b"STOPPING=1", &buffer[..length]);
+    }
+
+    std::env::remove_var("NOTIFY_SOCKET");
+    std::fs::remove_file(path).unwrap();
+}
+
+///
+/// Test the hello packet reports the build information and the server's configuration.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn hello() {
+    //
+    // Test a server without an origin restriction.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+    let mut socket = create_socket!(socket_addr);
+
+    write_message!(
+        socket,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Hello { version, capabilities, max_room_size, max_message_size, connection_id, chunk_size } => {
+        assert_eq!(env!("CARGO_PKG_VERSION"), version);
+        assert_eq!(Server::capabilities(""), capabilities);
+        assert_eq!(cfg!(feature = "compression"), capabilities.contains(&String::from("compression")));
+        assert_eq!(253, max_room_size);
+        assert_eq!(Server::MAX_MESSAGE_SIZE, max_message_size);
+        assert!(connection_id > 0);
+        assert_eq!(None, chunk_size);
+    });
+
+    close_socket!(socket);
+
+    //
+    // Test a server with an origin restriction.
+    //
+
+    let test_server = TestServer::start_with_config(Config {
+        auth: AuthConfig {
+            host: String::from("example.com"),
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    let socket_addr = test_server.addr;
+
+    let mut request = format!("ws://{}", socket_addr)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("Origin", HeaderValue::from_static("example.com"));
+
+    let (mut socket, _) = connect(request).unwrap();
+
+    write_message!(
+        socket,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Hello { capabilities, .. } => assert_eq!(Some(&String::from("origin")), capabilities.last()));
+
+    close_socket!(socket);
+}
+
+///
+/// Test tracing logs the frames of one connection, until it is toggled off or the connection ends.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn trace_connection() {
+    let test_server = TestServer::start().await;
+    let server = test_server.server.clone();
+    let trace_capture = TraceCapture::install(&server).await;
+
+    let mut socket_1 = test_server.connect();
+    let mut socket_2 = test_server.connect();
+
+    write_message!(
+        socket_1,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    let connection_id_1 =
+        read_message!(socket_1, ResponsePacket::Hello { connection_id, .. } => connection_id);
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    let connection_id_2 = read_message!(socket_2, ResponsePacket::Join { connection_id, .. } => connection_id.unwrap());
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+
+    assert!(trace_capture.lines().is_empty());
+
+    //
+    // Test the commands.
+    //
+
+    assert_eq!(
+        format!("Tracing connection {}", connection_id_1),
+        server
+            .read()
+            .await
+            .handle_command(&format!("trace connection {}", connection_id_1))
+    );
+    assert_eq!(
+        "Unknown connection: 0",
+        server.read().await.handle_command("trace connection 0")
+    );
+    assert_eq!(
+        "Unknown connection: abc",
+        server.read().await.handle_command("trace connection abc")
+    );
+    assert_eq!(
+        "Unknown command: restart",
+        server.read().await.handle_command(" restart ")
+    );
+
+    //
+    // Test only the frames of the traced connection are logged, without their payloads.
+    //
+
+    write_binary_message!(socket_1, vec![1, 10, 20, 30]);
+    assert_eq!(vec![0, 10, 20, 30], read_binary_message!(socket_2));
+
+    write_binary_message!(socket_2, vec![0, 40, 50]);
+    assert_eq!(vec![1, 40, 50], read_binary_message!(socket_1));
+
+    write_message!(
+        socket_2,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Hello { .. } => ());
+
+    let lines = trace_capture.lines();
+    let prefix = format!("Trace {} at ", connection_id_1);
+
+    assert_eq!(2, lines.len());
+    assert!(lines.iter().all(|line| line.starts_with(&prefix)));
+    assert!(lines[0].ends_with(": in binary of 4 bytes, destination 1"));
+    assert!(lines[1].ends_with(": out binary of 3 bytes, source 1"));
+
+    write_message!(
+        socket_1,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Hello { .. } => ());
+
+    let lines = trace_capture.lines();
+
+    assert_eq!(4, lines.len());
+    assert!(lines[2].ends_with(": in text of 16 bytes, type hello"));
+    assert!(lines[3].contains(": out text of "));
+    assert!(lines[3].ends_with(", type hello"));
+    assert!(!lines
+        .iter()
+        .any(|line| line.contains(&format!("Trace {} ", connection_id_2))));
+
+    //
+    // Test toggling the trace off stops the logging.
+    //
+
+    assert_eq!(
+        format!("Stopped tracing connection {}", connection_id_1),
+        server
+            .read()
+            .await
+            .handle_command(&format!("trace connection {}", connection_id_1))
+    );
+
+    write_binary_message!(socket_1, vec![1, 10, 20, 30]);
+    assert_eq!(vec![0, 10, 20, 30], read_binary_message!(socket_2));
+
+    assert_eq!(4, trace_capture.lines().len());
+
+    //
+    // Test the trace ends with the connection.
+    //
+
+    close_socket!(socket_1);
+
+    let started_at = Instant::now();
+
+    while server.read().await.traces.contains_key(&connection_id_1) {
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    close_socket!(socket_2);
+}
+
+///
+/// Test a room provisioned from the command line is joined with its options, and has no owner unless designated.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn provision_room() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let server = test_server.server.clone();
+
+    //
+    // Test the provision command validates its arguments.
+    //
+
+    assert_eq!(
+        "Provisioned room lobby",
+        Server::handle_provision_command(
+            &server,
+            r#"lobby 3 {"timestamps":true,"notifyMembers":false}"#
+        )
+        .await
+    );
+    assert_eq!(
+        "Failed to provision room lobby: AlreadyExists",
+        Server::handle_provision_command(&server, "lobby 3").await
+    );
+    assert_eq!(
+        "Failed to provision room other: InvalidSize",
+        Server::handle_provision_command(&server, "other 0").await
+    );
+    assert_eq!(
+        "Failed to provision room other: InvalidOptions",
+        Server::handle_provision_command(&server, r#"other 2 {"opaque":true,"timestamps":true}"#)
+            .await
+    );
+    assert_eq!(
+        "Invalid size: many",
+        Server::handle_provision_command(&server, "other many").await
+    );
+    assert!(Server::handle_provision_command(&server, "other 2 {")
+        .await
+        .starts_with("Invalid options: "));
+    assert!(!server.read().await.rooms.contains_key("other"));
+
+    //
+    // Test two clients join the provisioned room, and its options apply.
+    //
+
+    let mut socket_1 = test_server.connect_to("/room/lobby");
+    read_message!(socket_1, ResponsePacket::Join { capacity: Some(3), index: Some(0), .. } => ());
+
+    let mut socket_2 = test_server.connect_to("/room/lobby");
+    read_message!(socket_2, ResponsePacket::Join { capacity: Some(3), index: Some(1), .. } => ());
+
+    write_binary_message!(socket_2, vec![0, 7, 8]);
+
+    let data = read_binary_message!(socket_1);
+    assert_eq!(1 + 8 + 2, data.len());
+    assert_eq!(1, data[0]);
+    assert_eq!([7, 8], data[9..]);
+
+    //
+    // Test neither client owns the room.
+    //
+
+    for socket in [&mut socket_1, &mut socket_2] {
+        write_message!(socket, RequestPacket::CloseRoom { reason: None });
+        read_message!(socket, ResponsePacket::Error { message: Error::NotOwner, .. } => ());
+    }
+
+    write_binary_message!(socket_2, vec![frame::OWNER, 9]);
+    write_binary_message!(socket_2, vec![0, 10]);
+    assert_eq!(10, read_binary_message!(socket_1)[9]);
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+
+    //
+    // Test the first client to join a designated room becomes its owner.
+    //
+
+    assert_eq!(
+        "Provisioned room owned",
+        Server::handle_provision_command(&server, "owned 2 owner").await
+    );
+
+    let mut socket = test_server.connect_to("/room/owned");
+    read_message!(socket, ResponsePacket::Join { index: Some(0), .. } => ());
+
+    write_message!(socket, RequestPacket::CloseRoom { reason: None });
+    read_message!(socket, ResponsePacket::Close { reason: CloseReason::Closed, .. } => ());
+
+    close_socket!(socket);
+}
+
+///
+/// Test a provisioned room is removed when nobody joins it within the grace period.
+///
+#[tokio::test]
+async fn unit_provisioned_grace() {
+    macro_rules! wait_for {
+        ($condition:expr) => {
+            while !$condition {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+    }
+
+    let path = std::env::temp_dir().join(format!("relay-audit-{}.log", uuid::Uuid::new_v4()));
+    let server = Server::new(Config {
+        audit_log: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+    let clock = ManualClock::install(&server).await;
+    let (mut client, recorder) = create_client();
+
+    for room_id in ["vacant", "joined"] {
+        Server::provision_room(
+            &server,
+            room_id.to_string(),
+            2,
+            RoomOptions::default(),
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    client
+        .handle_join_room(&server, String::from("joined"), None, None, None)
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Join { index: Some(0), .. } => ());
+
+    //
+    // Test only the room nobody joined is removed once the grace period elapses.
+    //
+
+    clock.advance(Room::PROVISIONED_GRACE - Duration::from_secs(1));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(server.read().await.rooms.contains_key("vacant"));
+
+    clock.advance(Duration::from_secs(1));
+    wait_for!(!server.read().await.rooms.contains_key("vacant"));
+    assert!(server.read().await.rooms.contains_key("joined"));
+
+    //
+    // Test the removal is recorded as an expired close, like a room whose time to live elapsed.
+    //
+
+    let lines = read_audit_log(&path, 2).await;
+    let entry = lines
+        .iter()
+        .find(|entry| entry["room_id"] == "vacant")
+        .unwrap();
+
+    assert_eq!("close", entry["event"]);
+    assert_eq!("Expired", entry["reason"]);
+    assert_eq!(0, entry["connection_id"]);
+    assert_eq!("0.0.0.0", entry["ip"]);
+
+    //
+    // Test the joined room is removed like any other once everyone has left.
+    //
+
+    client.handle_close(&server, RemovalReason::Left).await;
+    assert!(server.read().await.rooms.is_empty());
+}
+
+///
+/// Test a provisioned room whose time to live elapses before anyone joins is recorded as closed.
+///
+#[tokio::test]
+async fn unit_provisioned_expiry() {
+    const TTL: Duration = Duration::from_secs(10);
+
+    macro_rules! wait_for {
+        ($condition:expr) => {
+            while !$condition {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+    }
+
+    let path = std::env::temp_dir().join(format!("relay-audit-{}.log", uuid::Uuid::new_v4()));
+    let server = Server::new(Config {
+        audit_log: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+    let clock = ManualClock::install(&server).await;
+
+    for (room_id, first_joiner_owner) in [("ownerless", false), ("owned", true)] {
+        Server::provision_room(
+            &server,
+            room_id.to_string(),
+            2,
+            RoomOptions {
+                ttl_seconds: Some(TTL.as_secs()),
+                ..Default::default()
+            },
+            first_joiner_owner,
+        )
+        .await
+        .unwrap();
+    }
+
+    //
+    // Test both rooms expire, whether or not the first joiner would have owned them.
+    //
+
+    assert!(TTL < Room::PROVISIONED_GRACE);
+
+    clock.advance(TTL);
+    wait_for!(server.read().await.rooms.is_empty());
+
+    let lines = read_audit_log(&path, 2).await;
+
+    for room_id in ["ownerless", "owned"] {
+        let entry = lines
+            .iter()
+            .find(|entry| entry["room_id"] == room_id)
+            .unwrap();
+
+        assert_eq!("close", entry["event"]);
+        assert_eq!("Expired", entry["reason"]);
+        assert_eq!(0, entry["connection_id"]);
+        assert_eq!(0, entry["index"]);
+        assert_eq!("0.0.0.0", entry["ip"]);
+    }
+
+    fs::remove_file(&path).unwrap();
+}
+
+///
+/// Test rate limits, frame timestamps and frame expiry follow the clock installed on the server.
+///
+#[tokio::test]
+async fn unit_injected_clock() {
+    let server = Server::new(Config::default()).unwrap();
+    let clock = ManualClock::install(&server).await;
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    //
+    // Test a token bucket only refills as the given time advances.
+    //
+
+    let now = clock.now();
+    let mut bucket = TokenBucket::new(2);
+
+    assert!(bucket.take(2, now));
+    assert!(!bucket.take(1, now));
+    assert!(!bucket.take(1, now + Duration::from_millis(100)));
+    assert!(bucket.take(1, now + Duration::from_millis(600)));
+
+    //
+    // Test frame timestamps are measured from the creation of the room.
+    //
+
+    client_1
+        .handle_create_room(
+            &server,
+            Some(2),
+            RoomOptions {
+                timestamps: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+    recorder_1.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+
+    clock.advance(Duration::from_millis(5));
+
+    client_1
+        .handle_message(&server, Message::Binary(vec![1, 9]))
+        .await;
+
+    let data = read_recorded_binary_message!(recorder_2);
+    assert_eq!(5000, u64::from_be_bytes(data[1..9].try_into().unwrap()));
+
+    //
+    // Test a queued frame expires once the clock passes its time to live.
+    //
+
+    let recorder = Arc::new(Recorder::default());
+    let queue = Queue::new(
+        SlowRecorder {
+            recorder: recorder.clone(),
+            delay: Duration::from_millis(50),
+        },
+        server.read().await.outbound_budget.clone(),
+        server.read().await.send_policy.clone(),
+        server.read().await.clock.clone(),
+    );
+    let dropped = Arc::new(DroppedFrames::default());
+
+    queue
+        .send_frame(Message::Binary(vec![1]), None, None, &dropped)
+        .await
+        .unwrap();
+    queue
+        .send_frame(
+            Message::Binary(vec![2]),
+            Some(Duration::from_secs(1)),
+            None,
+            &dropped,
+        )
+        .await
+        .unwrap();
+
+    clock.advance(Duration::from_secs(2));
+
+    while queue.statistics().expired_frames.load(Ordering::Relaxed) == 0 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    assert_eq!(vec![1], read_recorded_binary_message!(recorder));
+    assert!(recorder.is_empty());
+    assert_eq!(Some((1, DropReason::Expired)), dropped.take());
+}
+
+///
+/// Test the test server tears down the listener and its connections when dropped.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn test_server() {
+    let test_server = TestServer::start().await;
+    let url = test_server.url("/");
+
+    let mut socket = test_server.connect();
+
+    write_message!(
+        socket,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Hello { .. } => ());
+
+    drop(test_server);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(socket.read().is_err());
+    assert!(connect(url).is_err());
+}
+
+///
+/// Test the server relays packets when running on the current-thread runtime.
+///
+#[test]
+fn current_thread_runtime() {
+    //
+    // Setup test.
+    //
+
+    let config = Config::parse(["--runtime", "current"].into_iter().map(String::from)).unwrap();
+    let runtime = config.build_runtime().unwrap();
+
+    let (addr_sender, addr_receiver) = std::sync::mpsc::channel();
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+
+    let handle = std::thread::spawn(move || {
+        runtime.block_on(async move {
+            let test_server = TestServer::start_with_config(config).await;
+            addr_sender.send(test_server.addr).unwrap();
+
+            let _ = shutdown_receiver.await;
+        })
+    });
+
+    let addr = addr_receiver.recv().unwrap();
+
+    //
+    // Test creating, joining and relaying in a room.
+    //
+
+    let mut socket_1 = create_socket!(addr);
+    let mut socket_2 = create_socket!(addr);
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: None,
+            template: None,
+            options: CreateOptions::default(),
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Join { index: Some(1), .. } => ());
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+
+    write_binary_message!(socket_2, vec![0, 1, 2, 3]);
+    assert_eq!(vec![1, 1, 2, 3], read_binary_message!(socket_1));
+
+    //
+    // Test shutting down the runtime.
+    //
+
+    shutdown_sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+///
+/// Test the self-test scenario passes against an ephemeral server, and fails on a step which times out.
+///
+#[tokio::test]
+async fn self_test() {
+    let report = selftest::run(selftest::STEP_TIMEOUT).await;
+    assert!(report.passed(), "{}", report);
+
+    let names: Vec<_> = report.steps.iter().map(|step| step.name).collect();
+    assert_eq!(
+        vec![
+            "start",
+            "connect",
+            "create",
+            "join",
+            "unicast",
+            "broadcast",
+            "leave",
+            "room-destroyed"
+        ],
+        names
+    );
+
+    //
+    // Test the scenario stops at the first step which exceeds the timeout.
+    //
+
+    let report = selftest::run(Duration::from_nanos(1)).await;
+    assert!(!report.passed());
+    assert!(report.to_string().ends_with("Self-test failed"));
+
+    let failed = report.steps.last().unwrap();
+    assert_eq!(Some(String::from("timed out after 0 ms")), failed.error);
+    assert!(report.steps[..report.steps.len() - 1]
+        .iter()
+        .all(|step| step.error.is_none()));
+}
+
+///
+/// Test connections accepted outside of the accept loop are handled with their own options.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn handle_connection() {
+    let server = Server::new(Config {
+        max_handshakes: 1,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socket_addr = listener.local_addr().unwrap();
+
+    let accept_loop = tokio::spawn({
+        let server = server.clone();
+
+        async move {
+            for restricted in [false, true] {
+                let (tcp_stream, _) = listener.accept().await.unwrap();
+                let mut options = ConnectionOptions::new(&*server.read().await);
+
+                if restricted {
+                    options.auth = Arc::new(AuthConfig {
+                        host: String::from("example.com"),
+                        ..Default::default()
+                    });
+                }
+
+                tokio::spawn(Server::handle_connection(
+                    server.clone(),
+                    tcp_stream,
+                    options,
+                ));
+            }
+        }
+    });
+
+    //
+    // Test the server's options are used by default.
+    //
+
+    let mut socket = create_socket!(socket_addr);
+
+    write_message!(
+        socket,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Hello { capabilities, .. } => assert!(!capabilities.contains(&String::from("origin"))));
+
+    //
+    // Test the options can restrict a single connection.
+    //
+
+    match connect(format!("ws://{}", socket_addr)) {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(StatusCode::BAD_REQUEST, response.status())
+        }
+        unknown => panic!("unexpected result: {:?}", unknown.map(|_| ())),
+    }
+
+    accept_loop.await.unwrap();
+    close_socket!(socket);
+}
+
+///
+/// Test a panicking connection is logged and counted while the server keeps serving other clients.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn panics() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let server = test_server.server.clone();
+
+    let mut socket_1 = test_server.connect();
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions {
+                max_bandwidth: Some(1000),
+
+                ..Default::default()
+            },
+            virtual_address: None,
+            template: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Inject a panic into the message handler by poisoning the bandwidth limiter of the room.
+    //
+
+    {
+        let server = server.read().await;
+        let bandwidth = server.rooms[&room_id].bandwidth.as_ref().unwrap();
+
+        let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let _bandwidth = bandwidth.lock().unwrap();
+            panic!("poisoned");
+        }));
+    }
+
+    write_binary_message!(socket_1, vec![Room::BROADCAST, 1, 2, 3]);
+    assert!(socket_1.read().is_err());
+
+    //
+    // Test the panic is counted, and the client is removed from its room.
+    //
+
+    let started_at = Instant::now();
+
+    while server.read().await.connection_panics() == 0 {
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert_eq!(1, server.read().await.connection_panics());
+    assert!(!server.read().await.rooms.contains_key(&room_id));
+
+    //
+    // Test the server keeps serving other clients.
+    //
+
+    let mut socket_2 = test_server.connect();
+
+    write_message!(
+        socket_2,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Hello { .. } => ());
+
+    close_socket!(socket_2);
+}
+
+///
+/// Test connections beyond the limit are rejected until a connection closes.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn max_connections() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start_with_config(Config {
+        max_connections: Some(1),
+        ..Default::default()
+    })
+    .await;
+
+    let mut socket_1 = test_server.connect();
+
+    write_message!(
+        socket_1,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Hello { .. } => ());
+
+    //
+    // Test connecting while saturated.
+    //
+
+    match connect(test_server.url("/")) {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status())
+        }
+        result => panic!("expected service unavailable: {:?}", result.err()),
+    }
+
+    //
+    // Test connecting after the connection closes.
+    //
+
+    close_socket!(socket_1);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut socket_2 = test_server.connect();
+
+    write_message!(
+        socket_2,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Hello { .. } => ());
+
+    close_socket!(socket_2);
+}
+
+///
+/// Test connections which never create or join a room are closed after the unjoined timeout.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn unjoined_timeout() {
+    let test_server = TestServer::start_with_config(Config {
+        unjoined_timeout: 1,
+        ..Default::default()
+    })
+    .await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Open an idle connection, a connection which only says hello, and two connections in a room.
+    //
+
+    let mut idle_socket = create_socket!(socket_addr);
+    let mut hello_socket = create_socket!(socket_addr);
+    let mut socket = create_socket!(socket_addr);
+    let mut socket_2 = create_socket!(socket_addr);
+
+    write_message!(
+        hello_socket,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(hello_socket, ResponsePacket::Hello { .. } => ());
+
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+    read_message!(socket, ResponsePacket::Join { .. } => ());
+
+    write_message!(socket_2, RequestPacket::Leave);
+    read_message!(socket_2, ResponsePacket::Left => ());
+    read_message!(socket, ResponsePacket::Leave { .. } => ());
+
+    //
+    // Test the unjoined connections are closed once the timeout passes.
+    //
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    for socket in [&mut idle_socket, &mut hello_socket] {
+        read_message!(socket, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::UnjoinedTimeout)));
+
+        match socket.read().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(CloseCode::Library(4002), frame.code),
+            unknown => panic!("unexpected message: {:?}", unknown),
+        }
+    }
+
+    //
+    // Test connections which joined a room stay open, even after leaving it.
+    //
+
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+
+    for socket in [&mut socket, &mut socket_2] {
+        write_message!(
+            socket,
+            RequestPacket::Hello {
+                max_message_size: None,
+                chunk_size: None,
+            }
+        );
+        read_message!(socket, ResponsePacket::Hello { .. } => ());
+    }
+
+    close_socket!(socket);
+    close_socket!(socket_2);
+}
+
+///
+/// Test connections which take too long to send a message once it started are closed, without affecting other connections.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_messages() {
+    let test_server = TestServer::start_with_config(Config {
+        message_timeout: 500,
+        ..Default::default()
+    })
+    .await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Setup a room with two connections, and a hello packet framed by hand with a zero mask.
+    //
+
+    let mut socket = create_socket!(socket_addr);
+    let mut socket_2 = create_socket!(socket_addr);
+
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+    read_message!(socket, ResponsePacket::Join { .. } => ());
+
+    let payload = br#"{"type":"hello"}"#;
+    let mut frame = vec![0x81, 0x80 | payload.len() as u8, 0, 0, 0, 0];
+    frame.extend_from_slice(payload);
+
+    //
+    // Test a message which arrives one byte at a time within the timeout is handled.
+    //
+
+    let mut slow_socket = create_socket!(socket_addr);
+    let MaybeTlsStream::Plain(stream) = slow_socket.get_mut() else {
+        unreachable!();
+    };
+    stream.set_nodelay(true).unwrap();
+
+    for byte in &frame {
+        stream.write_all(&[*byte]).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    read_message!(slow_socket, ResponsePacket::Hello { .. } => ());
+
+    //
+    // Test a message which arrives one byte at a time past the timeout closes the connection,
+    // while the room keeps relaying.
+    //
+
+    let MaybeTlsStream::Plain(stream) = slow_socket.get_mut() else {
+        unreachable!();
+    };
+
+    for (index, byte) in frame.iter().enumerate() {
+        let _ = stream.write_all(&[*byte]);
+        std::thread::sleep(Duration::from_millis(100));
+
+        write_binary_message!(socket_2, vec![0, index as u8]);
+        assert_eq!(vec![1, index as u8], read_binary_message!(socket));
+    }
+
+    match slow_socket.read().unwrap() {
+        Message::Close(Some(frame)) => {
+            assert_eq!(CloseCode::Library(4003), frame.code);
+            assert_eq!("TimedOut", frame.reason);
+        }
+        unknown => panic!("unexpected message: {:?}", unknown),
+    }
+
+    assert!(slow_socket.read().is_err());
+    assert_eq!(1, test_server.server.read().await.slow_messages());
+    #[cfg(feature = "metrics")]
+    assert!(test_server
+        .server
+        .read()
+        .await
+        .render_metrics()
+        .contains("relay_slow_messages_total 1\n"));
+
+    close_socket!(socket);
+    close_socket!(socket_2);
+}
+
+///
+/// Test rooms which only accept joins from their own list of origins.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn room_origins() {
+    let connect_from = |socket_addr: SocketAddr, origin: Option<&'static str>| {
+        let mut request = format!("ws://{}", socket_addr)
+            .into_client_request()
+            .unwrap();
+
+        if let Some(origin) = origin {
+            request
+                .headers_mut()
+                .insert("Origin", HeaderValue::from_static(origin));
+        }
+
+        connect(request).map(|(socket, _)| socket).ok()
+    };
+
+    let create_room = |socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+                       allowed_origins: &[&str]| {
+        write_message!(
+            socket,
+            RequestPacket::Create {
+                size: Some(8),
+                options: CreateOptions {
+                    allowed_origins: Some(
+                        allowed_origins
+                            .iter()
+                            .map(|host| host.to_string())
+                            .collect()
+                    ),
+
+                    ..Default::default()
+                },
+                virtual_address: None,
+                template: None,
+            }
+        );
+
+        read_message!(socket, ResponsePacket::Create { id, .. } => id)
+    };
+
+    let join_room = |socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, room_id: &str| {
+        write_message!(
+            socket,
+            RequestPacket::Join {
+                id: room_id.to_string(),
+                token: None,
+                reservation: None,
+                virtual_address: None,
+            }
+        );
+
+        match serde_json::from_str(&socket.read().unwrap().into_text().unwrap()).unwrap() {
+            ResponsePacket::Join { .. } => Ok(()),
+            ResponsePacket::Error { message, .. } => Err(message),
+            unknown => panic!("pattern doesn't match: {:?}", unknown),
+        }
+    };
+
+    //
+    // Test the room list is checked on top of the origin check of the server.
+    //
+
+    let test_server = TestServer::start_with_config(Config {
+        auth: AuthConfig {
+            host: String::from("example.com"),
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    let socket_addr = test_server.addr;
+
+    assert!(connect_from(socket_addr, Some("https://partner.com")).is_none());
+
+    let mut socket = connect_from(socket_addr, Some("https://example.com")).unwrap();
+    let room_id = create_room(&mut socket, &["Play.Example.com"]);
+
+    for (origin, expected) in [
+        ("https://play.example.com", true),
+        ("https://eu.play.example.com:8443", true),
+        ("https://www.example.com", false),
+        ("https://example.com", false),
+    ] {
+        let mut socket = connect_from(socket_addr, Some(origin)).unwrap();
+
+        match join_room(&mut socket, &room_id) {
+            Ok(()) => assert!(expected, "{} joined", origin),
+            Err(message) => {
+                assert!(!expected, "{} was rejected", origin);
+                assert!(matches!(message, Error::OriginNotAllowed));
+            }
+        }
+
+        close_socket!(socket);
+    }
+
+    close_socket!(socket);
+
+    //
+    // Test a room can admit origins of its own when the server does not check origins.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    let mut socket = connect_from(socket_addr, None).unwrap();
+    let room_id = create_room(&mut socket, &["partner.com"]);
+    let mut open_socket = connect_from(socket_addr, None).unwrap();
+    let open_room_id = create_room(&mut open_socket, &[]);
+
+    for (origin, expected) in [
+        (Some("https://partner.com"), true),
+        (Some("https://other.com"), false),
+        (Some("null"), false),
+        (None, false),
+    ] {
+        let mut socket = connect_from(socket_addr, origin).unwrap();
+
+        assert_eq!(
+            expected,
+            join_room(&mut socket, &room_id).is_ok(),
+            "{:?}",
+            origin
+        );
+        if !expected {
+            join_room(&mut socket, &open_room_id).unwrap();
+        }
+
+        close_socket!(socket);
+    }
+
+    //
+    // Test the list is capped in count and length.
+    //
+
+    close_socket!(socket);
+
+    let mut socket = connect_from(socket_addr, None).unwrap();
+    let too_many: Vec<String> = (0..=Room::MAX_ALLOWED_ORIGINS)
+        .map(|index| format!("{}.example.com", index))
+        .collect();
+
+    for allowed_origins in [
+        too_many,
+        vec!["a".repeat(Room::MAX_ORIGIN_LENGTH + 1)],
+        vec![String::new()],
+    ] {
+        write_message!(
+            socket,
+            RequestPacket::Create {
+                size: None,
+                options: CreateOptions {
+                    allowed_origins: Some(allowed_origins),
+
+                    ..Default::default()
+                },
+                virtual_address: None,
+                template: None,
+            }
+        );
+        read_message!(socket, ResponsePacket::Error { message: Error::InvalidOptions, detail } => assert_eq!(Some("allowedOrigins"), detail.as_deref()));
+    }
+
+    let allowed_origins: Vec<&str> = vec!["example.com"; Room::MAX_ALLOWED_ORIGINS];
+    create_room(&mut socket, &allowed_origins);
+
+    close_socket!(socket);
+    close_socket!(open_socket);
+}
+
+///
+/// Test stalled handshakes are limited separately from established connections.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn max_handshakes() {
+    //
+    // Setup test.
+    //
+
+    const MAX_HANDSHAKES: usize = 8;
+
+    let test_server = TestServer::start_with_config(Config {
+        max_connections: Some(2),
+        max_handshakes: MAX_HANDSHAKES,
+        ..Default::default()
+    })
+    .await;
+    let server = test_server.server.clone();
+
+    let mut socket_1 = test_server.connect();
+
+    write_message!(
+        socket_1,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Hello { .. } => ());
+
+    //
+    // Test connections which never complete the handshake are counted until the limit.
+    //
+
+    let stalled: Vec<_> = (0..MAX_HANDSHAKES)
+        .map(|_| TcpStream::connect(test_server.addr).unwrap())
+        .collect();
+
+    while server.read().await.handshakes() < MAX_HANDSHAKES {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert_eq!(1, server.read().await.connections());
+
+    match connect(test_server.url("/")) {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status())
+        }
+        result => panic!("expected service unavailable: {:?}", result.err()),
+    }
+
+    //
+    // Test established connections are unaffected by the stalled handshakes.
+    //
+
+    write_message!(
+        socket_1,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Hello { .. } => ());
+
+    //
+    // Test the stalled handshakes are released once they disconnect.
+    //
+
+    drop(stalled);
+
+    while server.read().await.handshakes() > 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut socket_2 = test_server.connect();
+
+    write_message!(
+        socket_2,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Hello { .. } => ());
+
+    assert_eq!(2, server.read().await.connections());
+    assert_eq!(0, server.read().await.handshakes());
+
+    //
+    // Test the established connection limit still applies after the handshake.
+    //
+
+    match connect(test_server.url("/")) {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status())
+        }
+        result => panic!("expected service unavailable: {:?}", result.err()),
+    }
+
+    assert_eq!(2, server.read().await.connections());
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+
+    while server.read().await.connections() > 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+///
+/// Test the handshake path routes.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn routes() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+
+    //
+    // Test unknown paths and rooms are not found.
+    //
+
+    for path in [
+        "/unknown",
+        "/room",
+        "/room/",
+        "/room/unknown",
+        "/create/unknown",
+    ] {
+        match connect(test_server.url(path)) {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(StatusCode::NOT_FOUND, response.status())
+            }
+            _ => panic!("expected not found for {}", path),
+        }
+    }
+
+    //
+    // Test the create path creates a room.
+    //
+
+    let mut socket_1 = test_server.connect_to("/create");
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test the room path joins the room.
+    //
+
+    let mut socket_2 = test_server.connect_to(&format!("/room/{}", room_id));
+
+    read_message!(socket_1, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+    read_message!(socket_2, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+
+    write_binary_message!(socket_2, vec![0, 1, 2, 3]);
+    assert_eq!(vec![1, 1, 2, 3], read_binary_message!(socket_1));
+
+    //
+    // Test the room path errors when the room is full.
+    //
+
+    let mut socket_3 = test_server.connect_to(&format!("/room/{}", room_id));
+    read_message!(socket_3, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    //
+    // Test the root path does not join a room.
+    //
+
+    let mut socket_4 = test_server.connect();
+
+    write_message!(socket_4, RequestPacket::Leave);
+    read_message!(socket_4, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+    close_socket!(socket_3);
+    close_socket!(socket_4);
+}
+
+///
+/// Test the responses to packets from future versions of the protocol are deliberate.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn future_packets() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+    let mut socket = create_socket!(socket_addr);
+
+    let fixtures: serde_json::Value =
+        serde_json::from_str(include_str!("../fixtures/future_packets.json")).unwrap();
+
+    //
+    // Test each fixture, where a null response means the request is ignored.
+    //
+
+    for fixture in fixtures.as_array().unwrap() {
+        let request = serde_json::to_string(&fixture["request"]).unwrap();
+        socket.send(Message::Text(request)).unwrap();
+
+        let expected_response = if fixture["response"].is_null() {
+            write_message!(
+                socket,
+                RequestPacket::Hello {
+                    max_message_size: None,
+                    chunk_size: None,
+                }
+            );
+
+            &serde_json::json!({ "type": "hello" })
+        } else {
+            &fixture["response"]
+        };
+
+        let response: serde_json::Value =
+            serde_json::from_str(socket.read().unwrap().to_text().unwrap()).unwrap();
+
+        for (key, value) in expected_response.as_object().unwrap() {
+            assert_eq!(value, &response[key], "{}", fixture);
+        }
+    }
+
+    close_socket!(socket);
+}
+
+///
+/// Test all possible error responses (excluding the UUID collision).
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn errors() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Test creating an invalid sized room.
+    //
+
+    let mut socket = create_socket!(socket_addr);
+
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: Some(0),
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidSize)));
+
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: Some(255),
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidSize)));
+
+    //
+    // Test creating a valid room.
+    //
+
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+
+    let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test joining an non-existent room.
+    //
+
+    let mut socket_2 = create_socket!(socket_addr);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: String::new(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::DoesNotExist)));
+
+    //
+    // Test joining the room.
+    //
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+
+    read_message!(socket_2, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+    read_message!(socket, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+    //
+    // Test joining a full room.
+    //
+
+    let mut socket_3 = create_socket!(socket_addr);
+
+    write_message!(
+        socket_3,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_3, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    //
+    // Test joining a removed room.
+    //
+
+    close_socket!(socket);
+    close_socket!(socket_2);
+
+    write_message!(
+        socket_3,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_3, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::DoesNotExist)));
+
+    //
+    // Test creating a single-occupant room.
+    //
+
+    write_message!(
+        socket_3,
+        RequestPacket::Create {
+            size: Some(1),
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+
+    let room_id = read_message!(socket_3, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test joining a single-occupant room.
+    //
+
+    let mut socket_4 = create_socket!(socket_addr);
+
+    write_message!(
+        socket_4,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_4, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    close_socket!(socket_3);
+    close_socket!(socket_4);
+}
+
+///
+/// Test every leave request produces either a leave packet or an error.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn leave() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    let mut socket = create_socket!(socket_addr);
+    let mut socket_2 = create_socket!(socket_addr);
+
+    //
+    // Test leaving while not inside a room.
+    //
+
+    write_message!(socket, RequestPacket::Leave);
+    read_message!(socket, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
+
+    //
+    // Test leaving a room twice.
+    //
+
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+
+    let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+    read_message!(socket, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+    write_message!(socket_2, RequestPacket::Leave);
+    read_message!(socket, ResponsePacket::Leave { index, .. } => assert_eq!(1, index));
+    read_message!(socket_2, ResponsePacket::Left => ());
+
+    write_message!(socket_2, RequestPacket::Leave);
+    read_message!(socket_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
+
+    close_socket!(socket);
+    close_socket!(socket_2);
+}
+
+///
+/// Test broadcasts skip congested clients, while unicasts are still queued.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn congestion() {
+    //
+    // The number and size of frames to broadcast, and the congestion threshold of the room.
+    //
+
+    const FRAMES: usize = 2048;
+    const FRAME_SIZE: usize = 16 * 1024;
+    const CONGESTION_THRESHOLD: usize = 1 << 20;
+
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Create a room with a sender, a fast client, and a slow client that never reads.
+    //
+
+    let mut socket = create_socket!(socket_addr);
+    let mut socket_fast = create_socket!(socket_addr);
+    let mut socket_slow = create_socket!(socket_addr);
+
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: Some(3),
+            options: CreateOptions {
+                congestion_threshold: Some(CONGESTION_THRESHOLD),
+
+                ..Default::default()
+            },
+            virtual_address: None,
+            template: None,
+        }
+    );
+
+    let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_fast,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_fast, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+    read_message!(socket, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+    write_message!(
+        socket_slow,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_slow, ResponsePacket::Join { size, .. } => assert_eq!(Some(2), size));
+    read_message!(socket, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+    read_message!(socket_fast, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+    //
+    // Test broadcast latency to the fast client stays flat.
+    //
+
+    let mut data = vec![0; FRAME_SIZE];
+    data[0] = u8::MAX;
+
+    let mut max_latency = Duration::ZERO;
+    for _ in 0..FRAMES {
+        let start = Instant::now();
+
+        write_binary_message!(socket, data.clone());
+        assert_eq!(FRAME_SIZE, read_binary_message!(socket_fast).len());
+
+        max_latency = max_latency.max(start.elapsed());
+    }
+
+    assert!(max_latency < Duration::from_millis(500));
+
+    //
+    // Test the slow client was skipped, and that unicasts are not skipped.
+    //
+
+    macro_rules! read_stats {
+        ($value:expr) => {
+            loop {
+                match serde_json::from_str($value.read().unwrap().to_text().unwrap()).unwrap() {
+                    ResponsePacket::Stats { members, .. } => break members,
+                    ResponsePacket::Dropped { .. } => continue,
+                    unknown => panic!("pattern doesn't match: {:?}", unknown),
+                }
+            }
+        };
+    }
+
+    write_message!(socket, RequestPacket::Stats);
+
+    let members = read_stats!(socket);
+    assert_eq!(3, members.len());
+    assert_eq!(0, members[1].congestion_skips);
+    assert!(members[2].congestion_skips > 0);
+    assert!(members[2].queued_bytes > CONGESTION_THRESHOLD);
+
+    let congestion_skips = members[2].congestion_skips;
+
+    data[0] = 2;
+    write_binary_message!(socket, data.clone());
+
+    write_message!(socket, RequestPacket::Stats);
+    assert_eq!(congestion_skips, read_stats!(socket)[2].congestion_skips);
+
+    //
+    // Close all sockets.
+    //
+
+    close_socket!(socket);
+    close_socket!(socket_fast);
+    close_socket!(socket_slow);
+}
+
+///
+/// Test the bandwidth cap of a room drops frames without affecting other rooms.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn bandwidth() {
+    //
+    // The number and size of frames to send, and the bandwidth cap of the room.
+    //
+
+    const FRAMES: usize = 10;
+    const FRAME_SIZE: usize = 40_000;
+    const MAX_BANDWIDTH: u64 = 100_000;
+
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Create a capped room and an uncapped room, with two clients each.
+    //
+
+    let mut sockets = vec![];
+
+    for max_bandwidth in [Some(MAX_BANDWIDTH), None] {
+        let mut socket = create_socket!(socket_addr);
+        let mut socket_2 = create_socket!(socket_addr);
+
+        write_message!(
+            socket,
+            RequestPacket::Create {
+                size: None,
+                options: CreateOptions {
+                    max_bandwidth,
+
+                    ..Default::default()
+                },
+                virtual_address: None,
+                template: None,
+            }
+        );
+
+        let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+        write_message!(
+            socket_2,
+            RequestPacket::Join {
+                id: room_id,
+                token: None,
+                reservation: None,
+                virtual_address: None,
+            }
+        );
+        read_message!(socket_2, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+        read_message!(socket, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+        sockets.push((socket, socket_2));
+    }
+
+    //
+    // Test exceeding the cap in the first room.
+    //
+
+    let mut data = vec![0; FRAME_SIZE];
+    data[0] = u8::MAX;
+
+    for (socket, _) in &mut sockets {
+        for _ in 0..FRAMES {
+            write_binary_message!(socket, data.clone());
+        }
+
+        write_message!(socket, RequestPacket::Stats);
+    }
+
+    let (socket, socket_2) = &mut sockets[0];
+
+    read_message!(socket, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::RateLimited)));
+    read_message!(socket, ResponsePacket::Stats { rate_limited_frames, .. } => assert_eq!(8, rate_limited_frames));
+
+    write_message!(socket_2, RequestPacket::Stats);
+
+    for _ in 0..2 {
+        assert_eq!(FRAME_SIZE, read_binary_message!(socket_2).len());
+    }
+
+    read_message!(socket_2, ResponsePacket::Stats { rate_limited_frames, .. } => assert_eq!(8, rate_limited_frames));
+
+    //
+    // Test the second room is unaffected.
+    //
+
+    let (socket, socket_2) = &mut sockets[1];
+
+    read_message!(socket, ResponsePacket::Stats { rate_limited_frames, .. } => assert_eq!(0, rate_limited_frames));
+
+    for _ in 0..FRAMES {
+        assert_eq!(FRAME_SIZE, read_binary_message!(socket_2).len());
+    }
+
+    //
+    // Close all sockets.
+    //
+
+    for (mut socket, mut socket_2) in sockets {
+        close_socket!(socket);
+        close_socket!(socket_2);
+    }
+}
+
+///
+/// Reads the lines of an audit log, waiting until the expected number of lines are written.
+///
+async fn read_audit_log(path: &Path, expected_lines: usize) -> Vec<serde_json::Value> {
+    for _ in 0..100 {
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        if lines.len() >= expected_lines {
+            return lines;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    panic!("audit log has less than {} lines", expected_lines);
+}
+
+///
+/// Test the audit log records the lifecycle of a room, and is reopened on reload.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn audit() {
+    //
+    // Setup test.
+    //
+
+    let path = std::env::temp_dir().join(format!("relay-audit-{}.log", uuid::Uuid::new_v4()));
+    let rotated_path = path.with_extension("log.1");
+
+    let test_server = TestServer::start_with_config(Config {
+        audit_log: Some(path.clone()),
+        ..Default::default()
+    })
+    .await;
+    let socket_addr = test_server.addr;
+    let server = test_server.server.clone();
+
+    //
+    // Test a session with a create, join, leave, and close.
+    //
+
+    let mut socket = create_socket!(socket_addr);
+    let mut socket_2 = create_socket!(socket_addr);
+
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+
+    let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    let connection_id =
+        read_message!(socket, ResponsePacket::Hello { connection_id, .. } => connection_id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    let connection_id_2 = read_message!(socket_2, ResponsePacket::Join { connection_id, .. } => connection_id.unwrap());
+    read_message!(socket, ResponsePacket::Join { connection_id, .. } => assert_eq!(None, connection_id));
+
+    assert_ne!(connection_id, connection_id_2);
+    assert_eq!(
+        vec![connection_id, connection_id_2],
+        server
+            .read()
+            .await
+            .inspect_room(&room_id)
+            .unwrap()
+            .members
+            .iter()
+            .map(|member| member.connection_id)
+            .collect::<Vec<_>>()
+    );
+
+    write_message!(socket, RequestPacket::Leave);
+    read_message!(socket, ResponsePacket::Left => ());
+    read_message!(socket_2, ResponsePacket::Leave { index, .. } => assert_eq!(0, index));
+
+    close_socket!(socket_2);
+
+    let lines = read_audit_log(&path, 4).await;
+    assert_eq!(4, lines.len());
+
+    for (line, (event, index, connection_id, reason)) in lines.iter().zip([
+        ("create", 0, connection_id, None),
+        ("join", 1, connection_id_2, None),
+        ("leave", 0, connection_id, Some("Left")),
+        ("leave", 0, connection_id_2, Some("Disconnected")),
+    ]) {
+        let entry = line.as_object().unwrap();
+
+        let mut keys: Vec<&String> = entry.keys().collect();
+        keys.sort();
+
+        let mut expected_keys = vec![
+            "connection_id",
+            "event",
+            "index",
+            "ip",
+            "room_id",
+            "timestamp",
+        ];
+        if reason.is_some() {
+            expected_keys.push("reason");
+            expected_keys.sort();
+        }
+
+        assert_eq!(expected_keys, keys);
+        assert_eq!(
+            reason,
+            entry.get("reason").and_then(|reason| reason.as_str())
+        );
+        assert_eq!(event, entry["event"]);
+        assert_eq!(connection_id, entry["connection_id"]);
+        assert_eq!(index, entry["index"]);
+        assert_eq!(room_id.as_str(), entry["room_id"]);
+        assert_eq!("127.0.0.1", entry["ip"]);
+        assert!(entry["timestamp"].is_u64());
+    }
+
+    //
+    // Test the audit log is reopened after being rotated.
+    //
+
+    fs::rename(&path, &rotated_path).unwrap();
+    server.write().await.reload();
+
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Create { .. } => ());
+
+    let lines = read_audit_log(&path, 1).await;
+    assert_eq!("create", lines[0]["event"]);
+    assert_eq!(4, read_audit_log(&rotated_path, 4).await.len());
+
+    close_socket!(socket);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&rotated_path).unwrap();
+}
+
+#[cfg(feature = "mqtt")]
+type MqttMessage = (String, Vec<u8>, bool);
+
+///
+/// An MQTT client which records every message published to it, and stalls while paused.
+///
+#[cfg(feature = "mqtt")]
+#[derive(Clone, Default)]
+struct MqttRecorder {
+    messages: Arc<Mutex<Vec<MqttMessage>>>,
+    paused: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "mqtt")]
+impl MqttClient for MqttRecorder {
+    fn publish<'a>(
+        &'a mut self,
+        topic: &'a str,
+        payload: &'a [u8],
+        retain: bool,
+    ) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            while self.paused.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            self.messages
+                .lock()
+                .unwrap()
+                .push((topic.to_string(), payload.to_vec(), retain));
+
+            Ok(())
+        })
+    }
+}
+
+///
+/// Test room lifecycle events and retained presence are published to MQTT, and never block when the broker stalls.
+///
+#[cfg(feature = "mqtt")]
+#[tokio::test]
+async fn unit_mqtt() {
+    let server = Server::new(Config::default()).unwrap();
+    let mqtt_recorder = MqttRecorder::default();
+    server
+        .write()
+        .await
+        .set_mqtt_publisher(MqttPublisher::start(mqtt_recorder.clone(), true));
+
+    let (mut client, recorder) = create_client();
+    let (mut client_2, _) = create_client();
+
+    let take_messages = || async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        mqtt_recorder
+            .messages
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|(topic, payload, retain)| {
+                let payload = (!payload.is_empty())
+                    .then(|| serde_json::from_slice::<serde_json::Value>(&payload).unwrap());
+
+                (topic, payload, retain)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    //
+    // Test creating, joining, leaving and closing a room.
+    //
+
+    client
+        .handle_create_room(&server, Some(4), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_2.handle_leave_room(&server).await;
+    client.handle_close_room(&server, None).await;
+
+    let events_topic = format!("relay/rooms/{}/events", room_id);
+    let presence_topic = format!("relay/rooms/{}/presence", room_id);
+    let messages = take_messages().await;
+    assert_eq!(8, messages.len());
+
+    for (event_messages, (event, index, presence)) in messages.chunks(2).zip([
+        ("create", 0, Some(json!({ "occupancy": 1, "size": 4 }))),
+        ("join", 1, Some(json!({ "occupancy": 2, "size": 4 }))),
+        ("leave", 1, Some(json!({ "occupancy": 1, "size": 4 }))),
+        ("close", 0, None),
+    ]) {
+        let (topic, payload, retain) = &event_messages[0];
+        let payload = payload.as_ref().unwrap();
+
+        assert_eq!(&events_topic, topic);
+        assert_eq!(event, payload["event"]);
+        assert_eq!(room_id, payload["room_id"]);
+        assert_eq!(index, payload["index"]);
+        assert!(!retain);
+
+        assert_eq!(
+            &(presence_topic.clone(), presence, true),
+            &event_messages[1]
+        );
+    }
+
+    //
+    // Test presence is not published unless it is enabled.
+    //
+
+    MqttPublisher::start(mqtt_recorder.clone(), false).presence(&room_id, None);
+    assert!(take_messages().await.is_empty());
+
+    //
+    // Test events are dropped and counted instead of queued without bound while the broker stalls.
+    //
+
+    mqtt_recorder.paused.store(true, Ordering::Relaxed);
+
+    let mqtt = MqttPublisher::start(mqtt_recorder.clone(), true);
+    mqtt.presence(&room_id, None);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    for _ in 0..MqttPublisher::QUEUE_CAPACITY + 2 {
+        mqtt.presence(&room_id, None);
+    }
+
+    assert_eq!(2, mqtt.dropped());
+
+    mqtt_recorder.paused.store(false, Ordering::Relaxed);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        MqttPublisher::QUEUE_CAPACITY + 1,
+        take_messages().await.len()
+    );
+}
+
+///
+/// Test the broker client connects with its credentials, and publishes with the MQTT wire format.
+///
+#[cfg(feature = "mqtt")]
+#[tokio::test]
+async fn unit_mqtt_broker() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut broker = Broker::new(MqttConfig {
+        url: format!("mqtt://{}", listener.local_addr().unwrap()),
+        username: Some(String::from("user")),
+        password: Some(String::from("pass")),
+        presence: true,
+    });
+
+    //
+    // Test URLs are validated, and the default port is added.
+    //
+
+    assert_eq!(
+        Ok(String::from("broker:1883")),
+        Broker::parse_url("mqtt://broker")
+    );
+    assert_eq!(
+        Ok(String::from("[::1]:1883")),
+        Broker::parse_url("mqtt://[::1]")
+    );
+    assert_eq!(
+        Ok(String::from("broker:8883")),
+        Broker::parse_url("mqtt://broker:8883")
+    );
+    assert!(Broker::parse_url("tcp://broker").is_err());
+    assert!(Broker::parse_url("mqtt://broker:port").is_err());
+    assert!(Broker::parse_url("mqtt://broker/topic").is_err());
+
+    //
+    // Test the connect packet, followed by a retained publish.
+    //
+
+    let accept = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut connect = [0; 2];
+        stream.read_exact(&mut connect).await.unwrap();
+        let mut body = vec![0; usize::from(connect[1])];
+        stream.read_exact(&mut body).await.unwrap();
+
+        stream.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+
+        let mut publish = vec![0; 12];
+        stream.read_exact(&mut publish).await.unwrap();
+
+        (connect[0], body, publish)
+    });
+
+    broker.publish("a/b", b"hello", true).await.unwrap();
+
+    let (header, body, publish) = accept.await.unwrap();
+    assert_eq!(0x10, header);
+    assert_eq!(b"\0\x04MQTT\x04\xc2\0\0\0\x26relay-", &body[..18]);
+    assert_eq!(b"\0\x04user\0\x04pass", &body[body.len() - 12..]);
+    assert_eq!(b"\x31\x0a\0\x03a/bhello", &publish[..]);
+
+    //
+    // Test a refused connection is reported.
+    //
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut broker = Broker::new(MqttConfig {
+        url: format!("mqtt://{}", listener.local_addr().unwrap()),
+        ..Default::default()
+    });
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut connect = [0; 2];
+        stream.read_exact(&mut connect).await.unwrap();
+        let mut body = vec![0; usize::from(connect[1])];
+        stream.read_exact(&mut body).await.unwrap();
+
+        stream.write_all(&[0x20, 2, 0, 5]).await.unwrap();
+    });
+
+    let error = broker.publish("a/b", b"hello", false).await.unwrap_err();
+    assert_eq!(std::io::ErrorKind::ConnectionRefused, error.kind());
+}
+
+///
+/// Test the deny list rejects connections and rooms, and is swapped on reload without dropping connections.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn deny_list() {
+    //
+    // Setup test.
+    //
+
+    let path = std::env::temp_dir().join(format!("relay-deny-{}.txt", uuid::Uuid::new_v4()));
+    fs::write(&path, "10.0.0.0/8\n").unwrap();
+
+    let test_server = TestServer::start_with_config(Config {
+        deny_list: Some(path.clone()),
+        ..Default::default()
+    })
+    .await;
+    let server = test_server.server.clone();
+
+    let mut socket_1 = test_server.connect();
+    let mut socket_2 = test_server.connect();
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test reloading a list which denies the room and the loopback network.
+    //
+
+    fs::write(&path, format!("# Abuse.\n127.0.0.0/8\n{}\n", room_id)).unwrap();
+    server.write().await.reload();
+
+    match connect(test_server.url("/")) {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(StatusCode::FORBIDDEN, response.status())
+        }
+        result => panic!("expected forbidden: {:?}", result.err()),
+    }
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::Denied)));
+
+    //
+    // Test existing connections are not dropped.
+    //
+
+    write_message!(
+        socket_1,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Hello { .. } => ());
+
+    //
+    // Test reloading an invalid list keeps the previous list.
+    //
+
+    fs::write(&path, "127.0.0.0/33\n").unwrap();
+    server.write().await.reload();
+
+    assert!(connect(test_server.url("/")).is_err());
+
+    //
+    // Test reloading an empty list allows connections again.
+    //
+
+    fs::write(&path, "").unwrap();
+    server.write().await.reload();
+
+    let mut socket_3 = test_server.connect();
+
+    write_message!(
+        socket_3,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_3, ResponsePacket::Join { .. } => ());
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+    close_socket!(socket_3);
+
+    fs::remove_file(&path).unwrap();
+}
+
+///
+/// Test sending to the owner of the room, including after the owner leaves.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn owner() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Create a room with three clients, where the first client is the owner.
+    //
+
+    let mut sockets: Vec<WebSocket<MaybeTlsStream<TcpStream>>> = vec![];
+    let mut room_id = String::new();
+
+    for expected_size in 0..3 {
+        let mut socket = create_socket!(socket_addr);
+
+        if expected_size == 0 {
+            write_message!(
+                socket,
+                RequestPacket::Create {
+                    size: Some(3),
+                    options: CreateOptions::default(),
+                    template: None,
+                    virtual_address: None,
+                }
+            );
+            read_message!(socket, ResponsePacket::Create { id, .. } => room_id = id);
+        } else {
+            write_message!(
+                socket,
+                RequestPacket::Join {
+                    id: room_id.clone(),
+                    token: None,
+                    reservation: None,
+                    virtual_address: None,
+                }
+            );
+            read_message!(socket, ResponsePacket::Join { size, .. } => assert_eq!(Some(expected_size), size));
+
+            for socket in &mut sockets {
+                read_message!(socket, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+            }
+        }
+
+        sockets.push(socket);
+    }
+
+    //
+    // Test sending to the owner.
+    //
+
+    write_binary_message!(sockets[2], vec![Room::OWNER, 1, 2, 3]);
+    assert_eq!(vec![2, 1, 2, 3], read_binary_message!(sockets[0]));
+
+    //
+    // Test the owner only receives its own packets when sending to its index.
+    //
+
+    write_binary_message!(sockets[0], vec![Room::OWNER, 7, 8, 9]);
+    write_binary_message!(sockets[0], vec![0, 4, 5, 6]);
+    assert_eq!(vec![0, 4, 5, 6], read_binary_message!(sockets[0]));
+
+    //
+    // Test sending to the new owner after the owner leaves.
+    //
+
+    let mut socket = sockets.remove(0);
+
+    write_message!(socket, RequestPacket::Leave);
+    read_message!(socket, ResponsePacket::Left => ());
+
+    for socket in &mut sockets {
+        read_message!(socket, ResponsePacket::Leave { index, .. } => assert_eq!(0, index));
+    }
+
+    write_binary_message!(sockets[1], vec![Room::OWNER, 1, 2, 3]);
+    assert_eq!(vec![1, 1, 2, 3], read_binary_message!(sockets[0]));
+
+    //
+    // Close all sockets.
+    //
+
+    close_socket!(socket);
+
+    for mut socket in sockets {
+        close_socket!(socket);
+    }
+}
+
+///
+/// Test the owner of a room can lock and unlock the room to new clients.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn lock() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+
+    let mut socket_1 = test_server.connect();
+    let mut socket_2 = test_server.connect();
+    let mut socket_3 = test_server.connect();
+
+    //
+    // Test locking while not inside a room.
+    //
+
+    write_message!(socket_1, RequestPacket::Lock);
+    read_message!(socket_1, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: Some(3),
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test locking as a client which is not the owner.
+    //
+
+    write_message!(socket_2, RequestPacket::Lock);
+    read_message!(socket_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotOwner)));
+
+    //
+    // Test joining while the room is locked.
+    //
+
+    write_message!(socket_1, RequestPacket::Lock);
+    read_message!(socket_1, ResponsePacket::Locked { locked } => assert!(locked));
+    read_message!(socket_2, ResponsePacket::Locked { locked } => assert!(locked));
+
+    write_message!(
+        socket_3,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_3, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsLocked)));
+
+    //
+    // Test locking an already locked room only notifies the owner.
+    //
+
+    write_message!(socket_1, RequestPacket::Lock);
+    read_message!(socket_1, ResponsePacket::Locked { locked } => assert!(locked));
+
+    //
+    // Test joining after the room is unlocked.
+    //
+
+    write_message!(socket_1, RequestPacket::Unlock);
+    read_message!(socket_1, ResponsePacket::Locked { locked } => assert!(!locked));
+    read_message!(socket_2, ResponsePacket::Locked { locked } => assert!(!locked));
+
+    write_message!(
+        socket_3,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_3, ResponsePacket::Join { size, .. } => assert_eq!(Some(2), size));
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+    close_socket!(socket_3);
+}
+
+///
+/// Test relayed frames are stamped with the time since the room was created when the room opts in.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn timestamps() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+
+    let mut socket_1 = test_server.connect();
+    let mut socket_2 = test_server.connect();
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions {
+                timestamps: Some(true),
+
+                ..Default::default()
+            },
+            virtual_address: None,
+            template: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test the timestamp follows the source byte and is monotonic.
+    //
+
+    let mut previous_timestamp = 0;
+
+    for payload in [vec![], vec![1], vec![1, 2, 3]] {
+        let mut data = vec![0];
+        data.extend(&payload);
+
+        write_binary_message!(socket_2, data);
+
+        let data = read_binary_message!(socket_1);
+        assert_eq!(1 + 8 + payload.len(), data.len());
+        assert_eq!(1, data[0]);
+        assert_eq!(payload, data[9..]);
+
+        let timestamp = u64::from_be_bytes(data[1..9].try_into().unwrap());
+        assert!(timestamp > previous_timestamp);
+
+        previous_timestamp = timestamp;
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+}
+
+///
+/// Test compressed rooms deliver large frames compressed only to clients which declared the lz4 capability.
+///
+#[cfg(feature = "compression")]
+#[tokio::test(flavor = "multi_thread")]
+async fn compression() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+
+    let mut socket_1 = test_server.connect();
+    let mut socket_2 = test_server.connect_to("/?capabilities=lz4");
+    let mut socket_3 = test_server.connect();
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: Some(3),
+            options: CreateOptions {
+                compression: Some(Compression::Lz4),
+
+                ..Default::default()
+            },
+            virtual_address: None,
+            template: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, options, .. } => {
+        assert_eq!(Some(Compression::Lz4), options.compression);
+
+        id
+    });
+
+    for socket in [&mut socket_2, &mut socket_3] {
+        write_message!(
+            socket,
+            RequestPacket::Join {
+                id: room_id.clone(),
+                token: None,
+                reservation: None,
+                virtual_address: None,
+            }
+        );
+        read_message!(socket, ResponsePacket::Join { .. } => ());
+    }
+
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test a large compressible frame is compressed once for the lz4 client, and unchanged for the other.
+    //
+
+    let payload: Vec<u8> = (0..200 << 10)
+        .map(|index: usize| b"relay frame "[index % 12] ^ (index / 4096) as u8)
+        .collect();
+
+    let mut data = vec![Room::BROADCAST];
+    data.extend(&payload);
+    write_binary_message!(socket_1, data);
+
+    let data = read_binary_message!(socket_2);
+    assert!(data.len() < payload.len() / 4);
+    assert_eq!(0, data[0]);
+    assert_eq!(Room::LZ4, data[1]);
+
+    let length = u32::from_be_bytes(data[2..6].try_into().unwrap()) as usize;
+    assert_eq!(payload.len(), length);
+    assert_eq!(Some(payload.clone()), lz4::decompress(&data[6..], length));
+
+    let data = read_binary_message!(socket_3);
+    assert_eq!(0, data[0]);
+    assert_eq!(payload, data[1..]);
+
+    //
+    // Test a frame below the threshold is flagged as uncompressed for the lz4 client.
+    //
+
+    write_binary_message!(socket_1, vec![Room::BROADCAST, 1, 2, 3]);
+    assert_eq!(
+        vec![0, Room::UNCOMPRESSED, 1, 2, 3],
+        read_binary_message!(socket_2)
+    );
+    assert_eq!(vec![0, 1, 2, 3], read_binary_message!(socket_3));
+
+    //
+    // Test a frame sent by the lz4 client to the plain client is unchanged.
+    //
+
+    let mut data = vec![2];
+    data.extend(&payload);
+    write_binary_message!(socket_2, data);
+
+    let data = read_binary_message!(socket_3);
+    assert_eq!(1, data[0]);
+    assert_eq!(payload, data[1..]);
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+    close_socket!(socket_3);
+}
+
+///
+/// Test concurrent sets to an open store are delivered to every client in the same order.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn store() {
+    const SETS: usize = 100;
+
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+
+    let mut socket_1 = test_server.connect();
+    let mut socket_2 = test_server.connect();
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions {
+                open_store: Some(true),
+
+                ..Default::default()
+            },
+            virtual_address: None,
+            template: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test both clients observe the same order of sets.
+    //
+
+    for value in 0..SETS {
+        write_message!(
+            socket_1,
+            RequestPacket::Set {
+                key: String::from("score"),
+                value: json!(value)
+            }
+        );
+        write_message!(
+            socket_2,
+            RequestPacket::Set {
+                key: String::from("score"),
+                value: json!(-(value as i64))
+            }
+        );
+    }
+
+    let mut orders = vec![];
+
+    for socket in [&mut socket_1, &mut socket_2] {
+        let mut order = vec![];
+
+        for _ in 0..SETS * 2 {
+            read_message!(socket, ResponsePacket::Set { value, source, .. } => order.push((value, source)));
+        }
+
+        orders.push(order);
+    }
+
+    assert_eq!(orders[0], orders[1]);
+
+    write_message!(
+        socket_1,
+        RequestPacket::Get {
+            key: String::from("score")
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Get { value, .. } => assert_eq!(orders[0].last().unwrap().0, value));
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+}
+
+///
+/// Test observed addresses are only shared with room members when the room opts in.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn addresses() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    for share_addresses in [false, true] {
+        let mut socket_1 = create_socket!(socket_addr);
+        let mut socket_2 = create_socket!(socket_addr);
+
+        let MaybeTlsStream::Plain(stream_1) = socket_1.get_ref() else {
+            unreachable!();
+        };
+        let address_1 = stream_1.local_addr().unwrap();
+
+        let MaybeTlsStream::Plain(stream_2) = socket_2.get_ref() else {
+            unreachable!();
+        };
+        let address_2 = stream_2.local_addr().unwrap();
+
+        //
+        // Test each client can discover its own address.
+        //
+
+        write_message!(socket_1, RequestPacket::MyAddress);
+        read_message!(socket_1, ResponsePacket::MyAddress { address } => assert_eq!(address_1, address));
+
+        write_message!(socket_2, RequestPacket::MyAddress);
+        read_message!(socket_2, ResponsePacket::MyAddress { address } => assert_eq!(address_2, address));
+
+        //
+        // Test the join notifications include addresses only when sharing is enabled.
+        //
+
+        write_message!(
+            socket_1,
+            RequestPacket::Create {
+                size: None,
+                options: CreateOptions {
+                    share_addresses: Some(share_addresses),
+
+                    ..Default::default()
+                },
+                virtual_address: None,
+                template: None,
+            }
+        );
+        let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+        write_message!(
+            socket_2,
+            RequestPacket::Join {
+                id: room_id,
+                token: None,
+                reservation: None,
+                virtual_address: None,
+            }
+        );
+
+        read_message!(socket_1, ResponsePacket::Join { size, address, addresses, .. } => {
+            assert_eq!(None, size);
+            assert_eq!(share_addresses.then_some(address_2), address);
+            assert_eq!(None, addresses);
+        });
+
+        read_message!(socket_2, ResponsePacket::Join { size, address, addresses, .. } => {
+            assert_eq!(Some(1), size);
+            assert_eq!(None, address);
+            assert_eq!(share_addresses.then(|| vec![address_1, address_2]), addresses);
+        });
+
+        close_socket!(socket_1);
+        close_socket!(socket_2);
+    }
+}
+
+///
+/// Test rooms are closed once their time to live has elapsed.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn expiry() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+    let server = test_server.server.clone();
+
+    let mut socket_1 = create_socket!(socket_addr);
+    let mut socket_2 = create_socket!(socket_addr);
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions {
+                ttl_seconds: Some(1),
+
+                ..Default::default()
+            },
+            virtual_address: None,
+            template: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test each member receives a close packet and a close frame once the room expires.
+    //
+
+    let created_at = Instant::now();
+
+    for socket in [&mut socket_1, &mut socket_2] {
+        read_message!(socket, ResponsePacket::Close { reason, .. } => assert!(matches!(reason, CloseReason::Expired)));
+        assert!(socket.read().unwrap().is_close());
+    }
+
+    assert!(created_at.elapsed() >= Duration::from_millis(900));
+    assert!(!server.read().await.rooms.contains_key(&room_id));
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+}
+
+///
+/// Test a manual clock expires reservations and rooms as soon as it is advanced, and a seeded generator repeats its room identifiers.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn manual_clock() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+    let server = test_server.server.clone();
+
+    let clock = ManualClock::install(&server).await;
+    SeededRng::install(&server, 7).await;
+
+    let mut socket_1 = create_socket!(socket_addr);
+    let mut socket_2 = create_socket!(socket_addr);
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions {
+                ttl_seconds: Some(3600),
+                ..Default::default()
+            },
+            virtual_address: None,
+            template: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test another server with the same seed generates the same room identifier.
+    //
+
+    let other_server = Server::new(Config::default()).unwrap();
+    SeededRng::install(&other_server, 7).await;
+
+    let (mut client, recorder) = create_client();
+    client
+        .handle_create_room(&other_server, None, RoomOptions::default(), None)
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => assert_eq!(room_id, id));
+
+    //
+    // Test the reservation expires once the clock passes its time to live.
+    //
+
+    write_message!(
+        socket_1,
+        RequestPacket::Reserve {
+            id: room_id.clone(),
+            count: 1,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Reserve { .. } => ());
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    clock.advance(Room::RESERVATION_TTL);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test the room expires as soon as the clock passes its time to live.
+    //
+
+    let advanced_at = Instant::now();
+    clock.advance(Duration::from_secs(3600));
+
+    for socket in [&mut socket_1, &mut socket_2] {
+        read_message!(socket, ResponsePacket::Close { reason, .. } => assert!(matches!(reason, CloseReason::Expired)));
+
+        match socket.read().unwrap() {
+            Message::Close(Some(frame)) => {
+                assert_eq!(CloseCode::Library(4004), frame.code);
+                assert_eq!("Expired", frame.reason);
+            }
+            unknown => panic!("unexpected message: {:?}", unknown),
+        }
+    }
+
+    assert!(advanced_at.elapsed() < Duration::from_secs(1));
+    assert!(!server.read().await.rooms.contains_key(&room_id));
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+}
+
+///
+/// Test the owner can close the room for every member.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn close_room() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+    let server = test_server.server.clone();
+
+    let mut socket_1 = create_socket!(socket_addr);
+    let mut socket_2 = create_socket!(socket_addr);
+    let mut socket_3 = create_socket!(socket_addr);
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: Some(3),
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+
+    write_message!(
+        socket_3,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+    read_message!(socket_3, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test every member receives a close packet followed by a close frame.
+    //
+
+    write_message!(
+        socket_1,
+        RequestPacket::CloseRoom {
+            reason: Some("Game over".to_string())
+        }
+    );
+
+    for socket in [&mut socket_1, &mut socket_2, &mut socket_3] {
+        read_message!(socket, ResponsePacket::Close { reason, message } => {
+            assert!(matches!(reason, CloseReason::Closed));
+            assert_eq!(Some("Game over".to_string()), message);
+        });
+
+        match socket.read().unwrap() {
+            Message::Close(Some(frame)) => {
+                assert_eq!(CloseCode::Library(4001), frame.code);
+                assert_eq!("Closed", frame.reason);
+            }
+            unknown => panic!("unexpected message: {:?}", unknown),
+        }
+    }
+
+    assert!(!server.read().await.rooms.contains_key(&room_id));
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+    close_socket!(socket_3);
+}
+
+///
+/// Test whether messages are leaking between two different rooms.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn leaks() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Create four sockets, two sockets per room.
+    //
+
+    let mut socket_room1 = create_socket!(socket_addr);
+    let mut socket_second_room1 = create_socket!(socket_addr);
+
+    let mut socket_room2 = create_socket!(socket_addr);
+    let mut socket_second_room2 = create_socket!(socket_addr);
+
+    //
+    // Test creating two rooms.
+    //
+
+    write_message!(
+        socket_room1,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+    write_message!(
+        socket_room2,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
+        }
+    );
+
+    let room_1_id = read_message!(socket_room1, ResponsePacket::Create { id, .. } => id);
+    let room_2_id = read_message!(socket_room2, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test joining room 1.
+    //
+
+    write_message!(
+        socket_second_room1,
+        RequestPacket::Join {
+            id: room_1_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+
+    read_message!(socket_second_room1, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+    read_message!(socket_room1, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+    //
+    // Test joining room 2.
+    //
+
+    write_message!(
+        socket_second_room2,
+        RequestPacket::Join {
+            id: room_2_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+
+    read_message!(socket_second_room2, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+    read_message!(socket_room2, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+    //
+    // Test broadcasting.
+    //
+
+    write_binary_message!(socket_room1, vec![u8::MAX, 0, 1, 2, 3]);
+    assert_eq!(
+        vec![0, 0, 1, 2, 3],
+        read_binary_message!(socket_second_room1)
+    );
+
+    write_binary_message!(socket_room2, vec![u8::MAX, 4, 5, 6, 7]);
+    assert_eq!(
+        vec![0, 4, 5, 6, 7],
+        read_binary_message!(socket_second_room2)
+    );
+
+    write_binary_message!(socket_second_room1, vec![u8::MAX, 0, 1, 2, 3]);
+    assert_eq!(vec![1, 0, 1, 2, 3], read_binary_message!(socket_room1));
+
+    write_binary_message!(socket_second_room2, vec![u8::MAX, 4, 5, 6, 7]);
+    assert_eq!(vec![1, 4, 5, 6, 7], read_binary_message!(socket_room2));
+
+    //
+    // Test sending to each socket.
+    //
+
+    write_binary_message!(socket_room1, vec![1, 0, 1, 2, 3]);
+    assert_eq!(
+        vec![0, 0, 1, 2, 3],
+        read_binary_message!(socket_second_room1)
+    );
+
+    write_binary_message!(socket_room2, vec![1, 4, 5, 6, 7]);
+    assert_eq!(
+        vec![0, 4, 5, 6, 7],
+        read_binary_message!(socket_second_room2)
+    );
+
+    write_binary_message!(socket_second_room1, vec![0, 0, 1, 2, 3]);
+    assert_eq!(vec![1, 0, 1, 2, 3], read_binary_message!(socket_room1));
+
+    write_binary_message!(socket_second_room2, vec![0, 4, 5, 6, 7]);
+    assert_eq!(vec![1, 4, 5, 6, 7], read_binary_message!(socket_room2));
+
+    //
+    // Test sending to oneself.
+    //
+
+    write_binary_message!(socket_room1, vec![0, 0, 1, 2, 3]);
+    assert_eq!(vec![0, 0, 1, 2, 3], read_binary_message!(socket_room1));
+
+    write_binary_message!(socket_room2, vec![0, 4, 5, 6, 7]);
+    assert_eq!(vec![0, 4, 5, 6, 7], read_binary_message!(socket_room2));
+
+    write_binary_message!(socket_second_room1, vec![1, 0, 1, 2, 3]);
+    assert_eq!(
+        vec![1, 0, 1, 2, 3],
+        read_binary_message!(socket_second_room1)
+    );
+
+    write_binary_message!(socket_second_room2, vec![1, 4, 5, 6, 7]);
+    assert_eq!(
+        vec![1, 4, 5, 6, 7],
+        read_binary_message!(socket_second_room2)
+    );
+
+    //
+    // Close all sockets
+    //
+
+    close_socket!(socket_room1);
+    close_socket!(socket_room1);
+    close_socket!(socket_second_room1);
+    close_socket!(socket_second_room2);
+}
+
+///
+/// Test sending data between clients in the room (includes broadcast messages and empty messages).
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn communication() {
+    //
+    // The maximum number of clients to test.
+    //
+
+    const N: u8 = u8::MAX - 2;
+
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Create N clients, the first client creates a room, the rest join the room.
+    //
+
+    let mut sockets = vec![];
+    let mut room_id = String::new();
+
+    for expected_size in 0..usize::from(N) {
+        let mut socket = create_socket!(socket_addr);
+
+        if expected_size == 0 {
+            write_message!(
+                socket,
+                RequestPacket::Create {
+                    size: Some(N.into()),
+                    options: CreateOptions::default(),
+                    template: None,
+                    virtual_address: None,
+                }
+            );
+            read_message!(socket, ResponsePacket::Create { id, .. } => room_id = id);
+
+            sockets.push(socket);
+        } else {
+            write_message!(
+                socket,
+                RequestPacket::Join {
+                    id: room_id.clone(),
+                    token: None,
+                    reservation: None,
+                    virtual_address: None,
+                }
+            );
+            sockets.push(socket);
+
+            let size = sockets.len() - 1;
+            for (index, socket) in sockets.iter_mut().enumerate() {
+                if index == size {
+                    read_message!(socket, ResponsePacket::Join { size, capacity, index, .. } => {
+                        assert_eq!(Some(expected_size), size);
+                        assert_eq!(Some(usize::from(N)), capacity);
+                        assert_eq!(Some(expected_size), index);
+                    });
+                } else {
+                    read_message!(socket, ResponsePacket::Join { size, capacity, index, .. } => {
+                        assert_eq!(None, size);
+                        assert_eq!(Some(usize::from(N)), capacity);
+                        assert_eq!(Some(expected_size), index);
+                    });
+                }
+            }
+        }
+    }
+
+    //
+    // Test room bounds.
+    //
+
+    let mut socket = create_socket!(socket_addr);
+
+    write_message!(
+        socket,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    close_socket!(socket);
+
+    //
+    // Test broadcasting.
+    //
+
+    for expected_source in 0..N {
+        let source_socket = &mut sockets[usize::from(expected_source)];
+
+        write_binary_message!(source_socket, vec![u8::MAX]);
+        write_binary_message!(source_socket, vec![u8::MAX, 0, 1, 2, 3]);
+
+        for expected_destination in 0..N {
+            if expected_destination == expected_source {
+                continue;
+            }
+
+            let destination_socket = &mut sockets[usize::from(expected_destination)];
+
+            assert_eq!(
+                vec![expected_source],
+                read_binary_message!(destination_socket)
+            );
+            assert_eq!(
+                vec![expected_source, 0, 1, 2, 3],
+                read_binary_message!(destination_socket)
+            );
+        }
+    }
+
+    //
+    // Test sending to each other.
+    //
+
+    for expected_source in 0..N {
+        for expected_destination in 0..N {
+            let source_socket = &mut sockets[usize::from(expected_source)];
+
+            write_binary_message!(source_socket, vec![expected_destination]);
+            write_binary_message!(source_socket, vec![expected_destination, 0, 1, 2, 3]);
+
+            let destination_socket = &mut sockets[usize::from(expected_destination)];
+
+            assert_eq!(
+                vec![expected_source],
+                read_binary_message!(destination_socket)
+            );
+            assert_eq!(
+                vec![expected_source, 0, 1, 2, 3],
+                read_binary_message!(destination_socket)
+            );
+        }
+    }
+
+    //
+    // Close host and N clients.
+    //
+
+    for _ in 0..N {
+        let mut socket = sockets.remove(0);
+        close_socket!(socket);
+
+        for socket in &mut sockets {
+            read_message!(socket, ResponsePacket::Leave { index, .. } => assert_eq!(0, index));
+        }
+    }
+
+    //
+    // Test if room was removed.
+    //
+
+    let mut socket = create_socket!(socket_addr);
+
+    write_message!(
+        socket,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::DoesNotExist)));
+
+    close_socket!(socket);
+}
+
+///
+/// Test the indices returned by the join and leave packets.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn indices() {
+    //
+    // The maximum number of clients to test.
+    //
+
+    const N: u8 = u8::MAX - 2;
+
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+
+    //
+    // Perform the test by either closing the connection or leaving the room.
+    //
+
+    for method in ["close", "leave"] {
+        for direction in ["first", "last"] {
+            //
+            // Create N clients, the first client creates a room, the rest join the room.
+            //
+
+            let mut sockets = vec![];
+            let mut room_id = String::new();
+
+            for expected_size in 0..usize::from(N) {
+                let mut socket = create_socket!(socket_addr);
+
+                if expected_size == 0 {
+                    write_message!(
+                        socket,
+                        RequestPacket::Create {
+                            size: Some(N.into()),
+                            options: CreateOptions::default(),
+                            template: None,
+                            virtual_address: None,
+                        }
+                    );
+                    read_message!(socket, ResponsePacket::Create { id, .. } => room_id = id);
+
+                    sockets.push(socket);
+                } else {
+                    write_message!(
+                        socket,
+                        RequestPacket::Join {
+                            id: room_id.clone(),
+                            token: None,
+                            reservation: None,
+                            virtual_address: None,
+                        }
+                    );
+                    sockets.push(socket);
+
+                    let size = sockets.len() - 1;
+                    for (index, socket) in sockets.iter_mut().enumerate() {
+                        if index == size {
+                            read_message!(socket, ResponsePacket::Join { size, .. } => assert_eq!(Some(expected_size), size));
+                        } else {
+                            read_message!(socket, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+                        }
+                    }
+                }
+            }
+
+            //
+            // Test leave room indices.
+            //
+
+            for mut expected_index in (0..usize::from(N)).rev() {
+                let mut socket = if direction == "last" {
+                    sockets.pop().unwrap()
+                } else {
+                    sockets.remove(0)
+                };
+
+                if direction == "first" {
+                    expected_index = 0;
+                }
+
+                if method == "leave" {
+                    write_message!(socket, RequestPacket::Leave);
+                } else if method == "close" {
+                    close_socket!(socket);
+                }
+
+                for socket in &mut sockets {
+                    read_message!(socket, ResponsePacket::Leave { index, .. } => assert_eq!(expected_index, index));
+                }
+
+                if method == "leave" {
+                    read_message!(socket, ResponsePacket::Left => ());
+                    close_socket!(socket);
+                }
+            }
+        }
+    }
+}
+
+///
+/// Test creating a room without a socket.
+///
+#[tokio::test]
+async fn unit_create_room() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+
+    //
+    // Test creating an invalid sized room.
+    //
+
+    client
+        .handle_create_room(&server, Some(0), RoomOptions::default(), None)
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidSize)));
+
+    client
+        .handle_create_room(
+            &server,
+            Some(Room::MAX_ROOM_SIZE),
+            RoomOptions::default(),
+            None,
+        )
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidSize)));
+
+    for size in [frame::MAX_ROOM_SIZE + 1, usize::from(frame::BROADCAST)] {
+        client
+            .handle_create_room(&server, Some(size), RoomOptions::default(), None)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Error { message: Error::InvalidSize, .. } => {});
+    }
+
+    assert!(server.read().await.rooms.is_empty());
+
+    //
+    // Test creating a room of the largest addressable size.
+    //
+
+    client
+        .handle_create_room(
+            &server,
+            Some(frame::MAX_ROOM_SIZE),
+            RoomOptions::default(),
+            None,
+        )
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Create { size, .. } => assert_eq!(frame::MAX_ROOM_SIZE, size));
+
+    client.handle_leave_room(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Left => {});
+
+    //
+    // Test creating a valid room.
+    //
+
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    assert_eq!(Some(&room_id), client.room_id.as_ref());
+    assert_eq!(1, server.read().await.rooms[&room_id].members.len());
+
+    //
+    // Test creating a room while already inside a room.
+    //
+
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+
+    assert!(recorder.is_empty());
+    assert_eq!(1, server.read().await.rooms.len());
+
+    //
+    // Test invalid requests are rejected without the lock, before checking whether the client is inside a room.
+    //
+
+    let locked_server = server.write().await;
+
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        client.handle_create_room(&server, Some(0), RoomOptions::default(), None),
+    )
+    .await
+    .unwrap();
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidSize)));
+
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        client.handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                opaque: true,
+                timestamps: true,
+                ..Default::default()
+            },
+            None,
+        ),
+    )
+    .await
+    .unwrap();
+    read_recorded_message!(recorder, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::InvalidOptions));
+        assert_eq!(Some(String::from("timestamps")), detail);
+    });
+
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        client.handle_create_room(&server, None, RoomOptions::default(), None),
+    )
+    .await
+    .unwrap();
+    assert!(recorder.is_empty());
+
+    drop(locked_server);
+
+    assert_eq!(1, server.read().await.rooms.len());
+}
+
+///
+/// Test base64 encodes the RFC 4648 vectors, round trips arbitrary data, and rejects malformed input.
+///
+#[test]
+fn unit_base64() {
+    for (input, encoded) in [
+        (&b""[..], ""),
+        (b"f", "Zg=="),
+        (b"fo", "Zm8="),
+        (b"foo", "Zm9v"),
+        (b"foob", "Zm9vYg=="),
+        (b"fooba", "Zm9vYmE="),
+        (b"foobar", "Zm9vYmFy"),
+    ] {
+        assert_eq!(encoded, base64::encode(input));
+        assert_eq!(Some(input.to_vec()), base64::decode(encoded));
+    }
+
+    let input: Vec<u8> = (0..=u8::MAX).collect();
+
+    for length in 0..input.len() {
+        assert_eq!(
+            Some(input[..length].to_vec()),
+            base64::decode(&base64::encode(&input[..length]))
+        );
+    }
+
+    for malformed in ["Zg", "Zg=", "Z===", "Zg==Zg==", "Zm9v!A==", "Zm=v"] {
+        assert_eq!(None, base64::decode(malformed));
+    }
+}
+
+///
+/// Test LZ4 blocks round trip, and malformed blocks are rejected.
+///
+#[cfg(feature = "compression")]
+#[test]
+fn unit_lz4() {
+    let random: Vec<u8> = (0..4096u32)
+        .map(|index| (index.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+
+    for input in [
+        vec![],
+        vec![7],
+        b"abcdabcdabcd".to_vec(),
+        b"abcdabcdabcdabcdabcdabcdabcdabcd".to_vec(),
+        vec![0; 100_000],
+        (0..100_000).map(|index| (index % 7) as u8).collect(),
+        random.clone(),
+        [random.as_slice(), &random, &random].concat(),
+    ] {
+        let compressed = lz4::compress(&input);
+
+        assert_eq!(
+            Some(input.clone()),
+            lz4::decompress(&compressed, input.len())
+        );
+    }
+
+    let compressed = lz4::compress(&vec![0; 100_000]);
+    assert!(compressed.len() < 1000);
+
+    assert_eq!(None, lz4::decompress(&compressed, 99_999));
+    assert_eq!(None, lz4::decompress(&compressed, 100_001));
+    assert_eq!(
+        None,
+        lz4::decompress(&compressed[..compressed.len() - 1], 100_000)
+    );
+    assert_eq!(None, lz4::decompress(&[], 0));
+    assert_eq!(None, lz4::decompress(&[0x10, 1, 0, 0], 5));
+}
+
+///
+/// Test compressed rooms are rejected when the server is built without compression.
+///
+#[cfg(not(feature = "compression"))]
+#[tokio::test]
+async fn unit_compression_disabled() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                compression: Some(Compression::Lz4),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+
+    read_recorded_message!(recorder, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::InvalidOptions));
+        assert_eq!(Some(String::from("compression")), detail);
+    });
+    assert!(server.read().await.rooms.is_empty());
+}
+
+///
+/// Test the v1 wire format byte-for-byte against a server with every newer feature disabled.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn wire_format_v1() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start_with_config(Config {
+        wire_format: WireFormat::V1,
+        ..Default::default()
+    })
+    .await;
+
+    let mut socket_1 = test_server.connect();
+    let mut socket_2 = test_server.connect();
+    let mut socket_3 = test_server.connect();
+
+    macro_rules! read_text {
+        ($value:expr) => {
+            $value.read().unwrap().into_text().unwrap()
+        };
+    }
+
+    //
+    // Test creating and joining a room.
+    //
+
+    socket_1
+        .send(Message::Text(String::from(r#"{"type":"create","size":2}"#)))
+        .unwrap();
+
+    let text = read_text!(socket_1);
+    let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+    let room_id = value["id"].as_str().unwrap();
+    assert_eq!(format!(r#"{{"type":"create","id":"{}"}}"#, room_id), text);
+
+    socket_2
+        .send(Message::Text(format!(
+            r#"{{"type":"join","id":"{}"}}"#,
+            room_id
+        )))
+        .unwrap();
+
+    assert_eq!(r#"{"type":"join","size":1}"#, read_text!(socket_2));
+    assert_eq!(r#"{"type":"join"}"#, read_text!(socket_1));
+
+    //
+    // Test the v1 errors.
+    //
+
+    socket_3
+        .send(Message::Text(format!(
+            r#"{{"type":"join","id":"{}"}}"#,
+            room_id
+        )))
+        .unwrap();
+    assert_eq!(
+        r#"{"type":"error","message":"IsFull"}"#,
+        read_text!(socket_3)
+    );
+
+    socket_3
+        .send(Message::Text(String::from(
+            r#"{"type":"join","id":"missing"}"#,
+        )))
+        .unwrap();
+    assert_eq!(
+        r#"{"type":"error","message":"DoesNotExist"}"#,
+        read_text!(socket_3)
+    );
+
+    //
+    // Test the binary framing rewrites the destination into the source.
+    //
+
+    write_binary_message!(socket_2, vec![0, 1, 2, 3]);
+    assert_eq!(vec![1, 1, 2, 3], read_binary_message!(socket_1));
+
+    write_binary_message!(socket_1, vec![1, 4, 5]);
+    assert_eq!(vec![0, 4, 5], read_binary_message!(socket_2));
+
+    write_binary_message!(socket_1, vec![Room::BROADCAST, 6]);
+    assert_eq!(vec![0, 6], read_binary_message!(socket_2));
+
+    write_binary_message!(socket_2, vec![Room::BROADCAST]);
+    assert_eq!(vec![1], read_binary_message!(socket_1));
+
+    //
+    // Test leaving sends the v1 leave packet, and no left packet to the client which left.
+    //
+
+    socket_2
+        .send(Message::Text(String::from(r#"{"type":"leave"}"#)))
+        .unwrap();
+    assert_eq!(r#"{"type":"leave","index":1}"#, read_text!(socket_1));
+
+    socket_2
+        .send(Message::Text(String::from(
+            r#"{"type":"join","id":"missing"}"#,
+        )))
+        .unwrap();
+    assert_eq!(
+        r#"{"type":"error","message":"DoesNotExist"}"#,
+        read_text!(socket_2)
+    );
+
+    //
+    // Test closing sends the v1 leave packet, and nothing else.
+    //
+
+    socket_3
+        .send(Message::Text(format!(
+            r#"{{"type":"join","id":"{}"}}"#,
+            room_id
+        )))
+        .unwrap();
+    assert_eq!(r#"{"type":"join","size":1}"#, read_text!(socket_3));
+    assert_eq!(r#"{"type":"join"}"#, read_text!(socket_1));
+
+    close_socket!(socket_3);
+    assert_eq!(r#"{"type":"leave","index":1}"#, read_text!(socket_1));
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+}
+
+///
+/// Test room notifications are encoded in the wire format of each recipient in a room of mixed formats.
+///
+#[tokio::test]
+async fn unit_mixed_wire_formats() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    client_2.wire_format = WireFormat::V1;
+
+    let texts = |recorder: &Recorder| -> Vec<String> {
+        recorder
+            .messages
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|message| message.into_text().unwrap())
+            .collect()
+    };
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test each member receives the join packets in its own format.
+    //
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    assert_eq!(vec![r#"{"type":"join","size":1}"#], texts(&recorder_2));
+    read_recorded_message!(recorder, ResponsePacket::Join { index: Some(1), capacity: Some(3), .. } => ());
+
+    client_3
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Join { index: Some(2), .. } => ());
+    read_recorded_message!(recorder, ResponsePacket::Join { index: Some(2), capacity: Some(3), .. } => ());
+    assert_eq!(vec![r#"{"type":"join"}"#], texts(&recorder_2));
+
+    //
+    // Test packets the v1 format does not support are only sent to the v2 members.
+    //
+
+    client.handle_lock_room(&server, true).await;
+    read_recorded_message!(recorder, ResponsePacket::Locked { locked } => assert!(locked));
+    read_recorded_message!(recorder_3, ResponsePacket::Locked { locked } => assert!(locked));
+
+    client
+        .handle_set(&server, String::from("map"), json!("dust"))
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Set { source, .. } => assert_eq!(0, source));
+    read_recorded_message!(recorder_3, ResponsePacket::Set { source, .. } => assert_eq!(0, source));
+
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test each member receives the leave packet in its own format.
+    //
+
+    client_3.handle_leave_room(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Leave { index, reason } => {
+        assert_eq!(2, index);
+        assert_eq!(Some(RemovalReason::Left), reason);
+    });
+    assert_eq!(vec![r#"{"type":"leave","index":2}"#], texts(&recorder_2));
+
+    assert!(recorder.is_empty());
+}
+
+///
+/// Test a client demanding the relay-v1 subprotocol gets it echoed and receives the v1 wire format.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn subprotocol() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+
+    let request = |protocols: Option<&str>| {
+        let mut request = test_server.url("/").into_client_request().unwrap();
+
+        if let Some(protocols) = protocols {
+            request
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", protocols.parse().unwrap());
+        }
+
+        request
+    };
+
+    //
+    // Test the header is omitted when no subprotocol is offered.
+    //
+
+    let (mut socket_1, response) = connect(request(None)).unwrap();
+    assert_eq!(None, response.headers().get("Sec-WebSocket-Protocol"));
+
+    //
+    // Test the selected subprotocol is echoed and selects the wire format.
+    //
+
+    let (mut socket_2, response) = connect(request(Some("relay-v1"))).unwrap();
+    assert_eq!(
+        "relay-v1",
+        response.headers().get("Sec-WebSocket-Protocol").unwrap()
+    );
+
+    socket_2
+        .send(Message::Text(String::from(r#"{"type":"create","size":2}"#)))
+        .unwrap();
+
+    let text = socket_2.read().unwrap().into_text().unwrap();
+    let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(
+        format!(
+            r#"{{"type":"create","id":"{}"}}"#,
+            value["id"].as_str().unwrap()
+        ),
+        text
+    );
+
+    write_message!(
+        socket_1,
+        RequestPacket::Hello {
+            max_message_size: None,
+            chunk_size: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Hello { version, .. } => assert_eq!(Server::VERSION, version));
+
+    //
+    // Test the first supported subprotocol in the client's order is selected.
+    //
+
+    let (mut socket_3, response) = connect(request(Some("mqtt, relay, relay-v1"))).unwrap();
+    assert_eq!(
+        "relay",
+        response.headers().get("Sec-WebSocket-Protocol").unwrap()
+    );
+
+    //
+    // Test offering only unsupported subprotocols fails the handshake.
+    //
+
+    match connect(request(Some("mqtt, msgpack"))) {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(StatusCode::BAD_REQUEST, response.status())
+        }
+        unknown => panic!("unexpected result: {:?}", unknown.map(|_| ())),
+    }
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+    close_socket!(socket_3);
+}
+
+///
+/// Test every response packet serializes to its golden JSON fixture, and the v1 wire format only contains the v1 fields.
+///
+#[test]
+fn unit_wire_format() {
+    let address: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let options = RoomOptions {
+        congestion_threshold: Some(1),
+        max_bandwidth: Some(2),
+        share_addresses: true,
+        ttl_seconds: Some(3),
+        timestamps: true,
+        open_store: true,
+        opaque: true,
+        frame_ttl_ms: Some(4),
+        roster: true,
+        conflate: true,
+        allowed_origins: vec![String::from("example.com")],
+        compression: Some(Compression::Lz4),
+        compression_threshold: Some(5),
+        virtual_addresses: true,
+        notify_members: false,
+        paced: true,
+        tick_rate: Some(6),
+        max_paced_bytes: Some(7),
+    };
+
+    let packets = [
+        ResponsePacket::Hello {
+            version: String::from("1.0.0"),
+            capabilities: vec![String::from("lock")],
+            max_room_size: 253,
+            max_message_size: 1024,
+            connection_id: 7,
+            chunk_size: Some(65536),
+        },
+        ResponsePacket::Join {
+            size: None,
+            capacity: None,
+            index: None,
+            address: None,
+            addresses: None,
+            connection_id: None,
+            virtual_address: None,
+            virtual_addresses: None,
+        },
+        ResponsePacket::Join {
+            size: Some(1),
+            capacity: Some(4),
+            index: Some(1),
+            address: Some(address),
+            addresses: Some(vec![address]),
+            connection_id: Some(7),
+            virtual_address: Some(String::from("p1")),
+            virtual_addresses: Some(vec![String::from("p1")]),
+        },
+        ResponsePacket::Create {
+            id: String::from("abc"),
+            size: 2,
+            options: RoomOptions::default(),
+        },
+        ResponsePacket::Create {
+            id: String::from("abc"),
+            size: 2,
+            options: options.clone(),
+        },
+        ResponsePacket::Leave {
+            index: 1,
+            reason: Some(RemovalReason::TimedOut),
+        },
+        ResponsePacket::Left,
+        ResponsePacket::Stats {
+            members: vec![MemberStatistics {
+                index: 0,
+                queued_bytes: 1,
+                congestion_skips: 2,
+                expired_frames: 3,
+                conflated_frames: 4,
+                invalid_packets: 5,
+                oversized_frames: 7,
+            }],
+            rate_limited_frames: 6,
+            expires_at: Some(1_700_000_000_000),
+        },
+        ResponsePacket::MyAddress { address },
+        ResponsePacket::Close {
+            reason: CloseReason::Expired,
+            message: None,
+        },
+        ResponsePacket::Close {
+            reason: CloseReason::Closed,
+            message: Some(String::from("bye")),
+        },
+        ResponsePacket::Migrated {
+            id: String::from("abc"),
+            index: 1,
+        },
+        ResponsePacket::Locked { locked: true },
+        ResponsePacket::Set {
+            key: String::from("k"),
+            value: json!(1),
+            source: 0,
+        },
+        ResponsePacket::Get {
+            key: String::from("k"),
+            value: serde_json::Value::Null,
+        },
+        ResponsePacket::Store {
+            entries: [(String::from("k"), json!([1]))].into(),
+        },
+        ResponsePacket::Roster {
+            members: vec![MemberSummary { index: 0, id: 3 }],
+            more: true,
+        },
+        ResponsePacket::Occupancy {
+            count: 1,
+            capacity: 4,
+        },
+        ResponsePacket::Subscription { sources: None },
+        ResponsePacket::Subscription {
+            sources: Some(vec![0, 2]),
+        },
+        ResponsePacket::Reserve {
+            id: String::from("abc"),
+            reservation: String::from("r"),
+            count: 2,
+            ttl_seconds: 30,
+        },
+        ResponsePacket::Capture { active: true },
+        ResponsePacket::Dropped {
+            count: 3,
+            reason: DropReason::ReceiverCongested,
+        },
+        ResponsePacket::Info {
+            exists: true,
+            occupancy: 1,
+            size: 2,
+            locked: false,
+            expires_at: Some(1_700_000_000_000),
+        },
+        ResponsePacket::QueueDepths {
+            depths: vec![0, 4096],
+        },
+        ResponsePacket::Quality {
+            members: vec![
+                MemberQuality {
+                    index: 0,
+                    report: Some(QualityReport {
+                        received: 10,
+                        lost: 1,
+                        jitter_ms: 5,
+                    }),
+                    reported_at: Some(1_700_000_000_000),
+                    send_failures: 2,
+                    dropped_frames: 3,
+                },
+                MemberQuality {
+                    index: 1,
+                    report: None,
+                    reported_at: None,
+                    send_failures: 0,
+                    dropped_frames: 0,
+                },
+            ],
+        },
+        ResponsePacket::Error {
+            message: Error::IsFull,
+            detail: None,
+        },
+        ResponsePacket::Error {
+            message: Error::RateLimited,
+            detail: Some(String::from("3")),
+        },
+    ];
+
+    //
+    // Test the golden JSON of every variant, which must be extended when a variant is added.
+    //
+
+    let goldens: Vec<_> = include_str!("../fixtures/response_packets.jsonl")
+        .lines()
+        .collect();
+    assert_eq!(packets.len(), goldens.len());
+
+    let mut types = std::collections::BTreeSet::new();
+
+    for (packet, golden) in packets.iter().zip(goldens) {
+        let packet_type = match packet {
+            ResponsePacket::Hello { .. } => "hello",
+            ResponsePacket::Join { .. } => "join",
+            ResponsePacket::Create { .. } => "create",
+            ResponsePacket::Leave { .. } => "leave",
+            ResponsePacket::Left => "left",
+            ResponsePacket::Stats { .. } => "stats",
+            ResponsePacket::MyAddress { .. } => "myAddress",
+            ResponsePacket::Close { .. } => "close",
+            ResponsePacket::Migrated { .. } => "migrated",
+            ResponsePacket::Locked { .. } => "locked",
+            ResponsePacket::Set { .. } => "set",
+            ResponsePacket::Get { .. } => "get",
+            ResponsePacket::Store { .. } => "store",
+            ResponsePacket::Roster { .. } => "roster",
+            ResponsePacket::Occupancy { .. } => "occupancy",
+            ResponsePacket::Subscription { .. } => "subscription",
+            ResponsePacket::Reserve { .. } => "reserve",
+            ResponsePacket::Capture { .. } => "capture",
+            ResponsePacket::Dropped { .. } => "dropped",
+            ResponsePacket::Info { .. } => "info",
+            ResponsePacket::QueueDepths { .. } => "queueDepths",
+            ResponsePacket::Quality { .. } => "quality",
+            ResponsePacket::Error { .. } => "error",
+        };
+
+        assert_eq!(golden, WireFormat::V2.encode(packet).unwrap());
+        assert_eq!(golden, serde_json::to_string(packet).unwrap());
+        assert!(golden.starts_with(&format!(r#"{{"type":"{}""#, packet_type)));
+        assert!(WireFormat::V2.supports(packet));
+        assert_camel_case(&serde_json::from_str(golden).unwrap());
+
+        types.insert(packet_type);
+    }
+
+    assert_eq!(23, types.len());
+
+    //
+    // Test the v1 wire format strips every field added since v1, and only supports the v1 packets.
+    //
+
+    let v1_packets = [
+        (&packets[1], r#"{"type":"join"}"#),
+        (&packets[2], r#"{"type":"join","size":1}"#),
+        (&packets[3], r#"{"type":"create","id":"abc"}"#),
+        (&packets[4], r#"{"type":"create","id":"abc"}"#),
+        (&packets[5], r#"{"type":"leave","index":1}"#),
+        (&packets[26], r#"{"type":"error","message":"IsFull"}"#),
+    ];
+
+    for (packet, golden) in v1_packets {
+        assert!(WireFormat::V1.supports(packet));
+        assert_eq!(golden, WireFormat::V1.encode(packet).unwrap());
+    }
+
+    for message in [
+        Error::InvalidSize,
+        Error::AlreadyExists,
+        Error::DoesNotExist,
+        Error::IsFull,
+    ] {
+        let packet = ResponsePacket::Error {
+            message,
+            detail: Some(String::from("detail")),
+        };
+
+        assert!(WireFormat::V1.supports(&packet));
+        assert!(!WireFormat::V1.encode(&packet).unwrap().contains("detail"));
+    }
+
+    let supported = packets
+        .iter()
+        .filter(|packet| WireFormat::V1.supports(packet))
+        .count();
+
+    assert_eq!(v1_packets.len(), supported);
+}
+
+///
+/// Test every request packet round trips through its golden JSON fixture, and the snake case spellings of its fields are still accepted.
+///
+#[test]
+fn unit_request_packets() {
+    let goldens: Vec<_> = include_str!("../fixtures/request_packets.jsonl")
+        .lines()
+        .collect();
+
+    //
+    // Test the golden JSON of every variant, which must be extended when a variant is added.
+    //
+
+    let mut types = std::collections::BTreeSet::new();
+
+    for golden in &goldens {
+        let packet: RequestPacket = serde_json::from_str(golden).unwrap();
+        let packet_type = match packet {
+            RequestPacket::Hello { .. } => "hello",
+            RequestPacket::Join { .. } => "join",
+            RequestPacket::Reserve { .. } => "reserve",
+            RequestPacket::Unreserve { .. } => "unreserve",
+            RequestPacket::Create { .. } => "create",
+            RequestPacket::Leave => "leave",
+            RequestPacket::Stats => "stats",
+            RequestPacket::MyAddress => "myAddress",
+            RequestPacket::Lock => "lock",
+            RequestPacket::Unlock => "unlock",
+            RequestPacket::Info { .. } => "info",
+            RequestPacket::Set { .. } => "set",
+            RequestPacket::Get { .. } => "get",
+            RequestPacket::CloseRoom { .. } => "closeRoom",
+            RequestPacket::Migrate { .. } => "migrate",
+            RequestPacket::Subscribe { .. } => "subscribe",
+            RequestPacket::Unsubscribe => "unsubscribe",
+            RequestPacket::WatchOccupancy { .. } => "watchOccupancy",
+            RequestPacket::QueueDepths => "queueDepths",
+            RequestPacket::QualityReport { .. } => "qualityReport",
+            RequestPacket::Quality => "quality",
+            RequestPacket::Unsupported => "unsupported",
+        };
+
+        assert_eq!(*golden, serde_json::to_string(&packet).unwrap());
+        assert!(golden.starts_with(&format!(r#"{{"type":"{}""#, packet_type)));
+        assert_camel_case(&serde_json::from_str(golden).unwrap());
+
+        types.insert(packet_type);
+    }
+
+    assert_eq!(22, types.len());
+
+    //
+    // Test the snake case spellings accepted before fields were renamed to camel case.
+    //
+
+    for legacy in include_str!("../fixtures/legacy_request_packets.jsonl").lines() {
+        let packet: RequestPacket = serde_json::from_str(legacy).unwrap();
+        let serialized_packet = serde_json::to_string(&packet).unwrap();
+
+        assert!(goldens.contains(&serialized_packet.as_str()), "{}", legacy);
+    }
+}
+
+///
+/// Test every field of the golden JSON fixtures is declared by the TypeScript definitions.
+///
+#[test]
+fn unit_typescript_fixtures() {
+    for (fixtures, packets) in [
+        (
+            include_str!("../fixtures/request_packets.jsonl"),
+            typescript::REQUEST_PACKETS,
+        ),
+        (
+            include_str!("../fixtures/response_packets.jsonl"),
+            typescript::RESPONSE_PACKETS,
+        ),
+    ] {
+        for fixture in fixtures.lines() {
+            let fixture: serde_json::Value = serde_json::from_str(fixture).unwrap();
+            let tag = fixture["type"].as_str().unwrap();
+
+            if tag == "unsupported" {
+                continue;
+            }
+
+            let packet = packets
+                .iter()
+                .find(|packet| packet.tag == tag)
+                .unwrap_or_else(|| panic!("{} is not declared", tag));
+
+            for field in fixture.as_object().unwrap().keys() {
+                let declared = field == "type"
+                    || packet.field(field).is_some()
+                    || (packet.extends == Some("RoomOptions")
+                        && typescript::ROOM_OPTIONS
+                            .iter()
+                            .any(|(option, _)| option.trim_end_matches('?') == field));
+
+                assert!(declared, "{}.{} is not declared", tag, field);
+            }
+        }
+    }
+
+    //
+    // Test every room option is declared.
+    //
+
+    let options = serde_json::to_value(RoomOptions {
+        congestion_threshold: Some(1),
+        max_bandwidth: Some(1),
+        share_addresses: true,
+        ttl_seconds: Some(1),
+        timestamps: true,
+        open_store: true,
+        opaque: true,
+        frame_ttl_ms: Some(1),
+        roster: true,
+        conflate: true,
+        allowed_origins: vec![String::from("example.com")],
+        compression: Some(Compression::Lz4),
+        compression_threshold: Some(1),
+        virtual_addresses: true,
+        notify_members: false,
+        paced: true,
+        tick_rate: Some(1),
+        max_paced_bytes: Some(1),
+    })
+    .unwrap();
+
+    let declared: Vec<_> = typescript::ROOM_OPTIONS
+        .iter()
+        .map(|(option, _)| option.trim_end_matches('?'))
+        .collect();
+    let serialized: Vec<_> = options
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+
+    assert_eq!(declared.len(), serialized.len());
+    assert!(serialized.iter().all(|option| declared.contains(option)));
+}
+
+///
+/// Test rooms are closed once they reach the maximum duration of the server, regardless of activity.
+///
+#[tokio::test]
+async fn unit_max_room_duration() {
+    macro_rules! wait_for {
+        ($condition:expr) => {
+            while !$condition {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+    }
+
+    let server = Server::new(Config {
+        max_room_duration: 2,
+        ..Default::default()
+    })
+    .unwrap();
+    let clock = ManualClock::install(&server).await;
+
+    let (mut client, recorder) = create_client();
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                ttl_seconds: Some(3600),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, options, .. } => {
+        assert_eq!(Some(3600), options.ttl_seconds);
+        id
+    });
+
+    let (mut client_2, recorder_2) = create_client();
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test the deadline is advertised in the stats and info packets.
+    //
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    client.handle_stats(&server).await;
+    let expires_at = read_recorded_message!(recorder, ResponsePacket::Stats { expires_at: Some(expires_at), .. } => expires_at);
+    assert!((now + 2000..now + 2100).contains(&expires_at));
+
+    client_2.handle_info(&server, room_id.clone()).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Info { expires_at: Some(info_expires_at), .. } => {
+        assert!(info_expires_at.abs_diff(expires_at) < 100);
+    });
+
+    //
+    // Test the room relays until the deadline, and is then closed mid-traffic.
+    //
+
+    for _ in 0..3 {
+        clock.advance(Duration::from_millis(500));
+
+        client_2
+            .handle_message(&server, Message::Binary(vec![0, 7]))
+            .await;
+        assert_eq!(vec![1, 7], read_recorded_binary_message!(recorder));
+    }
+
+    clock.advance(Duration::from_millis(500));
+
+    for recorder in [&recorder, &recorder_2] {
+        wait_for!(recorder.messages.lock().unwrap().len() == 2);
+
+        read_recorded_message!(recorder, ResponsePacket::Close { reason, .. } => assert!(matches!(reason, CloseReason::MaxDuration)));
+        assert!(recorder.messages.lock().unwrap().remove(0).is_close());
+    }
+
+    assert!(!server.read().await.rooms.contains_key(&room_id));
+
+    //
+    // Test the maximum duration can be disabled.
+    //
+
+    let server = Server::new(Config {
+        max_room_duration: 0,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let (mut client, recorder) = create_client();
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Create { .. } => ());
+
+    client.handle_stats(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Stats { expires_at, .. } => assert_eq!(None, expires_at));
+}
+
+///
+/// Test the remaining members are told why a client was removed from the room.
+///
+#[tokio::test]
+async fn unit_removal_reasons() {
+    let server = Server::new(Config::default()).unwrap();
+
+    let (mut owner, owner_recorder) = create_client();
+    owner
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(owner_recorder, ResponsePacket::Create { id, .. } => id);
+
+    for (reason, close_frame) in [
+        (RemovalReason::Left, None),
+        (RemovalReason::Disconnected, None),
+        (RemovalReason::TimedOut, Some((4003, "TimedOut"))),
+    ] {
+        let (mut client, recorder) = create_client();
+        client
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+        read_recorded_message!(owner_recorder, ResponsePacket::Join { .. } => ());
+
+        //
+        // Test the reason is included in the leave packet.
+        //
+
+        if reason == RemovalReason::Left {
+            client.handle_leave_room(&server).await;
+            read_recorded_message!(recorder, ResponsePacket::Left => ());
+        } else {
+            client.handle_close(&server, reason).await;
+        }
+
+        read_recorded_message!(owner_recorder, ResponsePacket::Leave { index, reason: leave_reason } => {
+            assert_eq!(1, index);
+            assert_eq!(Some(reason), leave_reason);
+        });
+
+        //
+        // Test the removed client receives a close frame with the reason, when it has a close code.
+        //
+
+        let messages: Vec<_> = recorder.messages.lock().unwrap().drain(..).collect();
+        match (close_frame, messages.as_slice()) {
+            (None, []) => {}
+            (Some((code, text)), [Message::Close(Some(frame))]) => {
+                assert_eq!(CloseCode::Library(code), frame.code);
+                assert_eq!(text, frame.reason);
+            }
+            (_, unknown) => panic!("unexpected messages: {:?}", unknown),
+        }
+    }
+
+    assert!(owner_recorder.is_empty());
+}
+
+///
+/// Test room creation is limited per IP address.
+///
+#[tokio::test]
+async fn unit_create_cooldown() {
+    let server = Server::new(Config {
+        max_creates_per_minute: Some(3),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let (mut client, recorder) = create_client();
+
+    //
+    // Test creating rooms from one connection until the limit trips.
+    //
+
+    for _ in 0..3 {
+        client
+            .handle_create_room(&server, None, RoomOptions::default(), None)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Create { .. } => {});
+
+        client.handle_leave_room(&server).await;
+        read_recorded_message!(recorder, ResponsePacket::Left => {});
+    }
+
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    let retry_after = read_recorded_message!(recorder, ResponsePacket::Error { message: Error::RateLimited, detail: Some(detail) } => detail);
+    assert!((1..=20).contains(&retry_after.parse::<u64>().unwrap()));
+    assert!(server.read().await.rooms.is_empty());
+
+    //
+    // Test another connection from the same IP address shares the limit.
+    //
+
+    let (mut client_2, recorder_2) = create_client();
+
+    client_2
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message: Error::RateLimited, .. } => {});
+
+    //
+    // Test a different IP address is unaffected.
+    //
+
+    let recorder_3 = Arc::new(Recorder::default());
+    let mut client_3 = Client::new(
+        next_connection_id(),
+        recorder_3.clone(),
+        "127.0.0.2:0".parse().unwrap(),
+        vec![],
+    );
+
+    client_3
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Create { .. } => {});
+
+    //
+    // Test creating rooms is unlimited by default.
+    //
+
+    let server = Server::new(Config::default()).unwrap();
+
+    for _ in 0..10 {
+        client
+            .handle_create_room(&server, None, RoomOptions::default(), None)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Create { .. } => {});
+
+        client.handle_leave_room(&server).await;
+        read_recorded_message!(recorder, ResponsePacket::Left => {});
+    }
+}
+
+///
+/// Test room creation is limited per connection over its lifetime.
+///
+#[tokio::test]
+async fn unit_max_rooms_per_connection() {
+    let server = Server::new(Config {
+        max_rooms_per_connection: 3,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let (mut client, recorder) = create_client();
+
+    //
+    // Test failed creations are not counted.
+    //
+
+    for _ in 0..5 {
+        client
+            .handle_create_room(&server, Some(0), RoomOptions::default(), None)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Error { message: Error::InvalidSize, .. } => {});
+    }
+
+    //
+    // Test creating and leaving rooms until the limit trips.
+    //
+
+    for _ in 0..3 {
+        client
+            .handle_create_room(&server, None, RoomOptions::default(), None)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Create { .. } => {});
+
+        client.handle_leave_room(&server).await;
+        read_recorded_message!(recorder, ResponsePacket::Left => {});
+    }
+
+    for _ in 0..2 {
+        client
+            .handle_create_room(&server, None, RoomOptions::default(), None)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Error { message: Error::LimitExceeded, detail: None } => {});
+    }
+
+    assert!(server.read().await.rooms.is_empty());
+
+    //
+    // Test another connection from the same IP address has its own limit.
+    //
+
+    let (mut client_2, recorder_2) = create_client();
+
+    client_2
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Create { .. } => {});
+
+    //
+    // Test the default limit.
+    //
+
+    assert_eq!(100, Config::default().max_rooms_per_connection);
+}
+///
+/// Test the per-IP limiter refills, expires idle entries, and stays bounded.
+///
+#[test]
+fn unit_ip_limiter() {
+    let mut limiter = IpLimiter::new(2, Duration::from_secs(60), 2);
+    let now = Instant::now();
+    let ip = |last: u8| IpAddr::from([192, 0, 2, last]);
+
+    assert!(limiter.take(ip(1), now).is_ok());
+    assert!(limiter.take(ip(1), now).is_ok());
+    let retry_after = limiter.take(ip(1), now).unwrap_err();
+    assert_eq!(30, retry_after.as_secs_f64().round() as u64);
+    assert!(limiter.take(ip(1), now + Duration::from_secs(30)).is_ok());
+
+    //
+    // Test idle entries are expired before busy ones when the map is full.
+    //
+
+    let now = now + Duration::from_secs(30);
+
+    assert!(limiter.take(ip(2), now).is_ok());
+    assert_eq!(2, limiter.len());
+
+    assert!(limiter.take(ip(3), now + Duration::from_secs(60)).is_ok());
+    assert_eq!(1, limiter.len());
+
+    //
+    // Test the least recently used entry is evicted when none are idle.
+    //
+
+    let now = now + Duration::from_secs(60);
+
+    assert!(limiter.take(ip(4), now + Duration::from_secs(1)).is_ok());
+    assert!(limiter.take(ip(5), now + Duration::from_secs(2)).is_ok());
+    assert_eq!(2, limiter.len());
+
+    assert!(limiter.take(ip(4), now + Duration::from_secs(2)).is_ok());
+    assert!(limiter.take(ip(4), now + Duration::from_secs(2)).is_err());
+}
+
+///
+/// Test failures beyond the budget back off exponentially until the address is banned, and the bookkeeping is bounded.
+///
+#[test]
+fn unit_scan_guard() {
+    let mut scan_guard = ScanGuard::new(Some(1), None, 2, Instant::now());
+    let mut now = Instant::now();
+    let ip = |last: u8| IpAddr::from([192, 0, 2, last]);
+
+    //
+    // Test each strike doubles the backoff, until the address is banned.
+    //
+
+    assert_eq!(Ok(()), scan_guard.fail(ip(1), now));
+    assert_eq!(Ok(()), scan_guard.check(ip(1), now));
+
+    for strike in 1..ScanGuard::BAN_STRIKES {
+        let backoff = ScanGuard::BASE_BACKOFF * 2u32.pow(strike - 1);
+
+        assert_eq!(
+            Some(Err(backoff)),
+            (0..)
+                .map(|_| scan_guard.fail(ip(1), now))
+                .find(Result::is_err)
+        );
+        assert_eq!(Err(backoff), scan_guard.check(ip(1), now));
+        assert!(!scan_guard.is_banned(ip(1), now));
+
+        now += backoff;
+        assert_eq!(Ok(()), scan_guard.check(ip(1), now));
+    }
+
+    assert_eq!(
+        Some(Err(ScanGuard::BAN_DURATION)),
+        (0..)
+            .map(|_| scan_guard.fail(ip(1), now))
+            .find(Result::is_err)
+    );
+    assert!(scan_guard.is_banned(ip(1), now));
+    assert!(!scan_guard.is_banned(ip(2), now));
+    assert_eq!(1, scan_guard.banned(now));
+
+    //
+    // Test the ban is lifted after its duration, and forgotten after another.
+    //
+
+    now += ScanGuard::BAN_DURATION;
+    assert!(!scan_guard.is_banned(ip(1), now));
+    assert_eq!(Ok(()), scan_guard.check(ip(1), now));
+
+    now += ScanGuard::BAN_DURATION;
+    assert_eq!(Ok(()), scan_guard.fail(ip(1), now));
+    assert_eq!(Err(ScanGuard::BASE_BACKOFF), scan_guard.fail(ip(1), now));
+
+    //
+    // Test banned addresses outlive backoffs when the map is full.
+    //
+
+    let mut scan_guard = ScanGuard::new(Some(1), None, 2, now);
+
+    for _ in 0..ScanGuard::BAN_STRIKES {
+        (0..)
+            .map(|_| scan_guard.fail(ip(1), now))
+            .find(Result::is_err);
+        now += ScanGuard::MAX_BACKOFF;
+    }
+
+    assert!(scan_guard.is_banned(ip(1), now));
+
+    for last in 2..10 {
+        let _ = scan_guard.fail(ip(last), now);
+        let _ = scan_guard.fail(ip(last), now);
+        assert_eq!(2, scan_guard.len());
+    }
+
+    assert!(scan_guard.is_banned(ip(1), now));
+    assert!(scan_guard.check(ip(9), now).is_err());
+
+    //
+    // Test the global budget is shared by every address.
+    //
+
+    let mut scan_guard = ScanGuard::new(None, Some(2), 16, now);
+
+    assert_eq!(Ok(()), scan_guard.fail(ip(1), now));
+    assert_eq!(Ok(()), scan_guard.fail(ip(2), now));
+    assert_eq!(Err(ScanGuard::BASE_BACKOFF), scan_guard.fail(ip(3), now));
+    assert_eq!(Ok(()), scan_guard.check(ip(1), now));
+    assert!(scan_guard.check(ip(3), now).is_err());
+
+    assert_eq!(Ok(()), scan_guard.fail(ip(4), now + ScanGuard::PERIOD / 2));
+}
+
+///
+/// Computes the SHA-256 digest of the data.
+///
+fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    message.resize((message.len() + 8).div_ceil(64) * 64 - 8, 0);
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+
+        for i in 0..64 {
+            w[i] = if i < 16 {
+                u32::from_be_bytes(block[i * 4..i * 4 + 4].try_into().unwrap())
+            } else {
+                let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+                let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+
+                w[i - 16]
+                    .wrapping_add(s0)
+                    .wrapping_add(w[i - 7])
+                    .wrapping_add(s1)
+            };
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0; 32];
+    for (chunk, value) in digest.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+
+    digest
+}
+
+///
+/// Computes the HMAC-SHA256 of the message as a lowercase hex string.
+///
+fn hmac_sha256(key: &[u8], message: &[u8]) -> String {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let pad = |byte: u8| block.iter().map(|value| value ^ byte).collect::<Vec<u8>>();
+
+    let mut inner = pad(0x36);
+    inner.extend_from_slice(message);
+
+    let mut outer = pad(0x5c);
+    outer.extend_from_slice(&sha256(&inner));
+
+    sha256(&outer)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+///
+/// An authorizer which accepts tokens that are the HMAC of the room ID.
+///
+struct HmacAuthorizer {
+    key: Vec<u8>,
+}
+
+impl JoinAuthorizer for HmacAuthorizer {
+    fn authorize<'a>(
+        &'a self,
+        room_id: &'a str,
+        token: Option<&'a str>,
+        _: SocketAddr,
+    ) -> BoxFuture<'a, Result<(), DenyReason>> {
+        Box::pin(async move {
+            match token {
+                Some(token) if token == hmac_sha256(&self.key, room_id.as_bytes()) => Ok(()),
+                Some(_) => Err(DenyReason::new("invalid token")),
+                None => Err(DenyReason::new("missing token")),
+            }
+        })
+    }
+}
+
+///
+/// An authorizer which panics whenever it is consulted.
+///
+struct PanickingAuthorizer;
+
+impl JoinAuthorizer for PanickingAuthorizer {
+    fn authorize<'a>(
+        &'a self,
+        _: &'a str,
+        _: Option<&'a str>,
+        _: SocketAddr,
+    ) -> BoxFuture<'a, Result<(), DenyReason>> {
+        Box::pin(async { panic!("authorizer failure") })
+    }
+}
+
+///
+/// Test joining a room is decided by the join authorizer of the server.
+///
+#[tokio::test]
+async fn unit_join_authorizer() {
+    assert_eq!(
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        hmac_sha256(b"Jefe", b"what do ya want for nothing?")
+    );
+
+    let server = Server::new(Config::default()).unwrap();
+    server.write().await.set_join_authorizer(HmacAuthorizer {
+        key: b"secret".to_vec(),
+    });
+
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test joining without a valid token is denied.
+    //
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message: Error::Unauthorized, detail } => assert_eq!(Some("missing token"), detail.as_deref()));
+
+    let forged_token = hmac_sha256(b"guess", room_id.as_bytes());
+    client_2
+        .handle_join_room(&server, room_id.clone(), Some(forged_token), None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message: Error::Unauthorized, detail } => assert_eq!(Some("invalid token"), detail.as_deref()));
+
+    assert_eq!(1, server.read().await.rooms[&room_id].members.len());
+
+    //
+    // Test joining with a valid token is allowed.
+    //
+
+    let token = hmac_sha256(b"secret", room_id.as_bytes());
+    let request_packet = format!(
+        r#"{{"type":"join","id":"{}","token":"{}"}}"#,
+        room_id, token
+    );
+
+    let Ok(RequestPacket::Join {
+        token: Some(token), ..
+    }) = serde_json::from_str(&request_packet)
+    else {
+        panic!("expected a join packet with a token");
+    };
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), Some(token), None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { index, .. } => assert_eq!(Some(1), index));
+
+    //
+    // Test an authorizer which panics denies the join.
+    //
+
+    server
+        .write()
+        .await
+        .set_join_authorizer(PanickingAuthorizer);
+
+    let (mut client_3, recorder_3) = create_client();
+
+    client_3
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Error { message: Error::Unauthorized, detail } => assert_eq!(None, detail));
+
+    assert_eq!(2, server.read().await.rooms[&room_id].members.len());
+    assert!(client_3.room_id.is_none());
+}
+
+///
+/// Test reserving slots for a party, which expire or can be cancelled.
+///
+#[tokio::test]
+async fn unit_reservations() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+
+    client
+        .handle_create_room(&server, Some(5), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test invalid reservations.
+    //
+
+    let (leader, leader_recorder) = create_client();
+
+    leader.handle_reserve(&server, room_id.clone(), 0).await;
+    read_recorded_message!(leader_recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidSize)));
+
+    leader.handle_reserve(&server, room_id.clone(), 5).await;
+    read_recorded_message!(leader_recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    leader.handle_reserve(&server, String::new(), 1).await;
+    read_recorded_message!(leader_recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::DoesNotExist)));
+
+    //
+    // Test a party reserves the remaining slots, which rejects everyone else.
+    //
+
+    leader.handle_reserve(&server, room_id.clone(), 4).await;
+    let reservation = read_recorded_message!(leader_recorder, ResponsePacket::Reserve { id, reservation, count, ttl_seconds } => {
+        assert_eq!(room_id, id);
+        assert_eq!(4, count);
+        assert_eq!(Room::RESERVATION_TTL.as_secs(), ttl_seconds);
+
+        reservation
+    });
+
+    leader.handle_reserve(&server, room_id.clone(), 1).await;
+    read_recorded_message!(leader_recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    let (mut stranger, stranger_recorder) = create_client();
+
+    stranger
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(stranger_recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    stranger
+        .handle_join_room(
+            &server,
+            room_id.clone(),
+            None,
+            Some(String::from("forged")),
+            None,
+        )
+        .await;
+    read_recorded_message!(stranger_recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    //
+    // Test the party joins with the reservation.
+    //
+
+    for index in 1..=4 {
+        let (mut member, member_recorder) = create_client();
+
+        member
+            .handle_join_room(
+                &server,
+                room_id.clone(),
+                None,
+                Some(reservation.clone()),
+                None,
+            )
+            .await;
+        read_recorded_message!(member_recorder, ResponsePacket::Join { index: joined_index, .. } => assert_eq!(Some(index), joined_index));
+    }
+
+    assert_eq!(5, server.read().await.rooms[&room_id].members.len());
+    assert!(server.read().await.rooms[&room_id].reservations.is_empty());
+
+    //
+    // Test an expired reservation releases its slots.
+    //
+
+    let (mut client_2, recorder_2) = create_client();
+
+    client_2
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_2, ResponsePacket::Create { id, .. } => id);
+
+    leader.handle_reserve(&server, room_id.clone(), 2).await;
+    let reservation = read_recorded_message!(leader_recorder, ResponsePacket::Reserve { reservation, .. } => reservation);
+
+    stranger
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(stranger_recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    for reservation in server
+        .write()
+        .await
+        .rooms
+        .get_mut(&room_id)
+        .unwrap()
+        .reservations
+        .values_mut()
+    {
+        reservation.expires_at = Instant::now();
+    }
+
+    stranger
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(stranger_recorder, ResponsePacket::Join { index, .. } => assert_eq!(Some(1), index));
+
+    let (mut client_3, recorder_3) = create_client();
+
+    client_3
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Join { index, .. } => assert_eq!(Some(2), index));
+
+    let (mut client_4, recorder_4) = create_client();
+
+    client_4
+        .handle_join_room(&server, room_id.clone(), None, Some(reservation), None)
+        .await;
+    read_recorded_message!(recorder_4, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    //
+    // Test a cancelled reservation releases its slots.
+    //
+
+    let (mut client_5, recorder_5) = create_client();
+
+    client_5
+        .handle_create_room(&server, Some(2), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_5, ResponsePacket::Create { id, .. } => id);
+
+    leader.handle_reserve(&server, room_id.clone(), 1).await;
+    let reservation = read_recorded_message!(leader_recorder, ResponsePacket::Reserve { reservation, .. } => reservation);
+
+    client_4
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_4, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    leader
+        .handle_unreserve(&server, room_id.clone(), reservation)
+        .await;
+    assert!(leader_recorder.is_empty());
+
+    client_4
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+    read_recorded_message!(recorder_4, ResponsePacket::Join { index, .. } => assert_eq!(Some(1), index));
+}
+
+///
+/// Test joining a room without a socket.
+///
+#[tokio::test]
+async fn unit_join_room() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    //
+    // Test joining a non-existent room.
+    //
+
+    client_2
+        .handle_join_room(&server, String::new(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::DoesNotExist)));
+
+    //
+    // Test joining a room.
+    //
+
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+    read_recorded_message!(recorder, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+    //
+    // Test joining a room while already inside a room.
+    //
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test joining a full room.
+    //
+
+    client_3
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    //
+    // Test repeatedly joining the full room is rate limited, while joining another room is not.
+    //
+
+    for _ in 1..5 {
+        client_3
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+        read_recorded_message!(recorder_3, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+    }
+
+    client_3
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::RateLimited));
+        assert_eq!(Some(String::from("join")), detail);
+    });
+
+    client_3
+        .handle_join_room(&server, String::new(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::DoesNotExist)));
+
+    //
+    // Test joining another room in between does not reset the limit, which is shared by every connection from the same address.
+    //
+
+    let (mut client_4, recorder_4) = create_client();
+
+    for (client, recorder) in [(&mut client_3, &recorder_3), (&mut client_4, &recorder_4)] {
+        client
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::RateLimited)));
+    }
+
+    let recorder_5 = Arc::new(Recorder::default());
+    let mut client_5 = Client::new(
+        next_connection_id(),
+        recorder_5.clone(),
+        "192.0.2.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    client_5
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_5, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::IsFull)));
+
+    assert!(recorder.is_empty());
+    assert_eq!(2, server.read().await.rooms[&room_id].members.len());
+    assert_eq!(
+        2,
+        server.read().await.rooms[&room_id]
+            .occupancy
+            .load(Ordering::Relaxed)
+    );
+}
+
+///
+/// Test a client scanning for room IDs is throttled and banned, while other clients join their rooms as usual.
+///
+#[tokio::test]
+async fn unit_scanning() {
+    let server = Server::new(Config {
+        max_failed_joins_per_minute: Some(3),
+        ..Default::default()
+    })
+    .unwrap();
+    let clock = ManualClock::install(&server).await;
+
+    let (mut owner, owner_recorder) = create_client();
+    owner
+        .handle_create_room(&server, Some(4), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(owner_recorder, ResponsePacket::Create { id, .. } => id);
+
+    let scanner_ip = IpAddr::from([192, 0, 2, 1]);
+    let scanner_recorder = Arc::new(Recorder::default());
+    let mut scanner = Client::new(
+        next_connection_id(),
+        scanner_recorder.clone(),
+        SocketAddr::new(scanner_ip, 0),
+        vec![],
+    );
+
+    //
+    // Test unknown rooms are reported until the budget runs out, after which the scanner backs off.
+    //
+
+    for attempt in 0..4 {
+        scanner
+            .handle_join_room(&server, format!("guess-{}", attempt), None, None, None)
+            .await;
+        read_recorded_message!(scanner_recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::DoesNotExist)));
+    }
+
+    scanner
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(scanner_recorder, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::RateLimited));
+        assert_eq!(Some(String::from("1")), detail);
+    });
+
+    scanner.handle_info(&server, room_id.clone()).await;
+    read_recorded_message!(scanner_recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::RateLimited)));
+
+    //
+    // Test a client from another address is unaffected.
+    //
+
+    let (mut client, recorder) = create_client();
+    client
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Join { index, .. } => assert_eq!(Some(1), index));
+    read_recorded_message!(owner_recorder, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test scanning after each backoff doubles it, until the scanner is banned.
+    //
+
+    let mut guesses = 4;
+
+    for strike in 2..=ScanGuard::BAN_STRIKES {
+        clock.advance(ScanGuard::BASE_BACKOFF * 2u32.pow(strike - 2));
+        assert!(!server.read().await.denies_ip(scanner_ip));
+
+        loop {
+            scanner
+                .handle_join_room(&server, format!("guess-{}", guesses), None, None, None)
+                .await;
+
+            match read_recorded_message!(scanner_recorder, ResponsePacket::Error { message, detail } => (message, detail))
+            {
+                (Error::DoesNotExist, _) => guesses += 1,
+                (Error::RateLimited, detail) => {
+                    assert_ne!(Some(String::from("join")), detail);
+                    break;
+                }
+                unknown => panic!("unexpected error: {:?}", unknown),
+            }
+        }
+    }
+
+    assert!(server.read().await.denies_ip(scanner_ip));
+    assert!(!server.read().await.denies_ip(IpAddr::from([127, 0, 0, 1])));
+    assert_eq!(1, server.read().await.banned_ips());
+    assert_eq!(guesses, server.read().await.failed_joins());
+
+    #[cfg(feature = "metrics")]
+    assert!(server
+        .read()
+        .await
+        .render_metrics()
+        .contains(&format!("relay_failed_joins_total {}\n", guesses)));
+
+    let (mut client_2, recorder_2) = create_client();
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { index, .. } => assert_eq!(Some(2), index));
+
+    //
+    // Test the ban is lifted after its duration.
+    //
+
+    clock.advance(ScanGuard::BAN_DURATION);
+    assert!(!server.read().await.denies_ip(scanner_ip));
+}
+
+///
+/// Test a slow member does not delay the confirmation sent to a joining client.
+///
+#[tokio::test]
+async fn unit_join_fan_out() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    let slow_recorder = Arc::new(SlowRecorder {
+        recorder: Arc::default(),
+        delay: Duration::from_secs(1),
+    });
+    let mut slow_client = Client::new(
+        next_connection_id(),
+        slow_recorder.clone(),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    //
+    // Create a room with a slow member.
+    //
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    slow_client
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(slow_recorder.recorder, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+    read_recorded_message!(recorder, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+    //
+    // Test the joining client is confirmed before the slow member is notified.
+    //
+
+    let join = tokio::spawn({
+        let server = server.clone();
+
+        async move {
+            client_2
+                .handle_join_room(&server, room_id, None, None, None)
+                .await
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    read_recorded_message!(recorder_2, ResponsePacket::Join { size, .. } => assert_eq!(Some(2), size));
+    assert!(slow_recorder.recorder.is_empty());
+    assert!(!join.is_finished());
+
+    join.await.unwrap();
+
+    read_recorded_message!(recorder, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+    read_recorded_message!(slow_recorder.recorder, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+}
+
+///
+/// Test concurrent broadcasts are not gated by a slow member.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn unit_broadcast_fan_out() {
+    const FRAMES: u8 = 20;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    let slow_recorder = Arc::new(Recorder::default());
+    let mut slow_client = Client::new(
+        next_connection_id(),
+        Arc::new(Queue::new(
+            SlowRecorder {
+                recorder: slow_recorder.clone(),
+                delay: Duration::from_millis(100),
+            },
+            server.read().await.outbound_budget.clone(),
+            server.read().await.send_policy.clone(),
+            server.read().await.clock.clone(),
+        )),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    slow_client
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    //
+    // Test two broadcasters relaying at once each finish well before one slow write.
+    //
+
+    let broadcast = |mut client: Client| {
+        let server = server.clone();
+
+        tokio::spawn(async move {
+            let mut durations = vec![];
+
+            for frame in 0..FRAMES {
+                let started_at = Instant::now();
+
+                client
+                    .handle_message(&server, Message::Binary(vec![Room::BROADCAST, frame]))
+                    .await;
+                durations.push(started_at.elapsed());
+            }
+
+            durations
+        })
+    };
+
+    let (durations, durations_2) = tokio::join!(broadcast(client), broadcast(client_2));
+    let mut durations = [durations.unwrap(), durations_2.unwrap()].concat();
+    durations.sort();
+
+    let p95 = durations[durations.len() * 95 / 100];
+    assert!(
+        p95 < Duration::from_millis(50),
+        "p95 fan-out took {:?}",
+        p95
+    );
+
+    assert_eq!(
+        usize::from(FRAMES) + 2,
+        recorder.messages.lock().unwrap().len()
+    );
+    assert_eq!(
+        usize::from(FRAMES) + 2,
+        recorder_2.messages.lock().unwrap().len()
+    );
+}
+
+///
+/// Test rooms created without notify_members only send each member its own join and leave responses.
+///
+#[tokio::test]
+async fn unit_notify_members() {
+    const SIZE: usize = 200;
+
+    for notify_members in [true, false] {
+        let server = Server::new(Config::default()).unwrap();
+        let mut clients: Vec<_> = (0..SIZE).map(|_| create_client()).collect();
+
+        let count = |clients: &[(Client, Arc<Recorder>)]| {
+            clients
+                .iter()
+                .map(|(_, recorder)| recorder.messages.lock().unwrap().len())
+                .sum::<usize>()
+        };
+
+        //
+        // Test filling the room.
+        //
+
+        clients[0]
+            .0
+            .handle_create_room(
+                &server,
+                Some(SIZE),
+                RoomOptions {
+                    notify_members,
+                    ..Default::default()
+                },
+                None,
+            )
+            .await;
+        let room_id = read_recorded_message!(clients[0].1, ResponsePacket::Create { id, .. } => id);
+
+        for (client, _) in &mut clients[1..] {
+            client
+                .handle_join_room(&server, room_id.clone(), None, None, None)
+                .await;
+        }
+
+        let expected = if notify_members {
+            SIZE - 1 + SIZE * (SIZE - 1) / 2
+        } else {
+            SIZE - 1
+        };
+        assert_eq!(expected, count(&clients));
+
+        for (_, recorder) in &clients {
+            recorder.messages.lock().unwrap().clear();
+        }
+
+        //
+        // Test the owner can still query every member.
+        //
+
+        clients[0].0.handle_stats(&server).await;
+        read_recorded_message!(clients[0].1, ResponsePacket::Stats { members, .. } => assert_eq!(SIZE, members.len()));
+
+        //
+        // Test leaving the room.
+        //
+
+        clients[SIZE - 1].0.handle_leave_room(&server).await;
+
+        let expected = if notify_members { SIZE } else { 1 };
+        assert_eq!(expected, count(&clients));
+    }
+}
+
+///
+/// Test the owner watching the occupancy of their room as members join and leave.
+///
+#[tokio::test]
+async fn unit_watch_occupancy() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    //
+    // Test watching outside of a room.
+    //
+
+    client.handle_watch_occupancy(&server, true).await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
+
+    //
+    // Test only the owner can watch the room.
+    //
+
+    client
+        .handle_create_room(
+            &server,
+            Some(3),
+            RoomOptions {
+                notify_members: false,
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { .. } => {});
+
+    client_2.handle_watch_occupancy(&server, true).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotOwner)));
+
+    //
+    // Test enabling the stream sends the current occupancy.
+    //
+
+    client.handle_watch_occupancy(&server, true).await;
+    read_recorded_message!(recorder, ResponsePacket::Occupancy { count, capacity } => assert_eq!((2, 3), (count, capacity)));
+
+    //
+    // Test every join and leave sends the owner the new occupancy.
+    //
+
+    client_3
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Join { .. } => {});
+    read_recorded_message!(recorder, ResponsePacket::Occupancy { count, capacity } => assert_eq!((3, 3), (count, capacity)));
+
+    client_2.handle_leave_room(&server).await;
+    recorder_2.messages.lock().unwrap().clear();
+    read_recorded_message!(recorder, ResponsePacket::Occupancy { count, capacity } => assert_eq!((2, 3), (count, capacity)));
+
+    assert!(recorder.is_empty());
+    assert!(recorder_3.is_empty());
+
+    //
+    // Test disabling the stream.
+    //
+
+    client.handle_watch_occupancy(&server, false).await;
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    recorder_2.messages.lock().unwrap().clear();
+
+    assert!(recorder.is_empty());
+
+    //
+    // Test the stream stops when the owner leaves, rather than passing to the next owner.
+    //
+
+    client.handle_watch_occupancy(&server, true).await;
+    read_recorded_message!(recorder, ResponsePacket::Occupancy { count, .. } => assert_eq!(3, count));
+
+    client.handle_leave_room(&server).await;
+    client_2.handle_leave_room(&server).await;
+
+    assert!(recorder_3.is_empty());
+}
+
+///
+/// Test the owner migrating every member into a new room, preserving their indices.
+///
+#[tokio::test]
+async fn unit_migrate() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+    let (mut client_4, recorder_4) = create_client();
+
+    //
+    // Test migrating outside of a room.
+    //
+
+    client.handle_migrate(&server, None).await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
+
+    //
+    // Setup a room with three members and a reservation.
+    //
+
+    client
+        .handle_create_room(&server, Some(4), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    for (client, recorder) in [(&mut client_2, &recorder_2), (&mut client_3, &recorder_3)] {
+        client
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+    }
+
+    for recorder in [&recorder, &recorder, &recorder_2] {
+        read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+    }
+
+    client.handle_reserve(&server, room_id.clone(), 1).await;
+    let reservation = read_recorded_message!(recorder, ResponsePacket::Reserve { reservation, .. } => reservation);
+
+    //
+    // Test only the owner can migrate, and only into a room that fits every member.
+    //
+
+    client_2.handle_migrate(&server, None).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotOwner)));
+
+    client.handle_migrate(&server, Some(2)).await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidSize)));
+
+    //
+    // Test every member is moved with its index, without any leave packets.
+    //
+
+    client.handle_migrate(&server, Some(3)).await;
+
+    let mut migrated_room_id = String::new();
+    for (expected_index, recorder) in [&recorder, &recorder_2, &recorder_3]
+        .into_iter()
+        .enumerate()
+    {
+        read_recorded_message!(recorder, ResponsePacket::Migrated { id, index } => {
+            assert_eq!(expected_index, index);
+            migrated_room_id = id;
+        });
+        assert!(recorder.is_empty());
+    }
+
+    assert_ne!(room_id, migrated_room_id);
+    assert_eq!(Some(&migrated_room_id), client.room_id.as_ref());
+
+    {
+        let server = server.read().await;
+        assert!(!server.rooms.contains_key(&room_id));
+
+        let room = &server.rooms[&migrated_room_id];
+        assert_eq!(3, room.size);
+        assert_eq!(3, room.members.len());
+        assert_eq!(Some(0), room.owner);
+        assert!(room.reservations.is_empty());
+    }
+
+    //
+    // Test the previous room and its reservations are gone.
+    //
+
+    client_4
+        .handle_join_room(&server, room_id.clone(), None, Some(reservation), None)
+        .await;
+    read_recorded_message!(recorder_4, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::DoesNotExist)));
+
+    //
+    // Test the other members relay and leave within the new room.
+    //
+
+    client_3
+        .handle_message(&server, Message::Binary(vec![0, 7]))
+        .await;
+    assert_eq!(vec![2, 7], read_recorded_binary_message!(recorder));
+    assert_eq!(Some(&migrated_room_id), client_3.room_id.as_ref());
+
+    client_2.handle_leave_room(&server).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Left => ());
+    read_recorded_message!(recorder, ResponsePacket::Leave { index: 1, .. } => ());
+    read_recorded_message!(recorder_3, ResponsePacket::Leave { index: 1, .. } => ());
+
+    assert_eq!(
+        2,
+        server.read().await.rooms[&migrated_room_id].members.len()
+    );
+}
+
+///
+/// Test rooms which route binary packets by the virtual addresses their members registered.
+///
+#[tokio::test]
+async fn unit_virtual_addresses() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    let options = RoomOptions {
+        virtual_addresses: true,
+        ..Default::default()
+    };
+
+    //
+    // Test the creator must register a valid virtual address.
+    //
+
+    for virtual_address in [None, Some(String::new()), Some(String::from("too-long!"))] {
+        client
+            .handle_create_room(&server, Some(3), options.clone(), virtual_address)
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidVirtualAddress)));
+    }
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions::default(),
+            Some(String::from("alpha")),
+        )
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidVirtualAddress)));
+
+    client
+        .handle_create_room(&server, Some(3), options, Some(String::from("alpha")))
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test joining with a taken or missing virtual address, and the addresses announced on join.
+    //
+
+    for (virtual_address, expected_error) in [
+        (None, Error::InvalidVirtualAddress),
+        (Some("alpha"), Error::VirtualAddressTaken),
+    ] {
+        client_2
+            .handle_join_room(
+                &server,
+                room_id.clone(),
+                None,
+                None,
+                virtual_address.map(String::from),
+            )
+            .await;
+        read_recorded_message!(recorder_2, ResponsePacket::Error { message, .. } => {
+            assert_eq!(format!("{:?}", expected_error), format!("{:?}", message));
+        });
+    }
+
+    client_2
+        .handle_join_room(
+            &server,
+            room_id.clone(),
+            None,
+            None,
+            Some(String::from("bravo")),
+        )
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { virtual_addresses, .. } => {
+        assert_eq!(Some(vec![String::from("alpha"), String::from("bravo")]), virtual_addresses);
+    });
+    read_recorded_message!(recorder, ResponsePacket::Join { virtual_address, .. } => {
+        assert_eq!(Some(String::from("bravo")), virtual_address);
+    });
+
+    client_3
+        .handle_join_room(
+            &server,
+            room_id.clone(),
+            None,
+            None,
+            Some(String::from("charlie")),
+        )
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder_2, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test relaying by virtual address rewrites the destination into the source address.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(b"\x05bravo\x01\x02".to_vec()))
+        .await;
+    assert_eq!(
+        b"\x05alpha\x01\x02".to_vec(),
+        read_recorded_binary_message!(recorder_2)
+    );
+    assert!(recorder_3.is_empty());
+
+    client_3
+        .handle_message(&server, Message::Binary(b"\x00\x09".to_vec()))
+        .await;
+    assert_eq!(
+        b"\x07charlie\x09".to_vec(),
+        read_recorded_binary_message!(recorder)
+    );
+    assert_eq!(
+        b"\x07charlie\x09".to_vec(),
+        read_recorded_binary_message!(recorder_2)
+    );
+
+    client
+        .handle_message(&server, Message::Binary(b"\x05delta\x01".to_vec()))
+        .await;
+    assert!(recorder_2.is_empty() && recorder_3.is_empty());
+
+    client
+        .handle_message(&server, Message::Binary(b"\x09bravo".to_vec()))
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidFrame)));
+
+    //
+    // Test routing keeps working after a member leaves and rejoins with a new virtual address.
+    //
+
+    client_2.handle_leave_room(&server).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Left => ());
+    read_recorded_message!(recorder, ResponsePacket::Leave { index, .. } => assert_eq!(1, index));
+    read_recorded_message!(recorder_3, ResponsePacket::Leave { index, .. } => assert_eq!(1, index));
+
+    client
+        .handle_message(&server, Message::Binary(b"\x07charlie\x03".to_vec()))
+        .await;
+    assert_eq!(
+        b"\x05alpha\x03".to_vec(),
+        read_recorded_binary_message!(recorder_3)
+    );
+
+    client_2
+        .handle_join_room(&server, room_id, None, None, Some(String::from("delta")))
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder_3, ResponsePacket::Join { .. } => ());
+
+    client
+        .handle_message(&server, Message::Binary(b"\x05bravo\x04".to_vec()))
+        .await;
+    client
+        .handle_message(&server, Message::Binary(b"\x05delta\x05".to_vec()))
+        .await;
+    assert_eq!(
+        b"\x05alpha\x05".to_vec(),
+        read_recorded_binary_message!(recorder_2)
+    );
+    assert!(recorder_2.is_empty() && recorder_3.is_empty());
+}
+
+///
+/// Test broadcasts which exclude a list of indices.
+///
+#[tokio::test]
+async fn unit_exclusion() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+    let (mut client_4, recorder_4) = create_client();
+
+    client
+        .handle_create_room(&server, Some(4), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    for client in [&mut client_2, &mut client_3, &mut client_4] {
+        client
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+    }
+
+    for recorder in [&recorder, &recorder_2, &recorder_3, &recorder_4] {
+        recorder.messages.lock().unwrap().clear();
+    }
+
+    //
+    // Test the excluded indices are skipped and the header is stripped.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![Room::EXCLUDE, 1, 2, 7, 8]))
+        .await;
+    assert_eq!(vec![0, 7, 8], read_recorded_binary_message!(recorder_2));
+    assert_eq!(vec![0, 7, 8], read_recorded_binary_message!(recorder_4));
+    assert!(recorder.is_empty());
+    assert!(recorder_3.is_empty());
+
+    //
+    // Test the sender and out of range indices in the list are ignored.
+    //
+
+    client_2
+        .handle_message(
+            &server,
+            Message::Binary(vec![Room::EXCLUDE, 3, 1, 9, 200, 5]),
+        )
+        .await;
+    assert_eq!(vec![1, 5], read_recorded_binary_message!(recorder));
+    assert_eq!(vec![1, 5], read_recorded_binary_message!(recorder_3));
+    assert_eq!(vec![1, 5], read_recorded_binary_message!(recorder_4));
+    assert!(recorder_2.is_empty());
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![Room::EXCLUDE, 0]))
+        .await;
+    assert_eq!(vec![1], read_recorded_binary_message!(recorder));
+    assert_eq!(vec![1], read_recorded_binary_message!(recorder_3));
+    assert_eq!(vec![1], read_recorded_binary_message!(recorder_4));
+
+    //
+    // Test a unicast with the same bytes is not read as an exclusion list.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![2, 1, 2, 7, 8]))
+        .await;
+    assert_eq!(
+        vec![0, 1, 2, 7, 8],
+        read_recorded_binary_message!(recorder_3)
+    );
+    assert!(recorder_2.is_empty());
+    assert!(recorder_4.is_empty());
+
+    //
+    // Test exclusion lists combine with subscriptions.
+    //
+
+    client_4.handle_subscribe(&server, Some(vec![1])).await;
+    recorder_4.messages.lock().unwrap().clear();
+
+    client
+        .handle_message(&server, Message::Binary(vec![Room::EXCLUDE, 1, 1, 6]))
+        .await;
+    assert_eq!(vec![0, 6], read_recorded_binary_message!(recorder_3));
+    assert!(recorder_2.is_empty());
+    assert!(recorder_4.is_empty());
+
+    //
+    // Test a truncated exclusion list is rejected.
+    //
+
+    for frame in [vec![Room::EXCLUDE], vec![Room::EXCLUDE, 2, 1]] {
+        client.handle_message(&server, Message::Binary(frame)).await;
+        read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidFrame)));
+    }
+
+    assert!(recorder_2.is_empty());
+    assert!(recorder_3.is_empty());
+    assert!(recorder_4.is_empty());
+
+    //
+    // Test a room cannot grow large enough for the sentinel to be the index of a client.
+    //
+
+    let (mut client_5, recorder_5) = create_client();
+
+    client_5
+        .handle_create_room(
+            &server,
+            Some(usize::from(Room::EXCLUDE) + 1),
+            RoomOptions::default(),
+            None,
+        )
+        .await;
+    read_recorded_message!(recorder_5, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidSize)));
+}
+
+///
+/// Test control packets are delivered ahead of a backlog of relayed frames.
+///
+#[tokio::test]
+async fn unit_priority() {
+    const FRAMES: usize = 100;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, _) = create_client();
+
+    let slow_recorder = Arc::new(Recorder::default());
+    let mut slow_client = Client::new(
+        next_connection_id(),
+        Arc::new(Queue::new(
+            SlowRecorder {
+                recorder: slow_recorder.clone(),
+                delay: Duration::from_millis(1),
+            },
+            server.read().await.outbound_budget.clone(),
+            server.read().await.send_policy.clone(),
+            server.read().await.clock.clone(),
+        )),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    let wait_for = |count: usize| {
+        let slow_recorder = slow_recorder.clone();
+
+        async move {
+            while slow_recorder.messages.lock().unwrap().len() < count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    };
+
+    //
+    // Create a room with a slow member.
+    //
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    slow_client
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_2
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    wait_for(2).await;
+    read_recorded_message!(slow_recorder, ResponsePacket::Join { size, .. } => assert_eq!(Some(1), size));
+    read_recorded_message!(slow_recorder, ResponsePacket::Join { size, .. } => assert_eq!(None, size));
+
+    //
+    // Flood the slow member with relayed frames, then leave the room.
+    //
+
+    for _ in 0..FRAMES {
+        client
+            .handle_message(&server, Message::Binary(vec![1; 1024]))
+            .await;
+    }
+
+    client_2.handle_leave_room(&server).await;
+
+    //
+    // Test the leave packet is delivered ahead of most of the backlog.
+    //
+
+    wait_for(FRAMES + 1).await;
+
+    let messages = slow_recorder.messages.lock().unwrap().split_off(0);
+    let position = messages
+        .iter()
+        .position(|message| message.is_text())
+        .unwrap();
+
+    assert!(position < FRAMES / 4);
+    assert_eq!(
+        FRAMES,
+        messages
+            .iter()
+            .filter(|message| message.is_binary())
+            .count()
+    );
+
+    match serde_json::from_str(messages[position].to_text().unwrap()).unwrap() {
+        ResponsePacket::Leave { index, .. } => assert_eq!(2, index),
+        unknown => panic!("pattern doesn't match: {:?}", unknown),
+    }
+}
+
+///
+/// Test relayed frames which outlive the time to live of the room are dropped from a stalled queue.
+///
+#[tokio::test]
+async fn unit_frame_ttl() {
+    const FRAMES: u8 = 10;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, _) = create_client();
+
+    let slow_recorder = Arc::new(Recorder::default());
+    let mut slow_client = Client::new(
+        next_connection_id(),
+        Arc::new(Queue::new(
+            SlowRecorder {
+                recorder: slow_recorder.clone(),
+                delay: Duration::from_millis(100),
+            },
+            server.read().await.outbound_budget.clone(),
+            server.read().await.send_policy.clone(),
+            server.read().await.clock.clone(),
+        )),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    //
+    // Create a room with a stalled member.
+    //
+
+    client
+        .handle_create_room(
+            &server,
+            Some(3),
+            RoomOptions {
+                frame_ttl_ms: Some(20),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    slow_client
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    slow_recorder.messages.lock().unwrap().clear();
+    recorder.messages.lock().unwrap().clear();
+
+    //
+    // Stall the member, then relay frames followed by a control packet.
+    //
+
+    let _ = slow_client
+        .sender
+        .send(Message::Ping(vec![]), Priority::High)
+        .await;
+
+    for frame in 0..FRAMES {
+        client
+            .handle_message(&server, Message::Binary(vec![2, frame]))
+            .await;
+    }
+
+    client_2.handle_leave_room(&server).await;
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+
+    //
+    // Test only fresh frames are delivered on resume, and the control packet is never dropped.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![1, FRAMES]))
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    assert!(slow_recorder.messages.lock().unwrap().remove(0).is_ping());
+    read_recorded_message!(slow_recorder, ResponsePacket::Leave { index, .. } => assert_eq!(1, index));
+    assert_eq!(
+        vec![0, FRAMES],
+        read_recorded_binary_message!(slow_recorder)
+    );
+    assert!(slow_recorder.is_empty());
+
+    read_recorded_message!(recorder, ResponsePacket::Leave { .. } => ());
+
+    client.handle_stats(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Stats { members, .. } => {
+        assert_eq!(0, members[0].expired_frames);
+        assert_eq!(u64::from(FRAMES), members[1].expired_frames);
+    });
+}
+
+///
+/// Test paced rooms deliver bursty frames in batches on tick boundaries.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn unit_paced_delivery() {
+    const TICK: Duration = Duration::from_millis(50);
+    const TOLERANCE: Duration = Duration::from_millis(20);
+    const BURST: u8 = 4;
+    const GAPS: [u64; 8] = [3, 17, 41, 8, 64, 29, 11, 52];
+
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+
+    let mut socket_1 = test_server.connect();
+    let mut socket_2 = test_server.connect();
+
+    //
+    // Test invalid pacing options are refused.
+    //
+
+    for (options, field) in [
+        (
+            CreateOptions {
+                paced: Some(true),
+                tick_rate: Some(0),
+                ..Default::default()
+            },
+            "tickRate",
+        ),
+        (
+            CreateOptions {
+                paced: Some(true),
+                tick_rate: Some(Room::MAX_TICK_RATE + 1),
+                ..Default::default()
+            },
+            "tickRate",
+        ),
+        (
+            CreateOptions {
+                paced: Some(true),
+                conflate: Some(true),
+                ..Default::default()
+            },
+            "conflate",
+        ),
+    ] {
+        write_message!(
+            socket_1,
+            RequestPacket::Create {
+                size: None,
+                options,
+                template: None,
+                virtual_address: None,
+            }
+        );
+        read_message!(socket_1, ResponsePacket::Error { message, detail } => {
+            assert!(matches!(message, Error::InvalidOptions));
+            assert_eq!(Some(String::from(field)), detail);
+        });
+    }
+
+    //
+    // Create a paced room.
+    //
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: None,
+            options: CreateOptions {
+                paced: Some(true),
+                tick_rate: Some((Duration::from_secs(1).as_millis() / TICK.as_millis()) as u32),
+                ..Default::default()
+            },
+            template: None,
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+
+    //
+    // Send bursts with irregular gaps while recording when each batch arrives.
+    //
+
+    let frames = GAPS.len() * usize::from(BURST);
+    let receiver = std::thread::spawn(move || {
+        let mut batches = vec![];
+        let mut received = 0;
+
+        while received < frames {
+            let batch = socket_2.read().unwrap().into_data();
+            let decoded: Vec<_> = frame::decode_batch(&batch)
+                .unwrap()
+                .into_iter()
+                .map(<[u8]>::to_vec)
+                .collect();
+
+            received += decoded.len();
+            batches.push((Instant::now(), decoded));
+        }
+
+        (socket_2, batches)
+    });
+
+    for (burst, gap) in GAPS.into_iter().enumerate() {
+        for frame in 0..BURST {
+            write_binary_message!(socket_1, vec![1, burst as u8, frame]);
+        }
+
+        std::thread::sleep(Duration::from_millis(gap));
+    }
+
+    let (mut socket_2, batches) = receiver.join().unwrap();
+
+    //
+    // Test every frame arrives in order, batched into fewer messages.
+    //
+
+    let expected: Vec<_> = (0..GAPS.len() as u8)
+        .flat_map(|burst| (0..BURST).map(move |frame| vec![0, burst, frame]))
+        .collect();
+    let received: Vec<_> = batches
+        .iter()
+        .flat_map(|(_, decoded)| decoded.iter().cloned())
+        .collect();
+
+    assert_eq!(expected, received);
+    assert!(batches.len() < frames);
+    assert!(batches.iter().all(|(_, decoded)| !decoded.is_empty()));
+
+    //
+    // Test the spacing between batches is within a tolerance of a whole number of ticks.
+    //
+
+    for pair in batches.windows(2) {
+        let spacing = pair[1].0 - pair[0].0;
+        let ticks = (spacing.as_secs_f64() / TICK.as_secs_f64())
+            .round()
+            .max(1.0);
+        let offset = (spacing.as_secs_f64() - ticks * TICK.as_secs_f64()).abs();
+
+        assert!(
+            offset <= TOLERANCE.as_secs_f64(),
+            "batches arrived {:?} apart",
+            spacing
+        );
+    }
+
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+}
+
+///
+/// Test the pacer drops frames beyond the buffer cap of a destination until the next tick.
+///
+#[test]
+fn unit_pacer() {
+    let pacer = Pacer::new(
+        Room::DEFAULT_TICK_RATE,
+        3 * (frame::BATCH_HEADER_LENGTH + 2),
+    );
+    let dropped_1 = Arc::new(DroppedFrames::default());
+    let dropped_2 = Arc::new(DroppedFrames::default());
+
+    assert_eq!(
+        Duration::from_secs(1) / Room::DEFAULT_TICK_RATE,
+        pacer.interval
+    );
+
+    for frame in 0..2 {
+        assert!(pacer.push(ClientId(1), &[0, frame], &dropped_1));
+    }
+
+    assert!(pacer.push(ClientId(1), &[1, 2], &dropped_2));
+    assert!(!pacer.push(ClientId(1), &[0, 3], &dropped_1));
+    assert!(pacer.push(ClientId(2), &[0, 4], &dropped_1));
+
+    let mut batches = pacer.take();
+    assert_eq!(2, batches.len());
+
+    let batch = batches.remove(&ClientId(1)).unwrap();
+    assert_eq!(
+        Ok(vec![&[0, 0][..], &[0, 1], &[1, 2]]),
+        frame::decode_batch(&batch.frames)
+    );
+    assert_eq!(2, batch.sources.len());
+
+    let batch = batches.remove(&ClientId(2)).unwrap();
+    assert_eq!(Ok(vec![&[0, 4][..]]), frame::decode_batch(&batch.frames));
+    assert_eq!(1, batch.sources.len());
+
+    //
+    // Test a dropped batch is reported to every sender with frames in it.
+    //
+
+    let forwarding = DroppedFrames::forwarding(vec![dropped_1.clone(), dropped_2.clone()]);
+    forwarding.record(DropReason::Expired);
+
+    assert_eq!(None, forwarding.take());
+    assert_eq!(Some((1, DropReason::Expired)), dropped_1.take());
+    assert_eq!(Some((1, DropReason::Expired)), dropped_2.take());
+
+    //
+    // Test the cap resets on every tick.
+    //
+
+    assert!(pacer.take().is_empty());
+    assert!(pacer.push(ClientId(1), &[0, 5], &dropped_1));
+    assert!(!pacer.push(ClientId(1), &[0; 32], &dropped_1));
+}
+
+///
+/// Test frames larger than the maximum message size advertised by a receiver are skipped and counted.
+///
+#[tokio::test]
+async fn unit_max_message_size() {
+    const LIMIT: usize = 16;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    //
+    // Test the advertised limit is clamped to the bounds of the server, and defaults to unlimited.
+    //
+
+    assert_eq!(usize::MAX, client_2.max_message_size);
+
+    for (max_message_size, expected) in [
+        (0, 1),
+        (usize::MAX, Server::MAX_MESSAGE_SIZE),
+        (LIMIT, LIMIT),
+    ] {
+        client_2
+            .handle_message(
+                &server,
+                Message::Text(format!(
+                    r#"{{"type":"hello","maxMessageSize":{}}}"#,
+                    max_message_size
+                )),
+            )
+            .await;
+        read_recorded_message!(recorder_2, ResponsePacket::Hello { .. } => ());
+
+        assert_eq!(expected, client_2.max_message_size);
+    }
+
+    //
+    // Create a room with a small-limit receiver and a normal receiver.
+    //
+
+    client_1
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    recorder_1.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+    recorder_3.messages.lock().unwrap().clear();
+
+    //
+    // Test a large broadcast only reaches the normal receiver, and a small broadcast reaches both.
+    //
+
+    let mut large = vec![Room::BROADCAST];
+    large.resize(LIMIT + 1, 7);
+
+    client_1
+        .handle_message(&server, Message::Binary(large.clone()))
+        .await;
+    client_1
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 1, 2]))
+        .await;
+
+    large[0] = 0;
+    assert_eq!(large, read_recorded_binary_message!(recorder_3));
+    assert_eq!(vec![0, 1, 2], read_recorded_binary_message!(recorder_3));
+    assert_eq!(vec![0, 1, 2], read_recorded_binary_message!(recorder_2));
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test a large unicast is skipped too.
+    //
+
+    client_3
+        .handle_message(&server, Message::Binary(vec![1; LIMIT + 1]))
+        .await;
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test the counters reflect the skips, and the senders are told.
+    //
+
+    client_1.handle_stats(&server).await;
+    read_recorded_message!(recorder_1, ResponsePacket::Stats { members, .. } => {
+        assert_eq!(
+            vec![0, 2, 0],
+            members
+                .iter()
+                .map(|member| member.oversized_frames)
+                .collect::<Vec<_>>()
+        );
+    });
+
+    for (client, recorder) in [(&client_1, &recorder_1), (&client_3, &recorder_3)] {
+        client.handle_dropped_frames().await;
+        read_recorded_message!(recorder, ResponsePacket::Dropped { count, reason } => {
+            assert_eq!(1, count);
+            assert_eq!(DropReason::ReceiverLimit, reason);
+        });
+    }
+}
+
+///
+/// Test frames larger than the negotiated chunk size of a receiver are split into chunks which reassemble intact.
+///
+#[tokio::test]
+async fn unit_chunking() {
+    const CHUNK_SIZE: usize = 64 << 10;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    //
+    // Test the negotiated chunk size is clamped to the bounds of the server and echoed back.
+    //
+
+    for (chunk_size, expected) in [
+        (1, Client::MIN_CHUNK_SIZE),
+        (usize::MAX, Server::MAX_MESSAGE_SIZE),
+        (CHUNK_SIZE, CHUNK_SIZE),
+    ] {
+        client_2
+            .handle_message(
+                &server,
+                Message::Text(format!(r#"{{"type":"hello","chunkSize":{}}}"#, chunk_size)),
+            )
+            .await;
+        read_recorded_message!(recorder_2, ResponsePacket::Hello { chunk_size, .. } => {
+            assert_eq!(Some(expected), chunk_size);
+        });
+    }
+
+    //
+    // Create a room with a chunking receiver and a normal receiver.
+    //
+
+    client_1
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    recorder_1.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+    recorder_3.messages.lock().unwrap().clear();
+
+    //
+    // Test a 1 MB broadcast reaches the normal receiver whole, and the chunking receiver in chunks.
+    //
+
+    let mut data = vec![Room::BROADCAST];
+    data.extend((0..1 << 20).map(|byte: u32| (byte % 251) as u8));
+
+    for message_id in 0..2 {
+        client_1
+            .handle_message(&server, Message::Binary(data.clone()))
+            .await;
+
+        let expected = read_recorded_binary_message!(recorder_3);
+        assert_eq!(data[1..], expected[1..]);
+        assert_eq!(0, expected[0]);
+
+        //
+        // Reassemble the chunks as a client would.
+        //
+
+        let chunks: Vec<_> = recorder_2.messages.lock().unwrap().drain(..).collect();
+        let count = expected
+            .len()
+            .div_ceil(CHUNK_SIZE - frame::CHUNK_HEADER_LENGTH);
+        assert_eq!(count, chunks.len());
+
+        let mut reassembled = vec![];
+        for (index, chunk) in chunks.iter().enumerate() {
+            let Message::Binary(chunk) = chunk else {
+                panic!("Expected a binary message, got {:?}", chunk);
+            };
+            assert!(chunk.len() <= CHUNK_SIZE);
+
+            let chunk = frame::decode_chunk(chunk).unwrap();
+            assert_eq!(0, chunk.source);
+            assert_eq!(message_id, chunk.message_id);
+            assert_eq!(index, usize::from(chunk.index));
+            assert_eq!(count, usize::from(chunk.count));
+
+            reassembled.extend_from_slice(chunk.data);
+        }
+
+        assert_eq!(expected, reassembled);
+    }
+
+    //
+    // Test a frame within the chunk size is delivered unchanged.
+    //
+
+    client_1
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 1, 2]))
+        .await;
+
+    assert_eq!(vec![0, 1, 2], read_recorded_binary_message!(recorder_2));
+    assert_eq!(vec![0, 1, 2], read_recorded_binary_message!(recorder_3));
+    assert!(recorder_1.is_empty());
+}
+
+///
+/// Test frames larger than the maximum message size of a chunking receiver are chunked to fit rather than skipped.
+///
+#[tokio::test]
+async fn unit_chunked_max_message_size() {
+    const LIMIT: usize = 2 * Client::MIN_CHUNK_SIZE;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    //
+    // Negotiate a chunk size above the limit, and a limit below the smallest chunk size.
+    //
+
+    for (client, recorder, max_message_size) in [
+        (&mut client_2, &recorder_2, LIMIT),
+        (&mut client_3, &recorder_3, Client::MIN_CHUNK_SIZE - 1),
+    ] {
+        client
+            .handle_message(
+                &server,
+                Message::Text(format!(
+                    r#"{{"type":"hello","maxMessageSize":{},"chunkSize":{}}}"#,
+                    max_message_size,
+                    4 * LIMIT
+                )),
+            )
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Hello { .. } => ());
+    }
+
+    client_1
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    recorder_1.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+    recorder_3.messages.lock().unwrap().clear();
+
+    //
+    // Test a frame within the chunk size but beyond the limit arrives in chunks within the limit.
+    //
+
+    let mut data = vec![Room::BROADCAST];
+    data.resize(2 * LIMIT, 7);
+
+    client_1
+        .handle_message(&server, Message::Binary(data.clone()))
+        .await;
+
+    let chunks: Vec<_> = recorder_2.messages.lock().unwrap().drain(..).collect();
+    assert_eq!(
+        data.len().div_ceil(LIMIT - frame::CHUNK_HEADER_LENGTH),
+        chunks.len()
+    );
+
+    let mut reassembled = vec![];
+    for chunk in &chunks {
+        let Message::Binary(chunk) = chunk else {
+            panic!("Expected a binary message, got {:?}", chunk);
+        };
+        assert!(chunk.len() <= LIMIT);
+
+        reassembled.extend_from_slice(frame::decode_chunk(chunk).unwrap().data);
+    }
+
+    data[0] = 0;
+    assert_eq!(data, reassembled);
+
+    //
+    // Test a receiver whose limit cannot fit a chunk still skips the frame.
+    //
+
+    assert!(recorder_3.is_empty());
+
+    client_1.handle_dropped_frames().await;
+    read_recorded_message!(recorder_1, ResponsePacket::Dropped { count, reason } => {
+        assert_eq!(1, count);
+        assert_eq!(DropReason::ReceiverLimit, reason);
+    });
+}
+
+///
+/// Test the maximum message size of a receiver applies to frames as compressed for it, including the flag byte.
+///
+#[cfg(feature = "compression")]
+#[tokio::test]
+async fn unit_compressed_max_message_size() {
+    const LIMIT: usize = 32;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    //
+    // Create a compressed room with an lz4 receiver and a plain receiver sharing the same limit.
+    //
+
+    client_2.lz4 = true;
+    client_2.max_message_size = LIMIT;
+    client_3.max_message_size = LIMIT;
+
+    client_1
+        .handle_create_room(
+            &server,
+            Some(3),
+            RoomOptions {
+                compression: Some(Compression::Lz4),
+                compression_threshold: Some(0),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    recorder_1.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+    recorder_3.messages.lock().unwrap().clear();
+
+    //
+    // Test an incompressible frame at the limit only reaches the plain receiver, as the flag byte pushes it past the limit.
+    //
+
+    let mut data = vec![Room::BROADCAST];
+    data.extend(1..LIMIT as u8);
+
+    client_1
+        .handle_message(&server, Message::Binary(data.clone()))
+        .await;
+
+    data[0] = 0;
+    assert_eq!(data, read_recorded_binary_message!(recorder_3));
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test a compressible frame beyond the limit only reaches the lz4 receiver, as it fits once compressed.
+    //
+
+    let mut data = vec![Room::BROADCAST];
+    data.resize(256, 7);
+
+    client_1
+        .handle_message(&server, Message::Binary(data))
+        .await;
+
+    let compressed = read_recorded_binary_message!(recorder_2);
+    assert!(compressed.len() <= LIMIT);
+    assert_eq!(Room::LZ4, compressed[1]);
+    assert!(recorder_3.is_empty());
+
+    //
+    // Test each skip is counted against its receiver and reported to the sender.
+    //
+
+    client_1.handle_stats(&server).await;
+    read_recorded_message!(recorder_1, ResponsePacket::Stats { members, .. } => {
+        assert_eq!(
+            vec![0, 1, 1],
+            members
+                .iter()
+                .map(|member| member.oversized_frames)
+                .collect::<Vec<_>>()
+        );
+    });
+
+    client_1.handle_dropped_frames().await;
+    read_recorded_message!(recorder_1, ResponsePacket::Dropped { count, reason } => {
+        assert_eq!(2, count);
+        assert_eq!(DropReason::ReceiverLimit, reason);
+    });
+}
+
+///
+/// Test senders are told how many of their frames were dropped, and the dominant reason.
+///
+#[tokio::test]
+async fn unit_dropped_frames() {
+    const FRAMES: u8 = 5;
+
+    let server = Server::new(Config::default()).unwrap();
+    let create_slow_client = || {
+        Client::new(
+            next_connection_id(),
+            Arc::new(Queue::new(
+                SlowRecorder {
+                    recorder: Arc::new(Recorder::default()),
+                    delay: Duration::from_millis(100),
+                },
+                server.try_read().unwrap().outbound_budget.clone(),
+                server.try_read().unwrap().send_policy.clone(),
+                server.try_read().unwrap().clock.clone(),
+            )),
+            "127.0.0.1:0".parse().unwrap(),
+            vec![],
+        )
+    };
+
+    //
+    // Test the dominant reason is reported, and the counters are reset.
+    //
+
+    let dropped = DroppedFrames::default();
+    dropped.record(DropReason::RateLimit);
+    dropped.record(DropReason::Expired);
+    dropped.record(DropReason::Expired);
+
+    assert_eq!(Some((3, DropReason::Expired)), dropped.take());
+    assert_eq!(None, dropped.take());
+
+    //
+    // Test frames dropped by the bandwidth limit.
+    //
+
+    let (mut client, recorder) = create_client();
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                max_bandwidth: Some(100),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Create { .. } => ());
+
+    for _ in 0..FRAMES {
+        client
+            .handle_message(&server, Message::Binary(vec![0; 60]))
+            .await;
+    }
+
+    recorder.messages.lock().unwrap().clear();
+
+    client.handle_dropped_frames().await;
+    read_recorded_message!(recorder, ResponsePacket::Dropped { count, reason } => {
+        assert_eq!(u64::from(FRAMES - 1), count);
+        assert_eq!(DropReason::RateLimit, reason);
+    });
+
+    client.handle_dropped_frames().await;
+    assert!(recorder.is_empty());
+
+    //
+    // Test frames skipped for a congested receiver.
+    //
+
+    let (mut client, recorder) = create_client();
+    let mut slow_client = create_slow_client();
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                congestion_threshold: Some(0),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    slow_client
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    recorder.messages.lock().unwrap().clear();
+
+    for frame in 0..FRAMES {
+        client
+            .handle_message(&server, Message::Binary(vec![Room::BROADCAST, frame]))
+            .await;
+    }
+
+    client.handle_dropped_frames().await;
+    read_recorded_message!(recorder, ResponsePacket::Dropped { count, reason } => {
+        assert_eq!(u64::from(FRAMES - 1), count);
+        assert_eq!(DropReason::ReceiverCongested, reason);
+    });
+
+    //
+    // Test frames which expired in the queue of a stalled receiver.
+    //
+
+    let (mut client, recorder) = create_client();
+    let mut slow_client = create_slow_client();
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                frame_ttl_ms: Some(20),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    slow_client
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    recorder.messages.lock().unwrap().clear();
+
+    let _ = slow_client
+        .sender
+        .send(Message::Ping(vec![]), Priority::High)
+        .await;
+
+    for frame in 0..FRAMES {
+        client
+            .handle_message(&server, Message::Binary(vec![1, frame]))
+            .await;
+    }
+
+    client.handle_dropped_frames().await;
+    assert!(recorder.is_empty());
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+
+    client.handle_dropped_frames().await;
+    read_recorded_message!(recorder, ResponsePacket::Dropped { count, reason } => {
+        assert_eq!(u64::from(FRAMES), count);
+        assert_eq!(DropReason::Expired, reason);
+    });
+}
+
+///
+/// Test transient send errors are skipped, timed out sends mark the queue congested until a send succeeds, and fatal errors or consecutive timeouts fail the queue.
+///
+#[tokio::test]
+async fn unit_send_failures() {
+    let server = Server::new(Config {
+        send_timeout: 50,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let create_queue = || {
+        let sink = FaultyRecorder::default();
+        let queue = Queue::new(
+            sink.clone(),
+            server.try_read().unwrap().outbound_budget.clone(),
+            server.try_read().unwrap().send_policy.clone(),
+            server.try_read().unwrap().clock.clone(),
+        );
+
+        (queue, sink)
+    };
+
+    let send_policy = server.read().await.send_policy.clone();
+
+    macro_rules! wait_for {
+        ($condition:expr) => {
+            while !$condition {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+    }
+
+    //
+    // Test the errors are classified.
+    //
+
+    for (failure, error) in [
+        (SendFailure::Fatal, tungstenite::Error::ConnectionClosed),
+        (SendFailure::Fatal, tungstenite::Error::AlreadyClosed),
+        (
+            SendFailure::Fatal,
+            tungstenite::Error::Io(io::ErrorKind::BrokenPipe.into()),
+        ),
+        (
+            SendFailure::Transient,
+            tungstenite::Error::Io(io::ErrorKind::WouldBlock.into()),
+        ),
+        (
+            SendFailure::Transient,
+            tungstenite::Error::WriteBufferFull(Message::Text(String::new())),
+        ),
+    ] {
+        assert_eq!(failure, SendFailure::classify(&error));
+    }
+
+    //
+    // Test a transient error drops the packet and keeps the queue writing.
+    //
+
+    let (queue, sink) = create_queue();
+    let failed = queue.failed();
+
+    sink.fail(tungstenite::Error::Io(io::ErrorKind::WouldBlock.into()));
+    queue
+        .send(Message::Text("a".into()), Priority::High)
+        .await
+        .unwrap();
+    queue
+        .send(Message::Text("b".into()), Priority::High)
+        .await
+        .unwrap();
+
+    wait_for!(!sink.recorder.is_empty());
+    assert_eq!(
+        "b",
+        sink.recorder.messages.lock().unwrap()[0].to_text().unwrap()
+    );
+    assert_eq!(1, send_policy.transient_errors());
+
+    //
+    // Test a timed out send marks the queue congested until the next send succeeds.
+    //
+
+    sink.stall();
+    queue
+        .send(Message::Text("c".into()), Priority::High)
+        .await
+        .unwrap();
+
+    wait_for!(send_policy.timeouts() == 1);
+    assert!(queue.is_congested());
+
+    queue
+        .send(Message::Text("d".into()), Priority::High)
+        .await
+        .unwrap();
+
+    wait_for!(sink.recorder.messages.lock().unwrap().len() == 2);
+    assert!(!queue.is_congested());
+
+    //
+    // Test only consecutive timeouts fail the queue.
+    //
+
+    for _ in 1..Queue::MAX_SEND_TIMEOUTS {
+        sink.stall();
+    }
+    queue
+        .send(Message::Text("e".into()), Priority::High)
+        .await
+        .unwrap();
+    queue
+        .send(Message::Text("f".into()), Priority::High)
+        .await
+        .unwrap();
+    queue
+        .send(Message::Text("g".into()), Priority::High)
+        .await
+        .unwrap();
+
+    wait_for!(sink.recorder.messages.lock().unwrap().len() == 3);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(10), failed.notified())
+            .await
+            .is_err()
+    );
+
+    for _ in 0..Queue::MAX_SEND_TIMEOUTS {
+        sink.stall();
+        queue
+            .send(Message::Text("h".into()), Priority::High)
+            .await
+            .unwrap();
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), failed.notified())
+        .await
+        .unwrap();
+    assert_eq!(
+        u64::from(Queue::MAX_SEND_TIMEOUTS * 2),
+        send_policy.timeouts()
+    );
+    assert_eq!(0, send_policy.fatal_errors());
+
+    //
+    // Test a fatal error fails the queue at once.
+    //
+
+    let (queue, sink) = create_queue();
+    let failed = queue.failed();
+
+    sink.fail(tungstenite::Error::ConnectionClosed);
+    queue
+        .send(Message::Text("a".into()), Priority::High)
+        .await
+        .unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), failed.notified())
+        .await
+        .unwrap();
+    assert_eq!(1, send_policy.fatal_errors());
+    assert!(sink.recorder.is_empty());
+
+    //
+    // Test the counters are exported.
+    //
+
+    #[cfg(feature = "metrics")]
+    {
+        let metrics = server.read().await.render_metrics();
+        assert!(metrics.contains("relay_fatal_send_errors_total 1\n"));
+        assert!(metrics.contains("relay_transient_send_errors_total 1\n"));
+        assert!(metrics.contains(&format!(
+            "relay_send_timeouts_total {}\n",
+            Queue::MAX_SEND_TIMEOUTS * 2
+        )));
+    }
+}
+
+///
+/// Test closing a queue flushes the frames queued before it, discards those sent after it, and gives up on a stalled writer.
+///
+#[tokio::test]
+async fn unit_queue_close() {
+    let server = Server::new(Config::default()).unwrap();
+    let send_policy = server.read().await.send_policy.clone();
+
+    let create_queue = || {
+        let sink = FaultyRecorder::default();
+        let queue = Queue::new(
+            sink.clone(),
+            server.try_read().unwrap().outbound_budget.clone(),
+            send_policy.clone(),
+            server.try_read().unwrap().clock.clone(),
+        );
+
+        (queue, sink)
+    };
+
+    let close_frame = CloseFrame {
+        code: CloseCode::Normal,
+        reason: "".into(),
+    };
+
+    //
+    // Test the queued frames are written before the close frame.
+    //
+
+    let (queue, sink) = create_queue();
+    let dropped = Arc::new(DroppedFrames::default());
+
+    queue
+        .send_frame(Message::Binary(vec![1]), None, None, &dropped)
+        .await
+        .unwrap();
+    queue
+        .send(Message::Text("a".into()), Priority::High)
+        .await
+        .unwrap();
+    queue.close(Some(close_frame.clone())).await.unwrap();
+
+    {
+        let messages = sink.recorder.messages.lock().unwrap();
+        assert_eq!(3, messages.len());
+        assert!(messages.contains(&Message::Binary(vec![1])));
+        assert!(messages.contains(&Message::Text("a".into())));
+        assert_eq!(Message::Close(Some(close_frame.clone())), messages[2]);
+    }
+
+    //
+    // Test the sends after the close are discarded without an error.
+    //
+
+    queue
+        .send(Message::Text("b".into()), Priority::High)
+        .await
+        .unwrap();
+    queue
+        .send_frame(Message::Binary(vec![2]), None, None, &dropped)
+        .await
+        .unwrap();
+    queue.close(Some(close_frame.clone())).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(3, sink.recorder.messages.lock().unwrap().len());
+    assert_eq!(0, queue.queued_bytes());
+
+    //
+    // Test the sends after the queue failed are discarded without an error.
+    //
+
+    let (queue, sink) = create_queue();
+    let failed = queue.failed();
+
+    sink.fail(tungstenite::Error::ConnectionClosed);
+    queue
+        .send(Message::Text("a".into()), Priority::High)
+        .await
+        .unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), failed.notified())
+        .await
+        .unwrap();
+
+    queue
+        .send(Message::Text("b".into()), Priority::High)
+        .await
+        .unwrap();
+    queue.close(Some(close_frame.clone())).await.unwrap();
+    assert!(sink.recorder.is_empty());
+    assert_eq!(1, send_policy.fatal_errors());
+
+    //
+    // Test a send after the peer closed the connection quietly stops the queue.
+    //
+
+    let (queue, sink) = create_queue();
+    let failed = queue.failed();
+
+    sink.fail(tungstenite::Error::Protocol(
+        ProtocolError::SendAfterClosing,
+    ));
+    queue
+        .send(Message::Text("a".into()), Priority::High)
+        .await
+        .unwrap();
+
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), failed.notified())
+            .await
+            .is_err()
+    );
+    assert_eq!(1, send_policy.fatal_errors());
+    assert_eq!(0, send_policy.transient_errors());
+
+    queue
+        .send(Message::Text("b".into()), Priority::High)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(sink.recorder.is_empty());
+
+    //
+    // Test closing a stalled queue gives up after the close timeout.
+    //
+
+    let (queue, sink) = create_queue();
+
+    sink.stall();
+    queue
+        .send(Message::Text("a".into()), Priority::High)
+        .await
+        .unwrap();
+
+    let started_at = Instant::now();
+    queue.close(Some(close_frame)).await.unwrap();
+
+    assert!(started_at.elapsed() >= Queue::CLOSE_TIMEOUT);
+    assert!(started_at.elapsed() < Queue::CLOSE_TIMEOUT * 2);
+    assert!(sink.recorder.is_empty());
+}
+
+///
+/// Test a client disconnecting while it is being sent broadcasts is closed promptly without send errors.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn close_racing_broadcast() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let send_policy = test_server.server.read().await.send_policy.clone();
+
+    let mut socket_1 = test_server.connect_to("/create");
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    let mut socket_2 = test_server.connect_to(&format!("/room/{}", room_id));
+    read_message!(socket_2, ResponsePacket::Join { index: Some(1), .. } => ());
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test the first client closes while the second client floods it with broadcasts.
+    //
+
+    let broadcaster = std::thread::spawn(move || {
+        for _ in 0..2000 {
+            write_binary_message!(socket_2, vec![Room::BROADCAST; 256]);
+        }
+
+        socket_2
+    });
+
+    read_binary_message!(socket_1);
+
+    let started_at = Instant::now();
+    close_socket!(socket_1);
+    assert!(started_at.elapsed() < Duration::from_secs(1));
+
+    let mut socket_2 = broadcaster.join().unwrap();
+
+    //
+    // Test the first client is removed without any send errors, and the room keeps working.
+    //
+
+    read_message!(socket_2, ResponsePacket::Leave { index: 0, .. } => ());
+
+    assert_eq!(0, send_policy.fatal_errors());
+    assert_eq!(0, send_policy.transient_errors());
+    assert_eq!(
+        1,
+        test_server.server.read().await.rooms[&room_id]
+            .members
+            .len()
+    );
+
+    close_socket!(socket_2);
+}
+
+///
+/// Test the outbound budget is shared by every queue, drops frames for the most backlogged receivers first, and recovers once queues drain.
+///
+#[tokio::test]
+async fn unit_outbound_budget() {
+    const FRAME: usize = 30;
+
+    let server = Server::new(Config {
+        max_outbound_bytes: 100,
+        ..Default::default()
+    })
+    .unwrap();
+    let (mut client, recorder) = create_client();
+    let create_slow_client = || {
+        Client::new(
+            next_connection_id(),
+            Arc::new(Queue::new(
+                SlowRecorder {
+                    recorder: Arc::new(Recorder::default()),
+                    delay: Duration::from_millis(100),
+                },
+                server.try_read().unwrap().outbound_budget.clone(),
+                server.try_read().unwrap().send_policy.clone(),
+                server.try_read().unwrap().clock.clone(),
+            )),
+            "127.0.0.1:0".parse().unwrap(),
+            vec![],
+        )
+    };
+    let mut slow_client = create_slow_client();
+    let mut slow_client_2 = create_slow_client();
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    for slow_client in [&mut slow_client, &mut slow_client_2] {
+        slow_client
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+    }
+
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    recorder.messages.lock().unwrap().clear();
+
+    let outbound_budget = server.read().await.outbound_budget.clone();
+    assert_eq!(0, outbound_budget.used());
+
+    //
+    // Test a broadcast which exceeds the budget is dropped for the most backlogged receiver only.
+    //
+
+    let mut frame = vec![0; FRAME];
+    frame[0] = 1;
+
+    for _ in 0..2 {
+        client
+            .handle_message(&server, Message::Binary(frame.clone()))
+            .await;
+    }
+
+    frame[0] = Room::BROADCAST;
+    client
+        .handle_message(&server, Message::Binary(frame.clone()))
+        .await;
+
+    assert_eq!(FRAME * 2, slow_client.sender.queued_bytes());
+    assert_eq!(FRAME, slow_client_2.sender.queued_bytes());
+    assert_eq!(FRAME * 3, outbound_budget.used());
+    assert_eq!(1, outbound_budget.dropped_frames());
+    #[cfg(feature = "metrics")]
+    assert!(server
+        .read()
+        .await
+        .render_metrics()
+        .contains("relay_budget_dropped_frames_total 1\n"));
+
+    client.handle_dropped_frames().await;
+    read_recorded_message!(recorder, ResponsePacket::Dropped { count, reason } => {
+        assert_eq!(1, count);
+        assert_eq!(DropReason::BudgetExhausted, reason);
+    });
+
+    //
+    // Test the budget is released as the queues drain, and broadcasts reach every receiver again.
+    //
+
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    assert_eq!(0, outbound_budget.used());
+
+    client
+        .handle_message(&server, Message::Binary(frame.clone()))
+        .await;
+
+    assert_eq!(FRAME, slow_client.sender.queued_bytes());
+    assert_eq!(FRAME, slow_client_2.sender.queued_bytes());
+    assert_eq!(1, outbound_budget.dropped_frames());
+
+    //
+    // Test the budget is released when a backlogged receiver is torn down.
+    //
+
+    slow_client_2
+        .handle_close(&server, RemovalReason::Disconnected)
+        .await;
+    drop(slow_client_2);
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert_eq!(0, outbound_budget.used());
+}
+
+///
+/// Test frames on a tagged channel are conflated in a stalled queue, while untagged frames are all delivered.
+///
+#[tokio::test]
+async fn unit_conflation() {
+    const UPDATES: u8 = 5;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+
+    let slow_recorder = Arc::new(Recorder::default());
+    let mut slow_client = Client::new(
+        next_connection_id(),
+        Arc::new(Queue::new(
+            SlowRecorder {
+                recorder: slow_recorder.clone(),
+                delay: Duration::from_millis(50),
+            },
+            server.read().await.outbound_budget.clone(),
+            server.read().await.send_policy.clone(),
+            server.read().await.clock.clone(),
+        )),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    //
+    // Create a conflating room with a stalled member.
+    //
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                conflate: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    slow_client
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    slow_recorder.messages.lock().unwrap().clear();
+    recorder.messages.lock().unwrap().clear();
+
+    //
+    // Test frames without a channel are rejected.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![1]))
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidFrame)));
+
+    //
+    // Stall the member, then interleave position updates on a tagged channel with reliable frames.
+    //
+
+    let _ = slow_client
+        .sender
+        .send(Message::Ping(vec![]), Priority::High)
+        .await;
+
+    for update in 0..UPDATES {
+        client
+            .handle_message(&server, Message::Binary(vec![1, 1, update]))
+            .await;
+        client
+            .handle_message(&server, Message::Binary(vec![1, 0, update]))
+            .await;
+    }
+
+    client
+        .handle_message(&server, Message::Binary(vec![1, 2, UPDATES]))
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+
+    //
+    // Test only the newest update on each channel arrives, and every reliable frame arrives in order.
+    //
+
+    assert!(slow_recorder.messages.lock().unwrap().remove(0).is_ping());
+
+    for update in 0..UPDATES - 1 {
+        assert_eq!(
+            vec![0, 0, update],
+            read_recorded_binary_message!(slow_recorder)
+        );
+    }
+
+    assert_eq!(
+        vec![0, 1, UPDATES - 1],
+        read_recorded_binary_message!(slow_recorder)
+    );
+    assert_eq!(
+        vec![0, 0, UPDATES - 1],
+        read_recorded_binary_message!(slow_recorder)
+    );
+    assert_eq!(
+        vec![0, 2, UPDATES],
+        read_recorded_binary_message!(slow_recorder)
+    );
+    assert!(slow_recorder.is_empty());
+
+    client.handle_stats(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Stats { members, .. } => {
+        assert_eq!(0, members[0].conflated_frames);
+        assert_eq!(u64::from(UPDATES - 1), members[1].conflated_frames);
+    });
+}
+
+///
+/// Test querying room information without joining, including the rate limit.
+///
+#[tokio::test]
+async fn unit_info() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    //
+    // Test information about an existing room.
+    //
+
+    client
+        .handle_create_room(&server, Some(4), RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2.handle_info(&server, room_id.clone()).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Info { exists, occupancy, size, locked, .. } => {
+        assert!(exists);
+        assert_eq!(1, occupancy);
+        assert_eq!(4, size);
+        assert!(!locked);
+    });
+
+    client.handle_lock_room(&server, true).await;
+    read_recorded_message!(recorder, ResponsePacket::Locked { .. } => ());
+
+    client_2.handle_info(&server, room_id.clone()).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Info { locked, .. } => assert!(locked));
+
+    assert_eq!(None, client_2.room_id);
+
+    //
+    // Test information about a non-existent room.
+    //
+
+    client_2.handle_info(&server, String::new()).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Info { exists, occupancy, size, locked, .. } => {
+        assert!(!exists);
+        assert_eq!(0, occupancy);
+        assert_eq!(0, size);
+        assert!(!locked);
+    });
+
+    //
+    // Test the rate limit once the burst is exhausted.
+    //
+
+    for _ in 0..7 {
+        client_2.handle_info(&server, String::new()).await;
+        read_recorded_message!(recorder_2, ResponsePacket::Info { .. } => ());
+    }
+
+    client_2.handle_info(&server, room_id).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::RateLimited));
+        assert_eq!(Some(String::from("info")), detail);
+    });
+
+    assert!(recorder.is_empty());
+}
+
+///
+/// Test the statistics and queue depths of a room are refused outside of a room, including after leaving it.
+///
+#[tokio::test]
+async fn unit_statistics_outside_room() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+
+    macro_rules! assert_not_in_room {
+        () => {
+            client.handle_stats(&server).await;
+            read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => {
+                assert_eq!(Error::NotInRoom, message);
+            });
+
+            client.handle_queue_depths(&server).await;
+            read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => {
+                assert_eq!(Error::NotInRoom, message);
+            });
+        };
+    }
+
+    assert_not_in_room!();
+
+    client
+        .handle_create_room(&server, Some(2), RoomOptions::default(), None)
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Create { .. } => ());
+
+    client.handle_stats(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Stats { .. } => ());
+
+    client.handle_leave_room(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Left => ());
+
+    assert_not_in_room!();
+    assert!(recorder.is_empty());
+}
+
+///
+/// Test the queue depths of a room report a stalled receiver, including the rate limit.
+///
+#[tokio::test]
+async fn unit_queue_depths() {
+    const FRAME: usize = 1000;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    let sink = FaultyRecorder::default();
+    let mut stalled_client = Client::new(
+        next_connection_id(),
+        Arc::new(Queue::new(
+            sink.clone(),
+            server.read().await.outbound_budget.clone(),
+            server.read().await.send_policy.clone(),
+            server.read().await.clock.clone(),
+        )),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    //
+    // Test an error is sent outside of a room.
+    //
+
+    client.handle_queue_depths(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => {
+        assert_eq!(Error::NotInRoom, message);
+    });
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    sink.stall();
+    stalled_client
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_2
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+    recorder.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+
+    //
+    // Test the stalled receiver is reported as backed up while the others are not.
+    //
+
+    let mut frame = vec![0; FRAME];
+    frame[0] = Room::BROADCAST;
+
+    for _ in 0..3 {
+        client
+            .handle_message(&server, Message::Binary(frame.clone()))
+            .await;
+    }
+
+    assert_eq!(3, recorder_2.messages.lock().unwrap().drain(..).count());
+
+    client_2.handle_queue_depths(&server).await;
+    read_recorded_message!(recorder_2, ResponsePacket::QueueDepths { depths } => {
+        assert_eq!(3, depths.len());
+        assert_eq!(0, depths[0]);
+        assert!(depths[1] >= 3 * FRAME as u32);
+        assert_eq!(0, depths[2]);
+    });
+
+    //
+    // Test the rate limit once the burst is exhausted.
+    //
+
+    for _ in 0..9 {
+        client_2.handle_queue_depths(&server).await;
+        read_recorded_message!(recorder_2, ResponsePacket::QueueDepths { .. } => ());
+    }
+
+    client_2.handle_queue_depths(&server).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message, detail } => {
+        assert_eq!(Error::RateLimited, message);
+        assert_eq!(Some(String::from("queueDepths")), detail);
+    });
+
+    assert!(recorder.is_empty());
+}
+
+///
+/// Test the quality of a room aggregates the latest report of each member with the counters of the server, including the rate limit.
+///
+#[tokio::test]
+async fn unit_quality() {
+    let path = std::env::temp_dir().join(format!("relay-audit-{}.log", uuid::Uuid::new_v4()));
+    let server = Server::new(Config {
+        audit_log: Some(path.clone()),
+        audit_quality: true,
+        ..Default::default()
+    })
+    .unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    let sink = FaultyRecorder::default();
+    let mut faulty_client = Client::new(
+        next_connection_id(),
+        Arc::new(Queue::new(
+            sink.clone(),
+            server.read().await.outbound_budget.clone(),
+            server.read().await.send_policy.clone(),
+            server.read().await.clock.clone(),
+        )),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    let report = QualityReport {
+        received: 10,
+        lost: 1,
+        jitter_ms: 5,
+    };
+    let report_2 = QualityReport {
+        received: 20,
+        lost: 4,
+        jitter_ms: 40,
+    };
+
+    //
+    // Test a report is refused outside of a room.
+    //
+
+    let (mut client_3, recorder_3) = create_client();
+
+    client_3.handle_quality_report(&server, report).await;
+    read_recorded_message!(recorder_3, ResponsePacket::Error { message, .. } => {
+        assert_eq!(Error::NotInRoom, message);
+    });
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    faulty_client
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    recorder.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+
+    //
+    // Test a failed send to a member is counted by the server.
+    //
+
+    sink.fail(tungstenite::Error::Io(io::ErrorKind::WouldBlock.into()));
+    client
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 1]))
+        .await;
+    recorder_2.messages.lock().unwrap().clear();
+
+    while faulty_client
+        .sender
+        .statistics()
+        .send_failures
+        .load(Ordering::Relaxed)
+        == 0
+    {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    //
+    // Test two members report, and the owner is given both reports with the counters of every member.
+    //
+
+    let text = serde_json::to_string(&RequestPacket::QualityReport {
+        received: report.received,
+        lost: report.lost,
+        jitter_ms: report.jitter_ms,
+    })
+    .unwrap();
+    client.handle_message(&server, Message::Text(text)).await;
+    client_2.handle_quality_report(&server, report_2).await;
+    assert!(recorder.is_empty());
+    assert!(recorder_2.is_empty());
+
+    client.handle_quality(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Quality { members } => {
+        assert_eq!(3, members.len());
+
+        assert_eq!(0, members[0].index);
+        assert_eq!(Some(report), members[0].report);
+        assert!(members[0].reported_at.is_some());
+
+        assert_eq!(1, members[1].index);
+        assert_eq!(Some(report_2), members[1].report);
+        assert!(members[1].reported_at.is_some());
+
+        assert!(matches!(
+            members[2],
+            MemberQuality {
+                index: 2,
+                report: None,
+                reported_at: None,
+                send_failures: 1,
+                ..
+            }
+        ));
+    });
+
+    //
+    // Test only the owner can fetch the quality of the room.
+    //
+
+    client_2.handle_quality(&server).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message, .. } => {
+        assert_eq!(Error::NotOwner, message);
+    });
+
+    //
+    // Test the rate limit of reports.
+    //
+
+    client.handle_quality_report(&server, report_2).await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, detail } => {
+        assert_eq!(Error::RateLimited, message);
+        assert_eq!(Some(String::from("qualityReport")), detail);
+    });
+
+    client.handle_quality(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Quality { members } => {
+        assert_eq!(Some(report), members[0].report);
+    });
+
+    //
+    // Test both reports are recorded in the audit log.
+    //
+
+    let lines = read_audit_log(&path, 5).await;
+    let reports: Vec<_> = lines
+        .iter()
+        .filter(|line| line["event"] == "quality")
+        .map(|line| (line["index"].clone(), line["quality"]["jitterMs"].clone()))
+        .collect();
+
+    assert_eq!(vec![(json!(0), json!(5)), (json!(1), json!(40))], reports);
+
+    fs::remove_file(&path).unwrap();
+}
+
+///
+/// Test members are identified by their client ID rather than their sender, so clients sharing a sender are distinct members.
+///
+#[tokio::test]
+async fn unit_client_id() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let mut client_2 = Client::new(
+        next_connection_id(),
+        client.sender.clone(),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Join { index, .. } => assert_eq!(Some(1), index));
+
+    {
+        let server = server.read().await;
+        let room = &server.rooms[&room_id];
+
+        assert_eq!(Some(0), room.position(client.client_id()));
+        assert_eq!(Some(1), room.position(client_2.client_id()));
+        assert!(room.is_owner(client.client_id()));
+        assert!(!room.is_owner(client_2.client_id()));
+        assert_eq!(Some(&room_id), server.room_of(client_2.client_id()));
+    }
+
+    //
+    // Test leaving only removes the member with the client ID of the client.
+    //
+
+    client_2.handle_leave_room(&server).await;
+
+    let server = server.read().await;
+    let room = &server.rooms[&room_id];
+
+    assert_eq!(1, room.members.len());
+    assert_eq!(Some(0), room.position(client.client_id()));
+    assert_eq!(None, server.room_of(client_2.client_id()));
+}
+
+///
+/// Test destinations resolve to the members they route to, without sockets.
+///
+#[test]
+fn unit_resolve() {
+    let mut room = Room::new(5, RoomOptions::default(), Instant::now());
+
+    for client_id in 0..4 {
+        let mut member = create_member(client_id);
+        member.virtual_address = Some(format!("p{}", client_id));
+
+        room.add(member);
+    }
+
+    room.members[3].sources = Some(std::collections::BTreeSet::from([room.members[1].id]));
+
+    let resolve = |room: &Room, source: usize, destination: frame::Destination| {
+        room.resolve(source, destination).map(|members| {
+            members
+                .iter()
+                .map(|member| member.client_id.0)
+                .collect::<Vec<_>>()
+        })
+    };
+
+    //
+    // Test unicasts reach any index in the room, and nothing beyond it.
+    //
+
+    assert_eq!(
+        Some(vec![1]),
+        resolve(&room, 0, frame::Destination::Index(1))
+    );
+    assert_eq!(
+        Some(vec![0]),
+        resolve(&room, 0, frame::Destination::Index(0))
+    );
+    assert_eq!(
+        Some(vec![3]),
+        resolve(&room, 0, frame::Destination::Index(3))
+    );
+    assert_eq!(None, resolve(&room, 0, frame::Destination::Index(4)));
+    assert_eq!(
+        None,
+        resolve(&room, 0, frame::Destination::Index(frame::MAX_INDEX))
+    );
+
+    //
+    // Test packets to the owner reach nobody without an owner, or when sent by the owner.
+    //
+
+    assert_eq!(Some(vec![]), resolve(&room, 0, frame::Destination::Owner));
+
+    room.owner = Some(2);
+    assert_eq!(Some(vec![2]), resolve(&room, 0, frame::Destination::Owner));
+    assert_eq!(Some(vec![]), resolve(&room, 2, frame::Destination::Owner));
+
+    //
+    // Test broadcasts skip the sender, and members which are not subscribed to it.
+    //
+
+    assert_eq!(
+        Some(vec![1, 2]),
+        resolve(&room, 0, frame::Destination::Broadcast)
+    );
+    assert_eq!(
+        Some(vec![0, 2, 3]),
+        resolve(&room, 1, frame::Destination::Broadcast)
+    );
+    assert_eq!(
+        Some(vec![1, 2]),
+        resolve(&room, 0, frame::Destination::Address(b""))
+    );
+
+    //
+    // Test exclusions skip the listed indices, and ignore duplicates and indices beyond the room.
+    //
+
+    assert_eq!(
+        Some(vec![0, 3]),
+        resolve(&room, 1, frame::Destination::Exclude(&[2, 2, 200]))
+    );
+    assert_eq!(
+        Some(vec![0, 2, 3]),
+        resolve(&room, 1, frame::Destination::Exclude(&[1]))
+    );
+    assert_eq!(
+        Some(vec![]),
+        resolve(&room, 1, frame::Destination::Exclude(&[0, 2, 3]))
+    );
+
+    //
+    // Test virtual addresses reach their member, and nothing when they are unknown.
+    //
+
+    assert_eq!(
+        Some(vec![2]),
+        resolve(&room, 0, frame::Destination::Address(b"p2"))
+    );
+    assert_eq!(None, resolve(&room, 0, frame::Destination::Address(b"p4")));
+}
+
+///
+/// Test a client ID cannot be added to the same room twice.
+///
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "Client 1 is already a member of the room")]
+fn unit_duplicate_member() {
+    let mut room = Room::new(2, RoomOptions::default(), Instant::now());
+
+    for _ in 0..2 {
+        room.add(create_member(1));
+    }
+}
+
+///
+/// Test a client ID cannot be a member of more than one room.
+///
+#[tokio::test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "Client 1 is a member of more than one room")]
+async fn unit_member_of_two_rooms() {
+    let server = Server::new(Config::default()).unwrap();
+    let mut server = server.write().await;
+
+    for room_id in ["a", "b"] {
+        let mut room = Room::new(2, RoomOptions::default(), Instant::now());
+        room.add(create_member(1));
+
+        server.rooms.insert(String::from(room_id), room);
+    }
+
+    server.room_of(ClientId(1));
+}
+
+///
+/// Test random sequences of joins, leaves, locks, reservations and removals keep the invariants of a room.
+///
+#[tokio::test]
+async fn unit_room_mutations() {
+    const CLIENTS: u64 = 8;
+    const STEPS: usize = 256;
+
+    let server = Server::new(Config::default()).unwrap();
+
+    for seed in 0..64 {
+        let rng = SeededRng::install(&server, seed).await;
+        let random = |bound: u64| (rng.next_u64() % bound) as usize;
+
+        let mut now = Instant::now();
+        let mut room = Room::new(1 + random(6), RoomOptions::default(), now);
+        let mut reservations: Vec<String> = vec![];
+
+        for _ in 0..STEPS {
+            now += Duration::from_secs(random(8) as u64);
+
+            let client_id = ClientId(random(CLIENTS) as u64);
+            let member = room.position(client_id).is_some();
+            let owner = room.is_owner(client_id);
+            let occupancy = room.members.len();
+            let locked = room.locked;
+
+            let mutation = match random(6) {
+                0 | 1 if !member => Mutation::Join {
+                    member: Box::new(create_member(client_id.0)),
+                    reservation: (!reservations.is_empty() && random(2) == 0)
+                        .then(|| reservations[random(reservations.len() as u64)].clone()),
+                },
+                0 | 1 => Mutation::Leave { client_id },
+                2 => Mutation::Lock {
+                    client_id,
+                    locked: random(2) == 0,
+                },
+                3 => Mutation::Reserve { count: random(3) },
+                4 if random(4) == 0 => Mutation::RemoveAll,
+                _ if !reservations.is_empty() => Mutation::Unreserve {
+                    reservation: reservations.swap_remove(random(reservations.len() as u64)),
+                },
+                _ => Mutation::Leave { client_id },
+            };
+
+            let effects = room.apply(mutation, &*rng, now);
+            assert_eq!(None, room.violation(now), "seed {}", seed);
+
+            //
+            // Test each effect agrees with the state of the room after the mutation.
+            //
+
+            match effects {
+                Ok(effects) => {
+                    for effect in effects {
+                        match effect {
+                            Effect::Joined { index, .. } => {
+                                assert!(!locked);
+                                assert_eq!(occupancy + 1, room.members.len());
+                                assert_eq!(Some(index), room.position(client_id));
+                            }
+                            Effect::Left { .. } => {
+                                assert_eq!(occupancy - 1, room.members.len());
+                                assert_eq!(None, room.position(client_id));
+                            }
+                            Effect::Locked { locked, recipients } => {
+                                assert!(owner);
+                                assert_eq!(locked, room.locked);
+                                assert!(!recipients.is_empty());
+                            }
+                            Effect::Reserved { reservation } => {
+                                assert!(!locked);
+                                reservations.push(reservation);
+                            }
+                            Effect::Removed { members } => {
+                                assert_eq!(occupancy, members.len());
+                                assert!(room.members.is_empty());
+                                reservations.clear();
+                            }
+                            Effect::Notify(..) => {}
+                        }
+                    }
+                }
+                Err(Error::IsFull) => assert_eq!(occupancy, room.members.len()),
+                Err(Error::IsLocked) => assert!(locked),
+                Err(Error::NotOwner) => assert!(!owner),
+                Err(Error::InvalidSize) => {}
+                Err(error) => panic!("unexpected error {:?} for seed {}", error, seed),
+            }
+        }
+    }
+}
+
+///
+/// Test opaque rooms refuse options which modify payloads and relay payloads unchanged.
+///
+#[tokio::test]
+async fn unit_opaque() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    //
+    // Test timestamps cannot be enabled on an opaque room.
+    //
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                opaque: true,
+                timestamps: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+
+    read_recorded_message!(recorder, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::InvalidOptions));
+        assert_eq!(Some(String::from("timestamps")), detail);
+    });
+
+    //
+    // Test compression cannot be enabled on an opaque room.
+    //
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                opaque: true,
+                compression: Some(Compression::Lz4),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+
+    read_recorded_message!(recorder, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::InvalidOptions));
+        assert_eq!(Some(String::from("compression")), detail);
+    });
+
+    assert_eq!(None, client.room_id);
+    assert!(server.read().await.rooms.is_empty());
+
+    //
+    // Test payloads are relayed unchanged.
+    //
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                opaque: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+
+    client
+        .handle_message(&server, Message::Binary(vec![1, 0, 255, 7]))
+        .await;
+    assert_eq!(
+        vec![0, 0, 255, 7],
+        read_recorded_binary_message!(recorder_2)
+    );
+}
+
+///
+/// Test the store of a room, including the snapshot sent to joining clients and the limits.
+///
+#[tokio::test]
+async fn unit_store() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    //
+    // Test setting while not inside a room.
+    //
+
+    client
+        .handle_set(&server, String::from("map"), json!("dust"))
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test only the owner can set when the store is not open.
+    //
+
+    client_2
+        .handle_set(&server, String::from("map"), json!("dust"))
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotOwner)));
+
+    client
+        .handle_set(&server, String::from("map"), json!("dust"))
+        .await;
+
+    for recorder in [&recorder, &recorder_2] {
+        read_recorded_message!(recorder, ResponsePacket::Set { key, value, source } => {
+            assert_eq!("map", key);
+            assert_eq!(json!("dust"), value);
+            assert_eq!(0, source);
+        });
+    }
+
+    //
+    // Test getting present and missing keys.
+    //
+
+    client_2.handle_get(&server, String::from("map")).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Get { key, value } => {
+        assert_eq!("map", key);
+        assert_eq!(json!("dust"), value);
+    });
+
+    client_2.handle_get(&server, String::from("score")).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Get { value, .. } => assert!(value.is_null()));
+
+    //
+    // Test joining clients receive a snapshot right after the join response.
+    //
+
+    client_3
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder_3, ResponsePacket::Store { entries } => {
+        assert_eq!(1, entries.len());
+        assert_eq!(Some(&json!("dust")), entries.get("map"));
+    });
+
+    recorder.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+
+    //
+    // Test the size limit.
+    //
+
+    let value = json!("x".repeat(Room::MAX_STORE_BYTES));
+
+    client.handle_set(&server, String::from("big"), value).await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::StoreFull)));
+
+    //
+    // Test the key limit, where replacing and removing keys is still allowed.
+    //
+
+    for index in 1..Room::MAX_STORE_KEYS {
+        client
+            .handle_set(&server, index.to_string(), json!(index))
+            .await;
+    }
+
+    client
+        .handle_set(&server, String::from("extra"), json!(0))
+        .await;
+    client
+        .handle_set(&server, String::from("map"), json!("nuke"))
+        .await;
+    client
+        .handle_set(&server, String::from("map"), json!(null))
+        .await;
+    client
+        .handle_set(&server, String::from("extra"), json!(0))
+        .await;
+
+    for _ in 1..Room::MAX_STORE_KEYS {
+        read_recorded_message!(recorder, ResponsePacket::Set { .. } => ());
+    }
+
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::StoreFull)));
+    read_recorded_message!(recorder, ResponsePacket::Set { value, .. } => assert_eq!(json!("nuke"), value));
+    read_recorded_message!(recorder, ResponsePacket::Set { value, .. } => assert!(value.is_null()));
+    read_recorded_message!(recorder, ResponsePacket::Set { key, .. } => assert_eq!("extra", key));
+
+    let room = &server.read().await.rooms[&room_id];
+    assert_eq!(Room::MAX_STORE_KEYS, room.store.len());
+    assert!(room.store_bytes <= Room::MAX_STORE_BYTES);
+}
+
+///
+/// Test leaving a room without a socket, including rooms that have vanished.
+///
+#[tokio::test]
+async fn unit_leave_room() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    //
+    // Test leaving while not inside a room.
+    //
+
+    client.handle_leave_room(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
 
-    use std::net::SocketAddr;
-    use tokio::net::TcpListener;
-    use tungstenite::{client::IntoClientRequest, connect, http::HeaderValue, Message};
+    //
+    // Test leaving a room.
+    //
 
-    macro_rules! create_socket {
-        ($value:expr) => {{
-            let (socket, _) = connect(format!("ws://{}", $value)).unwrap();
-            socket
-        }};
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+
+    client.handle_leave_room(&server).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Leave { index, .. } => assert_eq!(0, index));
+    read_recorded_message!(recorder, ResponsePacket::Left => ());
+
+    assert_eq!(None, client.room_id);
+    assert_eq!(1, server.read().await.rooms[&room_id].members.len());
+
+    //
+    // Test leaving a room that was removed between lock acquisitions.
+    //
+
+    server.write().await.rooms.remove(&room_id);
+
+    client_2.handle_leave_room(&server).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Left => ());
+
+    assert_eq!(None, client_2.room_id);
+    assert!(recorder.is_empty());
+
+    client_2.handle_leave_room(&server).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
+
+    //
+    // Test leaving a room which does not contain the sender.
+    //
+
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    server
+        .write()
+        .await
+        .rooms
+        .get_mut(&room_id)
+        .unwrap()
+        .members
+        .clear();
+
+    client.handle_leave_room(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Left => ());
+
+    assert_eq!(None, client.room_id);
+    assert!(server.read().await.rooms.contains_key(&room_id));
+}
+
+///
+/// Test the binary dispatch logic without a socket.
+///
+#[tokio::test]
+async fn unit_binary_dispatch() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    //
+    // Test sending while not inside a room.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![0, 1, 2, 3]))
+        .await;
+    assert!(recorder.is_empty());
+
+    //
+    // Setup a room with two clients.
+    //
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    read_recorded_message!(recorder_2, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder, ResponsePacket::Join { .. } => ());
+
+    //
+    // Test unicast, broadcast, and an out-of-range destination.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![1, 1, 2, 3]))
+        .await;
+    assert_eq!(vec![0, 1, 2, 3], read_recorded_binary_message!(recorder_2));
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![u8::MAX, 4, 5]))
+        .await;
+    assert_eq!(vec![1, 4, 5], read_recorded_binary_message!(recorder));
+    assert!(recorder_2.is_empty());
+
+    client
+        .handle_message(&server, Message::Binary(vec![2, 1, 2, 3]))
+        .await;
+    assert!(recorder.is_empty());
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test sending to the owner.
+    //
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![Room::OWNER, 6]))
+        .await;
+    assert_eq!(vec![1, 6], read_recorded_binary_message!(recorder));
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test an empty payload.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![]))
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidFrame)));
+    assert!(recorder.is_empty());
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test sending from a sender which is not inside the room.
+    //
+
+    server
+        .write()
+        .await
+        .rooms
+        .get_mut(&room_id)
+        .unwrap()
+        .members
+        .remove(0);
+
+    client
+        .handle_message(&server, Message::Binary(vec![u8::MAX, 1, 2, 3]))
+        .await;
+    assert!(recorder_2.is_empty());
+
+    //
+    // Test sending to a room which has vanished.
+    //
+
+    server.write().await.rooms.remove(&room_id);
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![u8::MAX, 1, 2, 3]))
+        .await;
+    assert!(recorder.is_empty());
+}
+
+///
+/// Test empty frames are rejected, and frames containing only a destination are relayed without data.
+///
+#[tokio::test]
+async fn unit_frame_sizes() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    recorder.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+    recorder_3.messages.lock().unwrap().clear();
+
+    //
+    // Test an empty frame is rejected.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![]))
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::InvalidFrame)));
+
+    assert!(recorder.is_empty());
+    assert!(recorder_2.is_empty());
+    assert!(recorder_3.is_empty());
+
+    //
+    // Test a unicast frame without data.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![1]))
+        .await;
+    assert_eq!(vec![0], read_recorded_binary_message!(recorder_2));
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![Room::OWNER]))
+        .await;
+    assert_eq!(vec![1], read_recorded_binary_message!(recorder));
+
+    assert!(recorder_3.is_empty());
+
+    //
+    // Test a broadcast frame without data.
+    //
+
+    client_3
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST]))
+        .await;
+    assert_eq!(vec![2], read_recorded_binary_message!(recorder));
+    assert_eq!(vec![2], read_recorded_binary_message!(recorder_2));
+
+    assert!(recorder.is_empty());
+    assert!(recorder_2.is_empty());
+    assert!(recorder_3.is_empty());
+}
+
+///
+/// Test every roster sent during a churn of joins and leaves is consistent with the source of later frames.
+///
+#[tokio::test]
+async fn unit_roster() {
+    const STEPS: usize = 200;
+    const SIZE: usize = 4;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+
+    client
+        .handle_create_room(
+            &server,
+            Some(SIZE),
+            RoomOptions {
+                roster: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Churn through members, broadcasting the identifier of each member after every change.
+    //
+
+    let mut members = vec![(client, recorder, 0u64)];
+    let mut recorders = vec![];
+    let mut next_id = 1;
+    let mut seed = 7u64;
+
+    for _ in 0..STEPS {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let choice = (seed >> 33) as usize;
+
+        if members.len() == 1 || (members.len() < SIZE && choice.is_multiple_of(2)) {
+            let (mut client, recorder) = create_client();
+
+            client
+                .handle_join_room(&server, room_id.clone(), None, None, None)
+                .await;
+            members.push((client, recorder, next_id));
+            next_id += 1;
+        } else {
+            let (mut client, recorder, _) = members.remove(choice % members.len());
+
+            client.handle_leave_room(&server).await;
+            recorders.push(recorder);
+        }
+
+        for (client, _, id) in &mut members {
+            let frame = [vec![Room::BROADCAST], id.to_be_bytes().to_vec()].concat();
+
+            client.handle_message(&server, Message::Binary(frame)).await;
+        }
     }
 
-    macro_rules! close_socket {
-        ($value:expr) => {{
-            $value.close(None).unwrap();
-            loop {
-                if $value.read().is_err() {
-                    break;
+    //
+    // Test every roster is contiguous and ordered, and matches the source of every later frame.
+    //
+
+    let final_roster = server.read().await.rooms[&room_id].roster();
+    let departed = recorders.len();
+    recorders.extend(members.into_iter().map(|(_, recorder, _)| recorder));
+
+    for (position, recorder) in recorders.into_iter().enumerate() {
+        let mut roster: Option<Vec<MemberSummary>> = None;
+
+        for message in recorder.messages.lock().unwrap().drain(..) {
+            match message {
+                Message::Text(text) => {
+                    if let ResponsePacket::Roster { members, .. } =
+                        serde_json::from_str(&text).unwrap()
+                    {
+                        assert!(members
+                            .iter()
+                            .enumerate()
+                            .all(|(index, member)| member.index == index));
+                        assert!(members.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+                        roster = Some(members);
+                    }
                 }
+                Message::Binary(data) => {
+                    let roster = roster.as_ref().unwrap();
+                    let id = u64::from_be_bytes(data[1..].try_into().unwrap());
+
+                    assert_eq!(id, roster[usize::from(data[0])].id);
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        assert!(roster.is_some());
+
+        if position >= departed {
+            assert_eq!(Some(final_roster.clone()), roster);
+        }
+    }
+}
+
+///
+/// Test a roster longer than the response limit is split into pages which each fit the limit.
+///
+#[tokio::test]
+async fn unit_roster_pages() {
+    const MAX_LENGTH: usize = 256;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+
+    client
+        .handle_create_room(&server, Some(64), RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    let mut clients = vec![];
+    for _ in 1..64 {
+        let (mut client, _) = create_client();
+
+        client
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+        clients.push(client);
+    }
+
+    //
+    // Test every page fits the limit, only the last page has no more pages after it, and the pages add up to the roster.
+    //
+
+    let server = server.read().await;
+    let room = &server.rooms[&room_id];
+    let pages = room.roster_pages(MAX_LENGTH);
+    let count = pages.len();
+    assert!(count > 1);
+
+    let mut members = vec![];
+    for (position, page) in pages.into_iter().enumerate() {
+        assert!(serde_json::to_string(&page).unwrap().len() <= MAX_LENGTH);
+
+        match page {
+            ResponsePacket::Roster {
+                members: page_members,
+                more,
+            } => {
+                assert!(!page_members.is_empty());
+                assert_eq!(position + 1 < count, more);
+
+                members.extend(page_members);
             }
-        }};
+            unknown => panic!("pattern doesn't match: {:?}", unknown),
+        }
     }
 
-    macro_rules! write_binary_message {
-        ($value:expr, $value2:expr) => {
-            $value.send(Message::Binary($value2)).unwrap()
-        };
+    assert_eq!(room.roster(), members);
+
+    //
+    // Test a roster within the limit is sent as a single page.
+    //
+
+    match &room.roster_pages(Client::MAX_RESPONSE_LENGTH)[..] {
+        [ResponsePacket::Roster {
+            members,
+            more: false,
+        }] => assert_eq!(&room.roster(), members),
+        unknown => panic!("pattern doesn't match: {:?}", unknown),
+    }
+}
+
+///
+/// Test responses which are too large are dropped and counted rather than panicking, and unsupported responses are skipped.
+///
+#[tokio::test]
+async fn unit_dropped_responses() {
+    let server = Server::new(Config::default()).unwrap();
+    let other_server = Server::new(Config::default()).unwrap();
+    let dropped_responses = server.read().await.dropped_responses.clone();
+
+    //
+    // Test a packet the wire format does not support is skipped without being counted as dropped.
+    //
+
+    let packet = ResponsePacket::Locked { locked: true };
+    assert!(client::encode(WireFormat::V1, &packet, &dropped_responses).is_none());
+    assert!(client::encode(WireFormat::V2, &packet, &dropped_responses).is_some());
+    assert_eq!(0, server.read().await.dropped_responses());
+
+    //
+    // Test a packet larger than the response limit is dropped, and one at the limit is sent.
+    //
+
+    let padding = r#"{"type":"get","key":"k","value":""}"#.len();
+    let packet = |length: usize| ResponsePacket::Get {
+        key: String::from("k"),
+        value: json!("x".repeat(length - padding)),
+    };
+
+    assert!(client::encode(
+        WireFormat::V2,
+        &packet(Client::MAX_RESPONSE_LENGTH),
+        &dropped_responses
+    )
+    .is_some());
+    assert!(client::encode(
+        WireFormat::V2,
+        &packet(Client::MAX_RESPONSE_LENGTH + 1),
+        &dropped_responses
+    )
+    .is_none());
+    assert_eq!(1, server.read().await.dropped_responses());
+
+    //
+    // Test the count belongs to the server, rather than to every server in the process.
+    //
+
+    assert_eq!(0, other_server.read().await.dropped_responses());
+
+    //
+    // Test the dropped responses are exported.
+    //
+
+    #[cfg(feature = "metrics")]
+    assert!(server
+        .read()
+        .await
+        .render_metrics()
+        .contains("relay_dropped_responses_total 1\n"));
+}
+
+///
+/// Test room snapshots report the traffic of each member.
+///
+#[tokio::test]
+async fn unit_room_snapshots() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, _) = create_client();
+    let (mut client_3, _) = create_client();
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    //
+    // Relay a scripted session.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![1, 1, 2, 3]))
+        .await;
+    client
+        .handle_message(&server, Message::Binary(vec![u8::MAX, 9, 9]))
+        .await;
+    client_2
+        .handle_message(&server, Message::Binary(vec![0, 5]))
+        .await;
+
+    //
+    // Test the counters match the session.
+    //
+
+    let snapshot = server.read().await.inspect_room(&room_id).unwrap();
+    assert_eq!(room_id, snapshot.id);
+    assert_eq!(3, snapshot.size);
+    assert_eq!(Some(0), snapshot.owner);
+    assert!(!snapshot.locked);
+
+    let counters: Vec<_> = snapshot
+        .members
+        .iter()
+        .map(|member| (member.index, member.bytes_sent, member.bytes_received))
+        .collect();
+    assert_eq!(vec![(0, 7, 2), (1, 2, 7), (2, 0, 3)], counters);
+
+    for member in &snapshot.members {
+        assert_eq!("127.0.0.1:0".parse::<SocketAddr>().unwrap(), member.address);
+        assert!(member.uptime >= Duration::from_millis(10));
+    }
+
+    assert!(snapshot.members[0].idle < Duration::from_millis(10));
+    assert!(snapshot.members[2].idle >= Duration::from_millis(10));
+
+    //
+    // Test listing and inspecting rooms.
+    //
+
+    assert_eq!(
+        vec![room_id.clone()],
+        server
+            .read()
+            .await
+            .list_rooms()
+            .into_iter()
+            .map(|room| room.id)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(None, server.read().await.inspect_room("missing"));
+
+    client_2.handle_leave_room(&server).await;
+
+    let snapshot = server.read().await.inspect_room(&room_id).unwrap();
+    assert_eq!(2, snapshot.members.len());
+    assert_eq!(
+        (1, 0, 3),
+        (
+            snapshot.members[1].index,
+            snapshot.members[1].bytes_sent,
+            snapshot.members[1].bytes_received
+        )
+    );
+}
+
+///
+/// Test parsing the command-line arguments.
+///
+#[test]
+fn unit_config() {
+    let parse = |args: &[&str]| Config::parse(args.iter().map(|arg| arg.to_string()));
+
+    assert_eq!(Ok(Config::default()), parse(&[]));
+
+    let config = parse(&["127.0.0.1", "8080", "example.com"]).unwrap();
+    assert_eq!("127.0.0.1", config.address);
+    assert_eq!("8080", config.port);
+    assert_eq!("example.com", config.auth.host);
+    assert_eq!(None, config.max_room_bandwidth);
+
+    let config = parse(&["127.0.0.1", "--max-room-bandwidth", "1000", "8080"]).unwrap();
+    assert_eq!("8080", config.port);
+    assert_eq!(Some(1000), config.max_room_bandwidth);
+
+    let config = parse(&["--max-room-ttl", "7200"]).unwrap();
+    assert_eq!(Some(7200), config.max_room_ttl);
+
+    let config = parse(&["--max-room-duration", "0"]).unwrap();
+    assert_eq!(0, config.max_room_duration);
+    assert_eq!(86400, Config::default().max_room_duration);
+
+    let config = parse(&["--test-console", "on"]).unwrap();
+    assert!(config.test_console);
+    assert!(!Config::default().test_console);
+
+    let config = parse(&["--long-polling", "on"]).unwrap();
+    assert!(config.long_polling);
+    assert!(!Config::default().long_polling);
+
+    let config = parse(&["--audit-quality", "on"]).unwrap();
+    assert!(config.audit_quality);
+    assert!(!Config::default().audit_quality);
+
+    let config = parse(&["--send-timeout", "100"]).unwrap();
+    assert_eq!(100, config.send_timeout);
+    assert_eq!(0, Config::default().send_timeout);
+    assert!(parse(&["--send-timeout", "-1"]).is_err());
+
+    let config = parse(&["--message-timeout", "250"]).unwrap();
+    assert_eq!(250, config.message_timeout);
+    assert_eq!(
+        Config::DEFAULT_MESSAGE_TIMEOUT,
+        Config::default().message_timeout
+    );
+    assert!(parse(&["--message-timeout", "soon"]).is_err());
+
+    let config = parse(&["--unjoined-timeout", "0"]).unwrap();
+    assert_eq!(0, config.unjoined_timeout);
+    assert_eq!(
+        Config::DEFAULT_UNJOINED_TIMEOUT,
+        Config::default().unjoined_timeout
+    );
+
+    let config = parse(&["--max-connections", "1000"]).unwrap();
+    assert_eq!(Some(1000), config.max_connections);
+
+    let config = parse(&["--max-creates-per-minute", "10"]).unwrap();
+    assert_eq!(Some(10), config.max_creates_per_minute);
+    assert_eq!(None, Config::default().max_creates_per_minute);
+    assert!(parse(&["--max-creates-per-minute", "0"]).is_err());
+
+    let config = parse(&[
+        "--max-failed-joins-per-minute",
+        "30",
+        "--max-global-failed-joins-per-minute",
+        "600",
+    ])
+    .unwrap();
+    assert_eq!(Some(30), config.max_failed_joins_per_minute);
+    assert_eq!(Some(600), config.max_global_failed_joins_per_minute);
+    assert_eq!(None, Config::default().max_failed_joins_per_minute);
+    assert_eq!(None, Config::default().max_global_failed_joins_per_minute);
+    assert!(parse(&["--max-failed-joins-per-minute", "0"]).is_err());
+
+    let config = parse(&["--max-rooms-per-connection", "20"]).unwrap();
+    assert_eq!(20, config.max_rooms_per_connection);
+    assert!(parse(&["--max-rooms-per-connection", "0"]).is_err());
+
+    let config = parse(&["--max-handshakes", "64"]).unwrap();
+    assert_eq!(64, config.max_handshakes);
+    assert_eq!(
+        Config::DEFAULT_MAX_HANDSHAKES,
+        Config::default().max_handshakes
+    );
+
+    let config = parse(&["--audit-log", "audit.log", "--deny-list", "deny.txt"]).unwrap();
+    assert_eq!(Some(Path::new("audit.log").to_path_buf()), config.audit_log);
+    assert_eq!(Some(Path::new("deny.txt").to_path_buf()), config.deny_list);
+
+    let config = parse(&["--templates", "templates.json"]).unwrap();
+    assert_eq!(
+        Some(Path::new("templates.json").to_path_buf()),
+        config.templates
+    );
+
+    let config = parse(&[
+        "--nodelay",
+        "off",
+        "--send-buffer-bytes",
+        "65536",
+        "--recv-buffer-bytes",
+        "32768",
+    ])
+    .unwrap();
+    assert!(!config.socket.nodelay);
+    assert_eq!(Some(65536), config.socket.send_buffer_bytes);
+    assert_eq!(Some(32768), config.socket.recv_buffer_bytes);
+    assert!(Config::default().socket.nodelay);
+
+    let config = parse(&["--null-origin", "on"]).unwrap();
+    assert!(config.auth.allow_null_origin);
+    assert!(!Config::default().auth.allow_null_origin);
+    assert!(parse(&["--null-origin", "yes"]).is_err());
+
+    #[cfg(feature = "metrics")]
+    {
+        let config = parse(&["--metrics-port", "9090"]).unwrap();
+        assert_eq!(Some(String::from("9090")), config.metrics_port);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    assert!(parse(&["--metrics-port", "9090"]).is_err());
+    assert_eq!(None, Config::default().metrics_port);
+
+    let config = parse(&["--workers", "4"]).unwrap();
+    assert_eq!(4, config.workers);
+    assert!(Config::default().workers > 0);
+    assert!(parse(&["--workers", "0"]).is_err());
+
+    assert_eq!(Runtime::Multi, Config::default().runtime);
+    assert_eq!(None, Config::default().worker_threads);
+
+    let config = parse(&["--runtime", "current"]).unwrap();
+    assert_eq!(Runtime::Current, config.runtime);
+
+    let config = parse(&["--runtime", "multi", "--worker-threads", "2"]).unwrap();
+    assert_eq!(Runtime::Multi, config.runtime);
+    assert_eq!(Some(2), config.worker_threads);
+
+    let config = parse(&["--worker-threads", "3"]).unwrap();
+    assert_eq!(Some(3), config.worker_threads);
+
+    assert!(parse(&["--runtime", "single"]).is_err());
+    assert!(parse(&["--worker-threads", "0"]).is_err());
+    assert_eq!(
+        Err(String::from(
+            "Invalid combination: --worker-threads requires --runtime multi"
+        )),
+        parse(&["--worker-threads", "2", "--runtime", "current"])
+    );
+
+    let config = parse(&["127.0.0.1", "--check-config", "8080"]).unwrap();
+    assert_eq!(Command::CheckConfig, config.command);
+    assert_eq!("8080", config.port);
+
+    assert_eq!(Command::Version, parse(&["--version"]).unwrap().command);
+    assert_eq!(Command::SelfTest, parse(&["--self-test"]).unwrap().command);
+    assert_eq!(Command::Run, Config::default().command);
+
+    let config = parse(&["--api-key", "first", "--api-key", "second"]).unwrap();
+    assert_eq!(vec!["first", "second"], config.auth.api_keys);
+
+    assert!(parse(&["--nodelay", "yes"]).is_err());
+    assert!(parse(&["--send-buffer-bytes", "0"]).is_err());
+    assert!(parse(&["--recv-buffer-bytes", "-1"]).is_err());
+    assert!(parse(&["--max-room-bandwidth"]).is_err());
+    assert!(parse(&["--max-room-bandwidth", "-1"]).is_err());
+    assert!(parse(&["--max-room-ttl", "1h"]).is_err());
+    assert!(parse(&["--max-connections", "0"]).is_err());
+    assert!(parse(&["--unknown", "1"]).is_err());
+    assert!(parse(&["127.0.0.1", "8080", "example.com", "extra"]).is_err());
+}
+
+///
+/// Test loading arguments from a config file, with the command line taking precedence.
+///
+#[test]
+fn unit_config_file() {
+    let parse = |args: &[&str]| Config::parse(args.iter().map(|arg| arg.to_string()));
+    let path = std::env::temp_dir().join(format!("relay-config-{}.toml", uuid::Uuid::new_v4()));
+    let file = path.to_str().unwrap();
+
+    fs::write(
+        &path,
+        r#"
+            # Relay
+            address = "127.0.0.1"
+            port = 8080
+            max-connections = 100
+            long-polling = true
+            runtime = "current"
+            api-key = ["first", "second"]
+
+            [templates.duel]
+            size = 2
+            timestamps = true
+        "#,
+    )
+    .unwrap();
+
+    //
+    // Values are read from the file.
+    //
+
+    let config = parse(&["--config", file]).unwrap();
+    assert_eq!("127.0.0.1", config.address);
+    assert_eq!("8080", config.port);
+    assert_eq!(Some(100), config.max_connections);
+    assert!(config.long_polling);
+    assert_eq!(Runtime::Current, config.runtime);
+    assert_eq!(vec!["first", "second"], config.auth.api_keys);
+
+    let template = config.inline_templates.get("duel").unwrap();
+    assert_eq!(Some(2), template.size);
+    assert!(template.options.timestamps);
+
+    //
+    // The command line overrides the file, regardless of order.
+    //
+
+    let config = parse(&[
+        "--max-connections",
+        "5",
+        "0.0.0.0",
+        "9090",
+        "--config",
+        file,
+    ])
+    .unwrap();
+    assert_eq!("0.0.0.0", config.address);
+    assert_eq!("9090", config.port);
+    assert_eq!(Some(5), config.max_connections);
+    assert_eq!(vec!["first", "second"], config.auth.api_keys);
+
+    let config = parse(&[
+        "--config",
+        file,
+        "--api-key",
+        "third",
+        "--api-key",
+        "fourth",
+    ])
+    .unwrap();
+    assert_eq!(vec!["third", "fourth"], config.auth.api_keys);
+
+    //
+    // Invalid files are rejected.
+    //
+
+    for (contents, message) in [
+        (
+            "max-connections = 0",
+            "Invalid value for --max-connections: 0",
+        ),
+        ("max-connections 100", "line 1"),
+        (
+            "max-connections = 1.5",
+            "Invalid value for max-connections: 1.5",
+        ),
+        ("api-key = [1]", "Invalid value for api-key: 1"),
+        ("nodelay = [true]", "Invalid value for nodelay: [true]"),
+        ("config = \"other.toml\"", "Unexpected argument: config"),
+        ("unknown = 1", "Unknown argument: --unknown"),
+        (
+            "[templates.duel]\nsize = \"two\"",
+            "Invalid value for templates",
+        ),
+        (
+            "[templates.duel]\nsize = 0",
+            "size 0 of template duel is not between 1 and 253",
+        ),
+    ] {
+        fs::write(&path, contents).unwrap();
+
+        let error = parse(&["--config", file]).unwrap_err();
+        assert!(error.starts_with("Invalid config "), "{}", error);
+        assert!(error.contains(message), "{}", error);
     }
 
-    macro_rules! write_message {
-        ($value:expr, $value2:expr) => {
-            let packet = $value2;
-            let serialized_packet = serde_json::to_string(&packet).unwrap();
-            $value.send(Message::Text(serialized_packet)).unwrap()
+    fs::remove_file(&path).unwrap();
+    assert!(parse(&["--config", file]).is_err());
+    assert!(parse(&["--config"]).is_err());
+}
+
+///
+/// Test validating the configuration without starting the server.
+///
+#[test]
+fn unit_validate_config() {
+    let directory = std::env::temp_dir();
+    let deny_list = directory.join(format!("relay-deny-{}.txt", uuid::Uuid::new_v4()));
+
+    assert_eq!(Ok(()), Config::default().validate());
+
+    //
+    // Test the bind address.
+    //
+
+    let config = Config {
+        address: String::from("localhost:8080"),
+        ..Default::default()
+    };
+    assert_eq!(
+        Err(String::from("Invalid address: localhost:8080")),
+        config.validate()
+    );
+
+    let config = Config {
+        port: String::from("65536"),
+        ..Default::default()
+    };
+    assert_eq!(Err(String::from("Invalid port: 65536")), config.validate());
+
+    #[cfg(feature = "metrics")]
+    {
+        let config = Config {
+            metrics_port: Some(String::from("metrics")),
+            ..Default::default()
         };
+        assert_eq!(
+            Err(String::from("Invalid metrics port: metrics")),
+            config.validate()
+        );
     }
 
-    macro_rules! read_message {
-        ($value:expr, $pattern:pat => $extracted_value:expr) => {
-            match serde_json::from_str(&$value.read().unwrap().clone().into_text().unwrap())
-                .unwrap()
-            {
-                $pattern => $extracted_value,
-                unknown => panic!("pattern doesn't match: {:?}", unknown),
+    //
+    // Test the audit log.
+    //
+
+    let config = Config {
+        audit_log: Some(directory.join("relay-audit.log")),
+        ..Default::default()
+    };
+    assert_eq!(Ok(()), config.validate());
+
+    let config = Config {
+        audit_log: Some(
+            directory
+                .join(uuid::Uuid::new_v4().to_string())
+                .join("audit.log"),
+        ),
+        ..Default::default()
+    };
+    assert!(config
+        .validate()
+        .unwrap_err()
+        .starts_with("Invalid audit log"));
+
+    //
+    // Test the deny list.
+    //
+
+    let config = Config {
+        deny_list: Some(deny_list.clone()),
+        ..Default::default()
+    };
+    assert!(config
+        .validate()
+        .unwrap_err()
+        .starts_with("Invalid deny list"));
+
+    fs::write(&deny_list, "10.0.0.0/33\n").unwrap();
+    assert_eq!(
+        Err(format!(
+            "Invalid deny list {}: Invalid network on line 1: 10.0.0.0/33",
+            deny_list.display()
+        )),
+        config.validate()
+    );
+
+    fs::write(&deny_list, "10.0.0.0/8\n").unwrap();
+    assert_eq!(Ok(()), config.validate());
+
+    fs::remove_file(&deny_list).unwrap();
+
+    //
+    // Test the templates.
+    //
+
+    let templates = directory.join(format!("relay-templates-{}.json", uuid::Uuid::new_v4()));
+    let config = Config {
+        templates: Some(templates.clone()),
+        ..Default::default()
+    };
+
+    fs::write(&templates, r#"{ "duel": { "size": "two" } }"#).unwrap();
+    assert!(config
+        .validate()
+        .unwrap_err()
+        .starts_with("Invalid templates"));
+
+    for size in [0, frame::MAX_ROOM_SIZE + 1, usize::from(frame::BROADCAST)] {
+        fs::write(
+            &templates,
+            format!(r#"{{ "duel": {{ "size": {} }} }}"#, size),
+        )
+        .unwrap();
+        assert!(config.validate().unwrap_err().ends_with(&format!(
+            "size {} of template duel is not between 1 and 253",
+            size
+        )));
+    }
+
+    fs::write(&templates, r#"{ "duel": { "size": 2 } }"#).unwrap();
+    assert_eq!(Ok(()), config.validate());
+
+    let config = Config {
+        inline_templates: Templates::parse(r#"{ "duel": { "size": 4 } }"#).unwrap(),
+        ..config
+    };
+    assert!(config
+        .validate()
+        .unwrap_err()
+        .ends_with("template duel is defined more than once"));
+
+    fs::remove_file(&templates).unwrap();
+}
+
+///
+/// Test authorizing handshake requests with an origin header or an API key.
+///
+#[test]
+fn unit_authorize() {
+    let request = |uri: &str, headers: &[(&str, &str)]| {
+        let mut request = Request::builder().uri(uri);
+
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        request.body(()).unwrap()
+    };
+
+    let config = AuthConfig {
+        host: String::from("example.com"),
+        api_keys: vec![String::from("first"), String::from("second")],
+        ..Default::default()
+    };
+
+    //
+    // Test a server without a host accepts every request.
+    //
+
+    let unrestricted = AuthConfig {
+        api_keys: config.api_keys.clone(),
+        ..Default::default()
+    };
+
+    assert_eq!(Ok(()), Server::authorize(&request("/", &[]), &unrestricted));
+    assert_eq!(
+        Ok(()),
+        Server::authorize(&request("/?key=wrong", &[]), &unrestricted)
+    );
+
+    //
+    // Test the origin header.
+    //
+
+    assert_eq!(
+        Ok(()),
+        Server::authorize(&request("/", &[("Origin", "https://example.com")]), &config)
+    );
+    assert_eq!(
+        Ok(()),
+        Server::authorize(
+            &request("/", &[("Origin", "https://www.example.com")]),
+            &config
+        )
+    );
+    assert_eq!(
+        Err(StatusCode::FORBIDDEN),
+        Server::authorize(&request("/", &[("Origin", "https://example.org")]), &config)
+    );
+    assert_eq!(
+        Err(StatusCode::BAD_REQUEST),
+        Server::authorize(&request("/", &[]), &config)
+    );
+
+    //
+    // Test the authorization header.
+    //
+
+    assert_eq!(
+        Ok(()),
+        Server::authorize(&request("/", &[("Authorization", "Bearer first")]), &config)
+    );
+    assert_eq!(
+        Ok(()),
+        Server::authorize(
+            &request("/", &[("Authorization", "Bearer second")]),
+            &config
+        )
+    );
+    assert_eq!(
+        Err(StatusCode::UNAUTHORIZED),
+        Server::authorize(&request("/", &[("Authorization", "Bearer third")]), &config)
+    );
+    assert_eq!(
+        Err(StatusCode::UNAUTHORIZED),
+        Server::authorize(&request("/", &[("Authorization", "Bearer firs")]), &config)
+    );
+    assert_eq!(
+        Err(StatusCode::BAD_REQUEST),
+        Server::authorize(&request("/", &[("Authorization", "Basic first")]), &config)
+    );
+
+    //
+    // Test the key query parameter.
+    //
+
+    assert_eq!(
+        Ok(()),
+        Server::authorize(&request("/?key=first", &[]), &config)
+    );
+    assert_eq!(
+        Ok(()),
+        Server::authorize(&request("/create?size=2&key=second", &[]), &config)
+    );
+    assert_eq!(
+        Err(StatusCode::UNAUTHORIZED),
+        Server::authorize(&request("/?key=", &[]), &config)
+    );
+
+    //
+    // Test a valid key bypasses the origin header, and an invalid key is never ignored.
+    //
+
+    assert_eq!(
+        Ok(()),
+        Server::authorize(
+            &request("/?key=first", &[("Origin", "https://example.org")]),
+            &config
+        )
+    );
+    assert_eq!(
+        Err(StatusCode::UNAUTHORIZED),
+        Server::authorize(
+            &request("/?key=third", &[("Origin", "https://example.com")]),
+            &config
+        )
+    );
+
+    //
+    // Test a server without keys rejects every key.
+    //
+
+    let without_keys = AuthConfig {
+        host: config.host.clone(),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        Err(StatusCode::UNAUTHORIZED),
+        Server::authorize(&request("/?key=first", &[]), &without_keys)
+    );
+}
+
+///
+/// Test the origin check against a matrix of origin headers.
+///
+#[test]
+fn unit_check_origin() {
+    let config = AuthConfig {
+        host: String::from("example.com"),
+        ..Default::default()
+    };
+
+    let check = |origin: &[u8], config: &AuthConfig| {
+        Server::check_origin(Some(&HeaderValue::from_bytes(origin).unwrap()), config)
+    };
+
+    let huge_origin = format!(
+        "https://{}.example.com",
+        "a".repeat(Server::MAX_HEADER_LENGTH)
+    );
+
+    let cases: [(&[u8], Result<(), StatusCode>); 16] = [
+        (b"https://example.com", Ok(())),
+        (b"https://EXAMPLE.com", Ok(())),
+        (b"https://a.b.example.com", Ok(())),
+        (b"http://example.com:8080", Ok(())),
+        (b"https://example.org", Err(StatusCode::FORBIDDEN)),
+        (b"https://badexample.com", Err(StatusCode::FORBIDDEN)),
+        (b"https://example.com.evil.org", Err(StatusCode::FORBIDDEN)),
+        (b"null", Err(StatusCode::FORBIDDEN)),
+        (b"https://exam\xffple.com", Err(StatusCode::BAD_REQUEST)),
+        (
+            huge_origin.as_bytes(),
+            Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+        ),
+        (b"https://", Err(StatusCode::BAD_REQUEST)),
+        (b"https:", Err(StatusCode::FORBIDDEN)),
+        (b"", Err(StatusCode::BAD_REQUEST)),
+        (b"http://192.0.2.1:8080", Err(StatusCode::FORBIDDEN)),
+        (b"https://xn--bcher-kva.example.com", Ok(())),
+        (
+            b"https://b\xc3\xbccher.example.com",
+            Err(StatusCode::BAD_REQUEST),
+        ),
+    ];
+
+    for (origin, expected) in cases {
+        assert_eq!(
+            expected,
+            check(origin, &config),
+            "{}",
+            String::from_utf8_lossy(origin)
+        );
+    }
+
+    //
+    // Test a missing origin header.
+    //
+
+    assert_eq!(
+        Err(StatusCode::BAD_REQUEST),
+        Server::check_origin(None, &config)
+    );
+
+    //
+    // Test the null origin is only accepted when it is allowed.
+    //
+
+    let allow_null_origin = AuthConfig {
+        allow_null_origin: true,
+        ..config.clone()
+    };
+
+    assert_eq!(Ok(()), check(b"null", &allow_null_origin));
+    assert_eq!(
+        Err(StatusCode::FORBIDDEN),
+        check(b"https://example.org", &allow_null_origin)
+    );
+
+    //
+    // Test IP-literal hosts with ports, and internationalized hosts in their ASCII form.
+    //
+
+    let ip_config = AuthConfig {
+        host: String::from("192.0.2.1"),
+        ..Default::default()
+    };
+
+    assert_eq!(Ok(()), check(b"http://192.0.2.1:8080", &ip_config));
+    assert_eq!(
+        Err(StatusCode::FORBIDDEN),
+        check(b"http://192.0.2.10:8080", &ip_config)
+    );
+
+    let idn_config = AuthConfig {
+        host: String::from("xn--bcher-kva.example"),
+        ..Default::default()
+    };
+
+    assert_eq!(Ok(()), check(b"https://xn--bcher-kva.example", &idn_config));
+    assert_eq!(
+        Ok(()),
+        check(b"https://www.xn--bcher-kva.example", &idn_config)
+    );
+}
+
+///
+/// Test selecting a subprotocol from the offered Sec-WebSocket-Protocol headers.
+///
+#[test]
+fn unit_select_subprotocol() {
+    let request = |headers: &[&str]| {
+        let mut request = Request::builder().uri("/");
+
+        for value in headers {
+            request = request.header("Sec-WebSocket-Protocol", *value);
+        }
+
+        request.body(()).unwrap()
+    };
+
+    assert_eq!(Ok(None), Server::select_subprotocol(&request(&[])));
+    assert_eq!(
+        Ok(Some(("relay", None))),
+        Server::select_subprotocol(&request(&["relay"]))
+    );
+    assert_eq!(
+        Ok(Some(("relay-v1", Some(WireFormat::V1)))),
+        Server::select_subprotocol(&request(&["relay-v1"]))
+    );
+    assert_eq!(
+        Ok(Some(("relay-v2", Some(WireFormat::V2)))),
+        Server::select_subprotocol(&request(&["chat,  relay-v2 ", "relay-v1"]))
+    );
+    assert_eq!(
+        Ok(Some(("relay-v1", Some(WireFormat::V1)))),
+        Server::select_subprotocol(&request(&["chat", "relay-v1, relay"]))
+    );
+    assert_eq!(
+        Err(StatusCode::BAD_REQUEST),
+        Server::select_subprotocol(&request(&["chat, RELAY"]))
+    );
+    assert_eq!(
+        Err(StatusCode::BAD_REQUEST),
+        Server::select_subprotocol(&request(&[""]))
+    );
+}
+
+///
+/// Test the lengths of the request URI and the checked headers are capped.
+///
+#[test]
+fn unit_check_limits() {
+    let request = |uri: &str, headers: &[(&str, &str)]| {
+        let mut request = Request::builder().uri(uri);
+
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        request.body(()).unwrap()
+    };
+
+    let long_path = format!("/room/{}", "a".repeat(Server::MAX_URI_LENGTH));
+    let long_value = "a".repeat(Server::MAX_HEADER_LENGTH + 1);
+
+    assert_eq!(Ok(()), Server::check_limits(&request("/", &[])));
+    assert_eq!(
+        Ok(()),
+        Server::check_limits(&request("/", &[("Origin", "https://example.com")]))
+    );
+    assert_eq!(
+        Err(StatusCode::URI_TOO_LONG),
+        Server::check_limits(&request(&long_path, &[]))
+    );
+    assert_eq!(
+        Err(StatusCode::URI_TOO_LONG),
+        Server::check_limits(&request(&format!("/?key={}", long_value.repeat(2)), &[]))
+    );
+    assert_eq!(
+        Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+        Server::check_limits(&request("/", &[("Origin", long_value.as_str())]))
+    );
+    assert_eq!(
+        Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+        Server::check_limits(&request("/", &[("Authorization", long_value.as_str())]))
+    );
+    assert_eq!(
+        Ok(()),
+        Server::check_limits(&request("/", &[("User-Agent", long_value.as_str())]))
+    );
+}
+
+///
+/// Test unicasts are never delivered to the wrong client while another client churns through a slot.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn unit_relay_churn() {
+    const CYCLES: usize = 2000;
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, _) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+
+    let room_id = server.read().await.rooms.keys().next().unwrap().clone();
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    recorder_2.messages.lock().unwrap().clear();
+
+    //
+    // Churn the third client through index 2 while frames tagged with their intended recipient are sent.
+    //
+
+    let churn = tokio::spawn({
+        let server = server.clone();
+
+        async move {
+            for _ in 0..CYCLES {
+                client_3
+                    .handle_join_room(&server, room_id.clone(), None, None, None)
+                    .await;
+                client_3.handle_leave_room(&server).await;
             }
-        };
+        }
+    });
+
+    for cycle in 0..CYCLES {
+        let tag = (cycle % 256) as u8;
+
+        client
+            .handle_message(&server, Message::Binary(vec![1, 1, tag]))
+            .await;
+        client
+            .handle_message(&server, Message::Binary(vec![2, 2, tag]))
+            .await;
+
+        tokio::task::yield_now().await;
     }
 
-    macro_rules! read_binary_message {
-        ($value:expr) => {
-            $value.read().unwrap().clone().into_data()
+    churn.await.unwrap();
+
+    //
+    // Test each client only received the frames tagged for it.
+    //
+
+    let frames = |recorder: &Recorder| -> Vec<Vec<u8>> {
+        let messages = recorder.messages.lock().unwrap();
+
+        messages
+            .iter()
+            .filter(|message| message.is_binary())
+            .map(|message| message.clone().into_data())
+            .collect()
+    };
+
+    let frames_2 = frames(&recorder_2);
+    assert_eq!(CYCLES, frames_2.len());
+    assert!(frames_2.iter().all(|data| data[..2] == [0, 1]));
+
+    let frames_3 = frames(&recorder_3);
+    assert!(frames_3.iter().all(|data| data[..2] == [0, 2]));
+}
+
+///
+/// Test frames are never relayed between members before each has been sent the other's join notification.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn unit_membership_epochs() {
+    const CYCLES: usize = 500;
+
+    let server = Server::new(Config::default()).unwrap();
+    let stalled = FaultyRecorder::default();
+    let mut client = Client::new(
+        next_connection_id(),
+        Arc::new(stalled.clone()),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+
+    let room_id = server.read().await.rooms.keys().next().unwrap().clone();
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    stalled.recorder.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+
+    //
+    // Stall the join notification to the owner, and wait for the other notifications to be sent.
+    //
+
+    stalled.stall();
+
+    let join = tokio::spawn({
+        let server = server.clone();
+        let room_id = room_id.clone();
+
+        async move {
+            client_3
+                .handle_join_room(&server, room_id, None, None, None)
+                .await;
+        }
+    });
+
+    loop {
+        let acknowledged = {
+            let server = server.read().await;
+            let members = &server.rooms[&room_id].members;
+
+            members.len() == 3 && members[1].knows(&members[2]) && members[2].knows(&members[1])
         };
+
+        if acknowledged {
+            break;
+        }
+
+        tokio::time::sleep(Duration::from_millis(1)).await;
     }
 
-    ///
-    /// Starts up a test server and returns the address to the server.
-    ///
-    async fn setup(host: Option<String>) -> SocketAddr {
-        let listener = TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("Failed to bind");
+    read_recorded_message!(recorder_2, ResponsePacket::Join { index: Some(2), .. } => ());
+    read_recorded_message!(recorder_3, ResponsePacket::Join { index: Some(2), .. } => ());
+
+    //
+    // Test the owner's frames skip the joined member, while the other member's frames reach it.
+    //
+
+    client
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 0]))
+        .await;
+    client
+        .handle_message(&server, Message::Binary(vec![2, 0]))
+        .await;
+    assert_eq!(vec![0, 0], read_recorded_binary_message!(recorder_2));
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 1]))
+        .await;
+    assert_eq!(vec![1, 1], read_recorded_binary_message!(stalled.recorder));
+    assert_eq!(vec![1, 1], read_recorded_binary_message!(recorder_3));
+
+    assert!(recorder_2.is_empty());
+    assert!(recorder_3.is_empty());
+
+    join.abort();
+
+    //
+    // Churn fresh clients through index 2 while the other members broadcast.
+    //
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    client
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+
+    let room_id = server.read().await.rooms.keys().next().unwrap().clone();
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    recorder.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+
+    let running = Arc::new(AtomicBool::new(true));
+    let broadcasters: Vec<_> = [client, client_2]
+        .into_iter()
+        .map(|mut client| {
+            let server = server.clone();
+            let running = running.clone();
+
+            tokio::spawn(async move {
+                while running.load(Ordering::Relaxed) {
+                    client
+                        .handle_message(&server, Message::Binary(vec![Room::BROADCAST]))
+                        .await;
+
+                    tokio::task::yield_now().await;
+                }
+            })
+        })
+        .collect();
 
-        let server = Server::new();
-        let socket_addr = listener.local_addr().unwrap();
+    let mut churned = vec![];
 
-        tokio::spawn(async move {
-            while let Ok((tcp_stream, _)) = listener.accept().await {
-                tokio::spawn(Server::handle_connection(
-                    tcp_stream,
-                    server.clone(),
-                    host.clone().unwrap_or(String::new()),
-                ));
+    for _ in 0..CYCLES {
+        let (mut client_3, recorder_3) = create_client();
+
+        client_3
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+        client_3
+            .handle_message(&server, Message::Binary(vec![Room::BROADCAST]))
+            .await;
+        client_3.handle_leave_room(&server).await;
+
+        churned.push(recorder_3);
+        tokio::task::yield_now().await;
+    }
+
+    running.store(false, Ordering::Relaxed);
+
+    for broadcaster in broadcasters {
+        broadcaster.await.unwrap();
+    }
+
+    //
+    // Test each churned client received its join packet before any frame.
+    //
+
+    for recorder_3 in &churned {
+        read_recorded_message!(recorder_3, ResponsePacket::Join { index: Some(2), .. } => ());
+    }
+
+    //
+    // Test the other members only received frames from index 2 between its join and leave notifications.
+    //
+
+    for recorder in [&recorder, &recorder_2] {
+        let mut joined = false;
+        let mut frames = 0;
+
+        for message in recorder.messages.lock().unwrap().iter() {
+            match message {
+                Message::Binary(data) if data[0] == 2 => {
+                    assert!(joined);
+                    frames += 1;
+                }
+                Message::Text(text) => match serde_json::from_str(text).unwrap() {
+                    ResponsePacket::Join { index: Some(2), .. } => joined = true,
+                    ResponsePacket::Leave { index: 2, .. } => joined = false,
+                    _ => {}
+                },
+                _ => {}
             }
-        });
+        }
+
+        assert_eq!(CYCLES, frames);
+    }
+}
+
+///
+/// Test parsing the deny list and matching addresses and rooms against it.
+///
+#[test]
+fn unit_deny_list() {
+    let deny_list = DenyList::parse(
+        "
+        # Networks.
+        192.0.2.1
+        198.51.100.0/24
+        10.0.0.0/8
+        2001:db8::/32
+        ::1
+
+        # Rooms.
+        lobby
+        vanity-*
+        ",
+    )
+    .unwrap();
+
+    //
+    // Test matching addresses and networks.
+    //
+
+    let ip = |ip: &str| ip.parse().unwrap();
+
+    assert!(deny_list.denies_ip(ip("192.0.2.1")));
+    assert!(!deny_list.denies_ip(ip("192.0.2.2")));
+    assert!(deny_list.denies_ip(ip("198.51.100.0")));
+    assert!(deny_list.denies_ip(ip("198.51.100.255")));
+    assert!(!deny_list.denies_ip(ip("198.51.101.0")));
+    assert!(deny_list.denies_ip(ip("10.255.0.1")));
+    assert!(!deny_list.denies_ip(ip("11.0.0.1")));
+    assert!(deny_list.denies_ip(ip("2001:db8:1::1")));
+    assert!(!deny_list.denies_ip(ip("2001:db9::1")));
+    assert!(deny_list.denies_ip(ip("::1")));
+    assert!(!deny_list.denies_ip(ip("::2")));
+
+    //
+    // Test matching rooms and room prefixes.
+    //
+
+    assert!(deny_list.denies_room("lobby"));
+    assert!(!deny_list.denies_room("lobby-2"));
+    assert!(deny_list.denies_room("vanity-"));
+    assert!(deny_list.denies_room("vanity-room"));
+    assert!(!deny_list.denies_room("vanity"));
+
+    //
+    // Test networks are masked, and a zero-length prefix matches everything.
+    //
+
+    let deny_list = DenyList::parse("203.0.113.77/24\n0.0.0.0/0").unwrap();
+    assert!(deny_list.denies_ip(ip("203.0.113.1")));
+    assert!(deny_list.denies_ip(ip("8.8.8.8")));
+    assert!(!deny_list.denies_ip(ip("::1")));
+
+    //
+    // Test invalid networks.
+    //
+
+    assert!(DenyList::parse("10.0.0.0/33").is_err());
+    assert!(DenyList::parse("::/129").is_err());
+    assert!(DenyList::parse("10.0.0.0/").is_err());
+    assert!(DenyList::parse("not-an-ip/8").is_err());
+    assert!(!DenyList::default().denies_ip(ip("127.0.0.1")));
+}
+
+///
+/// Test the server-wide bandwidth cap can only be overridden downward.
+///
+#[tokio::test]
+async fn unit_bandwidth() {
+    let server = Server::new(Config {
+        max_room_bandwidth: Some(1000),
+        ..Default::default()
+    })
+    .unwrap();
+
+    for (max_bandwidth, expected_max_bandwidth) in [
+        (None, Some(1000)),
+        (Some(500), Some(500)),
+        (Some(5000), Some(1000)),
+    ] {
+        let (mut client, recorder) = create_client();
+
+        client
+            .handle_create_room(
+                &server,
+                None,
+                RoomOptions {
+                    max_bandwidth,
+                    ..Default::default()
+                },
+                None,
+            )
+            .await;
+
+        let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+        assert_eq!(
+            expected_max_bandwidth,
+            server.read().await.rooms[&room_id].options.max_bandwidth
+        );
+    }
+}
+
+///
+/// Test room expiry is capped by the server, and cancelled when the room is removed early.
+///
+#[tokio::test]
+async fn unit_expiry() {
+    let server = Server::new(Config {
+        max_room_ttl: Some(1),
+        ..Default::default()
+    })
+    .unwrap();
+    let (mut client, recorder) = create_client();
+
+    //
+    // Test the time to live is capped by the server.
+    //
+
+    let created_at = Instant::now();
+
+    client
+        .handle_create_room(
+            &server,
+            None,
+            RoomOptions {
+                ttl_seconds: Some(3600),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+    let expires_at = server.read().await.rooms[&room_id].expires_at.unwrap();
+
+    assert!(expires_at <= created_at + Duration::from_secs(2));
+
+    //
+    // Test the expiry does not fire on a room reusing the identifier of a removed room.
+    //
+
+    client.handle_leave_room(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Left => ());
+
+    server.write().await.rooms.insert(
+        room_id.clone(),
+        Room::new(2, RoomOptions::default(), Instant::now()),
+    );
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(server.read().await.rooms.contains_key(&room_id));
+    assert!(recorder.is_empty());
+
+    //
+    // Test the server maximum applies to rooms without a time to live.
+    //
+
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(!server.read().await.rooms.contains_key(&room_id));
+
+    read_recorded_message!(recorder, ResponsePacket::Close { reason, .. } => assert!(matches!(reason, CloseReason::Expired)));
+    assert!(recorder.messages.lock().unwrap().remove(0).is_close());
+}
+
+///
+/// Test churning through thousands of connections does not leak rooms or senders.
+///
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn soak() {
+    //
+    // The number of cycles to churn through.
+    //
+
+    const CYCLES: usize = 5000;
+
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+    let server = test_server.server.clone();
+
+    //
+    // Churn through connections, creating, joining, leaving, and abruptly disconnecting.
+    //
+
+    for cycle in 0..CYCLES {
+        let mut socket = create_socket!(socket_addr);
+
+        match cycle % 5 {
+            0 => drop(socket),
+            1 => {
+                write_message!(
+                    socket,
+                    RequestPacket::Create {
+                        size: None,
+                        options: CreateOptions::default(),
+                        template: None,
+                        virtual_address: None,
+                    }
+                );
+                read_message!(socket, ResponsePacket::Create { .. } => ());
+
+                drop(socket);
+            }
+            2 => {
+                write_message!(
+                    socket,
+                    RequestPacket::Create {
+                        size: None,
+                        options: CreateOptions::default(),
+                        template: None,
+                        virtual_address: None,
+                    }
+                );
+
+                let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+                let mut socket_2 = create_socket!(socket_addr);
+
+                write_message!(
+                    socket_2,
+                    RequestPacket::Join {
+                        id: room_id,
+                        token: None,
+                        reservation: None,
+                        virtual_address: None,
+                    }
+                );
+                read_message!(socket_2, ResponsePacket::Join { .. } => ());
+
+                write_message!(socket, RequestPacket::Leave);
+                write_binary_message!(socket_2, vec![Room::BROADCAST, 1, 2, 3]);
+
+                drop(socket_2);
+                close_socket!(socket);
+            }
+            3 => {
+                write_message!(
+                    socket,
+                    RequestPacket::Create {
+                        size: None,
+                        options: CreateOptions::default(),
+                        template: None,
+                        virtual_address: None,
+                    }
+                );
+
+                let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+                let mut socket_2 = create_socket!(socket_addr);
+
+                write_message!(
+                    socket_2,
+                    RequestPacket::Join {
+                        id: room_id,
+                        token: None,
+                        reservation: None,
+                        virtual_address: None,
+                    }
+                );
+                read_message!(socket_2, ResponsePacket::Join { .. } => ());
+                read_message!(socket, ResponsePacket::Join { .. } => ());
+
+                close_socket!(socket_2);
+                close_socket!(socket);
+            }
+            _ => {
+                write_message!(
+                    socket,
+                    RequestPacket::Join {
+                        id: String::new(),
+                        token: None,
+                        reservation: None,
+                        virtual_address: None,
+                    }
+                );
+                read_message!(socket, ResponsePacket::Error { .. } => ());
+
+                close_socket!(socket);
+            }
+        }
+    }
+
+    //
+    // Test all rooms and senders were released.
+    //
+
+    let empty_stats = DebugStats {
+        rooms: 0,
+        senders: 0,
+        sender_references: 0,
+    };
+
+    for _ in 0..100 {
+        if server.read().await.debug_stats() == empty_stats {
+            break;
+        }
 
-        return socket_addr;
+        tokio::time::sleep(Duration::from_millis(50)).await;
     }
 
-    ///
-    /// Test origin header restrictions.
-    ///
-    #[tokio::test(flavor = "multi_thread")]
-    async fn origin() {
+    assert_eq!(empty_stats, server.read().await.debug_stats());
+}
+
+///
+/// Test churning through clients without sockets does not retain any senders.
+///
+#[tokio::test]
+async fn unit_soak() {
+    let server = Server::new(Config::default()).unwrap();
+    let mut recorders = vec![];
+
+    for cycle in 0..1000 {
+        let (mut client, recorder) = create_client();
+        let (mut client_2, recorder_2) = create_client();
+
+        client
+            .handle_create_room(&server, None, RoomOptions::default(), None)
+            .await;
+
+        let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+        client_2
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+
         //
-        // Setup test.
+        // Each sender is referenced by its client, its room, and its recorder.
         //
 
-        let socket_addr = setup(Some(String::from("example.com"))).await;
+        let stats = server.read().await.debug_stats();
+        assert_eq!(2, stats.senders);
+        assert_eq!(6, stats.sender_references);
+
+        if cycle % 2 == 0 {
+            client.handle_leave_room(&server).await;
+        } else {
+            server.write().await.rooms.remove(&room_id);
+        }
+
+        client
+            .handle_close(&server, RemovalReason::Disconnected)
+            .await;
+        client_2
+            .handle_close(&server, RemovalReason::Disconnected)
+            .await;
+
+        recorders.push(recorder);
+        recorders.push(recorder_2);
+    }
+
+    assert_eq!(0, server.read().await.debug_stats().rooms);
+
+    for recorder in recorders {
+        assert_eq!(1, Arc::strong_count(&recorder));
+    }
+}
+
+///
+/// Test the socket options are applied to accepted sockets.
+///
+#[tokio::test]
+async fn socket_options() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socket_addr = listener.local_addr().unwrap();
+
+    for (nodelay, buffer_bytes) in [(true, None), (false, Some(65536))] {
+        let _tcp_stream = tokio::net::TcpStream::connect(socket_addr).await.unwrap();
+        let (tcp_stream, _) = listener.accept().await.unwrap();
+
+        let socket_options = SocketOptions {
+            nodelay,
+            send_buffer_bytes: buffer_bytes,
+            recv_buffer_bytes: buffer_bytes,
+        };
+        socket_options.apply(&tcp_stream).unwrap();
+
+        let socket = socket2::SockRef::from(&tcp_stream);
+        assert_eq!(nodelay, socket.nodelay().unwrap());
+
+        if let Some(buffer_bytes) = buffer_bytes {
+            assert!(socket.send_buffer_size().unwrap() >= buffer_bytes);
+            assert!(socket.recv_buffer_size().unwrap() >= buffer_bytes);
+        }
+    }
+}
+
+///
+/// Test closing a room is reserved for its owner and replaces the individual leave packets.
+///
+#[tokio::test]
+async fn unit_close_room() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    client_1
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    recorder_1.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+
+    //
+    // Test members outside of a room and members who are not the owner cannot close it.
+    //
+
+    client_3.handle_close_room(&server, None).await;
+    read_recorded_message!(recorder_3, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotInRoom)));
+
+    client_2.handle_close_room(&server, None).await;
+    read_recorded_message!(recorder_2, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::NotOwner)));
+    assert!(server.read().await.rooms.contains_key(&room_id));
+
+    //
+    // Test each member only receives a close packet and a close frame.
+    //
+
+    client_1.handle_close_room(&server, None).await;
+
+    for recorder in [&recorder_1, &recorder_2] {
+        read_recorded_message!(recorder, ResponsePacket::Close { reason, message } => {
+            assert!(matches!(reason, CloseReason::Closed));
+            assert_eq!(None, message);
+        });
+        assert!(recorder.messages.lock().unwrap().remove(0).is_close());
+        assert!(recorder.is_empty());
+    }
+
+    //
+    // Test the room can no longer be joined.
+    //
+
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Error { message, .. } => assert!(matches!(message, Error::DoesNotExist)));
+}
+
+///
+/// Test closing a large room sends a linear number of messages, even when a member leaves at the same time.
+///
+#[tokio::test]
+async fn unit_close_large_room() {
+    const MEMBERS: usize = 100;
+
+    let server = Server::new(Config::default()).unwrap();
+    let mut clients: Vec<_> = (0..MEMBERS).map(|_| create_client()).collect();
+
+    clients[0]
+        .0
+        .handle_create_room(&server, Some(MEMBERS), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(clients[0].1, ResponsePacket::Create { id, .. } => id);
+
+    for (client, _) in &mut clients[1..] {
+        client
+            .handle_join_room(&server, room_id.clone(), None, None, None)
+            .await;
+    }
+
+    for (_, recorder) in &clients {
+        recorder.messages.lock().unwrap().clear();
+    }
+
+    //
+    // Test the close racing a leave sends at most a close packet, a close frame, and a leave to each member.
+    //
+
+    let (owner, rest) = clients.split_first_mut().unwrap();
+    tokio::join!(
+        owner.0.handle_close_room(&server, None),
+        rest[0].0.handle_leave_room(&server)
+    );
+
+    let count = |clients: &[(Client, Arc<Recorder>)]| {
+        clients
+            .iter()
+            .map(|(_, recorder)| recorder.messages.lock().unwrap().len())
+            .sum::<usize>()
+    };
+
+    let sent = count(&clients);
+    assert!(sent >= 2 * (MEMBERS - 1));
+    assert!(sent <= 3 * MEMBERS);
+    assert!(server.read().await.rooms.is_empty());
+
+    //
+    // Test the members disconnecting afterwards do not send any leave packets.
+    //
+
+    for (client, _) in &mut clients {
+        client
+            .handle_close(&server, RemovalReason::Disconnected)
+            .await;
+    }
+
+    assert_eq!(sent, count(&clients));
+}
+
+///
+/// Test the details of error packets are stripped of markup and control characters, and truncated.
+///
+#[tokio::test]
+async fn unit_error_detail() {
+    for (segment, expected) in [
+        ("<script>alert(1)</script>", "scriptalert(1)/script"),
+        ("\u{1b}[31mred\u{1b}[0m", "[31mred[0m"),
+        ("first\r\nsecond\tthird\0", "first  second third"),
+        ("\u{202e}txt.exe\u{2066}", "txt.exe"),
+        ("caf\u{e9} \u{1f600}", "caf\u{e9} \u{1f600}"),
+        ("", ""),
+    ] {
+        assert_eq!(expected, Client::sanitize_detail(segment));
+    }
+
+    let payload = format!("\n{}", "\u{1b}a".repeat(1 << 20));
+    let sanitized = Client::sanitize_detail(&payload);
+    assert_eq!(format!(" {}", "a".repeat(127)), sanitized);
+
+    //
+    // Test segments are sanitized separately and paired with the error code.
+    //
+
+    match Client::error_packet(Error::InvalidPacket, &["<b>\n", &payload]) {
+        ResponsePacket::Error { message, detail } => {
+            assert!(matches!(message, Error::InvalidPacket));
+            assert_eq!(Some(format!("b : {}", sanitized)), detail);
+        }
+        packet => panic!("Expected an error packet, got {:?}", packet),
+    }
+
+    assert!(matches!(
+        Client::error_packet(Error::IsFull, &[]),
+        ResponsePacket::Error {
+            message: Error::IsFull,
+            detail: None
+        }
+    ));
+
+    //
+    // Test user-controlled details are sanitized before they are sent.
+    //
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+
+    for (text, expected_message, expected_detail) in [
+        (
+            r#"{"type":"<img src=x onerror=alert(1)>"}"#,
+            Error::UnsupportedPacket,
+            "img src=x onerror=alert(1)",
+        ),
+        (
+            r#"{"type":"create","template":"\u001b]0;owned\u0007<i>"}"#,
+            Error::UnknownTemplate,
+            "]0;ownedi",
+        ),
+    ] {
+        client
+            .handle_message(&server, Message::Text(text.to_string()))
+            .await;
+        read_recorded_message!(recorder, ResponsePacket::Error { message, detail } => {
+            assert_eq!(expected_message, message);
+            assert_eq!(Some(expected_detail.to_string()), detail);
+        });
+    }
+}
 
-        //
-        // Test no origin header.
-        //
+///
+/// Test malformed packets are counted, and answered with a throttled error packet.
+///
+#[tokio::test]
+async fn unit_invalid_packets() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+
+    client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Create { .. } => ());
+
+    //
+    // Test packets with an unknown type are still answered with an unsupported packet error.
+    //
+
+    client
+        .handle_message(&server, Message::Text(r#"{"type": "creat"}"#.to_string()))
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::UnsupportedPacket));
+        assert_eq!(Some("creat".to_string()), detail);
+    });
+    assert_eq!(0, server.read().await.invalid_packets());
+
+    //
+    // Test the first malformed packet is answered with the serde error and the original text.
+    //
+
+    let text = r#"{"type": "join", "room": "abc"}"#;
+
+    client
+        .handle_message(&server, Message::Text(text.to_string()))
+        .await;
+    read_recorded_message!(recorder, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::InvalidPacket));
+
+        let detail = detail.unwrap();
+        assert!(detail.contains("missing field `id`"));
+        assert!(detail.ends_with(text));
+    });
+
+    //
+    // Test later malformed packets are counted without being answered within the throttle window.
+    //
+
+    for text in [
+        "{\"type\": \"create\", \"size\": \"four\"}".to_string(),
+        "{\"size\": 4}".to_string(),
+        "{\"type\": ".to_string(),
+        format!(
+            "{{\"type\": \"create\", \"size\": \"{}\"}}",
+            "x".repeat(1 << 10)
+        ),
+    ] {
+        client.handle_message(&server, Message::Text(text)).await;
+    }
 
-        assert!(connect(format!("ws://{}", socket_addr)).is_err());
+    assert!(recorder.is_empty());
+    assert_eq!(5, server.read().await.invalid_packets());
 
-        //
-        // Test empty origin header.
-        //
+    client.handle_stats(&server).await;
+    read_recorded_message!(recorder, ResponsePacket::Stats { members, .. } => assert_eq!(5, members[0].invalid_packets));
 
-        let mut request = format!("ws://{}", socket_addr)
-            .into_client_request()
-            .unwrap();
-        request
-            .headers_mut()
-            .insert("Origin", HeaderValue::from_static(""));
+    //
+    // Test binary frames are never treated as malformed packets.
+    //
 
-        assert!(connect(request).is_err());
+    client
+        .handle_message(&server, Message::Binary(b"{\"type\": ".to_vec()))
+        .await;
 
-        //
-        // Test invalid origin header value.
-        //
+    assert!(recorder.is_empty());
+    assert_eq!(5, server.read().await.invalid_packets());
+}
 
-        let mut request = format!("ws://{}", socket_addr)
-            .into_client_request()
-            .unwrap();
-        request
-            .headers_mut()
-            .insert("Origin", HeaderValue::from_static("null"));
+///
+/// Test a busy room cannot delay the relayed packets of a quiet room.
+///
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn unit_fair_scheduling() {
+    const HOT_MEMBERS: usize = 8;
+    const FRAMES: usize = 120;
+    const MAX_LATENCY: Duration = Duration::from_millis(20);
+
+    let server = Server::new(Config {
+        workers: 2,
+        ..Default::default()
+    })
+    .unwrap();
+
+    //
+    // Setup a busy room, and a quiet room with two members.
+    //
+
+    let (mut owner, recorder) = create_client();
+
+    owner
+        .handle_create_room(&server, Some(HOT_MEMBERS), RoomOptions::default(), None)
+        .await;
+    let hot_room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    let mut hot_clients = vec![(owner, recorder)];
+    for _ in 1..HOT_MEMBERS {
+        let (mut client, recorder) = create_client();
+
+        client
+            .handle_join_room(&server, hot_room_id.clone(), None, None, None)
+            .await;
+        hot_clients.push((client, recorder));
+    }
 
-        assert!(connect(request).is_err());
+    let (mut quiet_client, quiet_recorder) = create_client();
+    let (mut quiet_client_2, quiet_recorder_2) = create_client();
+
+    quiet_client
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    let quiet_room_id =
+        read_recorded_message!(quiet_recorder, ResponsePacket::Create { id, .. } => id);
+
+    quiet_client_2
+        .handle_join_room(&server, quiet_room_id, None, None, None)
+        .await;
+    quiet_recorder_2.messages.lock().unwrap().clear();
+
+    //
+    // Flood the busy room with broadcasts from every member.
+    //
+
+    let running = Arc::new(AtomicBool::new(true));
+    let flooders: Vec<_> = hot_clients
+        .into_iter()
+        .map(|(mut client, recorder)| {
+            let server = server.clone();
+            let running = running.clone();
+
+            tokio::spawn(async move {
+                let mut frames = 0;
+
+                while running.load(Ordering::Relaxed) {
+                    client
+                        .handle_message(&server, Message::Binary(vec![Room::BROADCAST; 64]))
+                        .await;
+                    recorder.messages.lock().unwrap().clear();
+
+                    frames += 1;
+                }
 
-        //
-        // Test non-matching root host.
-        //
+                frames
+            })
+        })
+        .collect();
 
-        let mut request = format!("ws://{}", socket_addr)
-            .into_client_request()
-            .unwrap();
-        request
-            .headers_mut()
-            .insert("Origin", HeaderValue::from_static(".com"));
+    //
+    // Test a 60 Hz stream in the quiet room is relayed promptly throughout the flood.
+    //
 
-        assert!(connect(request).is_err());
+    let mut latencies = vec![];
+    let mut interval = tokio::time::interval(Duration::from_micros(16_667));
 
-        //
-        // Test non-matching root host.
-        //
+    for frame in 0..FRAMES as u8 {
+        interval.tick().await;
 
-        let mut request = format!("ws://{}", socket_addr)
-            .into_client_request()
-            .unwrap();
-        request
-            .headers_mut()
-            .insert("Origin", HeaderValue::from_static("not-example.com"));
+        let started_at = Instant::now();
 
-        assert!(connect(request).is_err());
+        quiet_client
+            .handle_message(&server, Message::Binary(vec![1, frame]))
+            .await;
 
-        //
-        // Test matching root host.
-        //
+        latencies.push(started_at.elapsed());
+        assert_eq!(
+            vec![0, frame],
+            read_recorded_binary_message!(quiet_recorder_2)
+        );
+    }
 
-        let mut request = format!("ws://{}", socket_addr)
-            .into_client_request()
-            .unwrap();
-        request
-            .headers_mut()
-            .insert("Origin", HeaderValue::from_static("example.com"));
+    running.store(false, Ordering::Relaxed);
 
-        assert!(connect(request).is_ok());
+    let mut hot_frames = 0;
+    for flooder in flooders {
+        hot_frames += flooder.await.unwrap();
+    }
 
-        //
-        // Test matching subdomain host.
-        //
+    latencies.sort();
 
-        let mut request = format!("ws://{}", socket_addr)
-            .into_client_request()
-            .unwrap();
-        request
-            .headers_mut()
-            .insert("Origin", HeaderValue::from_static("subdomain.example.com"));
+    let latency = latencies[FRAMES * 99 / 100];
 
-        assert!(connect(request).is_ok());
-    }
+    assert!(hot_frames > FRAMES * 10, "{} frames", hot_frames);
+    assert!(latency < MAX_LATENCY, "{:?} p99 latency", latency);
+}
 
-    ///
-    /// Test all possible error responses (excluding the UUID collision).
-    ///
-    #[tokio::test(flavor = "multi_thread")]
-    async fn errors() {
-        //
-        // Setup test.
-        //
+///
+/// Test a storm of joins against a full room does not delay the relayed packets of its members.
+///
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn unit_join_storm() {
+    const JOINERS: usize = 1000;
+    const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+    const FRAMES: usize = 120;
+    const MAX_LATENCY: Duration = Duration::from_millis(50);
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client, recorder) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    client
+        .handle_create_room(&server, Some(2), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    recorder.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+
+    //
+    // Retry joining the full room from every joiner until the members stop relaying.
+    //
+
+    let running = Arc::new(AtomicBool::new(true));
+    let barrier = Arc::new(tokio::sync::Barrier::new(JOINERS + 1));
+    let joiners: Vec<_> = (0..JOINERS)
+        .map(|joiner| {
+            let server = server.clone();
+            let room_id = room_id.clone();
+            let running = running.clone();
+            let barrier = barrier.clone();
+
+            tokio::spawn(async move {
+                let recorder = Arc::new(Recorder::default());
+                let mut joiner = Client::new(
+                    next_connection_id(),
+                    recorder.clone(),
+                    SocketAddr::new(IpAddr::from([10, 0, (joiner >> 8) as u8, joiner as u8]), 0),
+                    vec![],
+                );
+                let mut errors = vec![];
 
-        let socket_addr = setup(None).await;
+                barrier.wait().await;
 
-        //
-        // Test creating an invalid sized room.
-        //
+                while running.load(Ordering::Relaxed) {
+                    joiner
+                        .handle_join_room(&server, room_id.clone(), None, None, None)
+                        .await;
+                    errors.push(read_recorded_message!(recorder, ResponsePacket::Error { message, .. } => message));
 
-        let mut socket = create_socket!(socket_addr);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
 
-        write_message!(socket, RequestPacket::Create { size: Some(0) });
-        read_message!(socket, ResponsePacket::Error { message } => assert!(matches!(message, Error::InvalidSize)));
+                errors
+            })
+        })
+        .collect();
 
-        write_message!(socket, RequestPacket::Create { size: Some(255) });
-        read_message!(socket, ResponsePacket::Error { message } => assert!(matches!(message, Error::InvalidSize)));
+    barrier.wait().await;
 
-        //
-        // Test creating a valid room.
-        //
+    //
+    // Test packets between the members are relayed promptly throughout the storm.
+    //
 
-        write_message!(socket, RequestPacket::Create { size: None });
+    let mut latencies = vec![];
 
-        let room_id = read_message!(socket, ResponsePacket::Create { id } => id);
+    for frame in 0..FRAMES as u8 {
+        let started_at = Instant::now();
 
-        //
-        // Test joining an non-existent room.
-        //
+        client
+            .handle_message(&server, Message::Binary(vec![1, frame]))
+            .await;
 
-        let mut socket_2 = create_socket!(socket_addr);
+        latencies.push(started_at.elapsed());
+        assert_eq!(vec![0, frame], read_recorded_binary_message!(recorder_2));
 
-        write_message!(socket_2, RequestPacket::Join { id: String::new() });
-        read_message!(socket_2, ResponsePacket::Error { message } => assert!(matches!(message, Error::DoesNotExist)));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
 
-        //
-        // Test joining the room.
-        //
+    running.store(false, Ordering::Relaxed);
 
-        write_message!(
-            socket_2,
-            RequestPacket::Join {
-                id: room_id.clone()
-            }
-        );
+    //
+    // Test every joiner was told the room is full, and its retries were rate limited.
+    //
 
-        read_message!(socket_2, ResponsePacket::Join { size } => assert_eq!(Some(1), size));
-        read_message!(socket, ResponsePacket::Join { size } => assert_eq!(None, size));
+    for joiner in joiners {
+        let errors = joiner.await.unwrap();
 
-        //
-        // Test joining a full room.
-        //
+        assert!(matches!(errors.first(), Some(Error::IsFull)));
+        assert!(errors
+            .iter()
+            .all(|error| matches!(error, Error::IsFull | Error::RateLimited)));
+    }
 
-        let mut socket_3 = create_socket!(socket_addr);
+    latencies.sort();
 
-        write_message!(
-            socket_3,
-            RequestPacket::Join {
-                id: room_id.clone()
-            }
-        );
-        read_message!(socket_3, ResponsePacket::Error { message } => assert!(matches!(message, Error::IsFull)));
+    let latency = latencies[FRAMES * 99 / 100];
 
-        //
-        // Test joining a removed room.
-        //
+    assert!(latency < MAX_LATENCY, "{:?} p99 latency", latency);
+    assert_eq!(2, server.read().await.rooms[&room_id].members.len());
+    assert!(recorder.is_empty());
+}
 
-        close_socket!(socket);
-        close_socket!(socket_2);
+///
+/// Test subscribing to sources filters broadcasts, while packets sent directly always arrive.
+///
+#[tokio::test]
+async fn unit_subscribe() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    client_1
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    for recorder in [&recorder_1, &recorder_2, &recorder_3] {
+        recorder.messages.lock().unwrap().clear();
+    }
 
-        write_message!(
-            socket_3,
-            RequestPacket::Join {
-                id: room_id.clone()
-            }
-        );
-        read_message!(socket_3, ResponsePacket::Error { message } => assert!(matches!(message, Error::DoesNotExist)));
+    //
+    // Test only broadcasts from the subscribed source arrive.
+    //
+
+    client_3
+        .handle_message(
+            &server,
+            Message::Text(r#"{"type": "subscribe", "sources": [0, 7]}"#.to_string()),
+        )
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Subscription { sources } => assert_eq!(Some(vec![0]), sources));
+
+    client_1
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 1]))
+        .await;
+    client_2
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 2]))
+        .await;
+
+    assert_eq!(vec![0, 1], read_recorded_binary_message!(recorder_3));
+    assert!(recorder_3.is_empty());
+
+    assert_eq!(vec![1, 2], read_recorded_binary_message!(recorder_1));
+    assert_eq!(vec![0, 1], read_recorded_binary_message!(recorder_2));
+
+    //
+    // Test packets sent directly to the subscriber are not filtered.
+    //
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![2, 3]))
+        .await;
+    assert_eq!(vec![1, 3], read_recorded_binary_message!(recorder_3));
+
+    //
+    // Test the subscription follows its source, rather than the index, once indices shift.
+    //
+
+    client_1.handle_leave_room(&server).await;
+    read_recorded_message!(recorder_3, ResponsePacket::Leave { index, .. } => assert_eq!(0, index));
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 4]))
+        .await;
+    assert!(recorder_3.is_empty());
+
+    //
+    // Test unsubscribing receives broadcasts from every source again.
+    //
+
+    client_3
+        .handle_message(
+            &server,
+            Message::Text(r#"{"type": "unsubscribe"}"#.to_string()),
+        )
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Subscription { sources } => assert_eq!(None, sources));
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 5]))
+        .await;
+    assert_eq!(vec![0, 5], read_recorded_binary_message!(recorder_3));
+}
 
-        //
-        // Test creating a single-occupant room.
-        //
+///
+/// Test relayed packets are never echoed to their sender, even when indices shift mid-flight.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn unit_echo_suppression() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    client_1
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    for recorder in [&recorder_1, &recorder_2, &recorder_3] {
+        recorder.messages.lock().unwrap().clear();
+    }
 
-        write_message!(socket_3, RequestPacket::Create { size: Some(1) });
+    //
+    // Pause a broadcast between resolving its recipients and sending it.
+    //
 
-        let room_id = read_message!(socket_3, ResponsePacket::Create { id } => id);
+    let relay_hook = RelayHook::install(&server).await;
 
-        //
-        // Test joining a single-occupant room.
-        //
+    let relay = tokio::spawn({
+        let server = server.clone();
 
-        let mut socket_4 = create_socket!(socket_addr);
+        async move {
+            client_2
+                .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 1]))
+                .await;
 
-        write_message!(socket_4, RequestPacket::Join { id: room_id });
-        read_message!(socket_4, ResponsePacket::Error { message } => assert!(matches!(message, Error::IsFull)));
+            client_2
+        }
+    });
 
-        close_socket!(socket_3);
-        close_socket!(socket_4);
-    }
+    relay_hook.paused().await;
 
-    ///
-    /// Test whether messages are leaking between two different rooms.
-    ///
-    #[tokio::test(flavor = "multi_thread")]
-    async fn leaks() {
-        //
-        // Setup test.
-        //
+    //
+    // Shift the indices while the broadcast is in flight.
+    //
 
-        let socket_addr = setup(None).await;
+    let leave = tokio::spawn({
+        let server = server.clone();
 
-        //
-        // Create four sockets, two sockets per room.
-        //
+        async move {
+            client_1.handle_leave_room(&server).await;
 
-        let mut socket_room1 = create_socket!(socket_addr);
-        let mut socket_second_room1 = create_socket!(socket_addr);
+            client_1
+        }
+    });
 
-        let mut socket_room2 = create_socket!(socket_addr);
-        let mut socket_second_room2 = create_socket!(socket_addr);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!leave.is_finished());
 
-        //
-        // Test creating two rooms.
-        //
+    relay_hook.resume();
 
-        write_message!(socket_room1, RequestPacket::Create { size: None });
-        write_message!(socket_room2, RequestPacket::Create { size: None });
+    let mut client_2 = relay.await.unwrap();
+    leave.await.unwrap();
 
-        let room_1_id = read_message!(socket_room1, ResponsePacket::Create { id } => id);
-        let room_2_id = read_message!(socket_room2, ResponsePacket::Create { id } => id);
+    RelayHook::remove(&server).await;
 
-        //
-        // Test joining room 1.
-        //
+    //
+    // Test the broadcast reached the members at the time it was resolved, but not its sender.
+    //
 
-        write_message!(
-            socket_second_room1,
-            RequestPacket::Join {
-                id: room_1_id.clone()
-            }
-        );
+    assert_eq!(vec![1, 1], read_recorded_binary_message!(recorder_1));
+    assert_eq!(vec![1, 1], read_recorded_binary_message!(recorder_3));
 
-        read_message!(socket_second_room1, ResponsePacket::Join { size } => assert_eq!(Some(1), size));
-        read_message!(socket_room1, ResponsePacket::Join { size } => assert_eq!(None, size));
+    read_recorded_message!(recorder_2, ResponsePacket::Leave { index, .. } => assert_eq!(0, index));
+    read_recorded_message!(recorder_3, ResponsePacket::Leave { index, .. } => assert_eq!(0, index));
+    assert!(recorder_2.is_empty());
+    assert!(recorder_3.is_empty());
 
-        //
-        // Test joining room 2.
-        //
+    //
+    // Test the new owner does not receive packets sent to the owner, or its own broadcasts.
+    //
 
-        write_message!(
-            socket_second_room2,
-            RequestPacket::Join {
-                id: room_2_id.clone()
-            }
-        );
+    client_2
+        .handle_message(&server, Message::Binary(vec![Room::OWNER, 2]))
+        .await;
+    client_2
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 3]))
+        .await;
 
-        read_message!(socket_second_room2, ResponsePacket::Join { size } => assert_eq!(Some(1), size));
-        read_message!(socket_room2, ResponsePacket::Join { size } => assert_eq!(None, size));
+    assert!(recorder_2.is_empty());
+    assert_eq!(vec![0, 3], read_recorded_binary_message!(recorder_3));
 
-        //
-        // Test broadcasting.
-        //
+    //
+    // Test sending to the current index of the sender is an intentional loopback.
+    //
 
-        write_binary_message!(socket_room1, vec![u8::MAX, 0, 1, 2, 3]);
-        assert_eq!(
-            vec![0, 0, 1, 2, 3],
-            read_binary_message!(socket_second_room1)
-        );
+    client_2
+        .handle_message(&server, Message::Binary(vec![0, 4]))
+        .await;
+    client_3
+        .handle_message(&server, Message::Binary(vec![1, 5]))
+        .await;
 
-        write_binary_message!(socket_room2, vec![u8::MAX, 4, 5, 6, 7]);
-        assert_eq!(
-            vec![0, 4, 5, 6, 7],
-            read_binary_message!(socket_second_room2)
-        );
+    assert_eq!(vec![0, 4], read_recorded_binary_message!(recorder_2));
+    assert_eq!(vec![1, 5], read_recorded_binary_message!(recorder_3));
+    assert!(recorder_2.is_empty());
+    assert!(recorder_3.is_empty());
+}
 
-        write_binary_message!(socket_second_room1, vec![u8::MAX, 0, 1, 2, 3]);
-        assert_eq!(vec![1, 0, 1, 2, 3], read_binary_message!(socket_room1));
+///
+/// Test rooms created from a template use its settings, unless the create packet overrides them.
+///
+#[tokio::test]
+async fn unit_templates() {
+    let path = std::env::temp_dir().join(format!("relay-templates-{}.json", uuid::Uuid::new_v4()));
+    fs::write(
+        &path,
+        r#"{
+            "duel": { "size": 2, "timestamps": true, "ttl_seconds": 600 }
+        }"#,
+    )
+    .unwrap();
+
+    let server = Server::new(Config {
+        templates: Some(path.clone()),
+        inline_templates: Templates::parse(
+            r#"{ "lobby": { "size": 8, "roster": true, "max_bandwidth": 4096 } }"#,
+        )
+        .unwrap(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let create = |template: &str, size: Option<usize>| {
+        json!({ "type": "create", "template": template, "size": size }).to_string()
+    };
+
+    //
+    // Test the effective settings of each template are sent in the create packet.
+    //
+
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+
+    client_1
+        .handle_message(&server, Message::Text(create("duel", None)))
+        .await;
+    let duel_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, size, options } => {
+        assert_eq!(2, size);
+        assert!(options.timestamps);
+        assert!(!options.roster);
+        assert_eq!(Some(600), options.ttl_seconds);
+
+        id
+    });
+
+    client_2
+        .handle_message(&server, Message::Text(create("lobby", Some(4))))
+        .await;
+    let lobby_id = read_recorded_message!(recorder_2, ResponsePacket::Create { id, size, options } => {
+        assert_eq!(4, size);
+        assert!(!options.timestamps);
+        assert!(options.roster);
+        assert_eq!(Some(4096), options.max_bandwidth);
+
+        id
+    });
+
+    //
+    // Test the rooms behave differently.
+    //
+
+    let (mut client_3, recorder_3) = create_client();
+    let (mut client_4, recorder_4) = create_client();
+
+    client_3
+        .handle_join_room(&server, duel_id, None, None, None)
+        .await;
+    read_recorded_message!(recorder_3, ResponsePacket::Join { .. } => ());
+    assert!(recorder_3.is_empty());
+
+    client_4
+        .handle_join_room(&server, lobby_id, None, None, None)
+        .await;
+    read_recorded_message!(recorder_4, ResponsePacket::Join { .. } => ());
+    read_recorded_message!(recorder_4, ResponsePacket::Roster { members, more: false } => assert_eq!(2, members.len()));
+
+    recorder_1.messages.lock().unwrap().clear();
+    recorder_2.messages.lock().unwrap().clear();
+
+    client_3
+        .handle_message(&server, Message::Binary(vec![0, 1]))
+        .await;
+    client_4
+        .handle_message(&server, Message::Binary(vec![0, 1]))
+        .await;
+
+    assert_eq!(10, read_recorded_binary_message!(recorder_1).len());
+    assert_eq!(vec![1, 1], read_recorded_binary_message!(recorder_2));
+
+    //
+    // Test options in the create packet override the template, even when they are disabled.
+    //
+
+    let (mut client_5, recorder_5) = create_client();
+
+    client_5
+        .handle_message(
+            &server,
+            Message::Text(
+                json!({ "type": "create", "template": "duel", "timestamps": false }).to_string(),
+            ),
+        )
+        .await;
+    read_recorded_message!(recorder_5, ResponsePacket::Create { size, options, .. } => {
+        assert_eq!(2, size);
+        assert!(!options.timestamps);
+        assert_eq!(Some(600), options.ttl_seconds);
+    });
+
+    //
+    // Test an unknown template is rejected.
+    //
+
+    let (mut client_6, recorder_6) = create_client();
+
+    client_6
+        .handle_message(&server, Message::Text(create("arena", None)))
+        .await;
+    read_recorded_message!(recorder_6, ResponsePacket::Error { message, detail } => {
+        assert!(matches!(message, Error::UnknownTemplate));
+        assert_eq!(Some("arena".to_string()), detail);
+    });
+
+    fs::remove_file(path).unwrap();
+}
 
-        write_binary_message!(socket_second_room2, vec![u8::MAX, 4, 5, 6, 7]);
-        assert_eq!(vec![1, 4, 5, 6, 7], read_binary_message!(socket_room2));
+///
+/// Sends a plain HTTP request for the given path and reads the whole response.
+///
+fn scrape(addr: SocketAddr, path: &str) -> String {
+    use std::io::{Read, Write};
 
-        //
-        // Test sending to each socket.
-        //
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
 
-        write_binary_message!(socket_room1, vec![1, 0, 1, 2, 3]);
-        assert_eq!(
-            vec![0, 0, 1, 2, 3],
-            read_binary_message!(socket_second_room1)
-        );
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
 
-        write_binary_message!(socket_room2, vec![1, 4, 5, 6, 7]);
-        assert_eq!(
-            vec![0, 4, 5, 6, 7],
-            read_binary_message!(socket_second_room2)
-        );
+///
+/// Test relayed frames are recorded in the latency histograms, and scraped in the OpenMetrics format.
+///
+#[cfg(feature = "latency-metrics")]
+#[tokio::test(flavor = "multi_thread")]
+async fn unit_latency_metrics() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, _) = create_client();
+    let (mut client_3, _) = create_client();
+
+    client_1
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    //
+    // Relay two unicast frames, one frame to the owner, and one broadcast to two members.
+    //
+
+    client_2
+        .handle_message(&server, Message::Binary(vec![0, 1]))
+        .await;
+    client_3
+        .handle_message(&server, Message::Binary(vec![1, 2]))
+        .await;
+    client_3
+        .handle_message(&server, Message::Binary(vec![Room::OWNER, 3]))
+        .await;
+    client_1
+        .handle_message(&server, Message::Binary(vec![Room::BROADCAST, 4]))
+        .await;
+
+    //
+    // Test frames which are not delivered are not recorded.
+    //
+
+    client_1
+        .handle_message(&server, Message::Binary(vec![Room::OWNER, 5]))
+        .await;
+    client_1
+        .handle_message(&server, Message::Binary(vec![100, 6]))
+        .await;
+
+    {
+        let server = server.read().await;
+        let unicast = server.delivery_latency(Delivery::Unicast);
+        let broadcast = server.delivery_latency(Delivery::Broadcast);
+
+        assert_eq!(3, unicast.count());
+        assert_eq!(2, broadcast.count());
+        assert_eq!(Histogram::BOUNDS.len() + 1, unicast.buckets().len());
+        assert!(unicast.sum() > Duration::ZERO);
+    }
 
-        write_binary_message!(socket_second_room1, vec![0, 0, 1, 2, 3]);
-        assert_eq!(vec![1, 0, 1, 2, 3], read_binary_message!(socket_room1));
+    //
+    // Test the scrape reports cumulative buckets for every bound.
+    //
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::serve_metrics(server.clone(), listener));
+
+    let response = tokio::task::spawn_blocking(move || scrape(addr, "/metrics"))
+        .await
+        .unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert!(head.contains(Server::METRICS_CONTENT_TYPE));
+    assert!(body.ends_with("# EOF\n"));
+
+    assert!(body.contains("# TYPE relay_delivery_latency_seconds histogram\n"));
+    assert!(body.contains("# UNIT relay_delivery_latency_seconds seconds\n"));
+    assert!(body.contains("relay_rooms 1\n"));
+    assert!(body.contains("relay_invalid_packets_total 0\n"));
+
+    let bounds: Vec<_> = body
+        .lines()
+        .filter_map(|line| {
+            line.strip_prefix("relay_delivery_latency_seconds_bucket{delivery=\"unicast\",le=\"")
+        })
+        .map(|line| line.split_once('"').unwrap().0)
+        .collect();
+    assert_eq!(
+        vec![
+            "0.00001", "0.000025", "0.00005", "0.0001", "0.00025", "0.0005", "0.001", "0.0025",
+            "0.005", "0.01", "0.025", "0.05", "0.1", "0.25", "0.5", "1.0", "+Inf"
+        ],
+        bounds
+    );
+
+    let counts: Vec<u64> = body
+        .lines()
+        .filter(|line| {
+            line.starts_with("relay_delivery_latency_seconds_bucket{delivery=\"broadcast\"")
+        })
+        .map(|line| line.rsplit_once(' ').unwrap().1.parse().unwrap())
+        .collect();
+    assert!(counts.windows(2).all(|window| window[0] <= window[1]));
+    assert_eq!(Some(&2), counts.last());
+
+    assert!(body.contains("relay_delivery_latency_seconds_count{delivery=\"unicast\"} 3\n"));
+    assert!(body.contains("relay_delivery_latency_seconds_count{delivery=\"broadcast\"} 2\n"));
+    assert!(body.contains("relay_delivery_latency_seconds_sum{delivery=\"unicast\"} "));
+
+    //
+    // Test other paths are not found.
+    //
+
+    let response = tokio::task::spawn_blocking(move || scrape(addr, "/"))
+        .await
+        .unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+}
 
-        write_binary_message!(socket_second_room2, vec![0, 4, 5, 6, 7]);
-        assert_eq!(vec![1, 4, 5, 6, 7], read_binary_message!(socket_room2));
+///
+/// Test the latency of a relayed frame is recorded once its send completes, and not when the send fails.
+///
+#[cfg(feature = "latency-metrics")]
+#[tokio::test]
+async fn unit_latency_on_completion() {
+    const DELAY: Duration = Duration::from_millis(50);
+
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+
+    let slow_recorder = Arc::new(Recorder::default());
+    let mut client_2 = Client::new(
+        next_connection_id(),
+        Arc::new(SlowRecorder {
+            recorder: slow_recorder.clone(),
+            delay: DELAY,
+        }),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    let sink = FaultyRecorder::default();
+    let mut client_3 = Client::new(
+        next_connection_id(),
+        Arc::new(sink.clone()),
+        "127.0.0.1:0".parse().unwrap(),
+        vec![],
+    );
+
+    client_1
+        .handle_create_room(&server, Some(3), RoomOptions::default(), None)
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+    client_3
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+
+    //
+    // Test a frame to a slow receiver is recorded with the time taken to send it.
+    //
+
+    client_1
+        .handle_message(&server, Message::Binary(vec![1, 1]))
+        .await;
+
+    read_recorded_binary_message!(slow_recorder);
+
+    {
+        let server = server.read().await;
+        let unicast = server.delivery_latency(Delivery::Unicast);
+
+        assert_eq!(1, unicast.count());
+        assert!(unicast.sum() >= DELAY);
+    }
 
-        //
-        // Test sending to oneself.
-        //
+    //
+    // Test a frame whose send fails is not recorded.
+    //
 
-        write_binary_message!(socket_room1, vec![0, 0, 1, 2, 3]);
-        assert_eq!(vec![0, 0, 1, 2, 3], read_binary_message!(socket_room1));
+    sink.fail(tungstenite::Error::ConnectionClosed);
+    client_1
+        .handle_message(&server, Message::Binary(vec![2, 2]))
+        .await;
 
-        write_binary_message!(socket_room2, vec![0, 4, 5, 6, 7]);
-        assert_eq!(vec![0, 4, 5, 6, 7], read_binary_message!(socket_room2));
+    assert_eq!(
+        1,
+        server
+            .read()
+            .await
+            .delivery_latency(Delivery::Unicast)
+            .count()
+    );
+}
 
-        write_binary_message!(socket_second_room1, vec![1, 0, 1, 2, 3]);
-        assert_eq!(
-            vec![1, 0, 1, 2, 3],
-            read_binary_message!(socket_second_room1)
-        );
+///
+/// Test the latency of a frame in a paced room includes the time it waited for the tick that delivered it.
+///
+#[cfg(feature = "latency-metrics")]
+#[tokio::test]
+async fn unit_paced_latency() {
+    const TICK: Duration = Duration::from_millis(100);
+
+    macro_rules! wait_for {
+        ($condition:expr) => {
+            while !$condition {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+    }
 
-        write_binary_message!(socket_second_room2, vec![1, 4, 5, 6, 7]);
-        assert_eq!(
-            vec![1, 4, 5, 6, 7],
-            read_binary_message!(socket_second_room2)
-        );
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let unicast = || async {
+        let server = server.read().await;
+        let unicast = server.delivery_latency(Delivery::Unicast);
+
+        (unicast.count(), unicast.sum())
+    };
+
+    client_1
+        .handle_create_room(
+            &server,
+            Some(2),
+            RoomOptions {
+                paced: true,
+                tick_rate: Some((Duration::from_secs(1).as_millis() / TICK.as_millis()) as u32),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id, None, None, None)
+        .await;
+    recorder_2.messages.lock().unwrap().clear();
+
+    //
+    // Deliver a first batch, so the next frame is sent just after a tick.
+    //
+
+    client_1
+        .handle_message(&server, Message::Binary(vec![1, 1]))
+        .await;
+    wait_for!(!recorder_2.messages.lock().unwrap().is_empty());
+    recorder_2.messages.lock().unwrap().clear();
+    wait_for!(unicast().await.0 == 1);
+
+    //
+    // Test a buffered frame is only recorded once its batch is sent, after waiting for the tick.
+    //
+
+    let (_, sum) = unicast().await;
+
+    client_1
+        .handle_message(&server, Message::Binary(vec![1, 2]))
+        .await;
+    assert_eq!(1, unicast().await.0);
+
+    wait_for!(!recorder_2.messages.lock().unwrap().is_empty());
+    wait_for!(unicast().await.0 == 2);
+    assert!(unicast().await.1 - sum >= TICK / 2);
+}
 
-        //
-        // Close all sockets
-        //
+///
+/// Test the dump describes every room and member, redacts secrets, and is served on the metrics port.
+///
+#[cfg(feature = "metrics")]
+#[tokio::test(flavor = "multi_thread")]
+async fn unit_dump() {
+    let server = Server::new(Config {
+        max_connections: Some(16),
+        auth: AuthConfig {
+            api_keys: vec![String::from("secret-api-key")],
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .unwrap();
+
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, _) = create_client();
+    let (mut client_3, recorder_3) = create_client();
+
+    //
+    // Create a paced room with a reservation and a stored value, and a second room.
+    //
+
+    client_1
+        .handle_create_room(
+            &server,
+            Some(4),
+            RoomOptions {
+                paced: true,
+                tick_rate: Some(20),
+                ..Default::default()
+            },
+            None,
+        )
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+
+    client_1.handle_reserve(&server, room_id.clone(), 1).await;
+    let reservation = loop {
+        if let Ok(ResponsePacket::Reserve { reservation, .. }) = serde_json::from_str(
+            &recorder_1
+                .messages
+                .lock()
+                .unwrap()
+                .remove(0)
+                .into_text()
+                .unwrap(),
+        ) {
+            break reservation;
+        }
+    };
+
+    client_1
+        .handle_set(&server, String::from("key"), json!("secret-value"))
+        .await;
+
+    client_3
+        .handle_create_room(&server, None, RoomOptions::default(), None)
+        .await;
+    let other_room_id = read_recorded_message!(recorder_3, ResponsePacket::Create { id, .. } => id);
+
+    let (session_client, _) = create_client();
+    server.write().await.sessions.insert(
+        String::from("secret-session-token"),
+        Arc::new(Session::new(session_client, Arc::default())),
+    );
+
+    //
+    // Test the dump contains the expected structure.
+    //
+
+    let mut output = vec![];
+    dump::write(&server, &mut output).await.unwrap();
+
+    let text = String::from_utf8(output).unwrap();
+    let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+
+    assert!(text.starts_with("{\n  \"server\": {\n    \"version\""));
+    assert_eq!(2, value["server"]["rooms"]);
+    assert_eq!(1, value["server"]["sessions"]);
+    assert_eq!(
+        json!({ "limit": 16, "used": 0 }),
+        value["server"]["limits"]["connections"]
+    );
+    assert_eq!(
+        Config::default().max_outbound_bytes,
+        value["server"]["limits"]["outboundBytes"]["limit"]
+    );
+
+    let mut room_ids = [room_id.clone(), other_room_id];
+    room_ids.sort();
+
+    let rooms = value["rooms"].as_array().unwrap();
+    assert_eq!(
+        room_ids.to_vec(),
+        rooms
+            .iter()
+            .map(|room| room["id"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    );
+
+    let room = rooms.iter().find(|room| room["id"] == room_id).unwrap();
+    assert_eq!(4, room["size"]);
+    assert_eq!(0, room["owner"]);
+    assert_eq!(1, room["storeKeys"]);
+    assert_eq!(true, room["options"]["paced"]);
+    assert_eq!(50, room["timers"]["tickIntervalMs"]);
+    assert!(
+        room["timers"]["expiresInMs"].as_u64().unwrap() <= Config::DEFAULT_MAX_ROOM_DURATION * 1000
+    );
+    assert_eq!(1, room["reservations"][0]["remaining"]);
+
+    let members = room["members"].as_array().unwrap();
+    assert_eq!(2, members.len());
+    assert_eq!(client_1.connection_id, members[0]["connectionId"]);
+    assert_eq!(client_2.connection_id, members[1]["connectionId"]);
+
+    for member in members {
+        for field in [
+            "index",
+            "queuedBytes",
+            "congestionSkips",
+            "sendFailures",
+            "congested",
+        ] {
+            assert!(member.get(field).is_some(), "missing {}", field);
+        }
+    }
 
-        close_socket!(socket_room1);
-        close_socket!(socket_room1);
-        close_socket!(socket_second_room1);
-        close_socket!(socket_second_room2);
+    //
+    // Test secrets and payloads are redacted.
+    //
+
+    for secret in [
+        "secret-api-key",
+        "secret-session-token",
+        "secret-value",
+        &reservation,
+    ] {
+        assert!(!text.contains(secret), "dump contains {}", secret);
     }
 
-    ///
-    /// Test sending data between clients in the room (includes broadcast messages and empty messages).
-    ///
-    #[tokio::test(flavor = "multi_thread")]
-    async fn communication() {
-        //
-        // The maximum number of clients to test.
-        //
+    //
+    // Test the dump is served on the metrics port.
+    //
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::serve_metrics(server.clone(), listener));
+
+    let response = tokio::task::spawn_blocking(move || scrape(addr, "/dump"))
+        .await
+        .unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert!(head.contains("application/json"));
+
+    let served: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(
+        value["rooms"].as_array().unwrap().len(),
+        served["rooms"].as_array().unwrap().len()
+    );
+
+    //
+    // Test an empty server dumps an empty list of rooms.
+    //
+
+    let mut output = vec![];
+    dump::write(&Server::new(Config::default()).unwrap(), &mut output)
+        .await
+        .unwrap();
+
+    let text = String::from_utf8(output).unwrap();
+    assert!(text.ends_with("  \"rooms\": []\n}\n"));
+    assert_eq!(
+        json!([]),
+        serde_json::from_str::<serde_json::Value>(&text).unwrap()["rooms"]
+    );
+}
 
-        const N: u8 = u8::MAX - 1;
+///
+/// Test plain HTTP requests on the relay port are answered with the health check and, when enabled, the test console.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn test_console() {
+    for test_console in [true, false] {
+        let test_server = TestServer::start_with_config(Config {
+            test_console,
+            ..Default::default()
+        })
+        .await;
+        let addr = test_server.addr;
 
         //
-        // Setup test.
+        // Test the console is only served when enabled.
         //
 
-        let socket_addr = setup(None).await;
+        let response = tokio::task::spawn_blocking(move || scrape(addr, "/"))
+            .await
+            .unwrap();
+
+        if test_console {
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            assert!(head.starts_with("HTTP/1.1 200 OK"));
+            assert!(head.contains("Content-Type: text/html"));
+            assert!(body.contains("new WebSocket("));
+            assert!(body.contains(r#"send({ type: "hello" })"#));
+        } else {
+            assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+        }
 
         //
-        // Create N clients, the first client creates a room, the rest join the room.
+        // Test the health check is always served, and other paths are not found.
         //
 
-        let mut sockets = vec![];
-        let mut room_id = String::new();
-
-        for expected_size in 0..usize::from(N) {
-            let mut socket = create_socket!(socket_addr);
+        let response = tokio::task::spawn_blocking(move || scrape(addr, "/healthz"))
+            .await
+            .unwrap();
 
-            if expected_size == 0 {
-                write_message!(
-                    socket,
-                    RequestPacket::Create {
-                        size: Some(N.into())
-                    }
-                );
-                read_message!(socket, ResponsePacket::Create { id } => room_id = id);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("Content-Type: application/json"));
+        assert_eq!(Server::HEALTH, body);
 
-                sockets.push(socket);
-            } else {
-                write_message!(
-                    socket,
-                    RequestPacket::Join {
-                        id: room_id.clone()
-                    }
-                );
-                sockets.push(socket);
-
-                let size = sockets.len() - 1;
-                for (index, socket) in sockets.iter_mut().enumerate() {
-                    if index == size {
-                        read_message!(socket, ResponsePacket::Join { size } => assert_eq!(Some(expected_size), size));
-                    } else {
-                        read_message!(socket, ResponsePacket::Join { size } => assert_eq!(None, size));
-                    }
-                }
-            }
-        }
+        let response = tokio::task::spawn_blocking(move || scrape(addr, "/console"))
+            .await
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
 
         //
-        // Test room bounds.
+        // Test websocket connections to the same path are still accepted.
         //
 
-        let mut socket = create_socket!(socket_addr);
-
+        let mut socket = create_socket!(addr);
         write_message!(
             socket,
-            RequestPacket::Join {
-                id: room_id.clone()
+            RequestPacket::Hello {
+                max_message_size: None,
+                chunk_size: None,
             }
         );
-        read_message!(socket, ResponsePacket::Error { message } => assert!(matches!(message, Error::IsFull)));
-
+        read_message!(socket, ResponsePacket::Hello { .. } => ());
         close_socket!(socket);
+    }
+}
 
-        //
-        // Test broadcasting.
-        //
+fn long_poll(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    content_type: &str,
+    body: &str,
+) -> (u16, String) {
+    use std::io::Read;
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        content_type,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head[9..12].parse().unwrap(), body.to_string())
+}
 
-        for expected_source in 0..N {
-            let source_socket = &mut sockets[usize::from(expected_source)];
+fn poll_messages(addr: SocketAddr, token: &str) -> Vec<serde_json::Value> {
+    let (status, body) = long_poll(addr, "GET", &format!("/lp/poll?token={}", token), "", "");
+    assert_eq!(200, status);
 
-            write_binary_message!(source_socket, vec![u8::MAX]);
-            write_binary_message!(source_socket, vec![u8::MAX, 0, 1, 2, 3]);
+    let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+    response["messages"].as_array().unwrap().clone()
+}
 
-            for expected_destination in 0..N {
-                if expected_destination == expected_source {
-                    continue;
-                }
+fn send_packet(addr: SocketAddr, token: &str, packet: RequestPacket) -> u16 {
+    let body = serde_json::to_string(&packet).unwrap();
+
+    long_poll(
+        addr,
+        "POST",
+        &format!("/lp/send?token={}", token),
+        "application/json",
+        &body,
+    )
+    .0
+}
 
-                let destination_socket = &mut sockets[usize::from(expected_destination)];
+fn connect_session(addr: SocketAddr) -> String {
+    let (status, body) = long_poll(addr, "POST", "/lp/connect", "application/json", "");
+    assert_eq!(200, status);
 
-                assert_eq!(
-                    vec![expected_source],
-                    read_binary_message!(destination_socket)
-                );
-                assert_eq!(
-                    vec![expected_source, 0, 1, 2, 3],
-                    read_binary_message!(destination_socket)
-                );
-            }
-        }
+    let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+    response["token"].as_str().unwrap().to_string()
+}
 
-        //
-        // Test sending to each other.
-        //
+///
+/// Test a long-polling client joins a room and relays frames with a websocket client.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn long_polling() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start_with_config(Config {
+        long_polling: true,
+        ..Default::default()
+    })
+    .await;
+    let addr = test_server.addr;
+
+    let mut socket = create_socket!(addr);
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: Some(2),
+            template: None,
+            options: CreateOptions::default(),
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+    //
+    // Test a session joins the room with a control packet.
+    //
+
+    let token = connect_session(addr);
+    assert_eq!(
+        204,
+        send_packet(
+            addr,
+            &token,
+            RequestPacket::Join {
+                id: room_id,
+                token: None,
+                reservation: None,
+                virtual_address: None,
+            }
+        )
+    );
+    read_message!(socket, ResponsePacket::Join { index: Some(1), .. } => ());
+
+    let messages = poll_messages(addr, &token);
+    match serde_json::from_value(messages[0].clone()).unwrap() {
+        ResponsePacket::Join { index: Some(1), .. } => {}
+        unknown => panic!("pattern doesn't match: {:?}", unknown),
+    }
 
-        for expected_source in 0..N {
-            for expected_destination in 0..N {
-                let source_socket = &mut sockets[usize::from(expected_source)];
+    //
+    // Test frames are relayed in both directions as base64.
+    //
+
+    write_binary_message!(socket, vec![frame::BROADCAST, 1, 2, 3]);
+    assert_eq!(vec![json!("AAECAw==")], poll_messages(addr, &token));
+
+    let path = format!("/lp/send?token={}", token);
+    assert_eq!(
+        (204, String::new()),
+        long_poll(addr, "POST", &path, "text/plain", "AAQFBg==")
+    );
+    assert_eq!(vec![1, 4, 5, 6], read_binary_message!(socket));
+
+    assert_eq!(
+        400,
+        long_poll(addr, "POST", &path, "text/plain", "AAQFBg").0
+    );
+
+    //
+    // Test unknown and missing tokens are rejected.
+    //
+
+    assert_eq!(
+        404,
+        long_poll(addr, "GET", "/lp/poll?token=unknown", "", "").0
+    );
+    assert_eq!(400, long_poll(addr, "GET", "/lp/poll", "", "").0);
+    assert_eq!(404, long_poll(addr, "GET", "/lp/unknown", "", "").0);
+
+    //
+    // Test leaving through the session is announced to the room.
+    //
+
+    assert_eq!(204, send_packet(addr, &token, RequestPacket::Leave));
+    read_message!(
+        socket,
+        ResponsePacket::Leave {
+            index: 1,
+            reason: Some(RemovalReason::Left),
+        } => ()
+    );
+
+    close_socket!(socket);
+
+    //
+    // Test long polling is not served unless enabled.
+    //
+
+    let test_server = TestServer::start().await;
+    assert_eq!(
+        404,
+        long_poll(
+            test_server.addr,
+            "POST",
+            "/lp/connect",
+            "application/json",
+            ""
+        )
+        .0
+    );
+}
 
-                write_binary_message!(source_socket, vec![expected_destination]);
-                write_binary_message!(source_socket, vec![expected_destination, 0, 1, 2, 3]);
+///
+/// Test a long-polling session which misses its polls is removed from its room.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn long_polling_expiry() {
+    macro_rules! wait_for {
+        ($condition:expr) => {
+            while !$condition {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+    }
 
-                let destination_socket = &mut sockets[usize::from(expected_destination)];
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start_with_config(Config {
+        long_polling: true,
+        ..Default::default()
+    })
+    .await;
+    let addr = test_server.addr;
+    let server = test_server.server.clone();
+    let clock = ManualClock::install(&server).await;
+
+    let mut socket = create_socket!(addr);
+    write_message!(
+        socket,
+        RequestPacket::Create {
+            size: Some(2),
+            template: None,
+            options: CreateOptions::default(),
+            virtual_address: None,
+        }
+    );
+    let room_id = read_message!(socket, ResponsePacket::Create { id, .. } => id);
+
+    let token = connect_session(addr);
+    send_packet(
+        addr,
+        &token,
+        RequestPacket::Join {
+            id: room_id,
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        },
+    );
+    read_message!(socket, ResponsePacket::Join { index: Some(1), .. } => ());
+
+    //
+    // Test the session expires once no request is made for the expiry.
+    //
+
+    wait_for!({
+        clock.advance(Session::EXPIRY);
+        !server.read().await.sessions.contains_key(&token)
+    });
+
+    read_message!(
+        socket,
+        ResponsePacket::Leave {
+            index: 1,
+            reason: Some(RemovalReason::TimedOut),
+        } => ()
+    );
+
+    let path = format!("/lp/poll?token={}", token);
+    assert_eq!(404, long_poll(addr, "GET", &path, "", "").0);
+
+    close_socket!(socket);
+}
 
-                assert_eq!(
-                    vec![expected_source],
-                    read_binary_message!(destination_socket)
-                );
-                assert_eq!(
-                    vec![expected_source, 0, 1, 2, 3],
-                    read_binary_message!(destination_socket)
-                );
-            }
+///
+/// Test a capture of a room is announced, and its frames are replayed into a new room with their original timing.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn capture() {
+    //
+    // Setup test.
+    //
+
+    let test_server = TestServer::start().await;
+    let socket_addr = test_server.addr;
+    let server = test_server.server.clone();
+    let path = std::env::temp_dir().join(format!("relay-capture-{}.bin", uuid::Uuid::new_v4()));
+
+    let mut socket_1 = create_socket!(socket_addr);
+    let mut socket_2 = create_socket!(socket_addr);
+    let mut socket_3 = create_socket!(socket_addr);
+
+    write_message!(
+        socket_1,
+        RequestPacket::Create {
+            size: Some(3),
+            options: CreateOptions::default(),
+            template: None,
+            virtual_address: None,
         }
+    );
+    let room_id = read_message!(socket_1, ResponsePacket::Create { id, .. } => id);
+
+    write_message!(
+        socket_2,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
 
-        //
-        // Close host and N clients.
-        //
+    //
+    // Test the capture is announced to the members, and to members who join during it.
+    //
 
-        for _ in 0..N {
-            let mut socket = sockets.remove(0);
-            close_socket!(socket);
+    Server::start_capture(&server, &room_id, &path, CaptureLimits::default())
+        .await
+        .unwrap();
 
-            for socket in &mut sockets {
-                read_message!(socket, ResponsePacket::Leave { index } => assert_eq!(0, index));
-            }
+    assert!(
+        Server::start_capture(&server, &room_id, &path, CaptureLimits::default())
+            .await
+            .is_err()
+    );
+    assert!(
+        Server::start_capture(&server, "unknown", &path, CaptureLimits::default())
+            .await
+            .is_err()
+    );
+
+    read_message!(socket_1, ResponsePacket::Capture { active } => assert!(active));
+    read_message!(socket_2, ResponsePacket::Capture { active } => assert!(active));
+
+    write_message!(
+        socket_3,
+        RequestPacket::Join {
+            id: room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
         }
+    );
+    read_message!(socket_3, ResponsePacket::Join { .. } => ());
+    read_message!(socket_3, ResponsePacket::Capture { active } => assert!(active));
+    read_message!(socket_1, ResponsePacket::Join { .. } => ());
+    read_message!(socket_2, ResponsePacket::Join { .. } => ());
 
-        //
-        // Test if room was removed.
-        //
+    //
+    // Relay a unicast frame, followed by a broadcast after a delay.
+    //
 
-        let mut socket = create_socket!(socket_addr);
+    write_binary_message!(socket_1, vec![2, 10, 20]);
+    assert_eq!(vec![0, 10, 20], read_binary_message!(socket_3));
 
-        write_message!(
-            socket,
-            RequestPacket::Join {
-                id: room_id.clone()
-            }
-        );
-        read_message!(socket, ResponsePacket::Error { message } => assert!(matches!(message, Error::DoesNotExist)));
+    tokio::time::sleep(Duration::from_millis(100)).await;
 
-        close_socket!(socket);
+    write_binary_message!(socket_2, vec![Room::BROADCAST, 30]);
+    assert_eq!(vec![1, 30], read_binary_message!(socket_1));
+    assert_eq!(vec![1, 30], read_binary_message!(socket_3));
+
+    //
+    // Test stopping the capture is announced, and the file contains the relayed frames.
+    //
+
+    assert!(Server::stop_capture(&server, &room_id).await);
+    assert!(!Server::stop_capture(&server, &room_id).await);
+
+    for socket in [&mut socket_1, &mut socket_2, &mut socket_3] {
+        read_message!(socket, ResponsePacket::Capture { active } => assert!(!active));
     }
 
-    ///
-    /// Test the indices returned by the join and leave packets.
-    ///
-    #[tokio::test(flavor = "multi_thread")]
-    async fn indices() {
-        //
-        // The maximum number of clients to test.
-        //
+    let capture_file = CaptureFile::read(&path).unwrap();
+    assert_eq!(3, capture_file.size);
+    assert_eq!(2, capture_file.records.len());
+
+    let unicast = &capture_file.records[0];
+    assert_eq!(
+        (0, 2, 2),
+        (unicast.source, unicast.destination, unicast.length)
+    );
+    assert_eq!(Payload::Data(vec![10, 20]), unicast.payload);
+
+    let broadcast = &capture_file.records[1];
+    assert_eq!(
+        (1, Room::BROADCAST),
+        (broadcast.source, broadcast.destination)
+    );
+    assert_eq!(Payload::Data(vec![30]), broadcast.payload);
+    assert!(broadcast.offset - unicast.offset >= Duration::from_millis(100));
+
+    //
+    // Test the replayer recreates the senders, and relays their frames with the original timing.
+    //
+
+    let mut replayer = Replayer::connect(&test_server.url("/"), &capture_file)
+        .await
+        .unwrap();
+
+    let mut socket_4 = create_socket!(socket_addr);
+    write_message!(
+        socket_4,
+        RequestPacket::Join {
+            id: replayer.room_id.clone(),
+            token: None,
+            reservation: None,
+            virtual_address: None,
+        }
+    );
+    read_message!(socket_4, ResponsePacket::Join { index, .. } => assert_eq!(Some(2), index));
 
-        const N: u8 = u8::MAX - 1;
+    let replay = tokio::spawn(async move {
+        replayer.play(&capture_file.records).await.unwrap();
+        replayer
+    });
 
-        //
-        // Setup test.
-        //
+    assert_eq!(vec![0, 10, 20], read_binary_message!(socket_4));
+    let unicast_at = Instant::now();
 
-        let socket_addr = setup(None).await;
+    assert_eq!(vec![1, 30], read_binary_message!(socket_4));
+    assert!(unicast_at.elapsed() >= Duration::from_millis(50));
 
-        //
-        // Perform the test by either closing the connection or leaving the room.
-        //
+    replay.await.unwrap().close().await;
 
-        for method in ["close", "leave"] {
-            for direction in ["first", "last"] {
-                //
-                // Create N clients, the first client creates a room, the rest join the room.
-                //
+    close_socket!(socket_1);
+    close_socket!(socket_2);
+    close_socket!(socket_3);
+    close_socket!(socket_4);
 
-                let mut sockets = vec![];
-                let mut room_id = String::new();
+    fs::remove_file(path).unwrap();
+}
 
-                for expected_size in 0..usize::from(N) {
-                    let mut socket = create_socket!(socket_addr);
+///
+/// Test captures are bounded, and only record the hash of frames in opaque rooms.
+///
+#[tokio::test]
+async fn unit_capture_limits() {
+    let server = Server::new(Config::default()).unwrap();
+    let (mut client_1, recorder_1) = create_client();
+    let (mut client_2, recorder_2) = create_client();
+    let path = std::env::temp_dir().join(format!("relay-capture-{}.bin", uuid::Uuid::new_v4()));
+
+    let options = RoomOptions {
+        opaque: true,
+        ..Default::default()
+    };
+
+    client_1
+        .handle_create_room(&server, None, options, None)
+        .await;
+    let room_id = read_recorded_message!(recorder_1, ResponsePacket::Create { id, .. } => id);
+
+    client_2
+        .handle_join_room(&server, room_id.clone(), None, None, None)
+        .await;
+
+    //
+    // Test the capture ends once the next record would exceed the byte limit.
+    //
+
+    let limits = CaptureLimits {
+        max_bytes: 10 + 2 * 27,
+        ..Default::default()
+    };
+
+    Server::start_capture(&server, &room_id, &path, limits)
+        .await
+        .unwrap();
+
+    for recorder in [&recorder_1, &recorder_2] {
+        recorder.messages.lock().unwrap().clear();
+    }
 
-                    if expected_size == 0 {
-                        write_message!(
-                            socket,
-                            RequestPacket::Create {
-                                size: Some(N.into())
-                            }
-                        );
-                        read_message!(socket, ResponsePacket::Create { id } => room_id = id);
-
-                        sockets.push(socket);
-                    } else {
-                        write_message!(
-                            socket,
-                            RequestPacket::Join {
-                                id: room_id.clone()
-                            }
-                        );
-                        sockets.push(socket);
-
-                        let size = sockets.len() - 1;
-                        for (index, socket) in sockets.iter_mut().enumerate() {
-                            if index == size {
-                                read_message!(socket, ResponsePacket::Join { size } => assert_eq!(Some(expected_size), size));
-                            } else {
-                                read_message!(socket, ResponsePacket::Join { size } => assert_eq!(None, size));
-                            }
-                        }
-                    }
-                }
+    for value in 1..=3 {
+        client_1
+            .handle_message(&server, Message::Binary(vec![1, value, value]))
+            .await;
+    }
 
-                //
-                // Test leave room indices.
-                //
+    for value in 1..=3 {
+        assert_eq!(
+            vec![0, value, value],
+            read_recorded_binary_message!(recorder_2)
+        );
+    }
 
-                for mut expected_index in (0..usize::from(N)).rev() {
-                    let mut socket = if direction == "last" {
-                        sockets.pop().unwrap()
-                    } else {
-                        sockets.remove(0)
-                    };
+    read_recorded_message!(recorder_1, ResponsePacket::Capture { active } => assert!(!active));
+    read_recorded_message!(recorder_2, ResponsePacket::Capture { active } => assert!(!active));
 
-                    if direction == "first" {
-                        expected_index = 0;
-                    }
+    //
+    // Test stopping a full capture writes the file without announcing the end again.
+    //
 
-                    if method == "leave" {
-                        write_message!(socket, RequestPacket::Leave);
-                    } else if method == "close" {
-                        close_socket!(socket);
-                    }
+    assert!(Server::stop_capture(&server, &room_id).await);
+    assert!(recorder_1.is_empty());
+    assert!(recorder_2.is_empty());
 
-                    for socket in &mut sockets {
-                        read_message!(socket, ResponsePacket::Leave { index } => assert_eq!(expected_index, index));
-                    }
+    let capture_file = CaptureFile::read(&path).unwrap();
+    assert_eq!(2, capture_file.records.len());
 
-                    if method == "leave" {
-                        close_socket!(socket);
-                    }
-                }
-            }
-        }
+    for record in &capture_file.records {
+        assert!(matches!(record.payload, Payload::Hash(_)));
+        assert_eq!(vec![1, 0, 0], record.frame());
     }
+
+    assert_ne!(
+        capture_file.records[0].payload,
+        capture_file.records[1].payload
+    );
+
+    //
+    // Test payloads are truncated, and the capture ends after its maximum duration.
+    //
+
+    let options = RoomOptions::default();
+    let (mut client_3, recorder_3) = create_client();
+
+    client_3
+        .handle_create_room(&server, None, options, None)
+        .await;
+    let room_id = read_recorded_message!(recorder_3, ResponsePacket::Create { id, .. } => id);
+
+    let limits = CaptureLimits {
+        max_duration: Duration::from_millis(100),
+        max_payload: 2,
+        ..Default::default()
+    };
+
+    Server::start_capture(&server, &room_id, &path, limits)
+        .await
+        .unwrap();
+    read_recorded_message!(recorder_3, ResponsePacket::Capture { active } => assert!(active));
+
+    client_3
+        .handle_message(&server, Message::Binary(vec![0, 1, 2, 3, 4]))
+        .await;
+    assert_eq!(
+        vec![0, 1, 2, 3, 4],
+        read_recorded_binary_message!(recorder_3)
+    );
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    read_recorded_message!(recorder_3, ResponsePacket::Capture { active } => assert!(!active));
+    assert!(!Server::stop_capture(&server, &room_id).await);
+
+    let capture_file = CaptureFile::read(&path).unwrap();
+    assert_eq!(1, capture_file.records.len());
+    assert_eq!(4, capture_file.records[0].length);
+    assert_eq!(Payload::Data(vec![1, 2]), capture_file.records[0].payload);
+    assert_eq!(vec![0, 1, 2, 0, 0], capture_file.records[0].frame());
+
+    fs::remove_file(path).unwrap();
 }